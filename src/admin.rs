@@ -0,0 +1,445 @@
+// src/admin.rs
+
+use crate::auth;
+use crate::config::Config;
+use crate::state::ChatState;
+use crate::store::{AuditEntry, DbError, ScheduledMessage};
+use crate::websocket::db_call;
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+// Rows buffered between the database cursor and the HTTP response during an export
+const EXPORT_BUFFER_SIZE: usize = 256;
+
+/// A room in which the requested user currently has at least one connection.
+#[derive(Serialize)]
+pub struct UserRoom {
+    pub room: String,
+    pub client_ids: Vec<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct UserRooms {
+    pub username: String,
+    pub rooms: Vec<UserRoom>,
+}
+
+/// Load figures for one active room.
+#[derive(Serialize)]
+pub struct RoomMetrics {
+    pub room: String,
+    pub clients: usize,
+    /// Messages currently queued for the room's clients.
+    pub in_flight: usize,
+    /// The most messages that have been queued at once since the room opened.
+    pub high_water: usize,
+    /// Queued messages discarded because the room reached `ROOM_MAX_IN_FLIGHT`.
+    pub shed: u64,
+}
+
+#[derive(Serialize)]
+pub struct Metrics {
+    pub rooms: Vec<RoomMetrics>,
+    /// Stored messages that failed to decode since the server started (see `HISTORY_TOMBSTONES`).
+    pub undecodable_messages: u64,
+    /// Store calls that failed or timed out since the server started.
+    pub database_errors: u64,
+}
+
+/// Body of `POST /admin/schedule`.
+#[derive(Deserialize)]
+pub struct ScheduleRequest {
+    /// When to deliver, as an RFC 3339 timestamp.
+    pub at: DateTime<Utc>,
+    /// The room to announce in, or `*` for every active room.
+    pub room: String,
+    pub text: String,
+}
+
+/// Checks the `Authorization: Bearer <token>` header against `ADMIN_TOKEN`.
+/// The admin API is unavailable when no token is configured.
+pub(crate) fn authorize(headers: &HeaderMap, config: &Config) -> Result<(), (StatusCode, &'static str)> {
+    let Some(expected) = config.admin_token.as_deref() else {
+        return Err((StatusCode::FORBIDDEN, "Admin API is disabled (ADMIN_TOKEN is not set)"));
+    };
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !provided.is_some_and(|provided| auth::token_matches(provided, expected)) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid or missing admin token"));
+    }
+    Ok(())
+}
+
+/// `GET /admin/users/{username}/rooms`: every active room where `username` is connected, with their client ids.
+pub async fn user_rooms_handler(
+    headers: HeaderMap,
+    State(state): State<ChatState>,
+    Path(username): Path<String>,
+) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    // Only copy out the matching ids while the lock is held; sorting and serializing happen after it's released.
+    let mut rooms: Vec<UserRoom> = {
+        let rooms = state.rooms.lock().await;
+        rooms
+            .iter()
+            .filter_map(|(room_name, room)| {
+                let client_ids: Vec<Uuid> = room
+                    .clients
+                    .iter()
+                    .filter(|(_, client)| client.username.as_deref() == Some(username.as_str()))
+                    .map(|(id, _)| *id)
+                    .collect();
+                (!client_ids.is_empty()).then(|| UserRoom { room: room_name.clone(), client_ids })
+            })
+            .collect()
+    };
+    rooms.sort_by(|a, b| a.room.cmp(&b.room));
+
+    Json(UserRooms { username, rooms }).into_response()
+}
+
+/// `GET /admin/metrics`: outbound backlog figures for every active room, and counts of undecodable stored messages
+/// and failed database calls.
+pub async fn metrics_handler(headers: HeaderMap, State(state): State<ChatState>) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    let mut rooms: Vec<RoomMetrics> = {
+        let rooms = state.rooms.lock().await;
+        rooms
+            .iter()
+            .map(|(room_name, room)| RoomMetrics {
+                room: room_name.clone(),
+                clients: room.clients.len(),
+                in_flight: room.backlog.in_flight(),
+                high_water: room.backlog.high_water(),
+                shed: room.backlog.shed(),
+            })
+            .collect()
+    };
+    rooms.sort_by(|a, b| a.room.cmp(&b.room));
+
+    let database_errors = state.db_errors.load(Ordering::Relaxed);
+    Json(Metrics { rooms, undecodable_messages: state.store.undecodable_messages(), database_errors }).into_response()
+}
+
+/// `GET /admin/events`: the latest server events kept for replay (`EVENT_REPLAY_SIZE`), oldest first.
+pub async fn events_handler(headers: HeaderMap, State(state): State<ChatState>) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+    Json(state.events.recent()).into_response()
+}
+
+/// `GET /rooms/{room}/export`: the room's entire stored history as NDJSON, one `ServerMessage` per line, oldest first.
+/// Rows are streamed from the database straight into the response body, so memory use doesn't grow with the room.
+pub async fn export_room_handler(
+    headers: HeaderMap,
+    State(state): State<ChatState>,
+    Path(room_name): Path<String>,
+) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    let room_name = state.config.room_name_normalization.normalize(&room_name);
+    let room_name = state.config.room_aliases.resolve(&room_name).to_string();
+    println!("Exporting history of room '{}'.", room_name);
+    let (tx, rx) = mpsc::channel(EXPORT_BUFFER_SIZE);
+    let store = state.store.clone();
+    let export_room = room_name.clone();
+    tokio::spawn(async move { store.stream_room_messages(export_room, tx).await });
+
+    // A database error ends the body with an error, so the client sees a broken transfer instead of a short file.
+    let lines = stream::unfold((rx, state), |(mut rx, state)| async move {
+        let line = match rx.recv().await? {
+            Ok(message) => serde_json::to_string(&message)
+                .map(|json| json + "\n")
+                .map_err(axum::Error::new),
+            Err(e) => {
+                state.record_db_error(&e);
+                Err(axum::Error::new(e))
+            }
+        };
+        Some((line, (rx, state)))
+    });
+
+    let file_name: String = room_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    (
+        [
+            (CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (CONTENT_DISPOSITION, format!("attachment; filename=\"{}.ndjson\"", file_name)),
+        ],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct RecentRoomsParams {
+    /// How far back to look, in hours.
+    #[serde(default = "default_since_hours")]
+    pub since_hours: u32,
+}
+
+fn default_since_hours() -> u32 {
+    24
+}
+
+/// `GET /rooms/recent?since_hours=24`: rooms with stored activity in the window, whether or not anyone is connected now.
+pub async fn recent_rooms_handler(
+    headers: HeaderMap,
+    State(state): State<ChatState>,
+    Query(params): Query<RecentRoomsParams>,
+) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    let since = Utc::now() - Duration::hours(params.since_hours.into());
+    match db_call(&state, state.store.get_recent_rooms(since)).await {
+        Ok(rooms) => Json(rooms).into_response(),
+        Err(e) => db_error_response(&e),
+    }
+}
+
+// Largest page the audit and report endpoints will return
+const MAX_ADMIN_PAGE_SIZE: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct AuditParams {
+    /// Only entries for this room; all rooms when omitted.
+    pub room: Option<String>,
+    #[serde(default = "default_page")]
+    pub page: i64,
+    #[serde(default = "default_admin_page_size")]
+    pub page_size: i64,
+}
+
+fn default_page() -> i64 {
+    1
+}
+
+fn default_admin_page_size() -> i64 {
+    50
+}
+
+/// `GET /admin/audit?room=general&page=1&page_size=50`: recorded moderation actions, newest first.
+pub async fn audit_log_handler(
+    headers: HeaderMap,
+    State(state): State<ChatState>,
+    Query(params): Query<AuditParams>,
+) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    let page = params.page.max(1);
+    let page_size = params.page_size.clamp(1, MAX_ADMIN_PAGE_SIZE);
+    let entries = state.store.load_audit_log(params.room.as_deref(), page, page_size);
+    match db_call(&state, entries).await {
+        Ok(entries) => Json(entries).into_response(),
+        Err(e) => db_error_response(&e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReportsParams {
+    #[serde(default = "default_page")]
+    pub page: i64,
+    #[serde(default = "default_admin_page_size")]
+    pub page_size: i64,
+}
+
+/// `GET /admin/reports?page=1&page_size=50`: messages users flagged with `/report`, newest first.
+pub async fn reports_handler(
+    headers: HeaderMap,
+    State(state): State<ChatState>,
+    Query(params): Query<ReportsParams>,
+) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    let page = params.page.max(1);
+    let page_size = params.page_size.clamp(1, MAX_ADMIN_PAGE_SIZE);
+    match db_call(&state, state.store.load_reports(page, page_size)).await {
+        Ok(reports) => Json(reports).into_response(),
+        Err(e) => db_error_response(&e),
+    }
+}
+
+/// `PUT /admin/motd`: replaces the message of the day with the request body, sent to every client that connects from now on.
+pub async fn set_motd_handler(headers: HeaderMap, State(state): State<ChatState>, body: String) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+    let motd = body.trim().to_string();
+    if motd.is_empty() {
+        return (StatusCode::BAD_REQUEST, "The message of the day can't be empty; use DELETE to clear it").into_response();
+    }
+
+    println!("Message of the day set: {}", motd);
+    *state.motd.write().unwrap() = Some(motd.clone());
+    log_admin_action(&state, "set_motd", Some(motd)).await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// `DELETE /admin/motd`: stops sending a message of the day.
+pub async fn clear_motd_handler(headers: HeaderMap, State(state): State<ChatState>) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    println!("Message of the day cleared.");
+    *state.motd.write().unwrap() = None;
+    log_admin_action(&state, "clear_motd", None).await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// `PUT /admin/timestamp-format`: replaces the strftime format used for `{timestamp}` in plain-text rendering.
+/// A format chrono can't parse is rejected and the current one stays in place.
+pub async fn set_timestamp_format_handler(headers: HeaderMap, State(state): State<ChatState>, body: String) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+    let format = body.trim();
+    if let Err(e) = state.config.templates.timestamp_format.set(format) {
+        return (StatusCode::BAD_REQUEST, format!("Invalid timestamp format: {}", e)).into_response();
+    }
+
+    println!("Timestamp format set: {}", format);
+    log_admin_action(&state, "set_timestamp_format", Some(format.to_string())).await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// `PUT /admin/rooms/{room}`: registers a room, so clients can join it when `AUTO_CREATE_ROOMS` is off.
+pub async fn register_room_handler(headers: HeaderMap, State(state): State<ChatState>, Path(room_name): Path<String>) -> Response {
+    set_room_registered(headers, state, room_name, true).await
+}
+
+/// `DELETE /admin/rooms/{room}`: takes a room off the registry. Clients already in it stay connected.
+pub async fn unregister_room_handler(headers: HeaderMap, State(state): State<ChatState>, Path(room_name): Path<String>) -> Response {
+    set_room_registered(headers, state, room_name, false).await
+}
+
+async fn set_room_registered(headers: HeaderMap, state: ChatState, room_name: String, registered: bool) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    let room_name = state.config.room_name_normalization.normalize(&room_name);
+    let room_name = state.config.room_aliases.resolve(&room_name).to_string();
+    let store = state.store.set_room_registered(&room_name, registered);
+    if let Err(e) = db_call(&state, store).await {
+        return db_error_response(&e);
+    }
+
+    println!("Room '{}' {}.", room_name, if registered { "registered" } else { "unregistered" });
+    let action = if registered { "register_room" } else { "unregister_room" };
+    log_admin_action(&state, action, Some(room_name)).await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// `POST /admin/schedule`: queues an announcement for delivery at `at` to the clients in `room` at that time.
+/// Responds `201 Created` with the scheduled message, whose `id` cancels it.
+pub async fn schedule_message_handler(
+    headers: HeaderMap,
+    State(state): State<ChatState>,
+    Json(request): Json<ScheduleRequest>,
+) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    let text = request.text.trim();
+    if text.is_empty() {
+        return (StatusCode::BAD_REQUEST, "The announcement text must not be empty").into_response();
+    }
+    if request.at <= Utc::now() {
+        return (StatusCode::BAD_REQUEST, "The delivery time must be in the future").into_response();
+    }
+    let room = if request.room == "*" {
+        request.room
+    } else {
+        let room_name = state.config.room_name_normalization.normalize(&request.room);
+        state.config.room_aliases.resolve(&room_name).to_string()
+    };
+
+    let scheduled = ScheduledMessage { id: Uuid::new_v4(), room, text: text.to_string(), deliver_at: request.at };
+    if let Err(e) = db_call(&state, state.store.add_scheduled_message(&scheduled)).await {
+        return db_error_response(&e);
+    }
+
+    println!("Announcement {} scheduled for '{}' at {}.", scheduled.id, scheduled.room, scheduled.deliver_at);
+    let detail = format!("{} room={} at={}", scheduled.id, scheduled.room, scheduled.deliver_at.to_rfc3339());
+    log_admin_action(&state, "schedule_announcement", Some(detail)).await;
+    (StatusCode::CREATED, Json(scheduled)).into_response()
+}
+
+/// `DELETE /admin/schedule/{id}`: cancels an announcement that hasn't been delivered yet.
+pub async fn cancel_scheduled_message_handler(
+    headers: HeaderMap,
+    State(state): State<ChatState>,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+
+    match db_call(&state, state.store.cancel_scheduled_message(id)).await {
+        Err(e) => db_error_response(&e),
+        Ok(false) => (StatusCode::NOT_FOUND, "No pending announcement with that id").into_response(),
+        Ok(true) => {
+            println!("Scheduled announcement {} cancelled.", id);
+            log_admin_action(&state, "cancel_announcement", Some(id.to_string())).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+    }
+}
+
+/// Records an action taken through this API in the audit log.
+async fn log_admin_action(state: &ChatState, action: &str, detail: Option<String>) {
+    let entry = AuditEntry {
+        action: action.to_string(),
+        actor: "admin-api".to_string(),
+        target: None,
+        room: None,
+        timestamp: Utc::now(),
+        detail,
+    };
+    if db_call(state, state.store.log_audit(&entry)).await.is_err() {
+        eprintln!("Could not write '{}' to the audit log.", action);
+    }
+}
+
+/// The response to a request that failed because of the database: `503` if it timed out, `500` otherwise.
+fn db_error_response(error: &DbError) -> Response {
+    match error {
+        DbError::Timeout => (StatusCode::SERVICE_UNAVAILABLE, "The database is not responding").into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "The database could not complete the request").into_response(),
+    }
+}
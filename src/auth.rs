@@ -0,0 +1,71 @@
+// src/auth.rs
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// Separates a plain-text line from its signature: `<line> [sig:<hex HMAC-SHA256>]`.
+const SIGNATURE_PREFIX: &str = " [sig:";
+
+/// Hashes a username secret with Argon2 and a fresh salt, returning the PHC string to store.
+/// Hashing is deliberately slow, so it runs on the blocking pool instead of a runtime worker.
+pub async fn hash_secret(secret: String) -> Option<String> {
+    let result = tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default().hash_password(secret.as_bytes(), &salt).map(|hash| hash.to_string())
+    })
+    .await;
+    match result {
+        Ok(Ok(hash)) => Some(hash),
+        Ok(Err(e)) => {
+            eprintln!("Failed to hash secret: {}", e);
+            None
+        }
+        Err(e) => {
+            eprintln!("Secret hashing task failed: {}", e);
+            None
+        }
+    }
+}
+
+/// Checks a secret against a stored hash. Malformed hashes never verify.
+pub async fn verify_secret(secret: String, stored_hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&stored_hash)
+            .map(|hash| Argon2::default().verify_password(secret.as_bytes(), &hash).is_ok())
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+fn line_mac(line: &str, secret: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(line.as_bytes());
+    mac
+}
+
+/// Compares a token a client presented with the configured one in constant time, so response timing doesn't
+/// reveal how much of it was right.
+pub fn token_matches(provided: &str, expected: &str) -> bool {
+    provided.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// Appends an HMAC-SHA256 of `line` under `secret`, so plain-text clients that know the secret can
+/// tell a server-generated line from user content crafted to look like one.
+pub fn sign_line(line: &str, secret: &str) -> String {
+    let signature = hex::encode(line_mac(line, secret).finalize().into_bytes());
+    format!("{}{}{}]", line, SIGNATURE_PREFIX, signature)
+}
+
+/// Returns the line without its signature if it was signed by `sign_line` with the same secret.
+pub fn verify_signed_line<'a>(signed: &'a str, secret: &str) -> Option<&'a str> {
+    let (line, signature) = signed.strip_suffix(']')?.rsplit_once(SIGNATURE_PREFIX)?;
+    let signature = hex::decode(signature).ok()?;
+    line_mac(line, secret).verify_slice(&signature).ok()?;
+    Some(line)
+}
@@ -0,0 +1,797 @@
+// src/config.rs
+
+use crate::geoip::GeoIp;
+use crate::lru::DEFAULT_LRU_CAPACITY;
+use crate::models::ServerMessage;
+use crate::moderation::ContentModeration;
+use crate::outbound::{DropPolicy, ShedPolicy};
+use crate::state::IN_MEMORY_CACHE_SIZE;
+use crate::store::StorageBackend;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Usernames reserved for administrators when `RESERVED_USERNAMES` is unset.
+const DEFAULT_RESERVED_USERNAMES: &[&str] = &["admin", "system", "server", "mod"];
+
+/// Runtime configuration, read from environment variables once at startup.
+pub struct Config {
+    pub templates: DisplayTemplates,
+    /// Serve the built-in web client at `GET /`.
+    pub serve_client: bool,
+    /// When set, every chat message is also POSTed to this URL.
+    pub webhook_url: Option<String>,
+    /// Rooms where clients without a username may read the live stream and history (`*` = all rooms).
+    pub anon_read_rooms: Vec<String>,
+    /// How long a client may stay anonymous in rooms without anonymous read access; `None` disables the limit.
+    pub anon_timeout: Option<Duration>,
+    /// Most WebSocket connections the server holds at once; `None` disables the limit.
+    pub max_total_connections: Option<usize>,
+    /// Most connections a single room holds at once; `None` disables the limit.
+    pub room_max_clients: Option<usize>,
+    /// Addresses whose connections are refused.
+    pub banned_ips: Vec<IpAddr>,
+    /// How long a new connection has to send its upgrade request; `None` disables the limit.
+    pub handshake_timeout: Option<Duration>,
+    /// How long a named client may send nothing before the room is told it left; `None` disables the check.
+    pub heartbeat_timeout: Option<Duration>,
+    /// Rooms that only accept end-to-end encrypted messages (`*` = all rooms).
+    pub encrypted_rooms: Vec<String>,
+    pub text_macros: TextMacros,
+    /// How chat message content is cleaned up before it is broadcast and stored.
+    pub content_normalization: ContentNormalization,
+    /// Severity scoring of chat messages; `None` when no `PROFANITY_CATEGORIES` are configured.
+    pub content_moderation: Option<ContentModeration>,
+    /// Country lookup for connecting clients' IP addresses; `None` when `GEOIP_DB_PATH` is unset.
+    pub geoip: Option<GeoIp>,
+    /// Default for `/echo`: whether senders receive their own messages back.
+    pub echo_own_messages: bool,
+    /// Server-wide announcement sent to every client as it connects, until cleared through the admin API.
+    pub startup_motd: Option<String>,
+    /// Key for signing system lines sent to plain-text clients; unset sends them unsigned.
+    pub system_signing_secret: Option<String>,
+    /// Secret that grants administrator rights via `/admin <token>`; admin commands are disabled when unset.
+    pub admin_token: Option<String>,
+    /// How many of the most recent cached messages a client receives when they join.
+    pub join_replay_count: usize,
+    /// Whether clients may read stored history with `/history` and `/count`. Messages are persisted either way.
+    pub history_enabled: bool,
+    /// Whether `/history` lists joins and leaves alongside chat by default; `/history --with-presence` always does.
+    pub history_includes_presence: bool,
+    /// How long a graceful shutdown waits for connections to leave before closing them.
+    pub shutdown_drain: Duration,
+    /// How long to wait for a free connection from the database pool.
+    pub db_acquire_timeout: Duration,
+    /// Upper bound on any single database call made while serving a client.
+    pub db_timeout: Duration,
+    /// Start memory-only instead of failing when the database is unreachable, and keep retrying.
+    pub db_optional: bool,
+    /// How often to retry the database in `db_optional` mode.
+    pub db_retry_interval: Duration,
+    /// Where messages and other server data are persisted.
+    pub storage_backend: StorageBackend,
+    /// Database file used by the SQLite backend.
+    pub sqlite_path: String,
+    /// Store new messages gzip-compressed instead of as plain JSONB (PostgreSQL only).
+    pub compress_storage: bool,
+    /// Show an `Unreadable` placeholder in history where a stored message no longer decodes, instead of leaving it out.
+    pub history_tombstones: bool,
+    /// How many messages may wait in a client's outbound queue before `outbound_drop_policy` applies.
+    pub outbound_queue_size: usize,
+    pub outbound_drop_policy: DropPolicy,
+    /// Most messages a room's clients may have queued in total before `room_shed_policy` applies; `None` disables the limit.
+    pub room_max_in_flight: Option<usize>,
+    pub room_shed_policy: ShedPolicy,
+    /// Most frames of any kind a client may send per second before it is disconnected.
+    pub frame_rate_limit: u32,
+    /// Bytes a client may send per `inbound_window` before it is disconnected; `None` disables the budget.
+    pub max_inbound_bytes: Option<u64>,
+    pub inbound_window: Duration,
+    /// Largest single message a client may send, counted after its fragments are reassembled.
+    pub max_message_bytes: usize,
+    /// Longest chat message content plain-text clients are shown, in bytes; `None` shows it in full.
+    pub display_max_bytes: Option<usize>,
+    /// How long a JSON client has to acknowledge a chat message before it is resent; `None` disables acks.
+    pub ack_timeout: Option<Duration>,
+    /// How many times an unacknowledged message is resent before the client is disconnected.
+    pub ack_max_resends: u32,
+    /// Most history loads that may hit the database at once.
+    pub history_load_limit: usize,
+    /// What `/history` does when `history_load_limit` loads are already running.
+    pub history_busy_policy: BusyPolicy,
+    /// A chat message identical to the sender's previous one within this window is dropped; `None` disables the check.
+    pub duplicate_window: Option<Duration>,
+    /// How many levels of replies `/thread` lists under a message.
+    pub thread_max_depth: u32,
+    /// Minimum time between two renames by the same client.
+    pub rename_cooldown: Duration,
+    /// How room names from connection URLs are normalized before aliases are resolved.
+    pub room_name_normalization: RoomNameNormalization,
+    /// Alternative names under which rooms can be joined.
+    pub room_aliases: RoomAliases,
+    /// Password required to join each private room, keyed by room.
+    pub room_passwords: HashMap<String, String>,
+    /// Whether connecting to an unknown room creates it. When off, only active and registered rooms can be joined.
+    pub auto_create_rooms: bool,
+    /// Rooms registered in configuration, joinable even with `AUTO_CREATE_ROOMS` off. More are added through the admin API.
+    pub registered_rooms: Vec<String>,
+    /// How long an invite created with `/invite` stays valid.
+    pub invite_ttl: Duration,
+    /// Which message types are written to the store, per room.
+    pub persist_types: PersistTypes,
+    /// Most users each room keeps per-user side state for (missed-message buffers, flood records).
+    pub max_tracked_users: usize,
+    /// Shortest username `/user` accepts, in characters.
+    pub min_username_chars: usize,
+    /// Usernames only administrators may take, compared case-insensitively.
+    pub reserved_usernames: Vec<String>,
+    /// Whether senders get a `Delivered` receipt with the id, `seq` and server time assigned to each chat message.
+    pub delivery_receipts: bool,
+    /// Shortest and longest `expires_in_secs` a chat message may be sent with.
+    pub message_ttl_min: Duration,
+    pub message_ttl_max: Duration,
+    /// Most chat and custom messages a user may send per `message_rate_window`; `None` disables the limit.
+    pub message_rate_limit: Option<u32>,
+    /// Messages per second each room accepts from all of its members together.
+    pub room_rate_limits: RoomRateLimits,
+    /// How many of the latest server events the event bus keeps for replay; `0` keeps none.
+    pub event_replay_size: usize,
+    pub message_rate_window: Duration,
+    /// How long a user is muted for their first, second, ... violation of the message rate limit. The last
+    /// duration repeats for further violations; with none, messages over the limit are just dropped.
+    pub auto_mute_durations: Vec<Duration>,
+    /// How long after a mute ends a user must stay within the limit for their violations to be forgotten.
+    pub auto_mute_reset: Duration,
+    /// Most requests one address may make to the REST history endpoints per `rest_rate_window`; `None` disables the limit.
+    pub rest_rate_limit: Option<u32>,
+    pub rest_rate_window: Duration,
+    /// Most response bytes one address may receive from the REST history endpoints per `rest_rate_window`.
+    pub rest_max_response_bytes: Option<u64>,
+}
+
+impl Config {
+    /// Builds the configuration from the environment, falling back to defaults for anything unset or invalid.
+    pub fn from_env() -> Self {
+        Config {
+            templates: DisplayTemplates::from_env(),
+            serve_client: parse_env("SERVE_CLIENT", false),
+            webhook_url: env::var("WEBHOOK_URL").ok().filter(|url| !url.trim().is_empty()),
+            anon_read_rooms: parse_list(&env::var("ALLOW_ANON_READ").unwrap_or_default()),
+            anon_timeout: Some(Duration::from_secs(parse_env("ANON_TIMEOUT_SECS", 0))).filter(|timeout| !timeout.is_zero()),
+            max_total_connections: Some(parse_env("MAX_TOTAL_CONNECTIONS", 0)).filter(|&limit| limit > 0),
+            room_max_clients: Some(parse_env("ROOM_MAX_CLIENTS", 0)).filter(|&limit| limit > 0),
+            banned_ips: banned_ips_from_env(),
+            handshake_timeout: Some(Duration::from_secs(parse_env("HANDSHAKE_TIMEOUT_SECS", 10))).filter(|timeout| !timeout.is_zero()),
+            heartbeat_timeout: Some(Duration::from_secs(parse_env("HEARTBEAT_TIMEOUT_SECS", 0))).filter(|timeout| !timeout.is_zero()),
+            encrypted_rooms: parse_list(&env::var("ENCRYPTED_ROOMS").unwrap_or_default()),
+            text_macros: TextMacros::from_env(),
+            content_normalization: parse_env("CONTENT_NORMALIZATION", ContentNormalization::Trim),
+            content_moderation: ContentModeration::from_env(
+                Some(parse_env("PROFANITY_FLAG_AT", 0)).filter(|&at| at > 0),
+                Some(parse_env("PROFANITY_BLOCK_AT", 0)).filter(|&at| at > 0),
+            ),
+            geoip: GeoIp::from_env(),
+            echo_own_messages: parse_env("ECHO_OWN_MESSAGES", false),
+            startup_motd: env::var("STARTUP_MOTD").ok().filter(|motd| !motd.trim().is_empty()),
+            system_signing_secret: env::var("SYSTEM_SIGNING_SECRET").ok().filter(|secret| !secret.is_empty()),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            join_replay_count: parse_env("JOIN_REPLAY_COUNT", IN_MEMORY_CACHE_SIZE),
+            history_enabled: parse_env("HISTORY_ENABLED", true),
+            history_includes_presence: parse_env("HISTORY_INCLUDES_PRESENCE", false),
+            shutdown_drain: Duration::from_secs(parse_env("SHUTDOWN_DRAIN_SECS", 30)),
+            db_acquire_timeout: Duration::from_secs(parse_env("DB_ACQUIRE_TIMEOUT_SECS", 3)),
+            db_timeout: Duration::from_secs(parse_env("DB_TIMEOUT_SECS", 5)),
+            db_optional: parse_env("DB_OPTIONAL", false),
+            db_retry_interval: Duration::from_secs(parse_env("DB_RETRY_SECS", 10).max(1)),
+            storage_backend: parse_env("STORAGE_BACKEND", StorageBackend::Postgres),
+            sqlite_path: env::var("SQLITE_PATH").unwrap_or_else(|_| "chat.db".to_string()),
+            compress_storage: parse_env("COMPRESS_STORAGE", false),
+            history_tombstones: parse_env("HISTORY_TOMBSTONES", false),
+            outbound_queue_size: parse_env("OUTBOUND_QUEUE_SIZE", 1024),
+            outbound_drop_policy: parse_env("OUTBOUND_DROP_POLICY", DropPolicy::Disconnect),
+            room_max_in_flight: Some(parse_env("ROOM_MAX_IN_FLIGHT", 0)).filter(|&limit| limit > 0),
+            room_shed_policy: parse_env("ROOM_SHED_POLICY", ShedPolicy::DropOldest),
+            frame_rate_limit: parse_env("FRAME_RATE_LIMIT", 50),
+            max_inbound_bytes: Some(parse_env("MAX_INBOUND_BYTES", 10 * 1024 * 1024)).filter(|&budget| budget > 0),
+            inbound_window: Duration::from_secs(parse_env("INBOUND_WINDOW_SECS", 60).max(1)),
+            max_message_bytes: parse_env("MAX_MESSAGE_BYTES", 64 * 1024),
+            display_max_bytes: Some(parse_env("DISPLAY_MAX_BYTES", 0)).filter(|&limit| limit > 0),
+            ack_timeout: Some(Duration::from_secs(parse_env("ACK_TIMEOUT_SECS", 0))).filter(|timeout| !timeout.is_zero()),
+            ack_max_resends: parse_env("ACK_MAX_RESENDS", 3),
+            history_load_limit: parse_env("HISTORY_LOAD_LIMIT", 8),
+            history_busy_policy: parse_env("HISTORY_BUSY_POLICY", BusyPolicy::Queue),
+            duplicate_window: Some(Duration::from_millis(parse_env("DUPLICATE_WINDOW_MS", 0))).filter(|window| !window.is_zero()),
+            thread_max_depth: parse_env("THREAD_MAX_DEPTH", 5).max(1),
+            rename_cooldown: Duration::from_secs(parse_env("RENAME_COOLDOWN_SECS", 30)),
+            room_name_normalization: parse_env("ROOM_NAME_NORMALIZATION", RoomNameNormalization::AsIs),
+            room_aliases: RoomAliases::from_env(),
+            room_passwords: room_passwords_from_env(),
+            auto_create_rooms: parse_env("AUTO_CREATE_ROOMS", true),
+            registered_rooms: parse_list(&env::var("REGISTERED_ROOMS").unwrap_or_default()),
+            invite_ttl: Duration::from_secs(parse_env("INVITE_TTL_SECS", 3600).max(1)),
+            persist_types: PersistTypes::from_env(),
+            max_tracked_users: parse_env("MAX_TRACKED_USERS", DEFAULT_LRU_CAPACITY).max(1),
+            min_username_chars: parse_env("MIN_USERNAME_LENGTH", 2),
+            reserved_usernames: env::var("RESERVED_USERNAMES")
+                .map(|raw| parse_list(&raw))
+                .unwrap_or_else(|_| DEFAULT_RESERVED_USERNAMES.iter().map(|name| name.to_string()).collect()),
+            delivery_receipts: parse_env("DELIVERY_RECEIPTS", false),
+            message_ttl_min: Duration::from_secs(parse_env("MESSAGE_TTL_MIN_SECS", 5)),
+            message_ttl_max: Duration::from_secs(parse_env("MESSAGE_TTL_MAX_SECS", 7 * 24 * 60 * 60)),
+            message_rate_limit: Some(parse_env("MESSAGE_RATE_LIMIT", 0)).filter(|&limit| limit > 0),
+            message_rate_window: Duration::from_secs(parse_env("MESSAGE_RATE_WINDOW_SECS", 10).max(1)),
+            room_rate_limits: RoomRateLimits::from_env(),
+            event_replay_size: parse_env("EVENT_REPLAY_SIZE", 0),
+            auto_mute_durations: parse_list(&env::var("AUTO_MUTE_DURATIONS_SECS").unwrap_or_else(|_| "30,120,600".to_string()))
+                .iter()
+                .filter_map(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .collect(),
+            auto_mute_reset: Duration::from_secs(parse_env("AUTO_MUTE_RESET_SECS", 600)),
+            rest_rate_limit: Some(parse_env("REST_RATE_LIMIT", 0)).filter(|&limit| limit > 0),
+            rest_rate_window: Duration::from_secs(parse_env("REST_RATE_WINDOW_SECS", 60).max(1)),
+            rest_max_response_bytes: Some(parse_env("REST_MAX_RESPONSE_BYTES", 0)).filter(|&bytes| bytes > 0),
+        }
+    }
+
+    /// Whether `username` is on the `RESERVED_USERNAMES` list.
+    pub fn is_reserved_username(&self, username: &str) -> bool {
+        let username = username.to_lowercase();
+        self.reserved_usernames.iter().any(|reserved| reserved.to_lowercase() == username)
+    }
+
+    /// Whether anonymous clients in `room` receive broadcasts and may load history.
+    pub fn allows_anon_read(&self, room: &str) -> bool {
+        self.anon_read_rooms.iter().any(|r| r == "*" || r == room)
+    }
+
+    /// Whether `room` rejects plaintext chat messages.
+    pub fn requires_encryption(&self, room: &str) -> bool {
+        self.encrypted_rooms.iter().any(|r| r == "*" || r == room)
+    }
+
+    /// The password protecting `room`, if it is private.
+    pub fn room_password(&self, room: &str) -> Option<&str> {
+        self.room_passwords.get(room).map(String::as_str)
+    }
+
+    /// Features a client connecting to `room` can rely on, announced in the `Welcome` frame so
+    /// clients can adapt their UI. Optional ones are only listed when this configuration enables them.
+    pub fn features(&self, room: &str) -> Vec<String> {
+        let mut features = vec!["json", "reactions", "custom-messages", "rename", "topics", "pins", "ownership", "presence", "threads", "echo", "colors", "public-keys", "expiring-messages"];
+        if self.history_enabled {
+            features.push("history-paging");
+        }
+        if self.admin_token.is_some() {
+            features.push("admin");
+        }
+        if self.allows_anon_read(room) {
+            features.push("anon-read");
+        }
+        if self.requires_encryption(room) {
+            features.push("encrypted-only");
+        }
+        if self.ack_timeout.is_some() {
+            features.push("acks");
+        }
+        if self.heartbeat_timeout.is_some() {
+            features.push("heartbeat");
+        }
+        if self.delivery_receipts {
+            features.push("delivery-receipts");
+        }
+        if self.room_password(room).is_some() {
+            features.push("private");
+        }
+        features.into_iter().map(String::from).collect()
+    }
+}
+
+/// Reads and parses an environment variable, falling back to `default` if it's unset or malformed.
+fn parse_env<T: FromStr>(var: &str, default: T) -> T {
+    match env::var(var) {
+        Ok(raw) => raw.trim().parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value '{}' for {}. Using the default.", raw, var);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Reads `BANNED_IPS`, a comma-separated list of addresses. Entries that aren't an address are reported and ignored.
+fn banned_ips_from_env() -> Vec<IpAddr> {
+    parse_list(&env::var("BANNED_IPS").unwrap_or_default())
+        .into_iter()
+        .filter_map(|ip| match ip.parse() {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                eprintln!("Ignoring invalid BANNED_IPS entry '{}': {}", ip, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Splits a comma-separated environment value into its non-empty, trimmed entries.
+fn parse_list(raw: &str) -> Vec<String> {
+    raw.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect()
+}
+
+/// Templates used to render messages in the legacy plain-text format.
+pub struct DisplayTemplates {
+    pub new_message: Template,
+    pub user_joined: Template,
+    pub user_left: Template,
+    /// How `{timestamp}` is written; changed at runtime through `/admin/timestamp-format`.
+    pub timestamp_format: TimestampFormat,
+}
+
+impl DisplayTemplates {
+    fn from_env() -> Self {
+        DisplayTemplates {
+            timestamp_format: TimestampFormat::from_env(),
+            new_message: load_template("FORMAT_NEW_MESSAGE", "[{username}] {content}", &["id", "username", "content", "timestamp"]),
+            user_joined: load_template("FORMAT_USER_JOINED", "--> {username} joined the room", &["username", "timestamp"]),
+            user_left: load_template("FORMAT_USER_LEFT", "<-- {username} left the room", &["username", "timestamp"]),
+        }
+    }
+}
+
+/// A `chrono` strftime format string for the `{timestamp}` placeholder, swappable while the server runs.
+pub struct TimestampFormat {
+    format: RwLock<String>,
+}
+
+impl TimestampFormat {
+    const DEFAULT: &'static str = "%H:%M:%S";
+
+    /// Reads `TIMESTAMP_FORMAT`. An invalid format is reported and replaced by the default.
+    fn from_env() -> Self {
+        let format = match env::var("TIMESTAMP_FORMAT") {
+            Ok(raw) => match Self::validate(&raw) {
+                Ok(()) => raw,
+                Err(e) => {
+                    eprintln!("Invalid TIMESTAMP_FORMAT '{}': {}. Using the default.", raw, e);
+                    Self::DEFAULT.to_string()
+                }
+            },
+            Err(_) => Self::DEFAULT.to_string(),
+        };
+        TimestampFormat { format: RwLock::new(format) }
+    }
+
+    /// Checks `raw` against chrono's format specifiers without formatting anything.
+    pub fn validate(raw: &str) -> Result<(), String> {
+        if raw.is_empty() {
+            return Err("the format is empty".to_string());
+        }
+        if StrftimeItems::new(raw).any(|item| matches!(item, Item::Error)) {
+            return Err("it has an unknown or incomplete `%` specifier".to_string());
+        }
+        Ok(())
+    }
+
+    /// Replaces the format if `raw` is valid; otherwise the current one is kept.
+    pub fn set(&self, raw: &str) -> Result<(), String> {
+        Self::validate(raw)?;
+        *self.format.write().unwrap() = raw.to_string();
+        Ok(())
+    }
+
+    /// Writes `time` in the current format.
+    pub fn format(&self, time: DateTime<Utc>) -> String {
+        time.format(&self.format.read().unwrap()).to_string()
+    }
+}
+
+/// Reads a template from `var`, validating it against the allowed placeholders.
+/// An invalid template is reported and replaced by the default.
+fn load_template(var: &str, default: &str, allowed: &[&str]) -> Template {
+    let Ok(raw) = env::var(var) else {
+        return Template::parse(default, allowed).expect("default template must be valid");
+    };
+    match Template::parse(&raw, allowed) {
+        Ok(template) => template,
+        Err(e) => {
+            eprintln!("Invalid {} template '{}': {}. Using the default.", var, raw, e);
+            Template::parse(default, allowed).expect("default template must be valid")
+        }
+    }
+}
+
+/// A display template pre-parsed into literal text and `{placeholder}` slots.
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+enum Part {
+    Literal(String),
+    Placeholder(String),
+}
+
+impl Template {
+    /// Parses `raw`, rejecting unknown placeholders and unbalanced braces.
+    pub fn parse(raw: &str, allowed: &[&str]) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = raw;
+
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err("unmatched '}'".to_string());
+            }
+            let Some(len) = rest[start..].find('}') else {
+                return Err("unclosed '{'".to_string());
+            };
+            let name = &rest[start + 1..start + len];
+            if !allowed.contains(&name) {
+                return Err(format!("unknown placeholder '{{{}}}'", name));
+            }
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            parts.push(Part::Placeholder(name.to_string()));
+            rest = &rest[start + len + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(Template { parts })
+    }
+
+    /// Renders the template in a single pass, so placeholder-like text inside values is never expanded.
+    pub fn render(&self, values: &[(&str, &str)]) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Placeholder(name) => {
+                    if let Some((_, value)) = values.iter().find(|(key, _)| key == name) {
+                        out.push_str(value);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Tokens such as `/shrug` that are replaced inside chat messages before they are broadcast.
+pub struct TextMacros {
+    macros: HashMap<String, String>,
+}
+
+impl TextMacros {
+    /// Starts from the built-in macros and merges in `TEXT_MACROS`, a JSON object of token to replacement.
+    fn from_env() -> Self {
+        let mut macros = HashMap::from([("/shrug".to_string(), r"¯\_(ツ)_/¯".to_string())]);
+        if let Ok(raw) = env::var("TEXT_MACROS") {
+            match serde_json::from_str::<HashMap<String, String>>(&raw) {
+                Ok(custom) => macros.extend(custom),
+                Err(e) => eprintln!("Invalid TEXT_MACROS '{}': {}. Using the built-in macros only.", raw, e),
+            }
+        }
+        TextMacros { macros }
+    }
+
+    /// Replaces every whitespace-delimited token that exactly matches a macro.
+    /// Expansion is a single pass, so replacements are never expanded again.
+    pub fn expand(&self, content: &str) -> String {
+        let mut out = String::with_capacity(content.len());
+        for piece in content.split_inclusive(char::is_whitespace) {
+            let token = piece.trim_end_matches(char::is_whitespace);
+            out.push_str(self.macros.get(token).map_or(token, String::as_str));
+            out.push_str(&piece[token.len()..]);
+        }
+        out
+    }
+}
+
+/// Maps alias room names onto the canonical room they stand for, so clients joining either name
+/// share one room: the same members, messages and history.
+#[derive(Default)]
+pub struct RoomAliases {
+    canonical: HashMap<String, String>,
+}
+
+impl RoomAliases {
+    /// Reads `ROOM_ALIASES`, a JSON object of alias to target room.
+    fn from_env() -> Self {
+        let Ok(raw) = env::var("ROOM_ALIASES") else {
+            return RoomAliases::default();
+        };
+        match serde_json::from_str::<HashMap<String, String>>(&raw) {
+            Ok(aliases) => RoomAliases::new(aliases),
+            Err(e) => {
+                eprintln!("Invalid ROOM_ALIASES '{}': {}. Rooms have no aliases.", raw, e);
+                RoomAliases::default()
+            }
+        }
+    }
+
+    /// Builds the alias table, following chains (`a -> b -> c`) so every alias points straight at
+    /// its canonical room. Aliases with an empty target or that end up in a cycle are reported and ignored.
+    pub fn new(aliases: HashMap<String, String>) -> Self {
+        let mut canonical = HashMap::new();
+        for alias in aliases.keys() {
+            let mut seen = vec![alias.as_str()];
+            let mut target = alias.as_str();
+            let resolved = loop {
+                match aliases.get(target).map(|t| t.trim()) {
+                    Some("") => break Err(format!("'{}' has no target room", target)),
+                    Some(next) if seen.contains(&next) => break Err(format!("cycle through '{}'", next)),
+                    Some(next) => {
+                        seen.push(next);
+                        target = next;
+                    }
+                    None => break Ok(target),
+                }
+            };
+            match resolved {
+                Ok(room) => {
+                    canonical.insert(alias.clone(), room.to_string());
+                }
+                Err(e) => eprintln!("Ignoring room alias '{}': {}.", alias, e),
+            }
+        }
+        RoomAliases { canonical }
+    }
+
+    /// The room clients asking for `room` actually join: its canonical room if it's an alias, otherwise itself.
+    pub fn resolve<'a>(&'a self, room: &'a str) -> &'a str {
+        self.canonical.get(room).map_or(room, String::as_str)
+    }
+}
+
+/// Reads `ROOM_PASSWORDS`, a JSON object of room to password. Rooms with an empty password stay public.
+fn room_passwords_from_env() -> HashMap<String, String> {
+    let Ok(raw) = env::var("ROOM_PASSWORDS") else {
+        return HashMap::new();
+    };
+    match serde_json::from_str::<HashMap<String, String>>(&raw) {
+        Ok(passwords) => passwords.into_iter().filter(|(_, password)| !password.is_empty()).collect(),
+        Err(e) => {
+            eprintln!("Invalid ROOM_PASSWORDS: {}. No room is private.", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Message types `PERSIST_TYPES` can name: everything the server ever stores.
+const PERSISTABLE_TYPES: &[&str] = &["NewMessage", "Custom", "UserJoined", "UserLeft", "UserRenamed"];
+
+/// Which message types are saved to the store in each room. Messages left out are still broadcast and
+/// cached as usual, but don't reach `/history`, exports or the next server run.
+#[derive(Default)]
+pub struct PersistTypes {
+    /// Room (or `*` for every room not listed) to the types saved there. Without an entry, everything is saved.
+    rooms: HashMap<String, Vec<String>>,
+}
+
+impl PersistTypes {
+    /// Reads `PERSIST_TYPES`, a JSON object of room (or `*`) to the list of message types to save.
+    fn from_env() -> Self {
+        let Ok(raw) = env::var("PERSIST_TYPES") else {
+            return PersistTypes::default();
+        };
+        match serde_json::from_str::<HashMap<String, Vec<String>>>(&raw) {
+            Ok(rooms) => PersistTypes::new(rooms),
+            Err(e) => {
+                eprintln!("Invalid PERSIST_TYPES '{}': {}. Every message is persisted.", raw, e);
+                PersistTypes::default()
+            }
+        }
+    }
+
+    /// Builds the table from room to saved types. Names that aren't a persistable type are reported and ignored.
+    pub fn new(rooms: HashMap<String, Vec<String>>) -> Self {
+        let rooms = rooms
+            .into_iter()
+            .map(|(room, types)| {
+                let (known, unknown): (Vec<String>, Vec<String>) =
+                    types.into_iter().partition(|kind| PERSISTABLE_TYPES.contains(&kind.as_str()));
+                if !unknown.is_empty() {
+                    eprintln!(
+                        "Ignoring unknown PERSIST_TYPES for room '{}': {}. Known types: {}.",
+                        room,
+                        unknown.join(", "),
+                        PERSISTABLE_TYPES.join(", ")
+                    );
+                }
+                (room, known)
+            })
+            .collect();
+        PersistTypes { rooms }
+    }
+
+    /// Whether `message` should be saved in `room`.
+    pub fn allows(&self, room: &str, message: &ServerMessage) -> bool {
+        let Some(types) = self.rooms.get(room).or_else(|| self.rooms.get("*")) else {
+            return true;
+        };
+        message.persisted_type().is_some_and(|kind| types.iter().any(|t| t == kind))
+    }
+}
+
+/// Slowest `ROOM_RATE_LIMITS` rate accepted: one message an hour.
+pub const MIN_ROOM_RATE: f64 = 1.0 / 3600.0;
+
+/// Aggregate message rate of each room, so a flood spread over many accounts still can't swamp it.
+#[derive(Default)]
+pub struct RoomRateLimits {
+    /// Room (or `*` for every room not listed) to the chat and custom messages it accepts per second.
+    rooms: HashMap<String, f64>,
+}
+
+impl RoomRateLimits {
+    /// Reads `ROOM_RATE_LIMITS`, a JSON object of room (or `*`) to messages per second.
+    fn from_env() -> Self {
+        let Ok(raw) = env::var("ROOM_RATE_LIMITS") else {
+            return RoomRateLimits::default();
+        };
+        match serde_json::from_str::<HashMap<String, f64>>(&raw) {
+            Ok(rooms) => RoomRateLimits::new(rooms),
+            Err(e) => {
+                eprintln!("Invalid ROOM_RATE_LIMITS '{}': {}. Rooms have no aggregate rate limit.", raw, e);
+                RoomRateLimits::default()
+            }
+        }
+    }
+
+    /// Builds the table from room to rate. A rate of `0` exempts a room from the `*` rate; negative rates and
+    /// rates below `MIN_ROOM_RATE` are reported and ignored.
+    pub fn new(rooms: HashMap<String, f64>) -> Self {
+        let rooms = rooms
+            .into_iter()
+            .filter(|(room, rate)| {
+                let valid = rate.is_finite() && (*rate == 0.0 || *rate >= MIN_ROOM_RATE);
+                if !valid {
+                    eprintln!("Ignoring invalid ROOM_RATE_LIMITS rate {} for room '{}'.", rate, room);
+                }
+                valid
+            })
+            .collect();
+        RoomRateLimits { rooms }
+    }
+
+    /// Messages per second `room` accepts, or `None` if it isn't limited.
+    pub fn rate(&self, room: &str) -> Option<f64> {
+        self.rooms.get(room).or_else(|| self.rooms.get("*")).copied().filter(|&rate| rate > 0.0)
+    }
+}
+
+/// Characters that render as nothing; a message made only of these (and whitespace) looks empty.
+const ZERO_WIDTH_CHARS: &[char] = &['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
+
+fn is_invisible(c: char) -> bool {
+    c.is_whitespace() || ZERO_WIDTH_CHARS.contains(&c)
+}
+
+/// Policy for cleaning up chat message content, set with `CONTENT_NORMALIZATION`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentNormalization {
+    /// Send content exactly as written.
+    None,
+    /// Strip leading and trailing whitespace and zero-width characters.
+    Trim,
+    /// Trim, then collapse every run of whitespace (including newlines) into a single space.
+    Collapse,
+}
+
+impl FromStr for ContentNormalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ContentNormalization::None),
+            "trim" => Ok(ContentNormalization::Trim),
+            "collapse" => Ok(ContentNormalization::Collapse),
+            other => Err(format!("unknown normalization policy '{}'", other)),
+        }
+    }
+}
+
+impl ContentNormalization {
+    /// Applies the policy, returning `None` for content that would display as nothing under any policy.
+    pub fn normalize(self, content: &str) -> Option<String> {
+        if content.chars().all(is_invisible) {
+            return None;
+        }
+        let normalized = match self {
+            ContentNormalization::None => content.to_string(),
+            ContentNormalization::Trim => content.trim_matches(is_invisible).to_string(),
+            ContentNormalization::Collapse => {
+                let collapsed = content.split_whitespace().collect::<Vec<_>>().join(" ");
+                collapsed.trim_matches(is_invisible).to_string()
+            }
+        };
+        Some(normalized)
+    }
+}
+
+/// Policy for room names taken from connection URLs, set with `ROOM_NAME_NORMALIZATION`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomNameNormalization {
+    /// Every distinct spelling is its own room: `General` and `general` are two rooms.
+    AsIs,
+    /// Trim, collapse inner whitespace runs to one space and lowercase, so spelling variants share one room.
+    /// The normalized name is the room's canonical name everywhere: in `Welcome`, storage and the admin API.
+    Strict,
+}
+
+impl FromStr for RoomNameNormalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "as-is" => Ok(RoomNameNormalization::AsIs),
+            "strict" => Ok(RoomNameNormalization::Strict),
+            other => Err(format!("unknown room name normalization '{}'", other)),
+        }
+    }
+}
+
+impl RoomNameNormalization {
+    /// The canonical spelling of `room` under this policy.
+    pub fn normalize(self, room: &str) -> String {
+        match self {
+            RoomNameNormalization::AsIs => room.to_string(),
+            RoomNameNormalization::Strict => room.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase(),
+        }
+    }
+}
+
+/// What a `/history` request does when the history load limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusyPolicy {
+    /// Wait for a running load to finish.
+    Queue,
+    /// Tell the client the server is busy and to try again.
+    Reject,
+}
+
+impl FromStr for BusyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(BusyPolicy::Queue),
+            "reject" => Ok(BusyPolicy::Reject),
+            other => Err(format!("unknown busy policy '{}'", other)),
+        }
+    }
+}
+
+/// Shortens `text` to at most `max_bytes` bytes without splitting a grapheme cluster, so
+/// multibyte characters, emoji and combining sequences are either kept whole or dropped whole.
+pub fn truncate_graphemes(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let end = text
+        .grapheme_indices(true)
+        .map(|(start, grapheme)| start + grapheme.len())
+        .take_while(|&end| end <= max_bytes)
+        .last()
+        .unwrap_or(0);
+    &text[..end]
+}
+
+/// Shortens `text` for display to at most `max_bytes` bytes, ellipsis included, cutting between grapheme
+/// clusters (see `truncate_graphemes`) and ending it with `…` so readers can tell it was cut.
+pub fn truncate_for_display(text: &str, max_bytes: usize) -> Cow<'_, str> {
+    if text.len() <= max_bytes {
+        return Cow::Borrowed(text);
+    }
+    let kept = truncate_graphemes(text, max_bytes.saturating_sub(ELLIPSIS.len()));
+    Cow::Owned(format!("{}{}", kept, ELLIPSIS))
+}
+
+const ELLIPSIS: &str = "…";
@@ -0,0 +1,91 @@
+// src/events.rs
+
+use crate::models::ServerMessage;
+use crate::state::ChatState;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Events held for the slowest subscriber before it starts missing them. Bounds the bus's memory whatever
+/// the traffic, at the cost of gaps for a subscriber that can't keep up.
+pub const EVENT_BUS_CAPACITY: usize = 1024;
+
+/// Something that happened on the server, published on the `EventBus` for whoever is listening: the admin
+/// monitor feed, the webhook worker, and tests. Handlers have already done what the event describes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum ServerEvent {
+    /// A client connected to `room`. It has no username yet.
+    ClientJoined { room: String, client_id: Uuid },
+    /// Something `room`'s clients were shown: chat and custom messages, joins, leaves, renames, deletions,
+    /// and reports raised to administrators.
+    RoomMessage { room: String, message: ServerMessage },
+    /// A client's chat or custom message was accepted and delivered, stamped with when it arrived.
+    MessageSent { room: String, client_id: Uuid, message: ServerMessage, timestamp: DateTime<Utc> },
+    /// A client's connection to `room` ended. `username` is `None` if it never picked one.
+    ClientLeft { room: String, client_id: Uuid, username: Option<String> },
+    /// A failure on the server's side, such as a database call that failed.
+    Error { message: String },
+}
+
+/// Fans every `ServerEvent` out to live subscribers, keeping the latest `EVENT_REPLAY_SIZE` of them so a
+/// subscriber that starts late (or `GET /admin/events`) can see what led up to now.
+///
+/// The bus is a tap for observers, not the delivery path: rooms get their messages from `broadcast_message`
+/// whether or not anyone is subscribed, and a subscriber that falls behind only loses its own events.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+    recent: Arc<Mutex<VecDeque<ServerEvent>>>,
+    replay_size: usize,
+}
+
+impl EventBus {
+    pub fn new(replay_size: usize) -> Self {
+        EventBus { sender: broadcast::channel(EVENT_BUS_CAPACITY).0, recent: Arc::default(), replay_size }
+    }
+
+    /// Publishes the event `make` builds. Cheap when nobody is listening and nothing is kept for replay:
+    /// the event isn't even built.
+    pub fn publish(&self, make: impl FnOnce() -> ServerEvent) {
+        if self.sender.receiver_count() == 0 && self.replay_size == 0 {
+            return;
+        }
+        let event = make();
+        if self.replay_size > 0 {
+            let mut recent = self.recent.lock().unwrap();
+            if recent.len() == self.replay_size {
+                recent.pop_front();
+            }
+            recent.push_back(event.clone());
+        }
+        // Kept before it is sent, so `subscribe_with_replay` can't miss it; sent with the lock released,
+        // since callers may be holding the rooms lock.
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribes to events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribes to events published from now on, along with the kept events that came before, oldest first.
+    /// An event being published at that very moment may turn up both in the replay and live.
+    pub fn subscribe_with_replay(&self) -> (Vec<ServerEvent>, broadcast::Receiver<ServerEvent>) {
+        let recent = self.recent.lock().unwrap();
+        (recent.iter().cloned().collect(), self.sender.subscribe())
+    }
+
+    /// The kept events, oldest first.
+    pub fn recent(&self) -> Vec<ServerEvent> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Publishes something `room_name`'s clients were shown.
+pub fn tap(state: &ChatState, room_name: &str, message: &ServerMessage) {
+    state.events.publish(|| ServerEvent::RoomMessage { room: room_name.to_string(), message: message.clone() });
+}
@@ -0,0 +1,83 @@
+// src/expiry.rs
+
+use crate::state::ChatState;
+use crate::websocket::expire_message;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+// Longest the sweeper sleeps without looking at the queue, so it notices shutdown
+const SWEEPER_IDLE: Duration = Duration::from_secs(1);
+
+/// How long to wait before trying again when deleting an expired message times out.
+pub(crate) const EXPIRY_RETRY: Duration = Duration::from_secs(30);
+
+/// Room of each expiring message, keyed by (expiry, message id) so the soonest comes first.
+type Expiries = BTreeMap<(DateTime<Utc>, Uuid), String>;
+
+/// Messages sent with `expires_in_secs`, ordered by when they expire.
+#[derive(Clone, Default)]
+pub struct ExpiryQueue {
+    pending: Arc<Mutex<Expiries>>,
+    /// Wakes the sweeper when a message is queued, in case it expires before the one it is waiting for.
+    changed: Arc<Notify>,
+}
+
+impl ExpiryQueue {
+    /// Queues message `id` in `room` for deletion at `expires_at`.
+    pub fn schedule(&self, room: &str, id: Uuid, expires_at: DateTime<Utc>) {
+        self.pending.lock().unwrap().insert((expires_at, id), room.to_string());
+        self.changed.notify_one();
+    }
+
+    /// When the next message expires.
+    fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.pending.lock().unwrap().keys().next().map(|(expires_at, _)| *expires_at)
+    }
+
+    /// Removes and returns the messages expired at `now`, soonest first, as (room, message id).
+    fn take_due(&self, now: DateTime<Utc>) -> Vec<(String, Uuid)> {
+        let mut pending = self.pending.lock().unwrap();
+        let later = pending.split_off(&(now, Uuid::max()));
+        std::mem::replace(&mut *pending, later).into_iter().map(|((_, id), room)| (room, id)).collect()
+    }
+}
+
+/// Spawns the task that deletes expiring messages once their time is up. Expiries are kept in the store
+/// with the messages, so it starts by queueing those left over from the last run, including any that
+/// expired while the server was down.
+pub fn spawn_sweeper(state: ChatState) {
+    tokio::spawn(async move {
+        // Retried until it works, as messages left over from the last run are never deleted otherwise.
+        while !state.shutting_down.load(Ordering::SeqCst) {
+            match state.store.load_message_expiries().await {
+                Ok(expiries) => {
+                    for (room, id, expires_at) in expiries {
+                        state.expiries.schedule(&room, id, expires_at);
+                    }
+                    break;
+                }
+                Err(e) => {
+                    state.record_db_error(&e);
+                    tokio::time::sleep(EXPIRY_RETRY).await;
+                }
+            }
+        }
+        while !state.shutting_down.load(Ordering::SeqCst) {
+            let wait = state.expiries.next_due().map_or(SWEEPER_IDLE, |next| {
+                (next - Utc::now()).to_std().unwrap_or_default().min(SWEEPER_IDLE)
+            });
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = state.expiries.changed.notified() => continue,
+            }
+            for (room, id) in state.expiries.take_due(Utc::now()) {
+                expire_message(&state, &room, id).await;
+            }
+        }
+    });
+}
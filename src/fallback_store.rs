@@ -0,0 +1,240 @@
+// src/fallback_store.rs
+
+use crate::memory_store::MemoryStore;
+use crate::models::{ServerMessage, ThreadReply};
+use crate::store::{AuditEntry, DbResult, MessageStore, RecentRoom, Report, RoomOwnership, RoomTopic, ScheduledMessage};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Stands in for a database that was unreachable at startup (`DB_OPTIONAL`). Calls go to an in-memory
+/// store, so chat keeps working but nothing is persisted, while a background task keeps trying to
+/// connect. Once it succeeds every later call goes to the database; what was kept in memory until
+/// then is not copied over.
+pub struct FallbackStore {
+    active: Arc<RwLock<Arc<dyn MessageStore>>>,
+    connected: Arc<AtomicBool>,
+}
+
+impl FallbackStore {
+    /// Starts in memory-only mode and calls `connect` every `retry_interval` until it succeeds.
+    pub fn start<F, Fut>(connect: F, retry_interval: Duration) -> Self
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Arc<dyn MessageStore>, sqlx::Error>> + Send,
+    {
+        let active: Arc<RwLock<Arc<dyn MessageStore>>> = Arc::new(RwLock::new(Arc::new(MemoryStore::default())));
+        let connected = Arc::new(AtomicBool::new(false));
+
+        let (task_active, task_connected) = (active.clone(), connected.clone());
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(retry_interval).await;
+                match connect().await {
+                    Ok(store) => {
+                        *task_active.write().unwrap() = store;
+                        task_connected.store(true, Ordering::SeqCst);
+                        println!("Database is reachable again; messages are persisted from now on.");
+                        return;
+                    }
+                    Err(e) => eprintln!("Database still unavailable ({}); retrying in {:?}.", e, retry_interval),
+                }
+            }
+        });
+
+        FallbackStore { active, connected }
+    }
+
+    /// Whether the database has been reached and calls now go to it.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// The store calls go to right now. The lock is released before the call is awaited.
+    fn current(&self) -> Arc<dyn MessageStore> {
+        self.active.read().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl MessageStore for FallbackStore {
+    async fn save_message(&self, room_name: &str, seq: u64, message: &ServerMessage, created_at: DateTime<Utc>) -> DbResult<()> {
+        self.current().save_message(room_name, seq, message, created_at).await
+    }
+
+    async fn load_history(&self, room_name: &str, limit: usize) -> DbResult<VecDeque<ServerMessage>> {
+        self.current().load_history(room_name, limit).await
+    }
+
+    async fn load_history_paginated(
+        &self,
+        room_name: &str,
+        page: i64,
+        page_size: i64,
+        include_presence: bool,
+    ) -> DbResult<VecDeque<(ServerMessage, BTreeMap<String, i64>, DateTime<Utc>)>> {
+        self.current().load_history_paginated(room_name, page, page_size, include_presence).await
+    }
+
+    async fn get_message_count(&self, room_name: &str) -> DbResult<i64> {
+        self.current().get_message_count(room_name).await
+    }
+
+    async fn get_latest_seq(&self, room_name: &str) -> DbResult<u64> {
+        self.current().get_latest_seq(room_name).await
+    }
+
+    async fn set_latest_seq(&self, room_name: &str, seq: u64) -> DbResult<()> {
+        self.current().set_latest_seq(room_name, seq).await
+    }
+
+    async fn stream_room_messages(&self, room_name: String, tx: mpsc::Sender<DbResult<ServerMessage>>) {
+        self.current().stream_room_messages(room_name, tx).await
+    }
+
+    async fn add_reaction(&self, room_name: &str, message_id: Uuid, username: &str, emoji: &str) -> DbResult<bool> {
+        self.current().add_reaction(room_name, message_id, username, emoji).await
+    }
+
+    async fn delete_message(&self, room_name: &str, message_id: Uuid) -> DbResult<bool> {
+        self.current().delete_message(room_name, message_id).await
+    }
+
+    async fn clear_messages(&self, room_name: &str, seq: u64) -> DbResult<u64> {
+        self.current().clear_messages(room_name, seq).await
+    }
+
+    async fn load_message_expiries(&self) -> DbResult<Vec<(String, Uuid, DateTime<Utc>)>> {
+        self.current().load_message_expiries().await
+    }
+
+    async fn is_room_closed(&self, room_name: &str) -> DbResult<bool> {
+        self.current().is_room_closed(room_name).await
+    }
+
+    async fn set_room_closed(&self, room_name: &str, closed: bool) -> DbResult<()> {
+        self.current().set_room_closed(room_name, closed).await
+    }
+
+    async fn is_room_registered(&self, room_name: &str) -> DbResult<bool> {
+        self.current().is_room_registered(room_name).await
+    }
+
+    async fn set_room_registered(&self, room_name: &str, registered: bool) -> DbResult<()> {
+        self.current().set_room_registered(room_name, registered).await
+    }
+
+    async fn get_room_topic(&self, room_name: &str) -> DbResult<Option<RoomTopic>> {
+        self.current().get_room_topic(room_name).await
+    }
+
+    async fn set_room_topic(&self, room_name: &str, topic: &RoomTopic) -> DbResult<()> {
+        self.current().set_room_topic(room_name, topic).await
+    }
+
+    async fn get_room_metadata(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        self.current().get_room_metadata(room_name).await
+    }
+
+    async fn set_room_metadata(&self, room_name: &str, metadata: &BTreeMap<String, String>) -> DbResult<()> {
+        self.current().set_room_metadata(room_name, metadata).await
+    }
+
+    async fn get_public_keys(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        self.current().get_public_keys(room_name).await
+    }
+
+    async fn set_public_keys(&self, room_name: &str, keys: &BTreeMap<String, String>) -> DbResult<()> {
+        self.current().set_public_keys(room_name, keys).await
+    }
+
+    async fn get_username_pattern(&self, room_name: &str) -> DbResult<Option<String>> {
+        self.current().get_username_pattern(room_name).await
+    }
+
+    async fn set_username_pattern(&self, room_name: &str, pattern: Option<&str>) -> DbResult<()> {
+        self.current().set_username_pattern(room_name, pattern).await
+    }
+
+    async fn get_room_ownership(&self, room_name: &str) -> DbResult<RoomOwnership> {
+        self.current().get_room_ownership(room_name).await
+    }
+
+    async fn set_room_ownership(&self, room_name: &str, ownership: &RoomOwnership) -> DbResult<()> {
+        self.current().set_room_ownership(room_name, ownership).await
+    }
+
+    async fn load_message(&self, room_name: &str, message_id: Uuid) -> DbResult<Option<ServerMessage>> {
+        self.current().load_message(room_name, message_id).await
+    }
+
+    async fn load_thread(&self, room_name: &str, root: Uuid, max_depth: u32, limit: usize) -> DbResult<Vec<ThreadReply>> {
+        self.current().load_thread(room_name, root, max_depth, limit).await
+    }
+
+    async fn get_pinned_messages(&self, room_name: &str) -> DbResult<Vec<ServerMessage>> {
+        self.current().get_pinned_messages(room_name).await
+    }
+
+    async fn set_pinned_ids(&self, room_name: &str, message_ids: &[Uuid]) -> DbResult<()> {
+        self.current().set_pinned_ids(room_name, message_ids).await
+    }
+
+    async fn get_secret_hash(&self, username: &str) -> DbResult<Option<String>> {
+        self.current().get_secret_hash(username).await
+    }
+
+    async fn register_user(&self, username: &str, secret_hash: &str) -> DbResult<bool> {
+        self.current().register_user(username, secret_hash).await
+    }
+
+    async fn get_recent_rooms(&self, since: DateTime<Utc>) -> DbResult<Vec<RecentRoom>> {
+        self.current().get_recent_rooms(since).await
+    }
+
+    async fn add_report(&self, report: &Report) -> DbResult<bool> {
+        self.current().add_report(report).await
+    }
+
+    async fn load_reports(&self, page: i64, page_size: i64) -> DbResult<Vec<Report>> {
+        self.current().load_reports(page, page_size).await
+    }
+
+    async fn log_audit(&self, entry: &AuditEntry) -> DbResult<()> {
+        self.current().log_audit(entry).await
+    }
+
+    async fn load_audit_log(&self, room_name: Option<&str>, page: i64, page_size: i64) -> DbResult<Vec<AuditEntry>> {
+        self.current().load_audit_log(room_name, page, page_size).await
+    }
+
+    async fn get_last_seen(&self, room_name: &str, username: &str) -> DbResult<Option<DateTime<Utc>>> {
+        self.current().get_last_seen(room_name, username).await
+    }
+
+    async fn set_last_seen(&self, room_name: &str, username: &str, seen_at: DateTime<Utc>) -> DbResult<()> {
+        self.current().set_last_seen(room_name, username, seen_at).await
+    }
+
+    async fn add_scheduled_message(&self, message: &ScheduledMessage) -> DbResult<()> {
+        self.current().add_scheduled_message(message).await
+    }
+
+    async fn cancel_scheduled_message(&self, id: Uuid) -> DbResult<bool> {
+        self.current().cancel_scheduled_message(id).await
+    }
+
+    async fn take_due_scheduled_messages(&self, now: DateTime<Utc>) -> DbResult<Vec<ScheduledMessage>> {
+        self.current().take_due_scheduled_messages(now).await
+    }
+
+    fn undecodable_messages(&self) -> u64 {
+        self.current().undecodable_messages()
+    }
+}
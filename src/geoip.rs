@@ -0,0 +1,76 @@
+// src/geoip.rs
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::env;
+use std::net::IpAddr;
+
+/// Finds the country an IP address is registered in.
+pub trait CountryLookup: Send + Sync {
+    /// The ISO 3166-1 alpha-2 code (e.g. `GB`), or `None` when the address isn't known.
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+/// Looks addresses up in an offline MaxMind database, such as GeoLite2-Country or GeoIP2-City.
+pub struct MaxMindCountries {
+    reader: Reader<Vec<u8>>,
+}
+
+impl MaxMindCountries {
+    /// Loads the whole `.mmdb` file into memory.
+    pub fn open(path: &str) -> Result<Self, MaxMindDBError> {
+        Ok(MaxMindCountries { reader: Reader::open_readfile(path)? })
+    }
+}
+
+impl CountryLookup for MaxMindCountries {
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        record.country?.iso_code.map(String::from)
+    }
+}
+
+/// Tags connections with the country of their IP address. Only administrators ever see it.
+pub struct GeoIp {
+    lookup: Box<dyn CountryLookup>,
+}
+
+impl GeoIp {
+    pub fn new(lookup: Box<dyn CountryLookup>) -> Self {
+        GeoIp { lookup }
+    }
+
+    /// Opens the database at `GEOIP_DB_PATH`. Tagging is off when it is unset or can't be read.
+    pub(crate) fn from_env() -> Option<Self> {
+        let path = env::var("GEOIP_DB_PATH").ok().filter(|path| !path.trim().is_empty())?;
+        match MaxMindCountries::open(&path) {
+            Ok(countries) => Some(GeoIp::new(Box::new(countries))),
+            Err(e) => {
+                eprintln!("Failed to open GEOIP_DB_PATH '{}': {}. Connections are not geolocated.", path, e);
+                None
+            }
+        }
+    }
+
+    /// The country `ip` is registered in. Private, loopback and other non-routable addresses have none.
+    pub fn country_of(&self, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+        if !is_routable(ip) {
+            return None;
+        }
+        self.lookup.country(ip)
+    }
+}
+
+/// Whether `ip` can belong to a client on the public internet, as opposed to a LAN, a proxy on the same host and the like.
+fn is_routable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast())
+        }
+        IpAddr::V6(ip) => {
+            let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+        }
+    }
+}
@@ -0,0 +1,62 @@
+// src/lib.rs
+
+pub mod admin;
+pub mod auth;
+pub mod config;
+pub mod database;
+pub mod events;
+pub mod expiry;
+pub mod fallback_store;
+pub mod geoip;
+pub mod listener;
+pub mod lru;
+pub mod memory_store;
+pub mod models;
+pub mod moderation;
+pub mod monitor;
+pub mod outbound;
+pub mod scheduler;
+pub mod sqlite_store;
+pub mod state;
+pub mod store;
+pub mod throttle;
+pub mod webhook;
+pub mod websocket;
+
+use axum::{middleware, response::Html, routing::{delete, get, post, put}, Router};
+use state::ChatState;
+
+/// Builds the application's routes. The binary serves it on port 3000; tests serve it on an ephemeral port.
+pub fn router(state: ChatState) -> Router {
+    let mut app = Router::new()
+        .route("/ws/admin", get(monitor::monitor_handler))
+        .route("/ws/{room}", get(websocket::websocket_handler))
+        .route("/admin/users/{username}/rooms", get(admin::user_rooms_handler))
+        .route("/admin/audit", get(admin::audit_log_handler))
+        .route("/admin/reports", get(admin::reports_handler))
+        .route("/admin/metrics", get(admin::metrics_handler))
+        .route("/admin/events", get(admin::events_handler))
+        .route("/admin/motd", put(admin::set_motd_handler).delete(admin::clear_motd_handler))
+        .route("/admin/timestamp-format", put(admin::set_timestamp_format_handler))
+        .route("/admin/rooms/{room}", put(admin::register_room_handler).delete(admin::unregister_room_handler))
+        .route("/admin/schedule", post(admin::schedule_message_handler))
+        .route("/admin/schedule/{id}", delete(admin::cancel_scheduled_message_handler))
+        .merge(history_routes(state.clone()));
+    if state.config.serve_client {
+        app = app.route("/", get(web_client));
+    }
+    app.with_state(state)
+}
+
+/// Endpoints that read stored history, limited per client address (see `throttle`).
+fn history_routes(state: ChatState) -> Router<ChatState> {
+    Router::new()
+        .route("/rooms/recent", get(admin::recent_rooms_handler))
+        .route("/rooms/{room}/export", get(admin::export_room_handler))
+        .route_layer(middleware::from_fn_with_state(state, throttle::throttle_history))
+}
+
+/// The built-in demo client, embedded in the binary so it needs no files at runtime.
+async fn web_client() -> Html<&'static str> {
+    Html(include_str!("../static/index.html"))
+}
@@ -0,0 +1,138 @@
+// src/listener.rs
+
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Sleep;
+
+/// Marks the end of an HTTP request's headers.
+const END_OF_HEADERS: &[u8] = b"\r\n\r\n";
+
+/// A TCP listener whose connections must deliver a complete HTTP request head (for chat clients, the
+/// WebSocket upgrade request) within `timeout`, or they are dropped. Stops clients that open a
+/// connection and then stall or trickle the handshake from tying up a connection indefinitely.
+pub struct HandshakeTimeoutListener {
+    inner: TcpListener,
+    timeout: Option<Duration>,
+}
+
+impl HandshakeTimeoutListener {
+    /// Wraps `inner`; a `timeout` of `None` accepts connections as they are.
+    pub fn new(inner: TcpListener, timeout: Option<Duration>) -> Self {
+        HandshakeTimeoutListener { inner, timeout }
+    }
+}
+
+impl Listener for HandshakeTimeoutListener {
+    type Io = HandshakeStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, addr) = Listener::accept(&mut self.inner).await;
+        let deadline = self.timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout)));
+        (HandshakeStream { inner: stream, deadline, matched: 0 }, addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// The client's address for a connection accepted by `HandshakeTimeoutListener`, read by handlers through
+/// `ConnectInfo<PeerAddr>`. Serve the router with `into_make_service_with_connect_info::<PeerAddr>()`.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, HandshakeTimeoutListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, HandshakeTimeoutListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+/// A connection accepted by `HandshakeTimeoutListener`. Reads fail with `TimedOut` once the deadline
+/// passes before the end of the first request head was read; after that it is a plain `TcpStream`.
+pub struct HandshakeStream {
+    inner: TcpStream,
+    /// Cleared once the request head is complete.
+    deadline: Option<Pin<Box<Sleep>>>,
+    /// How many bytes of `END_OF_HEADERS` the data read so far ends with.
+    matched: usize,
+}
+
+impl HandshakeStream {
+    /// Advances the end-of-headers search over newly read bytes, disarming the deadline when found.
+    fn scan(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.matched = if byte == END_OF_HEADERS[self.matched] {
+                self.matched + 1
+            } else if byte == END_OF_HEADERS[0] {
+                1
+            } else {
+                0
+            };
+            if self.matched == END_OF_HEADERS.len() {
+                self.deadline = None;
+                return;
+            }
+        }
+    }
+}
+
+fn handshake_timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "client did not complete the handshake in time")
+}
+
+impl AsyncRead for HandshakeStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let already_filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if this.deadline.is_some() => {
+                this.scan(&buf.filled()[already_filled..]);
+                // A client trickling bytes never leaves the read pending, so check the deadline here too.
+                match &this.deadline {
+                    Some(deadline) if deadline.is_elapsed() => Poll::Ready(Err(handshake_timed_out())),
+                    _ => Poll::Ready(Ok(())),
+                }
+            }
+            Poll::Pending => {
+                if let Some(deadline) = &mut this.deadline
+                    && deadline.as_mut().poll(cx).is_ready()
+                {
+                    return Poll::Ready(Err(handshake_timed_out()));
+                }
+                Poll::Pending
+            }
+            ready => ready,
+        }
+    }
+}
+
+impl AsyncWrite for HandshakeStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
@@ -0,0 +1,93 @@
+// src/lru.rs
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Capacity of an `LruMap` built with `Default`, and the default of `MAX_TRACKED_USERS`.
+pub const DEFAULT_LRU_CAPACITY: usize = 1000;
+
+/// A map holding at most `capacity` entries, evicting the least recently used one to make room. Used for
+/// side tables keyed by usernames or client ids, which would otherwise grow with every transient user.
+///
+/// Eviction scans the entries for the oldest, which is cheap at the sizes these tables are capped to.
+pub struct LruMap<K, V> {
+    entries: HashMap<K, (V, u64)>,
+    capacity: usize,
+    /// Bumped on every insert and lookup; each entry is stamped with the value from its last use.
+    clock: u64,
+}
+
+impl<K: Eq + Hash + Clone, V> LruMap<K, V> {
+    /// Creates an empty map. A capacity of 0 is treated as 1.
+    pub fn new(capacity: usize) -> Self {
+        LruMap { entries: HashMap::new(), capacity: capacity.max(1), clock: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Inserts or replaces an entry and marks it most recently used. Returns the entry evicted to make room, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        let stamp = self.tick();
+        if self.entries.insert(key, (value, stamp)).is_some() || self.entries.len() <= self.capacity {
+            return None;
+        }
+        let oldest = self.entries.iter().min_by_key(|(_, (_, used))| *used).map(|(key, _)| key.clone())?;
+        self.entries.remove(&oldest).map(|(value, _)| (oldest, value))
+    }
+
+    /// Looks up an entry and marks it most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let stamp = self.tick();
+        let (value, used) = self.entries.get_mut(key)?;
+        *used = stamp;
+        Some(value)
+    }
+
+    /// Looks up an entry for updating and marks it most recently used.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let stamp = self.tick();
+        let (value, used) = self.entries.get_mut(key)?;
+        *used = stamp;
+        Some(value)
+    }
+
+    /// Whether `key` is present. Doesn't count as a use.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key).map(|(value, _)| value)
+    }
+
+    /// Keeps only the entries `keep` returns `true` for. Doesn't count as a use.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &mut V) -> bool) {
+        self.entries.retain(|key, (value, _)| keep(key, value));
+    }
+
+    /// Iterates over the values to update them in place. Doesn't count as a use.
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.entries.values_mut().map(|(value, _)| value)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> Default for LruMap<K, V> {
+    fn default() -> Self {
+        LruMap::new(DEFAULT_LRU_CAPACITY)
+    }
+}
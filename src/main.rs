@@ -1,34 +1,32 @@
 // src/main.rs
 
-mod database;
-mod models;
-mod state;
-mod websocket;
-
-use axum::{routing::get, Router};
-use state::ChatState;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::sync::Mutex;
-use websocket::websocket_handler;
+use chat_server::{
+    config::Config,
+    listener::{HandshakeTimeoutListener, PeerAddr},
+    router,
+    state::ChatState,
+    store,
+    websocket::{finish_connections, shut_down},
+};
+use std::net::SocketAddr;
 
 #[tokio::main]
 async fn main() {
-    // Set up the database connection pool.
-    // .expect will crash the app if the DB can't be opened, which is reasonable on startup.
-    let db_pool = database::setup_database()
+    let config = Config::from_env();
+
+    // Open the configured storage backend.
+    // .expect will crash the app if the store can't be opened, which is reasonable on startup.
+    let store = store::open_store(&config)
         .await
-        .expect("Failed to set up database");
+        .expect("Failed to set up storage");
 
-    // The ChatState is a struct holding the map of rooms and the db_pool.
-    let state = ChatState {
-        rooms: Arc::new(Mutex::new(HashMap::new())),
-        db_pool,
-    };
+    if let Some(motd) = &config.startup_motd {
+        println!("Message of the day: {}", motd);
+    }
 
-    // Define the application routes
-    let app = Router::new()
-        .route("/ws/{room}", get(websocket_handler))
-        .with_state(state);
+    // The ChatState is a struct holding the map of rooms and the message store.
+    let state = ChatState::new(config, store);
+    let app = router(state.clone());
 
     // Define the server address
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
@@ -38,9 +36,23 @@ async fn main() {
     let listener = tokio::net::TcpListener::bind(&addr)
         .await
         .expect("Failed to bind address");
+    let listener = HandshakeTimeoutListener::new(listener, state.config.handshake_timeout);
 
-    axum::serve(listener, app.into_make_service())
+    axum::serve(listener, app.into_make_service_with_connect_info::<PeerAddr>())
+        .with_graceful_shutdown(shutdown_signal(state.clone()))
         .await
         .unwrap();
+
+    // WebSocket connections outlive the HTTP server; let their last database writes land before exiting.
+    finish_connections(&state).await;
 }
 
+/// Resolves once the server should stop: on Ctrl-C, refuse new connections, give existing ones
+/// the configured drain period to leave, then close whatever is left.
+async fn shutdown_signal(state: ChatState) {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to install Ctrl-C handler");
+
+    shut_down(&state).await;
+}
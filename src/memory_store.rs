@@ -0,0 +1,422 @@
+// src/memory_store.rs
+
+use crate::models::{ServerMessage, ThreadReply};
+use crate::store::{AuditEntry, DbResult, MessageStore, RecentRoom, Report, RoomOwnership, RoomTopic, ScheduledMessage};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// A `MessageStore` kept entirely in process memory. Nothing is persisted, which makes it
+/// suitable for tests and demos that shouldn't need a database.
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Messages per room, in the order they were saved, with their `seq` (0 if saved without one) and when.
+    messages: HashMap<String, Vec<(ServerMessage, u64, DateTime<Utc>)>>,
+    closed_rooms: HashSet<String>,
+    registered_rooms: HashSet<String>,
+    topics: HashMap<String, RoomTopic>,
+    metadata: HashMap<String, BTreeMap<String, String>>,
+    public_keys: HashMap<String, BTreeMap<String, String>>,
+    username_patterns: HashMap<String, String>,
+    ownership: HashMap<String, RoomOwnership>,
+    /// Highest message number recorded or saved per room.
+    latest_seq: HashMap<String, u64>,
+    /// Pinned message ids per room, in pin order.
+    pinned: HashMap<String, Vec<Uuid>>,
+    /// (message id, username, emoji)
+    reactions: BTreeSet<(Uuid, String, String)>,
+    registered_users: HashMap<String, String>,
+    /// Oldest first.
+    reports: Vec<Report>,
+    /// Oldest first.
+    audit_log: Vec<AuditEntry>,
+    /// Read cursors, keyed by (room, username).
+    last_seen: HashMap<(String, String), DateTime<Utc>>,
+    /// Pending announcements, in the order they were scheduled.
+    scheduled: Vec<ScheduledMessage>,
+}
+
+impl Inner {
+    fn has_message(&self, room_name: &str, message_id: Uuid) -> bool {
+        self.find_message(room_name, message_id).is_some()
+    }
+
+    fn find_message(&self, room_name: &str, message_id: Uuid) -> Option<&ServerMessage> {
+        self.messages.get(room_name)?.iter().map(|(message, _, _)| message).find(|message| {
+            matches!(message, ServerMessage::NewMessage { id, .. } if *id == message_id)
+        })
+    }
+
+    fn reaction_counts(&self, message: &ServerMessage) -> BTreeMap<String, i64> {
+        let mut counts = BTreeMap::new();
+        if let ServerMessage::NewMessage { id, .. } = message {
+            for (_, _, emoji) in self.reactions.iter().filter(|(message_id, _, _)| message_id == id) {
+                *counts.entry(emoji.clone()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryStore {
+    async fn save_message(&self, room_name: &str, seq: u64, message: &ServerMessage, created_at: DateTime<Utc>) -> DbResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if seq > 0 {
+            let latest = inner.latest_seq.entry(room_name.to_string()).or_insert(0);
+            *latest = (*latest).max(seq);
+        }
+        // Loaded back the way the database stores return it: a message without its own time has the save's.
+        let message = message.clone().or_sent_at(created_at);
+        inner.messages.entry(room_name.to_string()).or_default().push((message, seq, created_at));
+        Ok(())
+    }
+
+    async fn load_history(&self, room_name: &str, limit: usize) -> DbResult<VecDeque<ServerMessage>> {
+        let inner = self.inner.lock().unwrap();
+        let Some(messages) = inner.messages.get(room_name) else {
+            return Ok(VecDeque::new());
+        };
+        let skip = messages.len().saturating_sub(limit);
+        Ok(messages.iter().skip(skip).map(|(message, _, _)| message.clone()).collect())
+    }
+
+    async fn load_history_paginated(
+        &self,
+        room_name: &str,
+        page: i64,
+        page_size: i64,
+        include_presence: bool,
+    ) -> DbResult<VecDeque<(ServerMessage, BTreeMap<String, i64>, DateTime<Utc>)>> {
+        let inner = self.inner.lock().unwrap();
+        let Some(messages) = inner.messages.get(room_name) else {
+            return Ok(VecDeque::new());
+        };
+        let messages: Vec<&(ServerMessage, u64, DateTime<Utc>)> =
+            messages.iter().filter(|(message, _, _)| include_presence || !message.is_presence()).collect();
+        // Pages count back from the newest message, like `ORDER BY timestamp DESC LIMIT .. OFFSET ..`.
+        let end = messages.len().saturating_sub(((page - 1) * page_size) as usize);
+        let start = end.saturating_sub(page_size as usize);
+        Ok(messages[start..end]
+            .iter()
+            .map(|(message, _, stored_at)| (message.clone(), inner.reaction_counts(message), *stored_at))
+            .collect())
+    }
+
+    async fn get_message_count(&self, room_name: &str) -> DbResult<i64> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.messages.get(room_name).map_or(0, |messages| messages.len() as i64))
+    }
+
+    async fn get_latest_seq(&self, room_name: &str) -> DbResult<u64> {
+        let inner = self.inner.lock().unwrap();
+        Ok(match inner.latest_seq.get(room_name) {
+            Some(seq) => *seq,
+            None => inner.messages.get(room_name).map_or(0, |messages| messages.len() as u64),
+        })
+    }
+
+    async fn set_latest_seq(&self, room_name: &str, seq: u64) -> DbResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let latest = inner.latest_seq.entry(room_name.to_string()).or_insert(0);
+        *latest = (*latest).max(seq);
+        Ok(())
+    }
+
+    async fn stream_room_messages(&self, room_name: String, tx: mpsc::Sender<DbResult<ServerMessage>>) {
+        // Copy the room out first so the lock isn't held while waiting on the receiver.
+        let messages: Vec<ServerMessage> = {
+            let inner = self.inner.lock().unwrap();
+            inner.messages.get(&room_name).map_or_else(Vec::new, |messages| {
+                messages.iter().map(|(message, _, _)| message.clone()).collect()
+            })
+        };
+        for message in messages {
+            if tx.send(Ok(message)).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn add_reaction(&self, room_name: &str, message_id: Uuid, username: &str, emoji: &str) -> DbResult<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        Ok(inner.has_message(room_name, message_id) && inner.reactions.insert((message_id, username.to_string(), emoji.to_string())))
+    }
+
+    async fn delete_message(&self, room_name: &str, message_id: Uuid) -> DbResult<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(messages) = inner.messages.get_mut(room_name) else {
+            return Ok(false);
+        };
+        let before = messages.len();
+        messages.retain(|(message, _, _)| !matches!(message, ServerMessage::NewMessage { id, .. } if *id == message_id));
+        let deleted = messages.len() < before;
+        if deleted {
+            inner.reactions.retain(|(id, _, _)| *id != message_id);
+        }
+        Ok(deleted)
+    }
+
+    async fn clear_messages(&self, room_name: &str, seq: u64) -> DbResult<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(messages) = inner.messages.get_mut(room_name) else {
+            return Ok(0);
+        };
+        let (cleared, kept): (Vec<_>, Vec<_>) = messages.drain(..).partition(|(_, saved_seq, _)| *saved_seq <= seq);
+        *messages = kept;
+        for (message, _, _) in &cleared {
+            if let ServerMessage::NewMessage { id, .. } = message {
+                inner.reactions.retain(|(message_id, _, _)| message_id != id);
+            }
+        }
+        Ok(cleared.len() as u64)
+    }
+
+    async fn load_message_expiries(&self) -> DbResult<Vec<(String, Uuid, DateTime<Utc>)>> {
+        let inner = self.inner.lock().unwrap();
+        let mut expiries = Vec::new();
+        for (room_name, messages) in &inner.messages {
+            for (message, _, _) in messages {
+                if let ServerMessage::NewMessage { id, expires_at: Some(expires_at), .. } = message {
+                    expiries.push((room_name.clone(), *id, *expires_at));
+                }
+            }
+        }
+        Ok(expiries)
+    }
+
+    async fn is_room_closed(&self, room_name: &str) -> DbResult<bool> {
+        Ok(self.inner.lock().unwrap().closed_rooms.contains(room_name))
+    }
+
+    async fn set_room_closed(&self, room_name: &str, closed: bool) -> DbResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if closed {
+            inner.closed_rooms.insert(room_name.to_string());
+        } else {
+            inner.closed_rooms.remove(room_name);
+        }
+        Ok(())
+    }
+
+    async fn is_room_registered(&self, room_name: &str) -> DbResult<bool> {
+        Ok(self.inner.lock().unwrap().registered_rooms.contains(room_name))
+    }
+
+    async fn set_room_registered(&self, room_name: &str, registered: bool) -> DbResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if registered {
+            inner.registered_rooms.insert(room_name.to_string());
+        } else {
+            inner.registered_rooms.remove(room_name);
+        }
+        Ok(())
+    }
+
+    async fn get_room_topic(&self, room_name: &str) -> DbResult<Option<RoomTopic>> {
+        Ok(self.inner.lock().unwrap().topics.get(room_name).cloned())
+    }
+
+    async fn set_room_topic(&self, room_name: &str, topic: &RoomTopic) -> DbResult<()> {
+        self.inner.lock().unwrap().topics.insert(room_name.to_string(), topic.clone());
+        Ok(())
+    }
+
+    async fn get_room_metadata(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        Ok(self.inner.lock().unwrap().metadata.get(room_name).cloned().unwrap_or_default())
+    }
+
+    async fn set_room_metadata(&self, room_name: &str, metadata: &BTreeMap<String, String>) -> DbResult<()> {
+        self.inner.lock().unwrap().metadata.insert(room_name.to_string(), metadata.clone());
+        Ok(())
+    }
+
+    async fn get_public_keys(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        Ok(self.inner.lock().unwrap().public_keys.get(room_name).cloned().unwrap_or_default())
+    }
+
+    async fn set_public_keys(&self, room_name: &str, keys: &BTreeMap<String, String>) -> DbResult<()> {
+        self.inner.lock().unwrap().public_keys.insert(room_name.to_string(), keys.clone());
+        Ok(())
+    }
+
+    async fn get_username_pattern(&self, room_name: &str) -> DbResult<Option<String>> {
+        Ok(self.inner.lock().unwrap().username_patterns.get(room_name).cloned())
+    }
+
+    async fn set_username_pattern(&self, room_name: &str, pattern: Option<&str>) -> DbResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        match pattern {
+            Some(pattern) => inner.username_patterns.insert(room_name.to_string(), pattern.to_string()),
+            None => inner.username_patterns.remove(room_name),
+        };
+        Ok(())
+    }
+
+    async fn get_room_ownership(&self, room_name: &str) -> DbResult<RoomOwnership> {
+        Ok(self.inner.lock().unwrap().ownership.get(room_name).cloned().unwrap_or_default())
+    }
+
+    async fn set_room_ownership(&self, room_name: &str, ownership: &RoomOwnership) -> DbResult<()> {
+        self.inner.lock().unwrap().ownership.insert(room_name.to_string(), ownership.clone());
+        Ok(())
+    }
+
+    async fn load_message(&self, room_name: &str, message_id: Uuid) -> DbResult<Option<ServerMessage>> {
+        Ok(self.inner.lock().unwrap().find_message(room_name, message_id).cloned())
+    }
+
+    async fn load_thread(&self, room_name: &str, root: Uuid, max_depth: u32, limit: usize) -> DbResult<Vec<ThreadReply>> {
+        let inner = self.inner.lock().unwrap();
+        let Some(messages) = inner.messages.get(room_name) else {
+            return Ok(Vec::new());
+        };
+        // A reply is always saved after the message it answers, so one pass in save order sees every parent first.
+        let mut depths = HashMap::from([(root, 0)]);
+        let mut replies = Vec::new();
+        for (message, _, _) in messages {
+            if let ServerMessage::NewMessage { id, reply_to: Some(parent), .. } = message
+                && let Some(&parent_depth) = depths.get(parent)
+                && parent_depth < max_depth
+            {
+                depths.insert(*id, parent_depth + 1);
+                replies.push(ThreadReply { depth: parent_depth + 1, message: message.clone() });
+                if replies.len() == limit {
+                    break;
+                }
+            }
+        }
+        Ok(replies)
+    }
+
+    async fn get_pinned_messages(&self, room_name: &str) -> DbResult<Vec<ServerMessage>> {
+        let inner = self.inner.lock().unwrap();
+        let Some(ids) = inner.pinned.get(room_name) else {
+            return Ok(Vec::new());
+        };
+        Ok(ids.iter().filter_map(|&id| inner.find_message(room_name, id).cloned()).collect())
+    }
+
+    async fn set_pinned_ids(&self, room_name: &str, message_ids: &[Uuid]) -> DbResult<()> {
+        self.inner.lock().unwrap().pinned.insert(room_name.to_string(), message_ids.to_vec());
+        Ok(())
+    }
+
+    async fn get_secret_hash(&self, username: &str) -> DbResult<Option<String>> {
+        Ok(self.inner.lock().unwrap().registered_users.get(username).cloned())
+    }
+
+    async fn register_user(&self, username: &str, secret_hash: &str) -> DbResult<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.registered_users.contains_key(username) {
+            return Ok(false);
+        }
+        inner.registered_users.insert(username.to_string(), secret_hash.to_string());
+        Ok(true)
+    }
+
+    async fn get_recent_rooms(&self, since: DateTime<Utc>) -> DbResult<Vec<RecentRoom>> {
+        let inner = self.inner.lock().unwrap();
+        let mut rooms: Vec<RecentRoom> = inner
+            .messages
+            .iter()
+            .filter_map(|(room, messages)| {
+                let last_active = messages.iter().map(|(_, _, created_at)| *created_at).max()?;
+                (last_active >= since).then(|| RecentRoom {
+                    room: room.clone(),
+                    last_active,
+                    message_count: messages.len() as i64,
+                    metadata: inner.metadata.get(room).cloned().unwrap_or_default(),
+                })
+            })
+            .collect();
+        rooms.sort_by_key(|room| std::cmp::Reverse(room.last_active));
+        Ok(rooms)
+    }
+
+    async fn add_report(&self, report: &Report) -> DbResult<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let duplicate = inner
+            .reports
+            .iter()
+            .any(|existing| existing.message_id == report.message_id && existing.reporter == report.reporter);
+        if duplicate || !inner.has_message(&report.room, report.message_id) {
+            return Ok(false);
+        }
+        inner.reports.push(report.clone());
+        Ok(true)
+    }
+
+    async fn load_reports(&self, page: i64, page_size: i64) -> DbResult<Vec<Report>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .reports
+            .iter()
+            .rev()
+            .skip(((page - 1) * page_size) as usize)
+            .take(page_size as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn log_audit(&self, entry: &AuditEntry) -> DbResult<()> {
+        self.inner.lock().unwrap().audit_log.push(entry.clone());
+        Ok(())
+    }
+
+    async fn load_audit_log(&self, room_name: Option<&str>, page: i64, page_size: i64) -> DbResult<Vec<AuditEntry>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .audit_log
+            .iter()
+            .rev()
+            .filter(|entry| room_name.is_none() || entry.room.as_deref() == room_name)
+            .skip(((page - 1) * page_size) as usize)
+            .take(page_size as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_last_seen(&self, room_name: &str, username: &str) -> DbResult<Option<DateTime<Utc>>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.last_seen.get(&(room_name.to_string(), username.to_string())).copied())
+    }
+
+    async fn set_last_seen(&self, room_name: &str, username: &str, seen_at: DateTime<Utc>) -> DbResult<()> {
+        self.inner.lock().unwrap().last_seen.insert((room_name.to_string(), username.to_string()), seen_at);
+        Ok(())
+    }
+
+    async fn add_scheduled_message(&self, message: &ScheduledMessage) -> DbResult<()> {
+        self.inner.lock().unwrap().scheduled.push(message.clone());
+        Ok(())
+    }
+
+    async fn cancel_scheduled_message(&self, id: Uuid) -> DbResult<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.scheduled.len();
+        inner.scheduled.retain(|message| message.id != id);
+        Ok(inner.scheduled.len() < before)
+    }
+
+    async fn take_due_scheduled_messages(&self, now: DateTime<Utc>) -> DbResult<Vec<ScheduledMessage>> {
+        let mut inner = self.inner.lock().unwrap();
+        let (mut due, pending): (Vec<_>, Vec<_>) = inner.scheduled.drain(..).partition(|message| message.deliver_at <= now);
+        inner.scheduled = pending;
+        due.sort_by_key(|message| message.deliver_at);
+        Ok(due)
+    }
+
+    /// Messages are kept as `ServerMessage`s, so there is never anything to decode.
+    fn undecodable_messages(&self) -> u64 {
+        0
+    }
+}
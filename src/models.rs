@@ -1,14 +1,143 @@
 // src/models.rs
 
+use axum::extract::ws::{close_code, CloseFrame, Message};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
 
 /// A message sent from a client to the server.
 /// Deserialized from incoming JSON text.
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")] // Use a 'type' field to determine which variant it is
 pub enum ClientMessage {
-    SetUsername { username: String },
-    Message { content: String },
+    SetUsername {
+        username: String,
+        /// Required when the name has been reserved with `/register`.
+        #[serde(default)]
+        secret: Option<String>,
+        /// Join without a `UserJoined` broadcast (admins only).
+        #[serde(default)]
+        silent: bool,
+    },
+    Message {
+        content: String,
+        /// The content is an end-to-end encrypted blob the server relays without processing.
+        #[serde(default)]
+        encrypted: bool,
+        /// The `id` of an earlier message in the room this one replies to.
+        #[serde(default)]
+        reply_to: Option<Uuid>,
+        /// Delete the message this many seconds after it is sent, within `MESSAGE_TTL_MIN_SECS`..`MESSAGE_TTL_MAX_SECS`.
+        #[serde(default)]
+        expires_in_secs: Option<u64>,
+    },
+    Custom { kind: String, payload: serde_json::Value },
+    /// Confirms delivery of a `NewMessage` when `ACK_TIMEOUT_SECS` is set.
+    Ack { message_id: Uuid },
+}
+
+/// What a field of a client message has to hold.
+#[derive(Clone, Copy)]
+enum FieldKind {
+    /// A string with something other than whitespace in it.
+    NonEmptyString,
+    String,
+    Bool,
+    Uuid,
+    UnsignedInt,
+    Any,
+}
+
+/// One field of a `ClientMessage` type, as described to clients whose frames don't parse.
+struct FieldSpec {
+    name: &'static str,
+    kind: FieldKind,
+    required: bool,
+}
+
+const fn field(name: &'static str, kind: FieldKind, required: bool) -> FieldSpec {
+    FieldSpec { name, kind, required }
+}
+
+/// The fields of each `ClientMessage` type. Kept in step with the enum by hand; it only exists to
+/// explain frames that don't parse.
+const CLIENT_MESSAGE_FIELDS: &[(&str, &[FieldSpec])] = &[
+    (
+        "SetUsername",
+        &[
+            field("username", FieldKind::NonEmptyString, true),
+            field("secret", FieldKind::String, false),
+            field("silent", FieldKind::Bool, false),
+        ],
+    ),
+    (
+        "Message",
+        &[
+            field("content", FieldKind::String, true),
+            field("encrypted", FieldKind::Bool, false),
+            field("reply_to", FieldKind::Uuid, false),
+            field("expires_in_secs", FieldKind::UnsignedInt, false),
+        ],
+    ),
+    ("Custom", &[field("kind", FieldKind::NonEmptyString, true), field("payload", FieldKind::Any, true)]),
+    ("Ack", &[field("message_id", FieldKind::Uuid, true)]),
+];
+
+impl ClientMessage {
+    /// Parses a JSON frame. When it isn't a valid message the error says what is wrong in terms a client
+    /// developer can act on, e.g. "SetUsername requires a non-empty 'username' string".
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let value: serde_json::Value = serde_json::from_str(text).map_err(|e| format!("Frame is not valid JSON: {}", e))?;
+        if let Some(problem) = find_schema_problem(&value) {
+            return Err(problem);
+        }
+        serde_json::from_value(value).map_err(|e| format!("Invalid message: {}", e))
+    }
+}
+
+/// Checks a frame against `CLIENT_MESSAGE_FIELDS`, describing the first problem found.
+fn find_schema_problem(value: &serde_json::Value) -> Option<String> {
+    let types: Vec<&str> = CLIENT_MESSAGE_FIELDS.iter().map(|(name, _)| *name).collect();
+    let Some(object) = value.as_object() else {
+        return Some("A message must be a JSON object with a 'type' field.".to_string());
+    };
+    let Some(message_type) = object.get("type").and_then(|t| t.as_str()) else {
+        return Some(format!("A message needs a 'type' string, one of: {}.", types.join(", ")));
+    };
+    let Some((_, fields)) = CLIENT_MESSAGE_FIELDS.iter().find(|(name, _)| *name == message_type) else {
+        return Some(format!("Unknown message type '{}'; expected one of: {}.", message_type, types.join(", ")));
+    };
+
+    for spec in fields.iter() {
+        let (field, kind, required) = (spec.name, spec.kind, spec.required);
+        let field_value = object.get(field).filter(|v| !v.is_null());
+        let valid = match (field_value, kind) {
+            (None, _) => !required,
+            (Some(v), FieldKind::NonEmptyString) => v.as_str().is_some_and(|s| !s.trim().is_empty()),
+            (Some(v), FieldKind::String) => v.is_string(),
+            (Some(v), FieldKind::Bool) => v.is_boolean(),
+            (Some(v), FieldKind::Uuid) => v.as_str().is_some_and(|s| Uuid::parse_str(s).is_ok()),
+            (Some(v), FieldKind::UnsignedInt) => v.is_u64(),
+            (Some(_), FieldKind::Any) => true,
+        };
+        if valid {
+            continue;
+        }
+        let expected = match kind {
+            FieldKind::NonEmptyString => "a non-empty string",
+            FieldKind::String => "a string",
+            FieldKind::Bool => "true or false",
+            FieldKind::Uuid => "a message id (UUID)",
+            FieldKind::UnsignedInt => "a whole number of 0 or more",
+            FieldKind::Any => "a value",
+        };
+        return Some(if required {
+            format!("{} requires '{}' to be {}.", message_type, field, expected)
+        } else {
+            format!("{}'s optional '{}' must be {} if given.", message_type, field, expected)
+        });
+    }
+    None
 }
 
 /// A message sent from the server to a client.
@@ -16,7 +145,315 @@ pub enum ClientMessage {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
-    UserJoined { username: String },
-    UserLeft { username: String },
-    NewMessage { username: String, content: String },
+    /// Sent once, as the very first frame, so the client learns the identity the server assigned it.
+    Welcome {
+        client_id: Uuid,
+        room: String,
+        server_version: String,
+        /// What this server supports in this room (see `Config::features`).
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    UserJoined {
+        username: String,
+        /// When the server accepted the join. Absent on messages stored before it was recorded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    /// A client that had already joined changed its name with `/user`.
+    UserRenamed { old_username: String, new_username: String },
+    UserLeft {
+        username: String,
+        /// Optional parting message given with `/quit`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        /// When the client left. Absent on messages stored before it was recorded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    NewMessage {
+        /// Server-assigned id that other messages (e.g. reactions) refer to. Nil for rows stored before ids existed.
+        #[serde(default)]
+        id: Uuid,
+        username: String,
+        content: String,
+        /// Set when `content` is client-side ciphertext; the server never normalizes or expands it.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        encrypted: bool,
+        /// The sender's display color chosen with `/color`, as `#rrggbb`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        color: Option<String>,
+        /// Profanity score given by the content filter, when it found anything.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        severity: Option<u32>,
+        /// The `id` of the message this one replies to.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<Uuid>,
+        /// When the server will delete the message, if the sender gave it an `expires_in_secs`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+        /// When the server accepted the message. Absent on messages stored before it was recorded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    /// A chat message was deleted, e.g. because it expired. Clients should remove it from view.
+    MessageDeleted { id: Uuid },
+    /// A moderator deleted the room's history with `/clear`. Clients should remove the messages they are showing.
+    HistoryCleared { by: String },
+    /// A reaction added to an earlier message. Stored in the `reactions` table rather than as history.
+    ReactionAdded { message_id: Uuid, username: String, emoji: String },
+    /// Stands in for a stored message that could no longer be decoded, so the gap in history is visible.
+    /// Only produced when `HISTORY_TOMBSTONES` is on; `row_id` identifies the row for an operator.
+    Unreadable { row_id: i64 },
+    /// A message returned by `/history`, together with its aggregated reaction counts.
+    HistoryEntry {
+        message: Box<ServerMessage>,
+        reactions: BTreeMap<String, i64>,
+        /// Whether the requester had read this far with `/markread`. Absent until they first use it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seen: Option<bool>,
+    },
+    /// Reply to `/get <message_id>`: the requested chat message.
+    FetchedMessage { message: Box<ServerMessage> },
+    /// Reply to `/thread <message_id>`: the message and the replies under it, oldest first, down to
+    /// `THREAD_MAX_DEPTH` levels. Each reply's `reply_to` says where it hangs in the tree.
+    Thread { root: Box<ServerMessage>, replies: Vec<ThreadReply> },
+    /// Reply to `/roommeta`: the room's metadata, or just the requested key.
+    RoomMetadata { room: String, metadata: BTreeMap<String, String> },
+    /// Reply to `/keys`: the public keys members of the room have published, keyed by username.
+    PublicKeys { room: String, keys: BTreeMap<String, String> },
+    /// Reply to `/stats-me`: the requesting connection's own session. `username` is `None` until it has set one.
+    SessionStats {
+        client_id: Uuid,
+        username: Option<String>,
+        room: String,
+        joined_at: chrono::DateTime<chrono::Utc>,
+        messages_sent: u64,
+    },
+    /// Reply to `/history-count`: the number of messages persisted for the room.
+    MessageCount { room: String, count: i64 },
+    /// Reply to `/invite`: a token that lets one client into the private room (`?invite=<token>`) until `expires_at`.
+    InviteCreated { room: String, token: String, expires_at: chrono::DateTime<chrono::Utc> },
+    /// A request from this client could not be completed (e.g. the database timed out). Never broadcast or persisted.
+    Error { message: String },
+    /// A server notice addressed to a single client (confirmations, usage hints). Never broadcast or persisted.
+    Notice { message: String },
+    /// Reply to `/ping`, stamped with the server's clock (milliseconds since the Unix epoch) so the
+    /// client can measure the round trip.
+    Pong { server_time_ms: i64 },
+    /// Sent to the author of a chat message once it has been broadcast, when `DELIVERY_RECEIPTS` is on: the `id`
+    /// and `seq` the server gave it and the server time it was stamped with (milliseconds since the Unix epoch),
+    /// so an optimistic client can swap its local copy for the server's and estimate its clock skew.
+    Delivered { id: Uuid, seq: u64, server_time_ms: i64 },
+    /// The server-wide message of the day, sent right after `Welcome` while one is set.
+    Motd { message: String },
+    /// An announcement scheduled by an administrator with `POST /admin/schedule`, sent to everyone in the room when it falls due.
+    Announcement { message: String },
+    /// A user reported a message with `/report`. Sent only to administrators, in every room.
+    ReportFiled {
+        message_id: Uuid,
+        room: String,
+        reporter: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// The room's topic, broadcast when an administrator changes it and sent to each client as it joins.
+    TopicChanged { topic: String, by: String },
+    /// An administrator pinned a chat message with `/pin`.
+    MessagePinned { message: Box<ServerMessage>, by: String },
+    /// An administrator unpinned a message with `/unpin`.
+    MessageUnpinned { message_id: Uuid, by: String },
+    /// The room's owner and operators, broadcast when they change and sent to each client as it joins.
+    /// `owner` is `None` once the room has no owner left.
+    OwnershipChanged { owner: Option<String>, operators: Vec<String> },
+    /// The room's pinned messages, in pin order, sent to each client as it joins (after the history).
+    PinnedMessages { messages: Vec<ServerMessage> },
+    /// Everyone named in the room, including the joiner, sent to each client as it joins. Later
+    /// `UserJoined`, `UserLeft` and `UserRenamed` events apply on top of it. `latest_seq` is the
+    /// `seq` of the room's newest message (0 if it has none).
+    PresenceSnapshot { users: Vec<PresenceEntry>, latest_seq: u64 },
+    /// Structured content (polls, locations, cards) relayed and persisted exactly as the client sent it.
+    Custom { username: String, kind: String, payload: serde_json::Value },
+}
+
+impl ServerMessage {
+    /// The `type` of the messages the server stores, as named in `PERSIST_TYPES`; `None` for everything else.
+    pub fn persisted_type(&self) -> Option<&'static str> {
+        match self {
+            ServerMessage::NewMessage { .. } => Some("NewMessage"),
+            ServerMessage::Custom { .. } => Some("Custom"),
+            ServerMessage::UserJoined { .. } => Some("UserJoined"),
+            ServerMessage::UserLeft { .. } => Some("UserLeft"),
+            ServerMessage::UserRenamed { .. } => Some("UserRenamed"),
+            _ => None,
+        }
+    }
+
+    /// The user who wrote a chat or custom message; `None` for everything else.
+    pub fn author(&self) -> Option<&str> {
+        match self {
+            ServerMessage::NewMessage { username, .. } | ServerMessage::Custom { username, .. } => Some(username),
+            _ => None,
+        }
+    }
+
+    /// When a chat message, join or leave was accepted by the server, if that is known.
+    pub fn sent_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self {
+            ServerMessage::NewMessage { sent_at, .. }
+            | ServerMessage::UserJoined { sent_at, .. }
+            | ServerMessage::UserLeft { sent_at, .. } => *sent_at,
+            _ => None,
+        }
+    }
+
+    /// Fills in `sent_at` with `stored_at` for a message stored before the time was recorded with it.
+    pub fn or_sent_at(mut self, stored_at: chrono::DateTime<chrono::Utc>) -> Self {
+        if let ServerMessage::NewMessage { sent_at, .. } | ServerMessage::UserJoined { sent_at, .. } | ServerMessage::UserLeft { sent_at, .. } =
+            &mut self
+        {
+            sent_at.get_or_insert(stored_at);
+        }
+        self
+    }
+
+    /// Whether this records someone arriving or leaving, which history can leave out (`HISTORY_INCLUDES_PRESENCE`).
+    pub fn is_presence(&self) -> bool {
+        matches!(self, ServerMessage::UserJoined { .. } | ServerMessage::UserLeft { .. })
+    }
+
+    /// Whether the server itself is speaking (joins, notices, announcements), as opposed to relaying
+    /// what a user wrote. Only system messages are signed for plain-text clients.
+    pub fn is_system(&self) -> bool {
+        match self {
+            ServerMessage::NewMessage { .. } | ServerMessage::Custom { .. } => false,
+            ServerMessage::HistoryEntry { message, .. } => message.is_system(),
+            _ => true,
+        }
+    }
+}
+
+/// Subprotocols the server accepts, in order of preference.
+pub const SUPPORTED_SUBPROTOCOLS: &[&str] = &["chat.v2", "chat.v1"];
+
+/// One message in a `Thread`. Direct replies to the root have `depth` 1, replies to those 2, and so on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadReply {
+    pub depth: u32,
+    pub message: ServerMessage,
+}
+
+/// One member of a room in a `PresenceSnapshot`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceEntry {
+    pub username: String,
+    pub status: PresenceStatus,
+}
+
+/// What a room member is up to. Connected members are always `online` for now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+}
+
+/// Wire format negotiated through the `Sec-WebSocket-Protocol` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// Plain-text rendering (`[alice] hello`). Used when the client asks for no subprotocol.
+    V1,
+    /// Every frame is a JSON-serialized `ServerMessage`.
+    V2,
+}
+
+impl ProtocolVersion {
+    pub fn from_subprotocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            "chat.v1" => Some(ProtocolVersion::V1),
+            "chat.v2" => Some(ProtocolVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+/// Why the server closed a connection. Each reason has a fixed close code and reason text so clients
+/// can tell a deliberate close from a dropped connection.
+///
+/// Application-specific codes use the 4000-4999 range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client left with `/quit`.
+    Quit,
+    /// The server is shutting down.
+    ShuttingDown,
+    /// The client's outbound queue overflowed under the `disconnect` drop policy.
+    TooSlow,
+    /// The client sent frames faster than `FRAME_RATE_LIMIT` allows.
+    Flooding,
+    /// The client stayed anonymous longer than `ANON_TIMEOUT_SECS`.
+    NameRequired,
+    /// The client sent more than `MAX_INBOUND_BYTES` within `INBOUND_WINDOW_SECS`.
+    InboundBudgetExceeded,
+    /// The client didn't acknowledge a message within `ACK_TIMEOUT_SECS`, even after `ACK_MAX_RESENDS` resends.
+    Unacknowledged,
+    /// A frame couldn't be read: invalid UTF-8 in a text message, a protocol violation, or a
+    /// message (after reassembling its fragments) larger than `MAX_MESSAGE_BYTES`.
+    InvalidFrame,
+    /// The room is private and the client gave neither its password nor a valid invite.
+    AuthFailed,
+    /// The client's address is listed in `BANNED_IPS`.
+    Banned,
+    /// The room already holds `ROOM_MAX_CLIENTS` connections.
+    RoomFull,
+    /// The room doesn't exist and `AUTO_CREATE_ROOMS` is off, so connecting didn't create it.
+    UnknownRoom,
+    /// The room couldn't be opened because the database failed while loading it.
+    RoomUnavailable,
+    /// A moderator removed the client's user from the room with `/kick` or `/ban`.
+    Kicked,
+}
+
+impl CloseReason {
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::Quit => close_code::NORMAL,
+            CloseReason::ShuttingDown => close_code::AWAY,
+            CloseReason::TooSlow => 4008,
+            CloseReason::Flooding => close_code::POLICY,
+            CloseReason::NameRequired => 4009,
+            CloseReason::InboundBudgetExceeded => 4010,
+            CloseReason::Unacknowledged => 4011,
+            CloseReason::InvalidFrame => close_code::INVALID,
+            CloseReason::AuthFailed => 4001,
+            CloseReason::Banned => 4002,
+            CloseReason::RoomFull => 4003,
+            CloseReason::UnknownRoom => 4004,
+            CloseReason::Kicked => 4005,
+            CloseReason::RoomUnavailable => close_code::ERROR,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseReason::Quit => "Goodbye",
+            CloseReason::ShuttingDown => "Server shutting down",
+            CloseReason::TooSlow => "Client is not keeping up with messages",
+            CloseReason::Flooding => "Too many frames",
+            CloseReason::NameRequired => "Set a username with /user to stay connected",
+            CloseReason::InboundBudgetExceeded => "Connection data budget exceeded",
+            CloseReason::Unacknowledged => "Messages were not acknowledged",
+            CloseReason::InvalidFrame => "Invalid frame",
+            CloseReason::AuthFailed => "Authentication failed",
+            CloseReason::Banned => "Banned",
+            CloseReason::RoomFull => "Room is full",
+            CloseReason::UnknownRoom => "Room does not exist",
+            CloseReason::RoomUnavailable => "Room is unavailable right now",
+            CloseReason::Kicked => "Removed from the room by a moderator",
+        }
+    }
+
+    /// The close frame to send for this reason.
+    pub fn close_message(self) -> Message {
+        Message::Close(Some(CloseFrame { code: self.code(), reason: self.reason().into() }))
+    }
 }
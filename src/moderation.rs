@@ -0,0 +1,106 @@
+// src/moderation.rs
+
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+
+/// Rates how offensive a chat message is. Zero means clean; the scale is up to the implementation,
+/// and the thresholds in `ContentModeration` are expressed on it.
+pub trait ContentScorer: Send + Sync {
+    fn score(&self, content: &str) -> u32;
+}
+
+/// Scores a message by the categories it falls into: each category is a regex with a weight, and
+/// the score is the sum of the weights of every category that matches.
+pub struct RegexScorer {
+    categories: Vec<(String, Regex, u32)>,
+}
+
+/// A category as written in `PROFANITY_CATEGORIES`.
+#[derive(Deserialize)]
+struct CategorySpec {
+    pattern: String,
+    weight: u32,
+}
+
+impl RegexScorer {
+    /// Builds a scorer from `(name, pattern, weight)` triples. Patterns that don't compile are reported and skipped.
+    pub fn new(categories: impl IntoIterator<Item = (String, String, u32)>) -> Self {
+        let categories = categories
+            .into_iter()
+            .filter_map(|(name, pattern, weight)| match Regex::new(&pattern) {
+                Ok(regex) => Some((name, regex, weight)),
+                Err(e) => {
+                    eprintln!("Ignoring profanity category '{}': invalid pattern: {}", name, e);
+                    None
+                }
+            })
+            .collect();
+        RegexScorer { categories }
+    }
+}
+
+impl ContentScorer for RegexScorer {
+    fn score(&self, content: &str) -> u32 {
+        self.categories
+            .iter()
+            .filter(|(_, regex, _)| regex.is_match(content))
+            .map(|(_, _, weight)| *weight)
+            .fold(0, u32::saturating_add)
+    }
+}
+
+/// What happens to a chat message after it has been scored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationAction {
+    /// Delivered as usual.
+    Allow,
+    /// Delivered, but tagged with its severity and reported to the moderators.
+    Flag,
+    /// Not delivered; only the sender is told.
+    Block,
+}
+
+/// Scores chat messages and decides what to do with them.
+pub struct ContentModeration {
+    scorer: Box<dyn ContentScorer>,
+    /// Messages scoring at least this much are flagged; `None` never flags.
+    flag_at: Option<u32>,
+    /// Messages scoring at least this much are blocked; `None` never blocks.
+    block_at: Option<u32>,
+}
+
+impl ContentModeration {
+    pub fn new(scorer: Box<dyn ContentScorer>, flag_at: Option<u32>, block_at: Option<u32>) -> Self {
+        ContentModeration { scorer, flag_at, block_at }
+    }
+
+    /// Reads `PROFANITY_CATEGORIES`, a JSON object of category to `{"pattern": ..., "weight": ...}`.
+    /// Scoring is off when no categories are set.
+    pub(crate) fn from_env(flag_at: Option<u32>, block_at: Option<u32>) -> Option<Self> {
+        let raw = env::var("PROFANITY_CATEGORIES").ok().filter(|raw| !raw.trim().is_empty())?;
+        let categories = match serde_json::from_str::<HashMap<String, CategorySpec>>(&raw) {
+            Ok(categories) => categories,
+            Err(e) => {
+                eprintln!("Invalid PROFANITY_CATEGORIES: {}. Messages are not scored.", e);
+                return None;
+            }
+        };
+        let scorer = RegexScorer::new(categories.into_iter().map(|(name, spec)| (name, spec.pattern, spec.weight)));
+        Some(ContentModeration::new(Box::new(scorer), flag_at, block_at))
+    }
+
+    /// Scores `content` and picks the action for that score.
+    pub fn assess(&self, content: &str) -> (u32, ModerationAction) {
+        let score = self.scorer.score(content);
+        let action = if self.block_at.is_some_and(|at| score >= at) {
+            ModerationAction::Block
+        } else if self.flag_at.is_some_and(|at| score >= at) {
+            ModerationAction::Flag
+        } else {
+            ModerationAction::Allow
+        };
+        (score, action)
+    }
+}
@@ -0,0 +1,70 @@
+// src/monitor.rs
+
+use crate::admin::authorize;
+use crate::events::ServerEvent;
+use crate::models::ServerMessage;
+use crate::state::ChatState;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::{sink::SinkExt, stream::StreamExt};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// One event on the admin monitor feed: something that happened in `room`, as its clients saw it.
+/// `room` is `None` for notices about the feed itself.
+#[derive(Debug, Clone, Serialize)]
+pub struct MonitorEvent {
+    pub room: Option<String>,
+    pub event: ServerMessage,
+}
+
+/// `GET /ws/admin`: a WebSocket streaming every room's events (messages, joins, leaves, renames, reports) as
+/// JSON `MonitorEvent`s, for moderators watching the whole server. Takes the same bearer token as the admin API.
+/// Anything the monitor sends is ignored. The feed is the `RoomMessage` events on the event bus, so it
+/// watches the rooms without being part of how they are delivered.
+pub async fn monitor_handler(ws: WebSocketUpgrade, headers: HeaderMap, State(state): State<ChatState>) -> Response {
+    if let Err(rejection) = authorize(&headers, &state.config) {
+        return rejection.into_response();
+    }
+    if state.shutting_down.load(Ordering::SeqCst) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server is shutting down").into_response();
+    }
+
+    let feed = state.events.subscribe();
+    println!("Admin monitor connected.");
+    ws.on_upgrade(move |socket| stream_feed(socket, feed))
+}
+
+async fn stream_feed(socket: WebSocket, mut feed: broadcast::Receiver<ServerEvent>) {
+    let (mut sender, mut receiver) = socket.split();
+    loop {
+        let event = tokio::select! {
+            event = feed.recv() => event,
+            frame = receiver.next() => match frame {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let event = match event {
+            Ok(ServerEvent::RoomMessage { room, message }) => MonitorEvent { room: Some(room), event: message },
+            Ok(_) => continue,
+            Err(RecvError::Lagged(skipped)) => {
+                let message = format!("The monitor fell behind and missed {} events.", skipped);
+                MonitorEvent { room: None, event: ServerMessage::Notice { message } }
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let Ok(json) = serde_json::to_string(&event) else { continue };
+        if sender.send(Message::Text(json.into())).await.is_err() {
+            break;
+        }
+    }
+    println!("Admin monitor disconnected.");
+}
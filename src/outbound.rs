@@ -0,0 +1,272 @@
+// src/outbound.rs
+
+use crate::models::CloseReason;
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{sink::SinkExt, stream::SplitSink};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// What to do when a client's outbound queue is full because it isn't reading fast enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Discard the new message and keep what is already queued.
+    DropNewest,
+    /// Treat the client as dead: stop its writer and let the caller evict it.
+    Disconnect,
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(DropPolicy::DropOldest),
+            "drop-newest" => Ok(DropPolicy::DropNewest),
+            "disconnect" => Ok(DropPolicy::Disconnect),
+            other => Err(format!("unknown drop policy '{}'", other)),
+        }
+    }
+}
+
+/// What happens once a room's clients together have `ROOM_MAX_IN_FLIGHT` messages waiting to be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShedPolicy {
+    /// Each new message for a client first discards that client's oldest queued one.
+    DropOldest,
+    /// New chat messages are refused until the room's queues drain.
+    Throttle,
+}
+
+impl FromStr for ShedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(ShedPolicy::DropOldest),
+            "throttle" => Ok(ShedPolicy::Throttle),
+            other => Err(format!("unknown shed policy '{}'", other)),
+        }
+    }
+}
+
+/// Counts the messages queued but not yet written across every client in one room.
+#[derive(Default)]
+pub struct RoomBacklog {
+    in_flight: AtomicUsize,
+    high_water: AtomicUsize,
+    shed: AtomicU64,
+}
+
+impl RoomBacklog {
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// The most messages that have been in flight at once.
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    /// How many queued messages were discarded because the room was over its limit.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the room has reached `limit`; always false when there is no limit.
+    pub fn is_over(&self, limit: Option<usize>) -> bool {
+        limit.is_some_and(|limit| self.in_flight() >= limit)
+    }
+
+    fn add(&self, count: usize) {
+        let now = self.in_flight.fetch_add(count, Ordering::Relaxed) + count;
+        self.high_water.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn remove(&self, count: usize) {
+        self.in_flight.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+/// Returned when a message can't be queued because the client's connection is gone or was dropped by policy.
+#[derive(Debug)]
+pub struct Disconnected;
+
+/// Handle to a client's outbound queue. Pushing never waits on the socket, so a slow client can't stall
+/// whoever is sending to it (usually while holding the rooms lock); a dedicated writer task drains the queue.
+/// Dropping the handle lets the writer flush what's left and exit.
+pub struct Outbound {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+    capacity: usize,
+    policy: DropPolicy,
+    /// Room backlog at which this client's oldest queued message is discarded for every new one.
+    shed_at: Option<usize>,
+}
+
+struct Queue {
+    messages: VecDeque<Message>,
+    /// No more messages are accepted; the writer exits once `messages` is empty.
+    closed: bool,
+    dropped: u64,
+    /// The backlog of the room this client is in, kept in step with `messages` once attached.
+    backlog: Option<Arc<RoomBacklog>>,
+}
+
+impl Queue {
+    fn push_back(&mut self, message: Message) {
+        self.messages.push_back(message);
+        if let Some(backlog) = &self.backlog {
+            backlog.add(1);
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<Message> {
+        let message = self.messages.pop_front()?;
+        if let Some(backlog) = &self.backlog {
+            backlog.remove(1);
+        }
+        Some(message)
+    }
+
+    fn clear(&mut self) {
+        if let Some(backlog) = &self.backlog {
+            backlog.remove(self.messages.len());
+        }
+        self.messages.clear();
+    }
+}
+
+impl Outbound {
+    /// Starts the writer task for `sink` and returns the handle used to queue messages for it.
+    pub fn spawn(
+        client_id: Uuid,
+        sink: SplitSink<WebSocket, Message>,
+        capacity: usize,
+        policy: DropPolicy,
+        shed_at: Option<usize>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue { messages: VecDeque::new(), closed: false, dropped: 0, backlog: None }),
+            notify: Notify::new(),
+            capacity: capacity.max(1),
+            policy,
+            shed_at,
+        });
+        tokio::spawn(write_to_client(client_id, sink, shared.clone()));
+        Outbound { shared }
+    }
+
+    /// Counts this queue, including anything already in it, towards the room's backlog from now on.
+    pub fn attach_backlog(&self, backlog: Arc<RoomBacklog>) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        backlog.add(queue.messages.len());
+        queue.backlog = Some(backlog);
+    }
+
+    /// Queues a message, applying the drop policy if the queue is full.
+    pub fn push(&self, message: Message) -> Result<(), Disconnected> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return Err(Disconnected);
+        }
+        // While the room is over its limit, every new message replaces this client's oldest one. An open
+        // queue never holds a close frame, so nothing that matters for the connection itself is lost.
+        if let Some(backlog) = queue.backlog.clone()
+            && backlog.is_over(self.shared.shed_at)
+            && queue.pop_front().is_some()
+        {
+            queue.dropped += 1;
+            backlog.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        if queue.messages.len() >= self.shared.capacity {
+            match self.shared.policy {
+                DropPolicy::DropOldest => {
+                    queue.pop_front();
+                    queue.dropped += 1;
+                }
+                DropPolicy::DropNewest => {
+                    queue.dropped += 1;
+                    return Ok(());
+                }
+                DropPolicy::Disconnect => {
+                    // Skip the backlog and tell the client why it's being dropped, in case it's still listening.
+                    queue.clear();
+                    queue.push_back(CloseReason::TooSlow.close_message());
+                    queue.closed = true;
+                    drop(queue);
+                    self.shared.notify.notify_one();
+                    return Err(Disconnected);
+                }
+            }
+        }
+        queue.push_back(message);
+        drop(queue);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    /// Queues a close frame after everything already pending and stops accepting messages.
+    /// The frame bypasses the capacity limit so a full queue can still be closed cleanly.
+    pub fn close(&self, reason: CloseReason) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if !queue.closed {
+            queue.push_back(reason.close_message());
+            queue.closed = true;
+        }
+        drop(queue);
+        self.shared.notify.notify_one();
+    }
+}
+
+impl Drop for Outbound {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+/// Drains a client's queue into its socket until the queue is closed and empty or the socket fails.
+async fn write_to_client(client_id: Uuid, mut sink: SplitSink<WebSocket, Message>, shared: Arc<Shared>) {
+    loop {
+        let next = {
+            let mut queue = shared.queue.lock().unwrap();
+            match queue.pop_front() {
+                Some(message) => Some(message),
+                None if queue.closed => break,
+                None => None,
+            }
+        };
+        let Some(message) = next else {
+            shared.notify.notified().await;
+            continue;
+        };
+
+        let is_close = matches!(message, Message::Close(_));
+        if sink.send(message).await.is_err() {
+            println!("Failed to write to client {}; stopping its writer.", client_id);
+            let mut queue = shared.queue.lock().unwrap();
+            queue.closed = true;
+            queue.clear();
+            break;
+        }
+        if is_close {
+            shared.queue.lock().unwrap().closed = true;
+            break;
+        }
+    }
+
+    let dropped = shared.queue.lock().unwrap().dropped;
+    if dropped > 0 {
+        println!("Dropped {} messages for slow client {}.", dropped, client_id);
+    }
+}
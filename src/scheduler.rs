@@ -0,0 +1,32 @@
+// src/scheduler.rs
+
+use crate::state::ChatState;
+use crate::websocket::deliver_announcement;
+use chrono::Utc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// How often the store is checked for announcements that have fallen due
+const SCHEDULER_TICK: Duration = Duration::from_secs(1);
+
+/// Spawns the background task that delivers announcements scheduled with `POST /admin/schedule`.
+/// Pending announcements live only in the store, so after a restart the first tick picks up
+/// whatever is still queued, including anything that fell due while the server was down.
+pub fn spawn_scheduler(state: ChatState) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SCHEDULER_TICK);
+        while !state.shutting_down.load(Ordering::SeqCst) {
+            ticker.tick().await;
+            // Not bounded by `DB_TIMEOUT`: abandoning the query could lose rows it already removed.
+            match state.store.take_due_scheduled_messages(Utc::now()).await {
+                Ok(due) => {
+                    for scheduled in due {
+                        deliver_announcement(&state, &scheduled).await;
+                    }
+                }
+                // Nothing was removed, so the next tick tries the same announcements again.
+                Err(e) => state.record_db_error(&e),
+            }
+        }
+    });
+}
@@ -0,0 +1,857 @@
+// src/sqlite_store.rs
+
+use crate::models::{ServerMessage, ThreadReply};
+use crate::store::{
+    AuditEntry, DbError, DbResult, DecodeFailures, MessageStore, Migration, RecentRoom, Report, RoomOwnership, RoomTopic, ScheduledMessage,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::stream::StreamExt;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqliteRow},
+    Row,
+};
+use std::collections::{BTreeMap, VecDeque};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// A `MessageStore` backed by a local SQLite file, for single-node deployments without PostgreSQL.
+/// Messages are stored as JSON text; `COMPRESS_STORAGE` only applies to PostgreSQL.
+pub struct SqliteStore {
+    pool: SqlitePool,
+    decode_failures: DecodeFailures,
+}
+
+/// One step of a SQLite migration.
+pub enum SqliteStep {
+    Sql(&'static str),
+    /// SQLite has no `ADD COLUMN IF NOT EXISTS`, so the runner checks the table's columns first.
+    AddColumn { table: &'static str, column: &'static str, definition: &'static str },
+}
+
+/// The SQLite schema, oldest first. Version 1 is the schema as it stood before migrations were
+/// versioned; its steps all tolerate existing tables and columns, so it also brings older files up to date.
+pub const MIGRATIONS: &[Migration<SqliteStep>] = &[Migration {
+    version: 1,
+    description: "baseline schema",
+    steps: &[
+        SqliteStep::Sql(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                room TEXT NOT NULL,
+                message TEXT NOT NULL,
+                message_id TEXT,
+                timestamp TEXT NOT NULL
+            )",
+        ),
+        SqliteStep::Sql("CREATE INDEX IF NOT EXISTS messages_room_timestamp ON messages (room, timestamp)"),
+        SqliteStep::Sql(
+            "CREATE TABLE IF NOT EXISTS rooms_meta (
+                room TEXT PRIMARY KEY,
+                closed BOOLEAN NOT NULL DEFAULT FALSE
+            )",
+        ),
+        SqliteStep::AddColumn { table: "rooms_meta", column: "topic", definition: "TEXT" },
+        SqliteStep::AddColumn { table: "rooms_meta", column: "topic_set_by", definition: "TEXT" },
+        SqliteStep::AddColumn { table: "rooms_meta", column: "username_pattern", definition: "TEXT" },
+        // JSON array of message ids, in pin order.
+        SqliteStep::AddColumn { table: "rooms_meta", column: "pinned_ids", definition: "TEXT" },
+        SqliteStep::AddColumn { table: "rooms_meta", column: "owner", definition: "TEXT" },
+        // JSON array of usernames.
+        SqliteStep::AddColumn { table: "rooms_meta", column: "operators", definition: "TEXT" },
+        SqliteStep::Sql(
+            "CREATE TABLE IF NOT EXISTS reactions (
+                message_id TEXT NOT NULL,
+                username TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                PRIMARY KEY (message_id, username, emoji)
+            )",
+        ),
+        SqliteStep::Sql(
+            "CREATE TABLE IF NOT EXISTS registered_users (
+                username TEXT PRIMARY KEY,
+                secret_hash TEXT NOT NULL,
+                registered_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+        ),
+        SqliteStep::Sql(
+            "CREATE TABLE IF NOT EXISTS reports (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id TEXT NOT NULL,
+                room TEXT NOT NULL,
+                reporter TEXT NOT NULL,
+                reason TEXT,
+                reported_at TEXT NOT NULL,
+                UNIQUE (message_id, reporter)
+            )",
+        ),
+        SqliteStep::Sql(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                action TEXT NOT NULL,
+                actor TEXT NOT NULL,
+                target TEXT,
+                room TEXT,
+                timestamp TEXT NOT NULL,
+                detail TEXT
+            )",
+        ),
+    ],
+}, Migration {
+    version: 2,
+    description: "reply threading",
+    steps: &[
+        SqliteStep::AddColumn { table: "messages", column: "reply_to", definition: "TEXT" },
+        SqliteStep::Sql("CREATE INDEX IF NOT EXISTS messages_room_reply_to ON messages (room, reply_to)"),
+    ],
+}, Migration {
+    version: 3,
+    description: "room metadata",
+    // JSON object of key to value, set with `/roommeta`.
+    steps: &[SqliteStep::AddColumn { table: "rooms_meta", column: "metadata", definition: "TEXT" }],
+}, Migration {
+    version: 4,
+    description: "presence flag",
+    steps: &[
+        SqliteStep::AddColumn { table: "messages", column: "presence", definition: "BOOLEAN NOT NULL DEFAULT FALSE" },
+        SqliteStep::Sql("UPDATE messages SET presence = TRUE WHERE json_extract(message, '$.type') IN ('UserJoined', 'UserLeft')"),
+    ],
+}, Migration {
+    version: 5,
+    description: "room registry",
+    steps: &[SqliteStep::AddColumn { table: "rooms_meta", column: "registered", definition: "BOOLEAN NOT NULL DEFAULT FALSE" }],
+}, Migration {
+    version: 6,
+    description: "scheduled messages",
+    steps: &[
+        SqliteStep::Sql(
+            "CREATE TABLE IF NOT EXISTS scheduled_messages (
+                id TEXT PRIMARY KEY,
+                room TEXT NOT NULL,
+                text TEXT NOT NULL,
+                deliver_at TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+        ),
+        SqliteStep::Sql("CREATE INDEX IF NOT EXISTS scheduled_messages_deliver_at ON scheduled_messages (deliver_at)"),
+    ],
+}, Migration {
+    version: 7,
+    description: "member public keys",
+    // JSON object of username to the public key published with `/key publish`.
+    steps: &[SqliteStep::AddColumn { table: "rooms_meta", column: "public_keys", definition: "TEXT" }],
+}, Migration {
+    version: 8,
+    description: "read cursors",
+    steps: &[SqliteStep::Sql(
+        "CREATE TABLE IF NOT EXISTS user_room_state (
+            room TEXT NOT NULL,
+            username TEXT NOT NULL,
+            last_seen_at TEXT NOT NULL,
+            PRIMARY KEY (room, username)
+        )",
+    )],
+}, Migration {
+    version: 9,
+    description: "message expiry",
+    // Set for messages sent with `expires_in_secs`; the row is deleted once it has passed.
+    steps: &[
+        SqliteStep::AddColumn { table: "messages", column: "expires_at", definition: "TEXT" },
+        SqliteStep::Sql("CREATE INDEX IF NOT EXISTS messages_expires_at ON messages (expires_at) WHERE expires_at IS NOT NULL"),
+    ],
+}, Migration {
+    version: 10,
+    description: "message numbering",
+    // The room's highest message `seq`, kept apart from the rows so deleting or skipping messages doesn't lower it.
+    steps: &[SqliteStep::AddColumn { table: "rooms_meta", column: "last_seq", definition: "INTEGER" }],
+}, Migration {
+    version: 11,
+    description: "message seq",
+    // Each message's `seq`, saved with it so numbering a message costs no separate write.
+    steps: &[
+        SqliteStep::AddColumn { table: "messages", column: "seq", definition: "INTEGER" },
+        SqliteStep::Sql("CREATE INDEX IF NOT EXISTS messages_room_seq ON messages (room, seq)"),
+    ],
+}];
+
+impl SqliteStore {
+    /// Opens (creating if needed) the database file at `path` and applies any pending schema migrations.
+    pub async fn connect(path: &str) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::new().filename(path).create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+
+        let version = run_migrations(&pool).await?;
+        println!("SQLite database '{}' setup complete (schema version {}).", path, version);
+        Ok(SqliteStore { pool, decode_failures: DecodeFailures::default() })
+    }
+
+    /// Makes history show a placeholder where a stored message can't be decoded, instead of leaving it out.
+    pub fn with_tombstones(mut self, tombstones: bool) -> Self {
+        self.decode_failures = DecodeFailures::new(tombstones);
+        self
+    }
+
+    /// The newest migration applied to the database.
+    pub async fn schema_version(&self) -> Result<i64, sqlx::Error> {
+        current_version(&self.pool).await
+    }
+
+    /// Decodes the message in a row, logging and counting it if that fails.
+    fn decode(&self, room_name: &str, row: &SqliteRow) -> Option<ServerMessage> {
+        match decode_message(row) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                self.decode_failures.record(room_name, row.try_get("id").unwrap_or(0), &e);
+                None
+            }
+        }
+    }
+
+    /// Like `decode`, for history: a row that can't be decoded becomes a placeholder when tombstones are on.
+    fn decode_history(&self, room_name: &str, row: &SqliteRow) -> Option<ServerMessage> {
+        self.decode(room_name, row).or_else(|| self.decode_failures.placeholder(row.try_get("id").unwrap_or(0)))
+    }
+}
+
+async fn current_version(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_version").fetch_one(pool).await
+}
+
+/// Applies the migrations the database hasn't seen yet, each in its own transaction, and returns the
+/// resulting schema version.
+async fn run_migrations(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    let current = current_version(pool).await?;
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        let mut tx = pool.begin().await?;
+        for step in migration.steps {
+            match step {
+                SqliteStep::Sql(sql) => {
+                    sqlx::query(sql).execute(&mut *tx).await?;
+                }
+                SqliteStep::AddColumn { table, column, definition } => {
+                    add_column_if_missing(&mut tx, table, column, definition).await?;
+                }
+            }
+        }
+        sqlx::query("INSERT INTO schema_version (version, description) VALUES (?, ?)")
+            .bind(migration.version)
+            .bind(migration.description)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        println!("Applied database migration {}: {}", migration.version, migration.description);
+    }
+
+    let version = current_version(pool).await?;
+    let known = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if version > known {
+        eprintln!("Database schema version {} is newer than this server's ({}); was it downgraded?", version, known);
+    }
+    Ok(version)
+}
+
+async fn add_column_if_missing(conn: &mut SqliteConnection, table: &str, column: &str, definition: &str) -> Result<(), sqlx::Error> {
+    let columns = sqlx::query(&format!("PRAGMA table_info({})", table)).fetch_all(&mut *conn).await?;
+    if !columns.iter().any(|row| row.get::<String, _>("name") == column) {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+fn decode_message(row: &SqliteRow) -> Result<ServerMessage, String> {
+    let json: String = row.try_get("message").map_err(|e| e.to_string())?;
+    let message: ServerMessage = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+    // Rows saved before messages carried their time still have the row's.
+    Ok(match row.try_get::<Option<DateTime<Utc>>, _>("timestamp") {
+        Ok(Some(stored_at)) => message.or_sent_at(stored_at),
+        _ => message,
+    })
+}
+
+#[async_trait]
+impl MessageStore for SqliteStore {
+    async fn save_message(&self, room_name: &str, seq: u64, message: &ServerMessage, created_at: DateTime<Utc>) -> DbResult<()> {
+        let json = serde_json::to_string(message)?;
+        let (message_id, reply_to, expires_at) = match message {
+            ServerMessage::NewMessage { id, reply_to, expires_at, .. } => {
+                (Some(id.to_string()), reply_to.map(|parent| parent.to_string()), *expires_at)
+            }
+            _ => (None, None, None),
+        };
+        sqlx::query(
+            "INSERT INTO messages (room, message, message_id, reply_to, presence, timestamp, expires_at, seq) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(room_name)
+        .bind(json)
+        .bind(message_id)
+        .bind(reply_to)
+        .bind(message.is_presence())
+        .bind(created_at)
+        .bind(expires_at)
+        .bind((seq > 0).then_some(seq as i64))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn load_history(&self, room_name: &str, limit: usize) -> DbResult<VecDeque<ServerMessage>> {
+        let rows = sqlx::query("SELECT id, message, timestamp FROM messages WHERE room = ? ORDER BY timestamp DESC, id DESC LIMIT ?")
+            .bind(room_name)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.iter().rev().filter_map(|row| self.decode_history(room_name, row)).collect())
+    }
+
+    async fn load_history_paginated(
+        &self,
+        room_name: &str,
+        page: i64,
+        page_size: i64,
+        include_presence: bool,
+    ) -> DbResult<VecDeque<(ServerMessage, BTreeMap<String, i64>, DateTime<Utc>)>> {
+        let offset = (page - 1) * page_size;
+        let query = "SELECT m.id, m.message, m.timestamp, COALESCE(r.counts, '{}') AS reactions
+            FROM messages m
+            LEFT JOIN (
+                SELECT message_id, json_group_object(emoji, n) AS counts
+                FROM (SELECT message_id, emoji, COUNT(*) AS n FROM reactions GROUP BY message_id, emoji)
+                GROUP BY message_id
+            ) r ON r.message_id = m.message_id
+            WHERE m.room = ? AND (? OR NOT m.presence)
+            ORDER BY m.timestamp DESC, m.id DESC
+            LIMIT ? OFFSET ?";
+
+        let query = sqlx::query(query).bind(room_name).bind(include_presence).bind(page_size).bind(offset);
+        let rows = query.fetch_all(&self.pool).await?;
+
+        Ok(rows
+            .iter()
+            .rev()
+            .filter_map(|row| {
+                let message = self.decode_history(room_name, row)?;
+                let reactions = row
+                    .try_get::<String, _>("reactions")
+                    .ok()
+                    .and_then(|counts| serde_json::from_str(&counts).ok())
+                    .unwrap_or_default();
+                let stored_at = row.try_get("timestamp").unwrap_or_default();
+                Some((message, reactions, stored_at))
+            })
+            .collect())
+    }
+
+    async fn get_message_count(&self, room_name: &str) -> DbResult<i64> {
+        let row = sqlx::query("SELECT COUNT(*) FROM messages WHERE room = ?").bind(room_name).fetch_one(&self.pool).await?;
+        Ok(row.get(0))
+    }
+
+    async fn get_latest_seq(&self, room_name: &str) -> DbResult<u64> {
+        // SQLite's `MAX(a, b)` is NULL if either is, so each side stands in for the other when it is missing.
+        let row = sqlx::query(
+            "WITH latest AS (
+                SELECT (SELECT last_seq FROM rooms_meta WHERE room = ?1) AS recorded, (SELECT MAX(seq) FROM messages WHERE room = ?1) AS saved
+             )
+             SELECT COALESCE(MAX(COALESCE(recorded, saved), COALESCE(saved, recorded)), (SELECT COUNT(*) FROM messages WHERE room = ?1))
+             FROM latest",
+        )
+        .bind(room_name)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.get::<i64, _>(0).max(0) as u64)
+    }
+
+    async fn set_latest_seq(&self, room_name: &str, seq: u64) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO rooms_meta (room, last_seq) VALUES (?, ?)
+             ON CONFLICT (room) DO UPDATE SET last_seq = MAX(COALESCE(rooms_meta.last_seq, 0), excluded.last_seq)",
+        )
+        .bind(room_name)
+        .bind(seq as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn stream_room_messages(&self, room_name: String, tx: mpsc::Sender<DbResult<ServerMessage>>) {
+        let mut rows = sqlx::query("SELECT id, message, timestamp FROM messages WHERE room = ? ORDER BY timestamp, id")
+            .bind(&room_name)
+            .fetch(&self.pool);
+        while let Some(row) = rows.next().await {
+            let item = match row {
+                Ok(row) => match self.decode_history(&room_name, &row) {
+                    Some(message) => Ok(message),
+                    None => continue,
+                },
+                Err(e) => Err(DbError::from(e)),
+            };
+            let failed = item.is_err();
+            if tx.send(item).await.is_err() || failed {
+                return;
+            }
+        }
+    }
+
+    async fn add_reaction(&self, room_name: &str, message_id: Uuid, username: &str, emoji: &str) -> DbResult<bool> {
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO reactions (message_id, username, emoji)
+             SELECT ?2, ?3, ?4
+             WHERE EXISTS (SELECT 1 FROM messages WHERE room = ?1 AND message_id = ?2)",
+        )
+        .bind(room_name)
+        .bind(message_id.to_string())
+        .bind(username)
+        .bind(emoji)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_message(&self, room_name: &str, message_id: Uuid) -> DbResult<bool> {
+        let message_id = message_id.to_string();
+        let mut tx = self.pool.begin().await?;
+        let deleted = sqlx::query("DELETE FROM messages WHERE room = ? AND message_id = ?")
+            .bind(room_name)
+            .bind(&message_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+        if deleted {
+            sqlx::query("DELETE FROM reactions WHERE message_id = ?").bind(&message_id).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn clear_messages(&self, room_name: &str, seq: u64) -> DbResult<u64> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM reactions WHERE message_id IN (
+                SELECT message_id FROM messages WHERE room = ?1 AND (seq IS NULL OR seq <= ?2)
+             )",
+        )
+        .bind(room_name)
+        .bind(seq as i64)
+        .execute(&mut *tx)
+        .await?;
+        let cleared = sqlx::query("DELETE FROM messages WHERE room = ?1 AND (seq IS NULL OR seq <= ?2)")
+            .bind(room_name)
+            .bind(seq as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(cleared)
+    }
+
+    async fn load_message_expiries(&self) -> DbResult<Vec<(String, Uuid, DateTime<Utc>)>> {
+        let query = "SELECT room, message_id, expires_at FROM messages WHERE expires_at IS NOT NULL AND message_id IS NOT NULL";
+        let rows = sqlx::query(query).fetch_all(&self.pool).await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let message_id = Uuid::parse_str(row.get("message_id")).ok()?;
+                Some((row.get("room"), message_id, row.try_get("expires_at").ok()?))
+            })
+            .collect())
+    }
+
+    async fn is_room_closed(&self, room_name: &str) -> DbResult<bool> {
+        let row = sqlx::query("SELECT closed FROM rooms_meta WHERE room = ?").bind(room_name).fetch_optional(&self.pool).await?;
+        Ok(row.map(|row| row.get("closed")).unwrap_or(false))
+    }
+
+    async fn set_room_closed(&self, room_name: &str, closed: bool) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO rooms_meta (room, closed) VALUES (?, ?)
+             ON CONFLICT (room) DO UPDATE SET closed = excluded.closed",
+        )
+        .bind(room_name)
+        .bind(closed)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn is_room_registered(&self, room_name: &str) -> DbResult<bool> {
+        let row = sqlx::query("SELECT registered FROM rooms_meta WHERE room = ?").bind(room_name).fetch_optional(&self.pool).await?;
+        Ok(row.map(|row| row.get("registered")).unwrap_or(false))
+    }
+
+    async fn set_room_registered(&self, room_name: &str, registered: bool) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO rooms_meta (room, registered) VALUES (?, ?)
+             ON CONFLICT (room) DO UPDATE SET registered = excluded.registered",
+        )
+        .bind(room_name)
+        .bind(registered)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_room_topic(&self, room_name: &str) -> DbResult<Option<RoomTopic>> {
+        let row = sqlx::query("SELECT topic, topic_set_by FROM rooms_meta WHERE room = ? AND topic IS NOT NULL")
+            .bind(room_name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| RoomTopic {
+            topic: row.get("topic"),
+            set_by: row.get::<Option<String>, _>("topic_set_by").unwrap_or_default(),
+        }))
+    }
+
+    async fn set_room_topic(&self, room_name: &str, topic: &RoomTopic) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO rooms_meta (room, topic, topic_set_by) VALUES (?, ?, ?)
+             ON CONFLICT (room) DO UPDATE SET topic = excluded.topic, topic_set_by = excluded.topic_set_by",
+        )
+        .bind(room_name)
+        .bind(&topic.topic)
+        .bind(&topic.set_by)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_room_metadata(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        let row = sqlx::query("SELECT metadata FROM rooms_meta WHERE room = ?")
+            .bind(room_name)
+            .fetch_optional(&self.pool)
+            .await?;
+        let metadata = row.and_then(|row| row.get::<Option<String>, _>("metadata"));
+        Ok(metadata.and_then(|metadata| serde_json::from_str(&metadata).ok()).unwrap_or_default())
+    }
+
+    async fn set_room_metadata(&self, room_name: &str, metadata: &BTreeMap<String, String>) -> DbResult<()> {
+        let metadata = serde_json::to_string(metadata)?;
+        sqlx::query(
+            "INSERT INTO rooms_meta (room, metadata) VALUES (?, ?)
+             ON CONFLICT (room) DO UPDATE SET metadata = excluded.metadata",
+        )
+        .bind(room_name)
+        .bind(metadata)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_public_keys(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        let row = sqlx::query("SELECT public_keys FROM rooms_meta WHERE room = ?")
+            .bind(room_name)
+            .fetch_optional(&self.pool)
+            .await?;
+        let keys = row.and_then(|row| row.get::<Option<String>, _>("public_keys"));
+        Ok(keys.and_then(|keys| serde_json::from_str(&keys).ok()).unwrap_or_default())
+    }
+
+    async fn set_public_keys(&self, room_name: &str, keys: &BTreeMap<String, String>) -> DbResult<()> {
+        let keys = serde_json::to_string(keys)?;
+        sqlx::query(
+            "INSERT INTO rooms_meta (room, public_keys) VALUES (?, ?)
+             ON CONFLICT (room) DO UPDATE SET public_keys = excluded.public_keys",
+        )
+        .bind(room_name)
+        .bind(keys)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_username_pattern(&self, room_name: &str) -> DbResult<Option<String>> {
+        let row = sqlx::query("SELECT username_pattern FROM rooms_meta WHERE room = ?")
+            .bind(room_name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.and_then(|row| row.get("username_pattern")))
+    }
+
+    async fn set_username_pattern(&self, room_name: &str, pattern: Option<&str>) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO rooms_meta (room, username_pattern) VALUES (?, ?)
+             ON CONFLICT (room) DO UPDATE SET username_pattern = excluded.username_pattern",
+        )
+        .bind(room_name)
+        .bind(pattern)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_room_ownership(&self, room_name: &str) -> DbResult<RoomOwnership> {
+        let row = sqlx::query("SELECT owner, operators FROM rooms_meta WHERE room = ?")
+            .bind(room_name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map_or_else(RoomOwnership::default, |row| RoomOwnership {
+            owner: row.get("owner"),
+            operators: row
+                .get::<Option<String>, _>("operators")
+                .and_then(|operators| serde_json::from_str(&operators).ok())
+                .unwrap_or_default(),
+        }))
+    }
+
+    async fn set_room_ownership(&self, room_name: &str, ownership: &RoomOwnership) -> DbResult<()> {
+        let operators = serde_json::to_string(&ownership.operators)?;
+        sqlx::query(
+            "INSERT INTO rooms_meta (room, owner, operators) VALUES (?, ?, ?)
+             ON CONFLICT (room) DO UPDATE SET owner = excluded.owner, operators = excluded.operators",
+        )
+        .bind(room_name)
+        .bind(&ownership.owner)
+        .bind(operators)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn load_message(&self, room_name: &str, message_id: Uuid) -> DbResult<Option<ServerMessage>> {
+        let row = sqlx::query("SELECT id, message, timestamp FROM messages WHERE room = ? AND message_id = ? LIMIT 1")
+            .bind(room_name)
+            .bind(message_id.to_string())
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.and_then(|row| self.decode(room_name, &row)))
+    }
+
+    async fn load_thread(&self, room_name: &str, root: Uuid, max_depth: u32, limit: usize) -> DbResult<Vec<ThreadReply>> {
+        let rows = sqlx::query(
+            "WITH RECURSIVE thread (message_id, depth) AS (
+                SELECT message_id, 1 FROM messages WHERE room = ?1 AND reply_to = ?2
+                UNION ALL
+                SELECT m.message_id, thread.depth + 1 FROM messages m JOIN thread ON m.reply_to = thread.message_id
+                WHERE m.room = ?1 AND thread.depth < ?3
+            )
+            SELECT m.id, m.message, m.timestamp, thread.depth FROM thread JOIN messages m ON m.room = ?1 AND m.message_id = thread.message_id
+            ORDER BY m.timestamp, m.id
+            LIMIT ?4",
+        )
+        .bind(room_name)
+        .bind(root.to_string())
+        .bind(max_depth as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| Some(ThreadReply { depth: row.get::<i64, _>("depth") as u32, message: self.decode(room_name, row)? }))
+            .collect())
+    }
+
+    async fn get_pinned_messages(&self, room_name: &str) -> DbResult<Vec<ServerMessage>> {
+        let row = sqlx::query("SELECT pinned_ids FROM rooms_meta WHERE room = ?")
+            .bind(room_name)
+            .fetch_optional(&self.pool)
+            .await?;
+        let ids = row.and_then(|row| row.get::<Option<String>, _>("pinned_ids"));
+        let ids: Vec<Uuid> = ids.and_then(|ids| serde_json::from_str(&ids).ok()).unwrap_or_default();
+
+        let mut pinned = Vec::with_capacity(ids.len());
+        for id in ids {
+            pinned.extend(self.load_message(room_name, id).await?);
+        }
+        Ok(pinned)
+    }
+
+    async fn set_pinned_ids(&self, room_name: &str, message_ids: &[Uuid]) -> DbResult<()> {
+        let ids = serde_json::to_string(message_ids)?;
+        sqlx::query(
+            "INSERT INTO rooms_meta (room, pinned_ids) VALUES (?, ?)
+             ON CONFLICT (room) DO UPDATE SET pinned_ids = excluded.pinned_ids",
+        )
+        .bind(room_name)
+        .bind(ids)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_secret_hash(&self, username: &str) -> DbResult<Option<String>> {
+        let row = sqlx::query("SELECT secret_hash FROM registered_users WHERE username = ?")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.get("secret_hash")))
+    }
+
+    async fn register_user(&self, username: &str, secret_hash: &str) -> DbResult<bool> {
+        let result = sqlx::query("INSERT OR IGNORE INTO registered_users (username, secret_hash) VALUES (?, ?)")
+            .bind(username)
+            .bind(secret_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn get_recent_rooms(&self, since: DateTime<Utc>) -> DbResult<Vec<RecentRoom>> {
+        let query = "SELECT room, MAX(timestamp) AS last_active, COUNT(*) AS message_count,
+                (SELECT metadata FROM rooms_meta WHERE rooms_meta.room = messages.room) AS metadata
+            FROM messages
+            GROUP BY room
+            HAVING MAX(timestamp) >= ?
+            ORDER BY last_active DESC";
+        let rows = sqlx::query(query).bind(since).fetch_all(&self.pool).await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(RecentRoom {
+                    room: row.get("room"),
+                    last_active: row.try_get("last_active").ok()?,
+                    message_count: row.get("message_count"),
+                    metadata: row
+                        .get::<Option<String>, _>("metadata")
+                        .and_then(|metadata| serde_json::from_str(&metadata).ok())
+                        .unwrap_or_default(),
+                })
+            })
+            .collect())
+    }
+
+    async fn add_report(&self, report: &Report) -> DbResult<bool> {
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO reports (message_id, room, reporter, reason, reported_at)
+             SELECT ?1, ?2, ?3, ?4, ?5
+             WHERE EXISTS (SELECT 1 FROM messages WHERE room = ?2 AND message_id = ?1)",
+        )
+        .bind(report.message_id.to_string())
+        .bind(&report.room)
+        .bind(&report.reporter)
+        .bind(&report.reason)
+        .bind(report.reported_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn load_reports(&self, page: i64, page_size: i64) -> DbResult<Vec<Report>> {
+        let query = "SELECT message_id, room, reporter, reason, reported_at FROM reports
+            ORDER BY reported_at DESC, id DESC
+            LIMIT ? OFFSET ?";
+        let rows = sqlx::query(query).bind(page_size).bind((page - 1) * page_size).fetch_all(&self.pool).await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                Some(Report {
+                    message_id: Uuid::parse_str(row.get("message_id")).ok()?,
+                    room: row.get("room"),
+                    reporter: row.get("reporter"),
+                    reason: row.get("reason"),
+                    reported_at: row.try_get("reported_at").ok()?,
+                })
+            })
+            .collect())
+    }
+
+    async fn log_audit(&self, entry: &AuditEntry) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO audit_log (action, actor, target, room, timestamp, detail) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&entry.action)
+        .bind(&entry.actor)
+        .bind(&entry.target)
+        .bind(&entry.room)
+        .bind(entry.timestamp)
+        .bind(&entry.detail)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn load_audit_log(&self, room_name: Option<&str>, page: i64, page_size: i64) -> DbResult<Vec<AuditEntry>> {
+        let query = "SELECT action, actor, target, room, timestamp, detail FROM audit_log
+            WHERE ?1 IS NULL OR room = ?1
+            ORDER BY timestamp DESC, id DESC
+            LIMIT ?2 OFFSET ?3";
+        let rows = sqlx::query(query)
+            .bind(room_name)
+            .bind(page_size)
+            .bind((page - 1) * page_size)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| AuditEntry {
+                action: row.get("action"),
+                actor: row.get("actor"),
+                target: row.get("target"),
+                room: row.get("room"),
+                timestamp: row.get("timestamp"),
+                detail: row.get("detail"),
+            })
+            .collect())
+    }
+
+    async fn get_last_seen(&self, room_name: &str, username: &str) -> DbResult<Option<DateTime<Utc>>> {
+        let row = sqlx::query("SELECT last_seen_at FROM user_room_state WHERE room = ? AND username = ?")
+            .bind(room_name)
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.get("last_seen_at")))
+    }
+
+    async fn set_last_seen(&self, room_name: &str, username: &str, seen_at: DateTime<Utc>) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO user_room_state (room, username, last_seen_at) VALUES (?, ?, ?)
+             ON CONFLICT (room, username) DO UPDATE SET last_seen_at = excluded.last_seen_at",
+        )
+        .bind(room_name)
+        .bind(username)
+        .bind(seen_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn add_scheduled_message(&self, message: &ScheduledMessage) -> DbResult<()> {
+        sqlx::query("INSERT INTO scheduled_messages (id, room, text, deliver_at) VALUES (?, ?, ?, ?)")
+            .bind(message.id.to_string())
+            .bind(&message.room)
+            .bind(&message.text)
+            .bind(message.deliver_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn cancel_scheduled_message(&self, id: Uuid) -> DbResult<bool> {
+        let result = sqlx::query("DELETE FROM scheduled_messages WHERE id = ?").bind(id.to_string()).execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn take_due_scheduled_messages(&self, now: DateTime<Utc>) -> DbResult<Vec<ScheduledMessage>> {
+        let query = "DELETE FROM scheduled_messages WHERE deliver_at <= ? RETURNING id, room, text, deliver_at";
+        let rows = sqlx::query(query).bind(now).fetch_all(&self.pool).await?;
+        let mut due: Vec<ScheduledMessage> = rows
+            .iter()
+            .filter_map(|row| {
+                Some(ScheduledMessage {
+                    id: Uuid::parse_str(row.get("id")).ok()?,
+                    room: row.get("room"),
+                    text: row.get("text"),
+                    deliver_at: row.try_get("deliver_at").ok()?,
+                })
+            })
+            .collect();
+        due.sort_by_key(|message| message.deliver_at);
+        Ok(due)
+    }
+
+    fn undecodable_messages(&self) -> u64 {
+        self.decode_failures.count()
+    }
+}
@@ -1,18 +1,84 @@
 // src/state.rs
 
-use crate::models::ServerMessage;
-use axum::extract::ws::{Message, WebSocket};
-use futures_util::stream::SplitSink;
-use sqlx::PgPool; // For PostgreSQL
-use std::collections::{HashMap, VecDeque};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use crate::config::Config;
+use crate::events::{EventBus, ServerEvent};
+use crate::expiry::ExpiryQueue;
+use crate::lru::LruMap;
+use crate::models::{PresenceEntry, PresenceStatus, ProtocolVersion, ServerMessage};
+use crate::outbound::{Outbound, RoomBacklog};
+use crate::store::{DbError, MessageStore, RoomOwnership, RoomTopic};
+use crate::throttle::{HistoryThrottle, TokenBucket};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify, Semaphore};
+use tokio::task::AbortHandle;
 use uuid::Uuid;
 
-/// Represents a connected client, holding their username and the sender part of their WebSocket.
+/// Represents a connected client, holding their username and the queue feeding their WebSocket.
 pub struct Client {
-    pub username: String,
-    pub sender: SplitSink<WebSocket, Message>,
+    /// `None` until the client sets a name with `/user`.
+    pub username: Option<String>,
+    pub sender: Outbound,
+    /// Wire format negotiated during the WebSocket handshake.
+    pub protocol: ProtocolVersion,
+    /// Sequence number of the first message this client received live; anything older must come from history replay.
+    pub live_from_seq: u64,
+    /// Set by the first `/user`; history is replayed and a join broadcast only until then, later names are renames.
+    pub has_joined: bool,
+    /// Whether the client's own chat and custom messages are sent back to it, as rendered by the server.
+    pub echo: bool,
+    /// Chat messages delivered to this client that it hasn't acknowledged yet (only when acks are required).
+    pub unacked: HashMap<Uuid, PendingAck>,
+    /// Display color set with `/color`, attached to the client's chat messages.
+    pub color: Option<String>,
+    /// When the client last renamed itself, for enforcing `RENAME_COOLDOWN_SECS`.
+    pub last_renamed_at: Option<Instant>,
+    /// Content and arrival time of the client's last chat message, for dropping accidental double-sends.
+    pub last_message: Option<(String, Instant)>,
+    /// Set once the client has authenticated with `/admin <token>`.
+    pub is_admin: bool,
+    /// Joined with `/user <name> --silent`: the room isn't told when this client joins, renames or leaves.
+    pub silent: bool,
+    /// Sent nothing for `HEARTBEAT_TIMEOUT_SECS`: the room was told it left, although the socket is still open.
+    /// Cleared, with a new join broadcast, by the client's next frame.
+    pub stale: bool,
+    /// Country of the client's IP address when `GEOIP_DB_PATH` is set. Shown to administrators only.
+    pub country: Option<String>,
+    /// Public key published with `/key publish` on this connection, for end-to-end encryption between clients.
+    pub public_key: Option<String>,
+    /// Users whose chat and custom messages this connection no longer receives, set with `/ignore`. Not persisted.
+    pub ignored: HashSet<String>,
+    /// When the connection was opened.
+    pub joined_at: DateTime<Utc>,
+    /// Chat and custom messages this connection has had delivered to the room.
+    pub messages_sent: u64,
+    /// Handle to the task reading from this client's socket, used to tear the connection down from elsewhere.
+    pub receive_task: AbortHandle,
+}
+
+/// A delivered message awaiting the client's acknowledgement.
+pub struct PendingAck {
+    /// The frame as it was sent, so a resend is identical.
+    pub frame: String,
+    pub sent_at: Instant,
+    pub resends: u32,
+}
+
+impl Client {
+    /// Whether this client has to acknowledge the chat messages it receives. Plain-text clients
+    /// may not see message ids, so only JSON clients are asked to.
+    pub fn requires_acks(&self, config: &Config) -> bool {
+        config.ack_timeout.is_some() && self.protocol == ProtocolVersion::V2
+    }
+
+    /// The client's name for logging, or "anonymous" if they haven't set one.
+    pub fn display_name(&self) -> &str {
+        self.username.as_deref().unwrap_or("anonymous")
+    }
 }
 
 /// Represents a chat room, containing all connected clients and a cached history of recent messages.
@@ -20,16 +86,520 @@ pub struct Client {
 pub struct Room {
     pub clients: HashMap<Uuid, Client>,
     pub history: VecDeque<ServerMessage>,
+    /// Sequence number that will be assigned to the next message added to `history`.
+    pub next_seq: u64,
+    /// A closed room is a read-only archive: history stays available but new messages are rejected.
+    pub closed: bool,
+    /// Cached copy of the topic in `rooms_meta`, sent to every client that joins.
+    pub topic: Option<RoomTopic>,
+    /// Compiled copy of the room's username pattern in `rooms_meta`.
+    pub username_pattern: Option<UsernamePattern>,
+    /// Cached copy of the room's owner and operators in `rooms_meta`.
+    pub ownership: RoomOwnership,
+    /// Cached copies of the messages pinned in `rooms_meta`, in pin order.
+    pub pinned: Vec<ServerMessage>,
+    /// Held for the whole of a change to `pinned`, `ownership`, `metadata` or `public_keys`: checking it, saving it with the rooms lock
+    /// released and installing it. Changes therefore run one at a time, and none is checked against or saved over
+    /// a stale copy.
+    pub settings_writes: Arc<Mutex<()>>,
+    /// Cached copy of the key/value metadata in `rooms_meta`.
+    pub metadata: BTreeMap<String, String>,
+    /// Cached copy of the public keys members published in `rooms_meta`, keyed by username.
+    pub public_keys: BTreeMap<String, String>,
+    /// Clients evicted after a failed send, kept (with their username) until `cleanup_client` announces their departure.
+    pub evicted: HashMap<Uuid, Option<String>>,
+    /// Messages missed by recently disconnected users, keyed by username, replayed if they return in time.
+    /// Holds at most `MAX_TRACKED_USERS` users; the longest-gone are forgotten first.
+    pub offline_buffers: LruMap<String, OfflineBuffer>,
+    /// Message rate and auto-mute state per username, kept across reconnects so leaving doesn't lift a mute.
+    /// Bounded by `MAX_TRACKED_USERS` like `offline_buffers`.
+    pub flood_records: LruMap<String, FloodRecord>,
+    /// Usernames banned with `/ban`, which nobody can take in this room until `/unban` or the room is emptied.
+    pub banned: HashSet<String>,
+    /// Usernames muted with `/mute`, and until when.
+    pub mutes: HashMap<String, Instant>,
+    /// The room's share of `ROOM_RATE_LIMITS`, created with its first limited message.
+    pub rate_bucket: Option<TokenBucket>,
+    /// Messages waiting in the outbound queues of this room's clients, checked against `ROOM_MAX_IN_FLIGHT`.
+    pub backlog: Arc<RoomBacklog>,
+}
+
+/// A regex that every username in a room must match in full, e.g. `[a-z]+\.[a-z]+` for `firstname.lastname`.
+pub struct UsernamePattern {
+    /// The pattern as an administrator wrote it, shown to users whose name is rejected.
+    pub pattern: String,
+    regex: Regex,
+}
+
+impl UsernamePattern {
+    pub fn compile(pattern: &str) -> Result<Self, regex::Error> {
+        // Anchor it so the whole name has to match, not just some part of it.
+        let regex = Regex::new(&format!("^(?:{})$", pattern))?;
+        Ok(UsernamePattern { pattern: pattern.to_string(), regex })
+    }
+
+    pub fn matches(&self, username: &str) -> bool {
+        self.regex.is_match(username)
+    }
+}
+
+/// Messages broadcast while a named user was disconnected, tagged with their room sequence number.
+pub struct OfflineBuffer {
+    pub messages: VecDeque<(u64, ServerMessage)>,
+    pub disconnected_at: Instant,
+}
+
+impl OfflineBuffer {
+    pub(crate) fn new() -> Self {
+        OfflineBuffer { messages: VecDeque::new(), disconnected_at: Instant::now() }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.disconnected_at.elapsed() > OFFLINE_GRACE_PERIOD
+    }
+
+    /// Records a missed message, dropping the oldest once the buffer is full.
+    pub fn push(&mut self, seq: u64, message: ServerMessage) {
+        self.messages.push_back((seq, message));
+        if self.messages.len() > OFFLINE_BUFFER_SIZE {
+            self.messages.pop_front();
+        }
+    }
+}
+
+/// One user's messages against `MESSAGE_RATE_LIMIT`, and the escalating mutes they have earned.
+pub struct FloodRecord {
+    window_start: Instant,
+    sent_in_window: u32,
+    /// Violations of the rate limit since the user last behaved for `AUTO_MUTE_RESET_SECS`.
+    violations: u32,
+    muted_until: Option<Instant>,
+    /// When the user's latest violation or mute ended, from which good behaviour is counted.
+    clean_since: Instant,
+}
+
+/// What to do with a message checked by `FloodRecord::check`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FloodVerdict {
+    Allowed,
+    /// The sender is still muted for this long.
+    Muted(Duration),
+    /// The message broke the limit and earned the sender a mute, their `violations`th.
+    AutoMuted { duration: Duration, violations: u32 },
+    /// The message broke the limit, but no mute durations are configured.
+    Dropped,
+}
+
+impl FloodRecord {
+    pub fn new(now: Instant) -> Self {
+        FloodRecord { window_start: now, sent_in_window: 0, violations: 0, muted_until: None, clean_since: now }
+    }
+
+    /// Counts one message sent at `now` against a limit of `limit` per `config.message_rate_window`.
+    pub fn check(&mut self, now: Instant, limit: u32, config: &Config) -> FloodVerdict {
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return FloodVerdict::Muted(until - now);
+            }
+            self.muted_until = None;
+        }
+        if self.violations > 0 && now.duration_since(self.clean_since) >= config.auto_mute_reset {
+            self.violations = 0;
+        }
+        if now.duration_since(self.window_start) >= config.message_rate_window {
+            self.window_start = now;
+            self.sent_in_window = 0;
+        }
+        self.sent_in_window += 1;
+        if self.sent_in_window <= limit {
+            return FloodVerdict::Allowed;
+        }
+
+        self.violations += 1;
+        self.window_start = now;
+        self.sent_in_window = 0;
+        let Some(&duration) = config.auto_mute_durations.get(self.violations as usize - 1).or(config.auto_mute_durations.last()) else {
+            self.clean_since = now;
+            return FloodVerdict::Dropped;
+        };
+        self.muted_until = Some(now + duration);
+        self.clean_since = now + duration;
+        FloodVerdict::AutoMuted { duration, violations: self.violations }
+    }
+}
+
+impl Room {
+    /// Creates a room whose cache is seeded with previously persisted messages, oldest first.
+    /// Only the newest `IN_MEMORY_CACHE_SIZE` messages are kept so the cache is bounded from the start.
+    /// `latest_seq` is the room's highest message number from the store (`MessageStore::get_latest_seq`), so
+    /// numbering continues from there and sequence numbers keep growing across restarts.
+    pub fn with_history(mut history: VecDeque<ServerMessage>, latest_seq: u64) -> Self {
+        if history.len() > IN_MEMORY_CACHE_SIZE {
+            history.drain(..history.len() - IN_MEMORY_CACHE_SIZE);
+        }
+        let next_seq = latest_seq.max(history.len() as u64);
+        Room { history, next_seq, ..Default::default() }
+    }
+
+    /// Appends a message to the in-memory cache, evicting the oldest entry once the cache is full.
+    pub fn push_history(&mut self, message: ServerMessage) {
+        self.history.push_back(message);
+        if self.history.len() > IN_MEMORY_CACHE_SIZE {
+            self.history.pop_front();
+        }
+        self.next_seq += 1;
+    }
+
+    /// Everyone in the room with a name, once each and sorted. Silent clients are left out.
+    pub fn presence(&self) -> Vec<PresenceEntry> {
+        let mut usernames: Vec<&String> =
+            self.clients.values().filter(|client| !client.silent && !client.stale).filter_map(|client| client.username.as_ref()).collect();
+        usernames.sort();
+        usernames.dedup();
+        usernames
+            .into_iter()
+            .map(|username| PresenceEntry { username: username.clone(), status: PresenceStatus::Online })
+            .collect()
+    }
+
+    /// Whether `client` may moderate this room: the room's owner, its operators and server administrators can.
+    pub fn is_moderator(&self, client: &Client) -> bool {
+        client.is_admin
+            || client.username.as_ref().is_some_and(|name| {
+                self.ownership.owner.as_ref() == Some(name) || self.ownership.operators.contains(name)
+            })
+    }
+
+    /// Picks who inherits the room when its owner leaves: an operator if one is present, otherwise the
+    /// member who has been here longest. Silent clients are never picked.
+    pub fn next_owner(&self) -> Option<String> {
+        let mut members: Vec<(&String, u64)> = self
+            .clients
+            .values()
+            .filter(|client| !client.silent)
+            .filter_map(|client| Some((client.username.as_ref()?, client.live_from_seq)))
+            .collect();
+        members.sort_by_key(|&(name, joined)| (!self.ownership.operators.contains(name), joined, name));
+        members.first().map(|&(name, _)| name.clone())
+    }
+
+    /// Returns the cached messages with a sequence number lower than `seq`, oldest first, with their sequence numbers.
+    pub fn history_before(&self, seq: u64) -> impl Iterator<Item = (u64, &ServerMessage)> {
+        let first_seq = self.next_seq - self.history.len() as u64;
+        let count = seq.saturating_sub(first_seq).min(self.history.len() as u64) as usize;
+        (first_seq..).zip(self.history.iter().take(count))
+    }
 }
 
 // Configuration constants for the hybrid approach
 pub const IN_MEMORY_CACHE_SIZE: usize = 50;  // Keep last 50 messages in memory
 pub const MAX_HISTORY_SIZE: usize = 1000;    // Maximum messages to load from DB
+pub const MAX_HISTORY_PAGE_SIZE: i64 = 100;  // Largest page a client may request with `/history <page> <page_size>`
+
+// Buffering for named users who briefly disconnect
+pub const OFFLINE_BUFFER_SIZE: usize = 100;
+pub const OFFLINE_GRACE_PERIOD: Duration = Duration::from_secs(120);
+
+// Limits for structured `Custom` messages and reactions
+pub const MAX_CUSTOM_PAYLOAD_BYTES: usize = 4096;
+pub const ALLOWED_CUSTOM_KINDS: &[&str] = &["poll", "location", "card"];
+pub const MAX_REACTION_CHARS: usize = 16;    // Room for multi-code-point emoji such as ZWJ sequences
+pub const MAX_TOPIC_CHARS: usize = 200;
+pub const MAX_PINNED_MESSAGES: usize = 10;
+pub const MAX_REPORT_REASON_CHARS: usize = 500;
+pub const MAX_ROOM_METADATA_KEYS: usize = 16;
+pub const MAX_METADATA_KEY_CHARS: usize = 32;
+pub const MAX_METADATA_VALUE_CHARS: usize = 200;
+pub const MAX_PUBLIC_KEY_CHARS: usize = 1024;
+pub const MAX_IGNORED_USERS: usize = 100;
+pub const MAX_MUTE_SECS: u64 = 7 * 24 * 60 * 60;
+
+// Window over which `FRAME_RATE_LIMIT` is counted
+pub const FRAME_RATE_WINDOW: Duration = Duration::from_secs(1);
 
 /// The application's shared state, accessible from all request handlers.
 /// This struct is created once in `main.rs` and shared across all connections via Axum's state management.
 #[derive(Clone)]
 pub struct ChatState {
     pub rooms: Arc<Mutex<HashMap<String, Room>>>,
-    pub db_pool: PgPool,
+    pub store: Arc<dyn MessageStore>,
+    pub config: Arc<Config>,
+    /// Set when a graceful shutdown starts; new WebSocket upgrades are refused from then on.
+    pub shutting_down: Arc<AtomicBool>,
+    /// Permits for loading history from the store, limited to `HISTORY_LOAD_LIMIT` at a time.
+    pub history_loads: Arc<Semaphore>,
+    /// Connection handlers that are still running.
+    pub connections: ConnectionTracker,
+    /// Current message of the day, starting from `STARTUP_MOTD` and changed through `/admin/motd`.
+    pub motd: Arc<RwLock<Option<String>>>,
+    /// Invites to private rooms that haven't been used yet.
+    pub invites: InviteBook,
+    /// Every server event, for the `/ws/admin` feed, the webhook worker and anyone else subscribed.
+    pub events: EventBus,
+    /// Per-address usage of the REST history endpoints.
+    pub history_throttle: HistoryThrottle,
+    /// Chat messages waiting to be deleted at the end of their `expires_in_secs`.
+    pub expiries: ExpiryQueue,
+    /// Store calls that have failed or timed out since startup, reported by `/admin/metrics`.
+    pub db_errors: Arc<AtomicU64>,
+    /// Messages that have been broadcast but not yet saved.
+    pub saves: SaveTracker,
+}
+
+impl ChatState {
+    /// Creates the state for a fresh server, starting the announcement scheduler, the expired message sweeper
+    /// and, if `WEBHOOK_URL` is configured, the webhook worker.
+    pub fn new(config: Config, store: Arc<dyn MessageStore>) -> Self {
+        let events = EventBus::new(config.event_replay_size);
+        if let Some(url) = config.webhook_url.clone() {
+            crate::webhook::spawn_webhook_worker(url, events.subscribe());
+        }
+        let motd = Arc::new(RwLock::new(config.startup_motd.clone()));
+        let history_loads = Arc::new(Semaphore::new(config.history_load_limit.max(1)));
+        let state = ChatState {
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            store,
+            config: Arc::new(config),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            history_loads,
+            connections: ConnectionTracker::default(),
+            motd,
+            invites: InviteBook::default(),
+            events,
+            history_throttle: HistoryThrottle::default(),
+            expiries: ExpiryQueue::default(),
+            db_errors: Arc::new(AtomicU64::new(0)),
+            saves: SaveTracker::default(),
+        };
+        crate::scheduler::spawn_scheduler(state.clone());
+        crate::expiry::spawn_sweeper(state.clone());
+        state
+    }
+
+    /// Logs a failed store call, counts it in `db_errors` and publishes it as an `Error` event.
+    pub fn record_db_error(&self, error: &DbError) {
+        self.db_errors.fetch_add(1, Ordering::Relaxed);
+        let message = match error {
+            DbError::Timeout => format!("Database call timed out after {:?}", self.config.db_timeout),
+            error => format!("Database call failed: {}", error),
+        };
+        eprintln!("{}", message);
+        self.events.publish(|| ServerEvent::Error { message });
+    }
+}
+
+/// Single-use invite tokens for private rooms, issued with `/invite`. Kept in memory only, so
+/// outstanding invites don't survive a restart.
+#[derive(Clone, Default)]
+pub struct InviteBook {
+    invites: Arc<std::sync::Mutex<HashMap<String, Invite>>>,
+}
+
+struct Invite {
+    room: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl InviteBook {
+    /// Creates an invite to `room` valid for `ttl`, returning its token and expiry.
+    pub fn issue(&self, room: &str, ttl: Duration) -> (String, DateTime<Utc>) {
+        let token = Uuid::new_v4().simple().to_string();
+        let now = Utc::now();
+        let expires_at = now + ttl;
+        let mut invites = self.invites.lock().unwrap();
+        invites.retain(|_, invite| invite.expires_at > now);
+        invites.insert(token.clone(), Invite { room: room.to_string(), expires_at });
+        (token, expires_at)
+    }
+
+    /// Whether `token` is an unexpired invite to `room`, without using it up.
+    pub fn is_valid(&self, token: &str, room: &str) -> bool {
+        let invites = self.invites.lock().unwrap();
+        invites.get(token).is_some_and(|invite| invite.room == room && invite.expires_at > Utc::now())
+    }
+
+    /// Uses up the invite `token` if it is for `room` and hasn't expired. Tokens for other rooms are left alone.
+    pub fn redeem(&self, token: &str, room: &str) -> bool {
+        let mut invites = self.invites.lock().unwrap();
+        match invites.get(token) {
+            Some(invite) if invite.room != room => false,
+            Some(invite) => {
+                let valid = invite.expires_at > Utc::now();
+                invites.remove(token);
+                valid
+            }
+            None => false,
+        }
+    }
+}
+
+/// When a connection last sent an application frame. Shared between the connection's reader and the task
+/// that looks for stale clients, so recording activity doesn't need the rooms lock.
+#[derive(Clone)]
+pub struct ActivityClock {
+    origin: Instant,
+    /// Milliseconds after `origin` of the latest activity.
+    last_ms: Arc<AtomicU64>,
+}
+
+impl ActivityClock {
+    pub fn start() -> Self {
+        ActivityClock { origin: Instant::now(), last_ms: Arc::new(AtomicU64::new(0)) }
+    }
+
+    /// Records activity now and returns how long the connection had been quiet before it.
+    pub fn touch(&self) -> Duration {
+        let now = self.origin.elapsed();
+        let previous = self.last_ms.swap(now.as_millis() as u64, Ordering::Relaxed);
+        now.saturating_sub(Duration::from_millis(previous))
+    }
+
+    /// How long the connection has been quiet.
+    pub fn idle_for(&self) -> Duration {
+        self.origin.elapsed().saturating_sub(Duration::from_millis(self.last_ms.load(Ordering::Relaxed)))
+    }
+}
+
+/// Counts running connection handlers so a shutdown can wait until they have all finished.
+#[derive(Clone, Default)]
+pub struct ConnectionTracker {
+    inner: Arc<TrackerInner>,
+}
+
+#[derive(Default)]
+struct TrackerInner {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+/// Marks one connection as running until it is dropped.
+pub struct ConnectionGuard {
+    inner: Arc<TrackerInner>,
+}
+
+impl ConnectionTracker {
+    pub fn enter(&self) -> ConnectionGuard {
+        self.inner.count.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard { inner: self.inner.clone() }
+    }
+
+    /// Like `enter`, but refuses once `limit` connections are running.
+    pub fn try_enter(&self, limit: Option<usize>) -> Option<ConnectionGuard> {
+        let Some(limit) = limit else { return Some(self.enter()) };
+        self.inner
+            .count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < limit).then_some(count + 1))
+            .ok()
+            .map(|_| ConnectionGuard { inner: self.inner.clone() })
+    }
+
+    pub fn count(&self) -> usize {
+        self.inner.count.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once no connection is running.
+    pub async fn wait_idle(&self) {
+        loop {
+            // Registered before the check, so a guard dropped in between still wakes us.
+            let idle = self.inner.idle.notified();
+            if self.count() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.inner.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+/// Tracks the `seq`s of messages that have been broadcast but not yet saved, so work that needs a room's store to
+/// hold everything up to some message, such as `/clear` or reopening the room, can wait for the saves still on
+/// their way. Also holds the latest `seq` of rooms that have emptied until it is recorded in the store.
+#[derive(Clone, Default)]
+pub struct SaveTracker {
+    inner: Arc<SaveTrackerInner>,
+}
+
+#[derive(Default)]
+struct SaveTrackerInner {
+    pending: std::sync::Mutex<HashMap<String, BTreeSet<u64>>>,
+    landed: Notify,
+    emptied: std::sync::Mutex<HashMap<String, u64>>,
+}
+
+/// A message that has been broadcast with number `seq` and is waiting to be saved. Dropping it, once the save has
+/// finished or failed, or because the handler saving it was stopped, stops it being waited for.
+#[derive(Default)]
+pub struct PendingSave {
+    tracker: Option<(Arc<SaveTrackerInner>, String)>,
+    seq: u64,
+}
+
+impl PendingSave {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl SaveTracker {
+    /// Records that message `seq` in `room` has been broadcast and will be saved, until the result is dropped.
+    pub fn expect(&self, room: &str, seq: u64) -> PendingSave {
+        self.inner.pending.lock().unwrap().entry(room.to_string()).or_default().insert(seq);
+        PendingSave { tracker: Some((self.inner.clone(), room.to_string())), seq }
+    }
+
+    /// Records that `room` emptied with `seq` as its latest number, which hasn't been recorded in the store yet.
+    pub fn room_emptied(&self, room: &str, seq: u64) {
+        self.inner.emptied.lock().unwrap().insert(room.to_string(), seq);
+    }
+
+    /// Forgets `room`'s latest number once `seq` has been recorded in the store, unless it has emptied again since.
+    pub fn seq_recorded(&self, room: &str, seq: u64) {
+        let mut emptied = self.inner.emptied.lock().unwrap();
+        if emptied.get(room) == Some(&seq) {
+            emptied.remove(room);
+        }
+    }
+
+    /// The latest number of `room` when it last emptied, if the store may not have it yet.
+    pub fn emptied_seq(&self, room: &str) -> Option<u64> {
+        self.inner.emptied.lock().unwrap().get(room).copied()
+    }
+
+    /// Resolves once no message in `room` numbered up to `seq` is still waiting to be saved, or after `timeout`.
+    pub async fn wait_saved(&self, room: &str, seq: u64, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                // Registered before the check, so a save landing in between still wakes us.
+                let landed = self.inner.landed.notified();
+                let pending = self.inner.pending.lock().unwrap().get(room).and_then(|seqs| seqs.first().copied());
+                if pending.is_none_or(|first| first > seq) {
+                    return;
+                }
+                landed.await;
+            }
+        })
+        .await;
+    }
+}
+
+impl Drop for PendingSave {
+    fn drop(&mut self) {
+        let Some((inner, room)) = &self.tracker else { return };
+        let mut pending = inner.pending.lock().unwrap();
+        if let Some(seqs) = pending.get_mut(room) {
+            seqs.remove(&self.seq);
+            if seqs.is_empty() {
+                pending.remove(room);
+            }
+        }
+        drop(pending);
+        inner.landed.notify_waiters();
+    }
 }
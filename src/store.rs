@@ -0,0 +1,379 @@
+// src/store.rs
+
+use crate::config::Config;
+use crate::database::PostgresStore;
+use crate::fallback_store::FallbackStore;
+use crate::memory_store::MemoryStore;
+use crate::models::{ServerMessage, ThreadReply};
+use crate::sqlite_store::SqliteStore;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Why a store call failed.
+#[derive(Debug)]
+pub enum DbError {
+    /// The database couldn't be reached or rejected the query.
+    Query(sqlx::Error),
+    /// A value couldn't be converted into the form it is stored in.
+    Encode(String),
+    /// The call didn't finish within `DB_TIMEOUT_SECS`.
+    Timeout,
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Query(e) => write!(f, "query failed: {}", e),
+            DbError::Encode(e) => write!(f, "could not encode value: {}", e),
+            DbError::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Query(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        DbError::Query(e)
+    }
+}
+
+impl From<serde_json::Error> for DbError {
+    fn from(e: serde_json::Error) -> Self {
+        DbError::Encode(e.to_string())
+    }
+}
+
+pub type DbResult<T> = Result<T, DbError>;
+
+/// Everything the server persists. Failures are returned to the caller, which decides whether to tell
+/// the client, retry, or carry on without the data; a row that can't be decoded is not a failure of the
+/// call, and is skipped (see `DecodeFailures`) so one bad message doesn't hide the rest of a room.
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    /// Saves a message; `created_at` is when the server accepted it, not when the write happens. `seq` is its
+    /// number in the room, or 0 for a message saved without one.
+    async fn save_message(&self, room_name: &str, seq: u64, message: &ServerMessage, created_at: DateTime<Utc>) -> DbResult<()>;
+
+    /// Loads the newest `limit` messages of a room, oldest first.
+    async fn load_history(&self, room_name: &str, limit: usize) -> DbResult<VecDeque<ServerMessage>>;
+
+    /// Loads one page of a room's history (page 1 is the newest), oldest first, with each message's reaction counts
+    /// and the time it was stored. Joins and leaves are skipped unless `include_presence` is set; pages are counted
+    /// over what is returned.
+    async fn load_history_paginated(
+        &self,
+        room_name: &str,
+        page: i64,
+        page_size: i64,
+        include_presence: bool,
+    ) -> DbResult<VecDeque<(ServerMessage, BTreeMap<String, i64>, DateTime<Utc>)>>;
+
+    /// Counts the messages stored for a room.
+    async fn get_message_count(&self, room_name: &str) -> DbResult<i64>;
+
+    /// The highest message number of a room: the greater of the one recorded with `set_latest_seq` and that of
+    /// its newest stored message. Rooms with neither (stored before numbers were kept) fall back to how many
+    /// messages they have stored.
+    async fn get_latest_seq(&self, room_name: &str) -> DbResult<u64>;
+
+    /// Records that a room has numbered its messages up to `seq`, for when it is emptied. Never lowers the
+    /// recorded number, so it still counts messages that were deleted, expired or never stored.
+    async fn set_latest_seq(&self, room_name: &str, seq: u64) -> DbResult<()>;
+
+    /// Sends every stored message of a room into `tx`, oldest first, without loading them all at once.
+    async fn stream_room_messages(&self, room_name: String, tx: mpsc::Sender<DbResult<ServerMessage>>);
+
+    /// Records a reaction. Returns `false` if the message isn't in the room or the reaction already exists.
+    async fn add_reaction(&self, room_name: &str, message_id: Uuid, username: &str, emoji: &str) -> DbResult<bool>;
+
+    /// Deletes a chat message and its reactions. Returns `false` if the room has no such message.
+    async fn delete_message(&self, room_name: &str, message_id: Uuid) -> DbResult<bool>;
+
+    /// Deletes a room's stored messages numbered up to `seq`, those saved without a number, and their reactions,
+    /// for `/clear`. Messages numbered after `seq` are kept. Returns how many were deleted.
+    async fn clear_messages(&self, room_name: &str, seq: u64) -> DbResult<u64>;
+
+    /// Loads every stored message that is set to expire, as (room, message id, expiry).
+    async fn load_message_expiries(&self) -> DbResult<Vec<(String, Uuid, DateTime<Utc>)>>;
+
+    async fn is_room_closed(&self, room_name: &str) -> DbResult<bool>;
+
+    async fn set_room_closed(&self, room_name: &str, closed: bool) -> DbResult<()>;
+
+    /// Whether an administrator has registered the room, allowing clients in when `AUTO_CREATE_ROOMS` is off.
+    async fn is_room_registered(&self, room_name: &str) -> DbResult<bool>;
+
+    async fn set_room_registered(&self, room_name: &str, registered: bool) -> DbResult<()>;
+
+    /// Loads a room's topic, if one has been set.
+    async fn get_room_topic(&self, room_name: &str) -> DbResult<Option<RoomTopic>>;
+
+    async fn set_room_topic(&self, room_name: &str, topic: &RoomTopic) -> DbResult<()>;
+
+    /// Loads the key/value metadata set on a room with `/roommeta`.
+    async fn get_room_metadata(&self, room_name: &str) -> DbResult<BTreeMap<String, String>>;
+
+    /// Replaces a room's metadata.
+    async fn set_room_metadata(&self, room_name: &str, metadata: &BTreeMap<String, String>) -> DbResult<()>;
+
+    /// Loads the public keys members of a room have published with `/key publish`, keyed by username.
+    async fn get_public_keys(&self, room_name: &str) -> DbResult<BTreeMap<String, String>>;
+
+    /// Replaces a room's published public keys.
+    async fn set_public_keys(&self, room_name: &str, keys: &BTreeMap<String, String>) -> DbResult<()>;
+
+    /// Loads the regex that usernames in a room must match, if one is set. Returned uncompiled.
+    async fn get_username_pattern(&self, room_name: &str) -> DbResult<Option<String>>;
+
+    /// Sets or (with `None`) removes a room's username pattern.
+    async fn set_username_pattern(&self, room_name: &str, pattern: Option<&str>) -> DbResult<()>;
+
+    /// Loads who owns and moderates a room.
+    async fn get_room_ownership(&self, room_name: &str) -> DbResult<RoomOwnership>;
+
+    async fn set_room_ownership(&self, room_name: &str, ownership: &RoomOwnership) -> DbResult<()>;
+
+    /// Loads a single chat message by id, if it was sent in the room.
+    async fn load_message(&self, room_name: &str, message_id: Uuid) -> DbResult<Option<ServerMessage>>;
+
+    /// Loads the replies under a chat message, oldest first: direct replies at depth 1, replies to those
+    /// at depth 2, and so on down to `max_depth`. At most `limit` replies are returned.
+    async fn load_thread(&self, room_name: &str, root: Uuid, max_depth: u32, limit: usize) -> DbResult<Vec<ThreadReply>>;
+
+    /// Loads a room's pinned messages in the order they were pinned, skipping any whose message is gone.
+    async fn get_pinned_messages(&self, room_name: &str) -> DbResult<Vec<ServerMessage>>;
+
+    /// Replaces the ids of a room's pinned messages, in pin order.
+    async fn set_pinned_ids(&self, room_name: &str, message_ids: &[Uuid]) -> DbResult<()>;
+
+    /// Looks up a registered username's secret hash; `Ok(None)` means the name is free for anyone.
+    async fn get_secret_hash(&self, username: &str) -> DbResult<Option<String>>;
+
+    /// Registers a username. Returns `false` if it was already registered.
+    async fn register_user(&self, username: &str, secret_hash: &str) -> DbResult<bool>;
+
+    /// Lists rooms whose latest message is newer than `since`, most recently active first.
+    async fn get_recent_rooms(&self, since: DateTime<Utc>) -> DbResult<Vec<RecentRoom>>;
+
+    /// Files a report against a message. Returns `false` if the message isn't in the room or the
+    /// reporter already reported it.
+    async fn add_report(&self, report: &Report) -> DbResult<bool>;
+
+    /// Loads one page of reports (page 1 is the newest), newest first.
+    async fn load_reports(&self, page: i64, page_size: i64) -> DbResult<Vec<Report>>;
+
+    /// Records a moderation action in the audit log.
+    async fn log_audit(&self, entry: &AuditEntry) -> DbResult<()>;
+
+    /// Loads one page of the audit log (page 1 is the newest), newest first, optionally for a single room.
+    async fn load_audit_log(&self, room_name: Option<&str>, page: i64, page_size: i64) -> DbResult<Vec<AuditEntry>>;
+
+    /// Loads how far `username` has read in a room: messages stored at or before the returned time count as seen.
+    async fn get_last_seen(&self, room_name: &str, username: &str) -> DbResult<Option<DateTime<Utc>>>;
+
+    /// Moves `username`'s read cursor in a room to `seen_at`, as set with `/markread`.
+    async fn set_last_seen(&self, room_name: &str, username: &str, seen_at: DateTime<Utc>) -> DbResult<()>;
+
+    /// Queues an announcement for delivery at `message.deliver_at`.
+    async fn add_scheduled_message(&self, message: &ScheduledMessage) -> DbResult<()>;
+
+    /// Cancels a pending announcement. Returns `false` if there is none with that id, e.g. because it was already delivered.
+    async fn cancel_scheduled_message(&self, id: Uuid) -> DbResult<bool>;
+
+    /// Removes and returns the announcements due at `now`, oldest first.
+    async fn take_due_scheduled_messages(&self, now: DateTime<Utc>) -> DbResult<Vec<ScheduledMessage>>;
+
+    /// How many stored messages have failed to decode since the store was opened.
+    fn undecodable_messages(&self) -> u64;
+}
+
+/// Tracks stored messages that no longer decode into a `ServerMessage`, e.g. after an incompatible change to
+/// a message type or a corrupted row, so they don't drop out of history unnoticed.
+#[derive(Default)]
+pub struct DecodeFailures {
+    count: AtomicU64,
+    /// Whether history shows an `Unreadable` placeholder where such a message was (`HISTORY_TOMBSTONES`).
+    tombstones: bool,
+}
+
+impl DecodeFailures {
+    pub fn new(tombstones: bool) -> Self {
+        DecodeFailures { count: AtomicU64::new(0), tombstones }
+    }
+
+    /// Logs and counts a row that failed to decode.
+    pub(crate) fn record(&self, room_name: &str, row_id: i64, error: &str) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        eprintln!("Stored message {} in room '{}' could not be decoded: {}", row_id, room_name, error);
+    }
+
+    /// What history shows in place of an undecodable row: a placeholder when tombstones are on, otherwise nothing.
+    pub(crate) fn placeholder(&self, row_id: i64) -> Option<ServerMessage> {
+        self.tombstones.then_some(ServerMessage::Unreadable { row_id })
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// A room with stored messages, as listed by `GET /rooms/recent`.
+#[derive(Serialize)]
+pub struct RecentRoom {
+    pub room: String,
+    pub last_active: DateTime<Utc>,
+    pub message_count: i64,
+    /// Set with `/roommeta`, so lobbies can filter and group rooms.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// One step in the evolution of a database schema. Each backend keeps an ordered list of these and
+/// applies the ones above the version recorded in its `schema_version` table at startup, recording
+/// each as it goes. Never edit a released migration; append a new one.
+pub struct Migration<Step: 'static> {
+    /// Strictly increasing, starting at 1.
+    pub version: i64,
+    pub description: &'static str,
+    pub steps: &'static [Step],
+}
+
+/// A room's topic and the administrator who set it, kept in `rooms_meta`.
+#[derive(Debug, Clone)]
+pub struct RoomTopic {
+    pub topic: String,
+    pub set_by: String,
+}
+
+/// A room's owner and the operators they appointed with `/op`, kept in `rooms_meta`. Both can moderate
+/// the room (topic, pins, closing...) without the server-wide admin token.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoomOwnership {
+    pub owner: Option<String>,
+    pub operators: Vec<String>,
+}
+
+impl RoomOwnership {
+    /// Whether `username` is the owner or one of the operators.
+    pub fn includes(&self, username: &str) -> bool {
+        self.owner.as_deref() == Some(username) || self.operators.iter().any(|op| op == username)
+    }
+}
+
+/// A message flagged for moderators with `/report`, as returned by `GET /admin/reports`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub message_id: Uuid,
+    pub room: String,
+    pub reporter: String,
+    pub reason: Option<String>,
+    pub reported_at: DateTime<Utc>,
+}
+
+/// One moderation action, as stored in the audit log and returned by `GET /admin/audit`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    /// What was done, e.g. `close_room`.
+    pub action: String,
+    /// Who did it.
+    pub actor: String,
+    /// The user the action was aimed at, if any.
+    pub target: Option<String>,
+    pub room: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub detail: Option<String>,
+}
+
+/// An announcement queued with `POST /admin/schedule`, kept in the store until it is delivered or cancelled.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledMessage {
+    pub id: Uuid,
+    /// The room to announce in, or `*` for every active room.
+    pub room: String,
+    pub text: String,
+    pub deliver_at: DateTime<Utc>,
+}
+
+/// Which `MessageStore` implementation to run with, set with `STORAGE_BACKEND`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    Postgres,
+    Sqlite,
+    /// Nothing survives a restart; meant for tests and demos.
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postgres" => Ok(StorageBackend::Postgres),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "memory" => Ok(StorageBackend::Memory),
+            other => Err(format!("unknown storage backend '{}'", other)),
+        }
+    }
+}
+
+/// Opens the configured storage backend. With `DB_OPTIONAL`, an unreachable database doesn't stop
+/// startup: the server runs memory-only and keeps retrying in the background.
+pub async fn open_store(config: &Config) -> Result<Arc<dyn MessageStore>, sqlx::Error> {
+    if config.storage_backend == StorageBackend::Memory {
+        println!("Using the in-memory store; messages will not survive a restart.");
+        return Ok(Arc::new(MemoryStore::default()));
+    }
+
+    let backend = config.storage_backend;
+    let sqlite_path = config.sqlite_path.clone();
+    let acquire_timeout = config.db_acquire_timeout;
+    let compress = config.compress_storage;
+    let tombstones = config.history_tombstones;
+    let connect = move || {
+        let sqlite_path = sqlite_path.clone();
+        async move { connect_database(backend, &sqlite_path, acquire_timeout, compress, tombstones).await }
+    };
+
+    match connect().await {
+        Ok(store) => Ok(store),
+        Err(e) if config.db_optional => {
+            eprintln!(
+                "WARNING: database unavailable at startup ({}). Running in memory-only mode; nothing is persisted until it comes back.",
+                e
+            );
+            Ok(Arc::new(FallbackStore::start(connect, config.db_retry_interval)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn connect_database(
+    backend: StorageBackend,
+    sqlite_path: &str,
+    acquire_timeout: Duration,
+    compress: bool,
+    tombstones: bool,
+) -> Result<Arc<dyn MessageStore>, sqlx::Error> {
+    let store: Arc<dyn MessageStore> = match backend {
+        StorageBackend::Postgres => Arc::new(PostgresStore::connect(acquire_timeout, compress, tombstones).await?),
+        StorageBackend::Sqlite => Arc::new(SqliteStore::connect(sqlite_path).await?.with_tombstones(tombstones)),
+        StorageBackend::Memory => Arc::new(MemoryStore::default()),
+    };
+    Ok(store)
+}
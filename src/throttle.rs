@@ -0,0 +1,127 @@
+// src/throttle.rs
+
+use crate::listener::PeerAddr;
+use crate::lru::LruMap;
+use crate::state::ChatState;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{header::RETRY_AFTER, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Most client addresses tracked at once; the least recently seen are forgotten first.
+pub const MAX_THROTTLED_CLIENTS: usize = 10_000;
+
+/// Per-IP limits on the REST endpoints that read stored history (`/rooms/recent`, `/rooms/{room}/export`),
+/// so they can't be used to pull the database out wholesale: `REST_RATE_LIMIT` requests and
+/// `REST_MAX_RESPONSE_BYTES` of response body per `REST_RATE_WINDOW_SECS`.
+#[derive(Clone)]
+pub struct HistoryThrottle {
+    clients: Arc<Mutex<LruMap<IpAddr, Usage>>>,
+}
+
+/// What one address has used in its current window.
+struct Usage {
+    window_start: Instant,
+    requests: u32,
+    bytes: u64,
+}
+
+impl Default for HistoryThrottle {
+    fn default() -> Self {
+        HistoryThrottle { clients: Arc::new(Mutex::new(LruMap::new(MAX_THROTTLED_CLIENTS))) }
+    }
+}
+
+impl HistoryThrottle {
+    /// Counts a request from `ip`. Returns how long until its window resets if it is over either limit.
+    fn admit(&self, ip: IpAddr, max_requests: Option<u32>, max_bytes: Option<u64>, window: Duration) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if !clients.contains_key(&ip) {
+            clients.insert(ip, Usage { window_start: now, requests: 0, bytes: 0 });
+        }
+        let Some(usage) = clients.get_mut(&ip) else { return Ok(()) };
+        if now.duration_since(usage.window_start) >= window {
+            *usage = Usage { window_start: now, requests: 0, bytes: 0 };
+        }
+        let over_requests = max_requests.is_some_and(|max| usage.requests >= max);
+        let over_bytes = max_bytes.is_some_and(|max| usage.bytes >= max);
+        if over_requests || over_bytes {
+            return Err(window.saturating_sub(now.duration_since(usage.window_start)));
+        }
+        usage.requests += 1;
+        Ok(())
+    }
+
+    /// Adds response bytes sent to `ip` to its current window.
+    fn record_bytes(&self, ip: IpAddr, bytes: usize) {
+        if let Some(usage) = self.clients.lock().unwrap().get_mut(&ip) {
+            usage.bytes += bytes as u64;
+        }
+    }
+}
+
+/// Tokens refilled at a steady rate, up to one second's worth (and at least one), spent one per message.
+/// Used for a room's aggregate `ROOM_RATE_LIMITS`.
+pub struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket holding all the tokens `rate` allows.
+    pub fn full(rate: f64, now: Instant) -> Self {
+        TokenBucket { tokens: rate.max(1.0), refilled_at: now }
+    }
+
+    /// Spends a token at `now`, refilling at `rate` per second first. Returns how long until one is
+    /// available if the bucket is empty.
+    pub fn take(&mut self, rate: f64, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.0));
+        self.refilled_at = now;
+        if self.tokens < 1.0 {
+            return Err(Duration::try_from_secs_f64((1.0 - self.tokens) / rate).unwrap_or(Duration::MAX));
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Middleware for the history endpoints: refuses clients over their limits with `429 Too Many Requests` and a
+/// `Retry-After`, and meters the bytes of every response it lets through, streamed exports included. A response
+/// already under way is never cut short; the bytes it sends count against the client's next request.
+pub async fn throttle_history(
+    State(state): State<ChatState>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = &state.config;
+    if config.rest_rate_limit.is_none() && config.rest_max_response_bytes.is_none() {
+        return next.run(request).await;
+    }
+
+    let ip = peer.ip().to_canonical();
+    if let Err(retry_after) = state.history_throttle.admit(ip, config.rest_rate_limit, config.rest_max_response_bytes, config.rest_rate_window) {
+        println!("Throttling history requests from {} for {:?}.", ip, retry_after);
+        let retry_after = retry_after.as_secs_f64().ceil().max(1.0).to_string();
+        return (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry_after)], "Too many history requests; try again later").into_response();
+    }
+
+    let throttle = state.history_throttle.clone();
+    next.run(request).await.map(|body| {
+        Body::from_stream(body.into_data_stream().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                throttle.record_bytes(ip, chunk.len());
+            }
+        }))
+    })
+}
@@ -0,0 +1,73 @@
+// src/webhook.rs
+
+use crate::events::ServerEvent;
+use crate::models::ServerMessage;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// Delivery settings for the outbound webhook
+const WEBHOOK_MAX_ATTEMPTS: u32 = 3;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// A chat message as posted to the configured webhook.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookEvent {
+    pub room: String,
+    pub username: String,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Spawns the background task that posts every chat message published on the event bus to `url`.
+/// Chat handlers never wait for it, so a slow or failing endpoint never blocks chat; if it falls more than
+/// the bus's capacity behind, the messages it missed are dropped.
+pub fn spawn_webhook_worker(url: String, mut events: broadcast::Receiver<ServerEvent>) {
+    println!("Webhook delivery enabled to {}", url);
+
+    tokio::spawn(async move {
+        let client = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Failed to build webhook HTTP client: {}", e);
+                return;
+            }
+        };
+
+        loop {
+            let event = match events.recv().await {
+                Ok(ServerEvent::MessageSent { room, message: ServerMessage::NewMessage { username, content, .. }, timestamp, .. }) => {
+                    WebhookEvent { room, username, content, timestamp }
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Webhook delivery fell behind; dropping {} events.", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            deliver(&client, &url, &event).await;
+        }
+    });
+}
+
+/// Posts a single event, retrying with a linear backoff before giving up.
+async fn deliver(client: &reqwest::Client, url: &str, event: &WebhookEvent) {
+    for attempt in 1..=WEBHOOK_MAX_ATTEMPTS {
+        match client.post(url).json(event).send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => {
+                eprintln!("Webhook attempt {}/{} got status {}", attempt, WEBHOOK_MAX_ATTEMPTS, response.status());
+            }
+            Err(e) => {
+                eprintln!("Webhook attempt {}/{} failed: {}", attempt, WEBHOOK_MAX_ATTEMPTS, e);
+            }
+        }
+        if attempt < WEBHOOK_MAX_ATTEMPTS {
+            tokio::time::sleep(WEBHOOK_RETRY_DELAY * attempt).await;
+        }
+    }
+    eprintln!("Dropping webhook event for room '{}' after {} attempts.", event.room, WEBHOOK_MAX_ATTEMPTS);
+}
@@ -1,63 +1,389 @@
 // src/websocket.rs
 
 use crate::{
-    database,
-    models::ServerMessage,
-    state::{ChatState, Client, Room, IN_MEMORY_CACHE_SIZE, MAX_HISTORY_SIZE},
+    auth,
+    config::{truncate_for_display, truncate_graphemes, BusyPolicy, Config},
+    events::{tap, ServerEvent},
+    expiry::EXPIRY_RETRY,
+    listener::PeerAddr,
+    lru::LruMap,
+    moderation::ModerationAction,
+    models::{ClientMessage, CloseReason, ProtocolVersion, ServerMessage, ThreadReply, SUPPORTED_SUBPROTOCOLS},
+    outbound::{Disconnected, Outbound, ShedPolicy},
+    state::{
+        ActivityClock, ChatState, Client, ConnectionGuard, FloodRecord, FloodVerdict, OfflineBuffer, PendingAck, PendingSave, Room, UsernamePattern, ALLOWED_CUSTOM_KINDS, FRAME_RATE_WINDOW, IN_MEMORY_CACHE_SIZE,
+        MAX_CUSTOM_PAYLOAD_BYTES, MAX_HISTORY_PAGE_SIZE, MAX_HISTORY_SIZE, MAX_REACTION_CHARS, MAX_REPORT_REASON_CHARS,
+        MAX_METADATA_KEY_CHARS, MAX_METADATA_VALUE_CHARS, MAX_IGNORED_USERS, MAX_MUTE_SECS, MAX_PINNED_MESSAGES, MAX_PUBLIC_KEY_CHARS, MAX_ROOM_METADATA_KEYS, MAX_TOPIC_CHARS,
+    },
+    store::{AuditEntry, DbError, DbResult, Report, RoomOwnership, RoomTopic, ScheduledMessage},
+    throttle::TokenBucket,
 };
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        Path, State,
+        ConnectInfo, Path, Query, State,
     },
-    response::IntoResponse,
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
 use futures_util::{
     sink::SinkExt,
     stream::{SplitStream, StreamExt},
 };
-use std::collections::HashMap;
-use tokio::sync::MutexGuard;
+use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::sync::{MutexGuard, OwnedMutexGuard};
 use uuid::Uuid;
 
+// Most bytes of a chat message's content written to the server log
+const LOG_PREVIEW_BYTES: usize = 200;
+
+/// Reporter name on reports filed by the content filter.
+const CONTENT_FILTER_REPORTER: &str = "content-filter";
+// Audit log actor for mutes handed out by the message rate limit
+const RATE_LIMITER_ACTOR: &str = "rate-limiter";
+
+/// Query parameters accepted when connecting to a room.
+#[derive(Deserialize)]
+pub struct ConnectParams {
+    /// The room's password, for private rooms.
+    password: Option<String>,
+    /// A single-use token from `/invite`, admitting one client to a private room without its password.
+    invite: Option<String>,
+}
+
 /// The main handler for WebSocket connections.
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
     State(state): State<ChatState>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
     Path(room_name): Path<String>,
-) -> impl IntoResponse {
-    println!("New client connecting to room: {}", room_name);
-    ws.on_upgrade(|socket| handle_socket(socket, state, room_name))
+    Query(params): Query<ConnectParams>,
+) -> Response {
+    // Existing connections may finish during a shutdown drain, but no new ones are accepted.
+    if state.shutting_down.load(Ordering::SeqCst) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server is shutting down").into_response();
+    }
+
+    // Counted from here until cleanup has finished, so shutdown can wait for this connection's last
+    // writes. Taking the slot before the upgrade keeps concurrent upgrades from overshooting the cap.
+    let Some(connection) = state.connections.try_enter(state.config.max_total_connections) else {
+        println!("Rejecting client for room '{}': the server is at its connection limit.", room_name);
+        return (StatusCode::SERVICE_UNAVAILABLE, "Too many connections; try again later").into_response();
+    };
+
+    // Negotiate the wire format. Clients that don't ask for a subprotocol get the legacy v1 format,
+    // but clients that only offer versions we don't speak are refused outright.
+    let ws = ws.protocols(SUPPORTED_SUBPROTOCOLS.iter().copied());
+    let protocol = match ws.selected_protocol() {
+        Some(selected) => selected
+            .to_str()
+            .ok()
+            .and_then(ProtocolVersion::from_subprotocol)
+            .unwrap_or(ProtocolVersion::V1),
+        None if headers.contains_key(SEC_WEBSOCKET_PROTOCOL) => {
+            println!("Rejecting client for room '{}': no supported subprotocol offered.", room_name);
+            let supported = SUPPORTED_SUBPROTOCOLS.join(", ");
+            return (StatusCode::BAD_REQUEST, format!("Unsupported subprotocol. Supported: {}", supported)).into_response();
+        }
+        None => ProtocolVersion::V1,
+    };
+
+    if state.config.banned_ips.contains(&peer.ip()) {
+        println!("Rejecting client {} for room '{}': the address is banned.", peer.ip(), room_name);
+        return reject(ws, CloseReason::Banned);
+    }
+
+    // Fragmented messages are reassembled before they reach us, and the limit applies to the
+    // reassembled message, so splitting a message into small frames doesn't get around it.
+    let ws = ws.max_message_size(state.config.max_message_bytes).max_frame_size(state.config.max_message_bytes);
+
+    let normalized = state.config.room_name_normalization.normalize(&room_name);
+    if normalized != room_name {
+        println!("Room '{}' is normalized to '{}'.", room_name, normalized);
+    }
+    let room_name = normalized;
+    let canonical = state.config.room_aliases.resolve(&room_name);
+    if canonical != room_name {
+        println!("Room '{}' is an alias of '{}'.", room_name, canonical);
+    }
+    let room_name = canonical.to_string();
+
+    if !state.config.auto_create_rooms && !room_exists(&state, &room_name).await {
+        println!("Rejecting client for room '{}': the room doesn't exist and AUTO_CREATE_ROOMS is off.", room_name);
+        return reject(ws, CloseReason::UnknownRoom);
+    }
+
+    if let Some(limit) = state.config.room_max_clients
+        && state.rooms.lock().await.get(&room_name).is_some_and(|room| room.clients.len() >= limit)
+    {
+        println!("Rejecting client for room '{}': the room is full.", room_name);
+        return reject(ws, CloseReason::RoomFull);
+    }
+
+    // An invite is only checked here. It is used up once the client is actually added to the room, so a failed
+    // handshake or a later rejection doesn't waste it.
+    let mut invite = None;
+    if let Some(password) = state.config.room_password(&room_name)
+        && !params.password.as_deref().is_some_and(|provided| auth::token_matches(provided, password))
+    {
+        match params.invite.filter(|token| state.invites.is_valid(token, &room_name)) {
+            Some(token) => invite = Some(token),
+            None => {
+                println!("Rejecting client for private room '{}': no valid password or invite.", room_name);
+                return reject(ws, CloseReason::AuthFailed);
+            }
+        }
+    }
+
+    // Looked up once, before the upgrade; only administrators ever see it (`/whois`).
+    let country = state.config.geoip.as_ref().and_then(|geoip| geoip.country_of(peer.ip()));
+
+    println!("New client connecting to room: {} ({:?})", room_name, protocol);
+    ws.on_upgrade(move |socket| handle_socket(socket, state, room_name, protocol, country, invite, connection))
+}
+
+/// Refuses a connection with a close frame rather than an HTTP error, so clients learn why like any other close.
+fn reject(ws: WebSocketUpgrade, reason: CloseReason) -> Response {
+    ws.on_upgrade(move |mut socket| async move {
+        let _ = socket.send(reason.close_message()).await;
+    })
+}
+
+/// Whether `room_name` is active or registered (in `REGISTERED_ROOMS` or through the admin API), so a
+/// client may join it without creating it.
+async fn room_exists(state: &ChatState, room_name: &str) -> bool {
+    if state.config.registered_rooms.iter().any(|room| room == room_name) || state.rooms.lock().await.contains_key(room_name) {
+        return true;
+    }
+    db_call(state, state.store.is_room_registered(room_name)).await.unwrap_or(false)
 }
 
 /// Manages the lifecycle of a client. A client is anonymous until they set a username.
-async fn handle_socket(socket: WebSocket, state: ChatState, room_name: String) {
+/// `invite` is the token that admitted it to a private room, redeemed once nothing else can turn the client away.
+/// `_connection` is held until cleanup has finished.
+async fn handle_socket(
+    socket: WebSocket,
+    state: ChatState,
+    room_name: String,
+    protocol: ProtocolVersion,
+    country: Option<String>,
+    invite: Option<String>,
+    _connection: ConnectionGuard,
+) {
     let client_id = Uuid::new_v4();
-    let (sender, receiver) = socket.split();
+    let (mut sender, receiver) = socket.split();
+
+    // Tell the client who it is before it can see any other traffic.
+    let welcome = ServerMessage::Welcome {
+        client_id,
+        room: room_name.clone(),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        features: state.config.features(&room_name),
+    };
+    if sender.send(Message::Text(render_message(&welcome, protocol, &state.config).into())).await.is_err() {
+        println!("Client {} disconnected before receiving the welcome frame.", client_id);
+        return;
+    }
+
+    // From here on everything reaches the socket through the client's bounded outbound queue.
+    let shed_at = state.config.room_max_in_flight.filter(|_| state.config.room_shed_policy == ShedPolicy::DropOldest);
+    let sender = Outbound::spawn(client_id, sender, state.config.outbound_queue_size, state.config.outbound_drop_policy, shed_at);
+    let motd = state.motd.read().unwrap().clone();
+    if let Some(message) = motd {
+        let _ = sender.push(Message::Text(render_message(&ServerMessage::Motd { message }, protocol, &state.config).into()));
+    }
+
+    // Add the client to the state without a username immediately.
+    let activity = ActivityClock::start();
+    let mut receive_task = {
+        // A new room is loaded from the database before the rooms lock is taken, so a slow store only
+        // holds up this client. Seeding still finishes before anyone can broadcast into the room, so the
+        // cache never mixes live messages with a later bulk load.
+        let mut seeded = None;
+        let mut rooms = loop {
+            let rooms = state.rooms.lock().await;
+            if seeded.is_some() || rooms.contains_key(&room_name) {
+                break rooms;
+            }
+            drop(rooms);
+            match load_room(&state, &room_name).await {
+                Ok((room, warning)) => {
+                    if let Some(warning) = warning {
+                        let _ = sender.push(Message::Text(render_message(&warning, protocol, &state.config).into()));
+                    }
+                    seeded = Some(room);
+                }
+                Err(e) => {
+                    eprintln!("Not opening room '{}': its message numbering couldn't be loaded: {}", room_name, e);
+                    let _ = sender.push(Message::Text(render_message(&db_error(&e), protocol, &state.config).into()));
+                    sender.close(CloseReason::RoomUnavailable);
+                    return;
+                }
+            }
+        };
+        // Checked again under the lock that adds the client: the check before the upgrade reserves nothing, so
+        // clients connecting at the same time could all pass it.
+        if let Some(limit) = state.config.room_max_clients
+            && rooms.get(&room_name).is_some_and(|room| room.clients.len() >= limit)
+        {
+            println!("Rejecting client {} for room '{}': the room is full.", client_id, room_name);
+            sender.close(CloseReason::RoomFull);
+            return;
+        }
+        // Someone else may have used the invite since it was checked.
+        if let Some(token) = &invite
+            && !state.invites.redeem(token, &room_name)
+        {
+            println!("Rejecting client {} for private room '{}': its invite was already used.", client_id, room_name);
+            sender.close(CloseReason::AuthFailed);
+            return;
+        }
+        // Another client may have opened the room while this one was loading it; its copy wins and this one is dropped.
+        let room = match seeded {
+            Some(seeded) => rooms.entry(room_name.clone()).or_insert(seeded),
+            None => rooms.get_mut(&room_name).expect("room was just ensured"),
+        };
+        sender.attach_backlog(room.backlog.clone());
+
+        // Spawn the task to handle all messages from this client. It can't touch the room
+        // until we release the lock, by which point the client is registered.
+        let receive_task =
+            tokio::spawn(read_from_client(receiver, client_id, state.clone(), room_name.clone(), activity.clone()));
 
-    // Add the client to the state as "anonymous" immediately.
-    {
-        let mut rooms = state.rooms.lock().await;
-        let room = rooms.entry(room_name.clone()).or_default();
         let client = Client {
-            username: "anonymous".to_string(),
+            username: None,
             sender,
+            protocol,
+            live_from_seq: room.next_seq,
+            has_joined: false,
+            echo: state.config.echo_own_messages,
+            unacked: HashMap::new(),
+            color: None,
+            last_renamed_at: None,
+            last_message: None,
+            is_admin: false,
+            silent: false,
+            stale: false,
+            country,
+            public_key: None,
+            ignored: HashSet::new(),
+            joined_at: Utc::now(),
+            messages_sent: 0,
+            receive_task: receive_task.abort_handle(),
         };
         room.clients.insert(client_id, client);
         println!("Client {} connected to room '{}' as anonymous.", client_id, room_name);
-    }
+        state.events.publish(|| ServerEvent::ClientJoined { room: room_name.clone(), client_id });
+        receive_task
+    };
 
-    // Spawn the task to handle all messages from this client.
-    let mut receive_task =
-        tokio::spawn(read_from_client(receiver, client_id, state.clone(), room_name.clone()));
+    // Wait for the client to disconnect. An aborted reader (e.g. after eviction) counts as dropped.
+    // The reader runs in its own task, so a panic in any handler ends up here instead of taking the
+    // connection task with it; the tokio lock guard is released during unwinding and cleanup still runs,
+    // leaving the room usable for everyone else.
+    //
+    // Clients that never pick a name are closed once the anonymous grace period runs out, unless
+    // the room lets anonymous clients read.
+    let anon_timeout = state.config.anon_timeout.filter(|_| !state.config.allows_anon_read(&room_name));
+    let anon_deadline = tokio::time::sleep(anon_timeout.unwrap_or_default());
+    tokio::pin!(anon_deadline);
+    let mut awaiting_name = anon_timeout.is_some();
 
-    // Wait for the client to disconnect.
-    tokio::select! {
-        _ = &mut receive_task => {}
-    }
+    // Unacknowledged messages are checked a few times per timeout period.
+    let ack_timeout = state.config.ack_timeout.filter(|_| protocol == ProtocolVersion::V2);
+    let mut ack_check = tokio::time::interval(ack_timeout.map_or(Duration::MAX, |timeout| timeout / 4));
+
+    // Likewise for clients whose app stopped talking while the socket stays up.
+    let heartbeat_timeout = state.config.heartbeat_timeout;
+    let mut heartbeat_check = tokio::time::interval(heartbeat_timeout.map_or(Duration::MAX, |timeout| timeout / 4));
+
+    let disconnect = loop {
+        tokio::select! {
+            result = &mut receive_task => break match result {
+                Ok(disconnect) => disconnect,
+                Err(e) if e.is_panic() => {
+                    eprintln!("Handler for client {} in room '{}' panicked; removing the client.", client_id, room_name);
+                    Disconnect::Dropped
+                }
+                Err(_) => Disconnect::Dropped,
+            },
+            _ = &mut anon_deadline, if awaiting_name => {
+                awaiting_name = false;
+                if close_if_anonymous(client_id, &state, &room_name).await {
+                    receive_task.abort();
+                    break Disconnect::Dropped;
+                }
+            }
+            _ = heartbeat_check.tick(), if heartbeat_timeout.is_some() => {
+                mark_stale_if_idle(client_id, &activity, &state, &room_name).await;
+            }
+            _ = ack_check.tick(), if ack_timeout.is_some() => {
+                if resend_unacked(client_id, &state, &room_name).await {
+                    receive_task.abort();
+                    break Disconnect::Dropped;
+                }
+            }
+        }
+    };
 
     // Client has disconnected, perform cleanup.
-    cleanup_client(&state, client_id, &room_name).await;
+    cleanup_client(&state, client_id, &room_name, disconnect).await;
+}
+
+/// Loads a room's cached state from the store, without holding the rooms lock. Fails only if the room's latest
+/// `seq` can't be loaded: numbering would restart and clients would take new messages for ones they have already
+/// seen. History that can't be loaded leaves the cache empty and comes back as a warning for the client.
+async fn load_room(state: &ChatState, room_name: &str) -> DbResult<(Room, Option<ServerMessage>)> {
+    println!("Loading history for room '{}' from database...", room_name);
+    // Messages from the room's last opening may still be on their way to the store, and its latest `seq` may not
+    // have been recorded yet: wait for the one and fall back on the other, so nothing is missed or numbered twice.
+    state.saves.wait_saved(room_name, u64::MAX, state.config.db_timeout).await;
+    let recorded_seq = db_call(state, state.store.get_latest_seq(room_name)).await?;
+    let latest_seq = recorded_seq.max(state.saves.emptied_seq(room_name).unwrap_or(0));
+    // Seeding always waits for a history permit: a room can't open without its cache.
+    let permit = state.history_loads.acquire().await.expect("history semaphore is never closed");
+    let history = db_call(state, state.store.load_history(room_name, IN_MEMORY_CACHE_SIZE)).await;
+    drop(permit);
+    let (history, warning) = match history {
+        Ok(history) => (history, None),
+        Err(e) => {
+            let reason = if matches!(e, DbError::Timeout) { "database timed out" } else { "database error" };
+            let error = ServerMessage::Error { message: format!("Room history is unavailable right now ({}).", reason) };
+            (Default::default(), Some(error))
+        }
+    };
+    let mut room = Room::with_history(history, latest_seq);
+    room.offline_buffers = LruMap::new(state.config.max_tracked_users);
+    room.flood_records = LruMap::new(state.config.max_tracked_users);
+    room.closed = db_call(state, state.store.is_room_closed(room_name)).await.unwrap_or(false);
+    room.topic = db_call(state, state.store.get_room_topic(room_name)).await.ok().flatten();
+    room.pinned = db_call(state, state.store.get_pinned_messages(room_name)).await.unwrap_or_default();
+    room.metadata = db_call(state, state.store.get_room_metadata(room_name)).await.unwrap_or_default();
+    room.public_keys = db_call(state, state.store.get_public_keys(room_name)).await.unwrap_or_default();
+    room.ownership = db_call(state, state.store.get_room_ownership(room_name)).await.unwrap_or_default();
+    // A pattern that no longer compiles (e.g. edited by hand in the database) is ignored rather than locking everyone out.
+    if let Ok(Some(pattern)) = db_call(state, state.store.get_username_pattern(room_name)).await {
+        match UsernamePattern::compile(&pattern) {
+            Ok(pattern) => room.username_pattern = Some(pattern),
+            Err(e) => eprintln!("Ignoring invalid username pattern for room '{}': {}", room_name, e),
+        }
+    }
+    Ok((room, warning))
+}
+
+/// Why a client's connection ended.
+enum Disconnect {
+    /// The client left with `/quit`, optionally with a parting message.
+    Quit(Option<String>),
+    /// The socket closed or failed without a `/quit`.
+    Dropped,
 }
 
 /// Reads messages from a client and processes them as commands or chat messages.
@@ -66,189 +392,2704 @@ async fn read_from_client(
     client_id: Uuid,
     state: ChatState,
     room_name: String,
-) {
-    while let Some(Ok(Message::Text(text))) = receiver.next().await {
+    activity: ActivityClock,
+) -> Disconnect {
+    // Every frame counts against the limit, whatever its type or content, so pings and binary
+    // frames can't be used to keep the server busy either.
+    let mut window_start = Instant::now();
+    let mut frames_in_window = 0;
+    // Counted over a much longer window than frames, so a slow drip of small frames is caught too.
+    let mut inbound_window_start = Instant::now();
+    let mut inbound_bytes: u64 = 0;
+
+    while let Some(frame) = receiver.next().await {
+        // Invalid UTF-8, protocol violations and oversized messages surface here. Tell the client
+        // what was wrong before closing instead of silently dropping the connection.
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                let Some(invalid) = invalid_frame_error(e) else { break };
+                println!("Client {} sent an invalid frame: {}", client_id, invalid);
+                let error = ServerMessage::Error { message: format!("Invalid frame: {}", invalid) };
+                send_message(&state, &room_name, client_id, &error).await;
+                close_client(client_id, &state, &room_name, CloseReason::InvalidFrame).await;
+                return Disconnect::Dropped;
+            }
+        };
+
+        if window_start.elapsed() >= FRAME_RATE_WINDOW {
+            window_start = Instant::now();
+            frames_in_window = 0;
+        }
+        frames_in_window += 1;
+        if frames_in_window > state.config.frame_rate_limit {
+            println!("Client {} exceeded {} frames per second; disconnecting.", client_id, state.config.frame_rate_limit);
+            close_client(client_id, &state, &room_name, CloseReason::Flooding).await;
+            return Disconnect::Dropped;
+        }
+
+        if inbound_window_start.elapsed() >= state.config.inbound_window {
+            inbound_window_start = Instant::now();
+            inbound_bytes = 0;
+        }
+        inbound_bytes += frame_len(&frame) as u64;
+        if let Some(budget) = state.config.max_inbound_bytes
+            && inbound_bytes > budget
+        {
+            println!("Client {} sent more than {} bytes in {:?}; disconnecting.", client_id, budget, state.config.inbound_window);
+            close_client(client_id, &state, &room_name, CloseReason::InboundBudgetExceeded).await;
+            return Disconnect::Dropped;
+        }
+
+        // Only data frames show the app is alive: pings may come from the client's WebSocket stack alone.
+        if matches!(frame, Message::Text(_) | Message::Binary(_))
+            && let Some(timeout) = state.config.heartbeat_timeout
+            && activity.touch() >= timeout
+        {
+            revive_stale_client(client_id, &state, &room_name).await;
+        }
+
+        let text = match frame {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
         let text = text.trim();
 
-        if text.starts_with("/user ") {
-            if let Some(username) = text.strip_prefix("/user ").and_then(|s| {
-                let trimmed = s.trim();
-                if !trimmed.is_empty() { Some(trimmed) } else { None }
-            }) {
-                handle_set_username(username.to_string(), client_id, &state, &room_name).await;
+        if text == "/quit" || text.starts_with("/quit ") {
+            let parting = text.strip_prefix("/quit").map(str::trim).filter(|m| !m.is_empty()).map(String::from);
+            close_client(client_id, &state, &room_name, CloseReason::Quit).await;
+            return Disconnect::Quit(parting);
+        } else if let Some(args) = text.strip_prefix("/user ") {
+            // `/user <name> [secret] [--silent]`: the secret is only needed for registered names.
+            let args = args.trim();
+            let (args, silent) = match args.strip_suffix("--silent") {
+                Some(rest) if rest.is_empty() || rest.ends_with(char::is_whitespace) => (rest.trim_end(), true),
+                _ => (args, false),
+            };
+            let mut parts = args.splitn(2, char::is_whitespace);
+            if let Some(username) = parts.next().filter(|name| !name.is_empty()) {
+                let secret = parts.next().map(str::trim).filter(|s| !s.is_empty()).map(String::from);
+                handle_set_username(username.to_string(), secret, silent, client_id, &state, &room_name).await;
+            }
+        } else if text == "/leave" || text.starts_with("/leave ") {
+            let target = text.strip_prefix("/leave").map(str::trim).filter(|room| !room.is_empty());
+            handle_leave(target, client_id, &state, &room_name).await;
+        } else if let Some(args) = text.strip_prefix("/reply ") {
+            let mut parts = args.trim().splitn(2, char::is_whitespace);
+            let parent = parts.next().and_then(|id| Uuid::parse_str(id).ok());
+            let content = parts.next().map(str::trim).filter(|content| !content.is_empty());
+            match (parent, content) {
+                (Some(parent), Some(content)) => {
+                    handle_chat_message(content.to_string(), false, Some(parent), None, client_id, &state, &room_name).await
+                }
+                _ => send_notice(&state, &room_name, client_id, "Usage: /reply <message_id> <message>".to_string()).await,
             }
-        } else if text == "/history" {
-            handle_load_full_history(client_id, &state, &room_name).await;
+        } else if let Some(message_id) = text.strip_prefix("/thread ") {
+            match Uuid::parse_str(message_id.trim()) {
+                Ok(message_id) => handle_show_thread(message_id, client_id, &state, &room_name).await,
+                Err(_) => send_notice(&state, &room_name, client_id, "Usage: /thread <message_id>".to_string()).await,
+            }
+        } else if let Some(secret) = text.strip_prefix("/register ") {
+            handle_register(secret.trim().to_string(), client_id, &state, &room_name).await;
+        } else if text == "/heartbeat" {
+            // Nothing to do: receiving it already counted as activity.
+        } else if text == "/ping" {
+            let pong = ServerMessage::Pong { server_time_ms: Utc::now().timestamp_millis() };
+            send_message(&state, &room_name, client_id, &pong).await;
+        } else if let Some(message_id) = text.strip_prefix("/ack ") {
+            match Uuid::parse_str(message_id.trim()) {
+                Ok(message_id) => handle_ack(message_id, client_id, &state, &room_name).await,
+                Err(_) => send_notice(&state, &room_name, client_id, "Usage: /ack <message_id>".to_string()).await,
+            }
+        } else if !state.config.history_enabled
+            && (text == "/history" || text.starts_with("/history ") || text == "/count" || text == "/history-count")
+        {
+            let error = ServerMessage::Error { message: "History is disabled on this server.".to_string() };
+            send_message(&state, &room_name, client_id, &error).await;
+        } else if text == "/count" || text == "/history-count" {
+            handle_history_count(client_id, &state, &room_name).await;
+        } else if let Some(args) = text.strip_prefix("/history").filter(|args| args.is_empty() || args.starts_with(' ')) {
+            let (args, with_presence) = match args.trim_end().strip_suffix("--with-presence") {
+                Some(args) => (args, true),
+                None => (args, false),
+            };
+            let include_presence = with_presence || state.config.history_includes_presence;
+            if args.trim().is_empty() {
+                handle_load_full_history(include_presence, client_id, &state, &room_name).await;
+            } else {
+                handle_load_history_page(args, include_presence, client_id, &state, &room_name).await;
+            }
+        } else if let Some(message_id) = text.strip_prefix("/get ") {
+            match Uuid::parse_str(message_id.trim()) {
+                Ok(message_id) => handle_get_message(message_id, client_id, &state, &room_name).await,
+                Err(_) => send_notice(&state, &room_name, client_id, "Usage: /get <message_id>".to_string()).await,
+            }
+        } else if let Some(args) = text.strip_prefix("/react ") {
+            handle_reaction(args, client_id, &state, &room_name).await;
+        } else if let Some(args) = text.strip_prefix("/report ") {
+            handle_report(args, client_id, &state, &room_name).await;
+        } else if let Some(token) = text.strip_prefix("/admin ") {
+            handle_admin_login(token.trim(), client_id, &state, &room_name).await;
+        } else if let Some(target) = text.strip_prefix("/whois ") {
+            handle_whois(target.trim(), client_id, &state, &room_name).await;
+        } else if text == "/stats-me" {
+            handle_session_stats(client_id, &state, &room_name).await;
+        } else if let Some(target) = text.strip_prefix("/find ") {
+            handle_find_user(target.trim(), client_id, &state, &room_name).await;
+        } else if let Some(color) = text.strip_prefix("/color ") {
+            handle_set_color(color.trim(), client_id, &state, &room_name).await;
+        } else if let Some(username) = text.strip_prefix("/ignore ") {
+            handle_ignore(username.trim(), true, client_id, &state, &room_name).await;
+        } else if let Some(username) = text.strip_prefix("/unignore ") {
+            handle_ignore(username.trim(), false, client_id, &state, &room_name).await;
+        } else if let Some(setting) = text.strip_prefix("/echo ") {
+            handle_set_echo(setting.trim(), client_id, &state, &room_name).await;
+        } else if text == "/close" {
+            handle_set_room_closed(true, client_id, &state, &room_name).await;
+        } else if text == "/reopen" {
+            handle_set_room_closed(false, client_id, &state, &room_name).await;
+        } else if let Some(args) = text.strip_prefix("/kick ") {
+            handle_kick(args, false, client_id, &state, &room_name).await;
+        } else if let Some(args) = text.strip_prefix("/ban ") {
+            handle_kick(args, true, client_id, &state, &room_name).await;
+        } else if let Some(target) = text.strip_prefix("/unban ") {
+            handle_unban(target.trim(), client_id, &state, &room_name).await;
+        } else if let Some(args) = text.strip_prefix("/mute ") {
+            handle_mute(args, true, client_id, &state, &room_name).await;
+        } else if let Some(args) = text.strip_prefix("/unmute ") {
+            handle_mute(args, false, client_id, &state, &room_name).await;
+        } else if text == "/clear" {
+            handle_clear_history(client_id, &state, &room_name).await;
+        } else if let Some(message) = text.strip_prefix("/broadcast ") {
+            handle_admin_broadcast(message.trim(), client_id, &state, &room_name).await;
+        } else if text == "/topic" {
+            handle_show_topic(client_id, &state, &room_name).await;
+        } else if let Some(args) = text.strip_prefix("/roommeta ") {
+            handle_room_metadata(args, client_id, &state, &room_name).await;
+        } else if let Some(args) = text.strip_prefix("/key ") {
+            handle_publish_key(args, client_id, &state, &room_name).await;
+        } else if text == "/keys" {
+            handle_list_keys(client_id, &state, &room_name).await;
+        } else if text == "/markread" {
+            handle_mark_read(client_id, &state, &room_name).await;
+        } else if let Some(pattern) = text.strip_prefix("/name-pattern ") {
+            handle_set_username_pattern(pattern.trim(), client_id, &state, &room_name).await;
+        } else if let Some(message_id) = text.strip_prefix("/pin ") {
+            match Uuid::parse_str(message_id.trim()) {
+                Ok(message_id) => handle_pin(message_id, client_id, &state, &room_name).await,
+                Err(_) => send_notice(&state, &room_name, client_id, "Usage: /pin <message_id>".to_string()).await,
+            }
+        } else if let Some(message_id) = text.strip_prefix("/unpin ") {
+            match Uuid::parse_str(message_id.trim()) {
+                Ok(message_id) => handle_unpin(message_id, client_id, &state, &room_name).await,
+                Err(_) => send_notice(&state, &room_name, client_id, "Usage: /unpin <message_id>".to_string()).await,
+            }
+        } else if let Some(target) = text.strip_prefix("/op ") {
+            handle_grant_operator(target.trim(), client_id, &state, &room_name).await;
+        } else if let Some(target) = text.strip_prefix("/transfer ") {
+            handle_transfer_ownership(target.trim(), client_id, &state, &room_name).await;
+        } else if text == "/invite" {
+            handle_create_invite(client_id, &state, &room_name).await;
+        } else if let Some(topic) = text.strip_prefix("/topic ") {
+            handle_set_topic(topic.trim(), client_id, &state, &room_name).await;
+        } else if text.starts_with('{') {
+            handle_client_json(text, client_id, &state, &room_name).await;
         } else {
-            handle_chat_message(text.to_string(), client_id, &state, &room_name).await;
+            handle_chat_message(text.to_string(), false, None, None, client_id, &state, &room_name).await;
         }
     }
+    Disconnect::Dropped
+}
+
+/// Returns the underlying error if a read failed because of what the client sent, or `None` if the
+/// connection simply went away (reset, I/O error), which is handled like any other disconnect.
+fn invalid_frame_error(e: axum::Error) -> Option<tungstenite::Error> {
+    let e = *e.into_inner().downcast::<tungstenite::Error>().ok()?;
+    match e {
+        tungstenite::Error::Protocol(tungstenite::error::ProtocolError::ResetWithoutClosingHandshake) => None,
+        tungstenite::Error::Utf8 | tungstenite::Error::Capacity(_) | tungstenite::Error::Protocol(_) => Some(e),
+        _ => None,
+    }
+}
+
+/// Payload size of an incoming frame, for the per-connection inbound budget.
+fn frame_len(frame: &Message) -> usize {
+    match frame {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(frame) => frame.as_ref().map_or(0, |frame| frame.reason.len() + 2),
+    }
 }
 
-/// Handles setting or updating a client's username and sends them the room history.
-async fn handle_set_username(username: String, client_id: Uuid, state: &ChatState, room_name: &str) {
+/// Resends messages the client hasn't acknowledged in time. Returns whether the client was closed
+/// because a message ran out of resends.
+async fn resend_unacked(client_id: Uuid, state: &ChatState, room_name: &str) -> bool {
+    let Some(timeout) = state.config.ack_timeout else { return false };
     let mut rooms = state.rooms.lock().await;
-    let mut old_username = "anonymous".to_string();
+    let Some(client) = rooms.get_mut(room_name).and_then(|room| room.clients.get_mut(&client_id)) else {
+        return false;
+    };
 
-    if let Some(room) = rooms.get_mut(room_name) {
-        // Lazy-load history from DB if the in-memory cache is empty.
-        if room.history.is_empty() {
-            println!("Loading history for room '{}' from database...", room_name);
-            room.history = database::load_history(&state.db_pool, room_name, MAX_HISTORY_SIZE).await;
+    let Client { unacked, sender, .. } = client;
+    for (message_id, pending) in unacked.iter_mut().filter(|(_, pending)| pending.sent_at.elapsed() >= timeout) {
+        if pending.resends >= state.config.ack_max_resends {
+            println!("Client {} never acknowledged message {}; disconnecting.", client_id, message_id);
+            sender.close(CloseReason::Unacknowledged);
+            return true;
         }
-
-        if let Some(client) = room.clients.get_mut(&client_id) {
-            old_username = client.username.clone();
-            client.username = username.clone();
-            
-            // Send room history to the user who just set their name.
-            for msg in &room.history {
-                let parsed_msg = parse_message_for_display(msg);
-                if client.sender.send(Message::Text(parsed_msg.into())).await.is_err() {
-                    println!("Failed to send history to client {}", client_id);
-                    return;
-                }
-            }
+        pending.resends += 1;
+        pending.sent_at = Instant::now();
+        if sender.push(Message::Text(pending.frame.clone().into())).is_err() {
+            return false; // Already on its way out.
         }
-    } else { return; } // Room doesn't exist, something is wrong
+    }
+    false
+}
 
-    println!("Client {} ({}) is now known as '{}' in room '{}'", client_id, old_username, &username, room_name);
+/// Handles an acknowledgement, forgetting the message so it isn't resent.
+async fn handle_ack(message_id: Uuid, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut rooms = state.rooms.lock().await;
+    if let Some(client) = rooms.get_mut(room_name).and_then(|room| room.clients.get_mut(&client_id)) {
+        client.unacked.remove(&message_id);
+    }
+}
 
-    let join_msg = ServerMessage::UserJoined { username };
-    broadcast_message(join_msg.clone(), &mut rooms, room_name, Some(client_id)).await;
+/// Closes the client if it still hasn't set a username. Returns whether it was closed.
+async fn close_if_anonymous(client_id: Uuid, state: &ChatState, room_name: &str) -> bool {
+    let mut rooms = state.rooms.lock().await;
+    if let Some(room) = rooms.get_mut(room_name)
+        && let Some(client) = room.clients.get_mut(&client_id)
+        && client.username.is_none()
+    {
+        println!("Client {} never set a username in room '{}'; disconnecting.", client_id, room_name);
+        client.sender.close(CloseReason::NameRequired);
+        return true;
+    }
+    false
+}
 
-    // Persist the join message to the database
-    database::save_message(&state.db_pool, room_name, &join_msg).await;
+/// Marks a named client that has sent nothing for `HEARTBEAT_TIMEOUT_SECS` as stale and tells the room it
+/// left. The connection stays open, in case the app was only frozen; its next frame brings it back.
+async fn mark_stale_if_idle(client_id: Uuid, activity: &ActivityClock, state: &ChatState, room_name: &str) {
+    let Some(timeout) = state.config.heartbeat_timeout else { return };
+    let mut rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    let Some(client) = room.clients.get_mut(&client_id) else { return };
+    // Checked under the lock, so a frame arriving meanwhile either prevents this or revives the client after it.
+    if client.stale || activity.idle_for() < timeout {
+        return;
+    }
+    let Some(username) = client.username.clone() else { return };
+    client.stale = true;
+    println!("Client {} ({}) in room '{}' sent nothing for {:?}; marking it stale.", client_id, username, room_name, timeout);
+    if client.silent {
+        return;
+    }
+    let created_at = Utc::now();
+    let left_msg = ServerMessage::UserLeft { username, message: None, sent_at: Some(created_at) };
+    let save = broadcast_message(left_msg.clone(), state, &mut rooms, room_name, Some(client_id)).await;
+    drop(rooms);
+    let _ = persist_message(state, room_name, save, &left_msg, created_at).await;
 }
 
-/// Handles loading full history from the database for a specific client.
-async fn handle_load_full_history(client_id: Uuid, state: &ChatState, room_name: &str) {
+/// Brings back a client marked stale, which has just sent a frame: the room is told it joined again.
+async fn revive_stale_client(client_id: Uuid, state: &ChatState, room_name: &str) {
     let mut rooms = state.rooms.lock().await;
-    
-    if let Some(room) = rooms.get_mut(room_name) {
-        if let Some(client) = room.clients.get_mut(&client_id) {
-            // Check if user has set a username
-            if client.username == "anonymous" {
-                let _ = client.sender.send(Message::Text("Please set a username with `/user <name>` before loading history.".to_string().into())).await;
-                return;
-            }
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    let Some(client) = room.clients.get_mut(&client_id) else { return };
+    if !client.stale {
+        return;
+    }
+    client.stale = false;
+    let Some(username) = client.username.clone() else { return };
+    println!("Client {} ({}) in room '{}' is active again.", client_id, username, room_name);
+    let notice = ServerMessage::Notice { message: "You were quiet for too long and the room was told you left; you are back now.".to_string() };
+    let _ = send_to_client(client, &notice, &state.config);
+    if client.silent {
+        return;
+    }
+    let created_at = Utc::now();
+    let join_msg = ServerMessage::UserJoined { username, sent_at: Some(created_at) };
+    let save = broadcast_message(join_msg.clone(), state, &mut rooms, room_name, Some(client_id)).await;
+    drop(rooms);
+    let _ = persist_message(state, room_name, save, &join_msg, created_at).await;
+}
 
-            println!("Loading full history for client {} in room '{}'", client_id, room_name);
-            
-            // Load full history from database
-            let full_history = database::load_history(&state.db_pool, room_name, MAX_HISTORY_SIZE).await;
-            
-            // Send history to the client
-            for msg in &full_history {
-                let parsed_msg = parse_message_for_display(msg);
-                if client.sender.send(Message::Text(parsed_msg.into())).await.is_err() {
-                    println!("Failed to send full history to client {}", client_id);
-                    return;
-                }
+/// Sends a close frame to a client whose connection is ending for `reason`.
+async fn close_client(client_id: Uuid, state: &ChatState, room_name: &str, reason: CloseReason) {
+    let mut rooms = state.rooms.lock().await;
+    if let Some(room) = rooms.get_mut(room_name)
+        && let Some(client) = room.clients.get_mut(&client_id)
+    {
+        client.sender.close(reason);
+    }
+}
+
+/// Parses a JSON `ClientMessage` frame and dispatches it to the matching handler.
+async fn handle_client_json(text: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    match ClientMessage::parse(text) {
+        Ok(ClientMessage::SetUsername { username, secret, silent }) => {
+            let username = username.trim();
+            if !username.is_empty() {
+                handle_set_username(username.to_string(), secret, silent, client_id, state, room_name).await;
             }
-            
-            println!("Sent {} messages from full history to client {}", full_history.len(), client_id);
+        }
+        Ok(ClientMessage::Message { content, encrypted, reply_to, expires_in_secs }) => {
+            handle_chat_message(content, encrypted, reply_to, expires_in_secs, client_id, state, room_name).await;
+        }
+        Ok(ClientMessage::Custom { kind, payload }) => {
+            handle_custom_message(kind, payload, client_id, state, room_name).await;
+        }
+        Ok(ClientMessage::Ack { message_id }) => {
+            handle_ack(message_id, client_id, state, room_name).await;
+        }
+        Err(problem) => {
+            send_message(state, room_name, client_id, &ServerMessage::Error { message: problem }).await;
         }
     }
 }
 
-/// Handles a regular chat message, adds it to history, and broadcasts it.
-async fn handle_chat_message(content: String, client_id: Uuid, state: &ChatState, room_name: &str) {
-    if content.trim().is_empty() { return; }
-    
-    let mut rooms = state.rooms.lock().await;
-    let new_msg: ServerMessage;
+/// Sends a notice to a single client.
+async fn send_notice(state: &ChatState, room_name: &str, client_id: Uuid, notice: String) {
+    send_message(state, room_name, client_id, &ServerMessage::Notice { message: notice }).await;
+}
 
-    if let Some(room) = rooms.get_mut(room_name) {
-        let username = match room.clients.get(&client_id) {
-            Some(client) => client.username.clone(),
-            None => return, // Client not found
+/// Looks up a client by id and queues a message for it alone.
+async fn send_message(state: &ChatState, room_name: &str, client_id: Uuid, message: &ServerMessage) {
+    let rooms = state.rooms.lock().await;
+    if let Some(room) = rooms.get(room_name)
+        && let Some(client) = room.clients.get(&client_id)
+    {
+        let _ = send_to_client(client, message, &state.config);
+    }
+}
+
+/// Runs a database call under the configured timeout, so a slow or exhausted pool can't hang
+/// the caller. A call that times out fails with `DbError::Timeout`; every failure is logged and
+/// counted before it is handed back.
+///
+/// The timeout only bounds a stall, so calls are never made while holding the rooms lock: handlers
+/// work out the change under the lock, release it for the call, then take it again to apply the change.
+pub(crate) async fn db_call<T>(state: &ChatState, call: impl Future<Output = DbResult<T>>) -> DbResult<T> {
+    let result = tokio::time::timeout(state.config.db_timeout, call).await.unwrap_or(Err(DbError::Timeout));
+    if let Err(e) = &result {
+        state.record_db_error(e);
+    }
+    result
+}
+
+/// The error sent to a client whose request failed because of the database.
+fn db_error(error: &DbError) -> ServerMessage {
+    let message = match error {
+        DbError::Timeout => "The database is not responding; please try again shortly.",
+        _ => "The database could not complete the request; please try again shortly.",
+    };
+    ServerMessage::Error { message: message.to_string() }
+}
+
+/// Queues a message for a single client, rendered in the client's negotiated protocol.
+fn send_to_client(client: &Client, message: &ServerMessage, config: &Config) -> Result<(), Disconnected> {
+    let rendered = render_message(message, client.protocol, config);
+    client.sender.push(Message::Text(rendered.into()))
+}
+
+/// Handles `/admin <token>`, granting administrator rights when the token matches `ADMIN_TOKEN`.
+async fn handle_admin_login(token: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut rooms = state.rooms.lock().await;
+    if let Some(room) = rooms.get_mut(room_name)
+        && let Some(client) = room.clients.get_mut(&client_id)
+    {
+        let granted = state.config.admin_token.as_deref().is_some_and(|expected| auth::token_matches(token, expected));
+        let message = if granted {
+            client.is_admin = true;
+            println!("Client {} ({}) authenticated as administrator.", client_id, client.display_name());
+            "You are now an administrator.".to_string()
+        } else {
+            println!("Client {} failed administrator authentication.", client_id);
+            "Invalid admin token.".to_string()
         };
+        let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+    }
+}
 
-        if username == "anonymous" {
-            if let Some(client) = room.clients.get_mut(&client_id) {
-                let _ = client.sender.send(Message::Text("Please set a username with `/user <name>` before sending messages.".to_string().into())).await;
-            }
-            return;
-        }
+/// Handles `/whois <username>` (administrators only), telling the admin which rooms the user is connected to
+/// and, when `GEOIP_DB_PATH` is set, the country each connection comes from. Each lookup is audited.
+async fn handle_whois(target: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let rooms = state.rooms.lock().await;
+    let Some(client) = rooms.get(room_name).and_then(|room| room.clients.get(&client_id)) else { return };
+    if !client.is_admin {
+        let notice = ServerMessage::Notice { message: "Only administrators can use /whois.".to_string() };
+        let _ = send_to_client(client, &notice, &state.config);
+        return;
+    }
 
-        println!("Message from {}({}): {}", &username, client_id, &content);
-        new_msg = ServerMessage::NewMessage { username, content };
-        broadcast_message(new_msg.clone(), &mut rooms, room_name, Some(client_id)).await;
+    let mut connections: Vec<(&str, &str)> = rooms
+        .iter()
+        .flat_map(|(name, room)| room.clients.values().map(move |client| (name, client)))
+        .filter(|(_, client)| client.username.as_deref() == Some(target))
+        .map(|(name, client)| (name.as_str(), client.country.as_deref().unwrap_or("unknown")))
+        .collect();
+    connections.sort();
+    let message = if connections.is_empty() {
+        format!("'{}' is not connected.", target)
     } else {
-        return; // Room not found
-    }
-    
-    // Persist the new message to the database
-    database::save_message(&state.db_pool, room_name, &new_msg).await;
+        let listed: Vec<String> =
+            connections.iter().map(|(room, country)| format!("room '{}' (country: {})", room, country)).collect();
+        format!("'{}' is connected to {}.", target, listed.join(", "))
+    };
+    let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+
+    let countries: Vec<&str> = connections.iter().map(|&(_, country)| country).collect();
+    let detail = (!countries.is_empty()).then(|| format!("country={}", countries.join(",")));
+    let actor = client.display_name().to_string();
+    drop(rooms);
+    log_audit(state, "whois", &actor, Some(target), Some(room_name), detail).await;
 }
 
-/// Broadcasts a message and adds it to the room's in-memory history cache.
-async fn broadcast_message(
-    message: ServerMessage,
-    rooms: &mut MutexGuard<'_, HashMap<String, Room>>,
-    room_name: &str,
-    exclude_client_id: Option<Uuid>,
-){
-    if let Some(room) = rooms.get_mut(room_name) {
-        // Add message to the in-memory cache, ensuring it doesn't exceed the cache size.
-        room.history.push_back(message.clone());
-        if room.history.len() > IN_MEMORY_CACHE_SIZE {
-            room.history.pop_front();
-        }
+/// Handles `/stats-me`, sending the client its own session details. Needs no name or rights: it only ever
+/// describes the requester.
+async fn handle_session_stats(client_id: Uuid, state: &ChatState, room_name: &str) {
+    let rooms = state.rooms.lock().await;
+    let Some(client) = rooms.get(room_name).and_then(|room| room.clients.get(&client_id)) else { return };
+    let stats = ServerMessage::SessionStats {
+        client_id,
+        username: client.username.clone(),
+        room: room_name.to_string(),
+        joined_at: client.joined_at,
+        messages_sent: client.messages_sent,
+    };
+    let _ = send_to_client(client, &stats, &state.config);
+}
 
-        let parsed_message = parse_message_for_display(&message);
-        for (id, client) in room.clients.iter_mut() {
-            if exclude_client_id.map_or(false, |exclude_id| *id == exclude_id) {
-                continue;
+/// Handles `/find <username>`, telling the requester which rooms the user is in and with how many connections.
+/// Administrators search every active room; everyone else only their own room, and doesn't see silent or
+/// stale connections, so nobody can probe rooms they aren't in.
+async fn handle_find_user(target: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let rooms = state.rooms.lock().await;
+    let Some(client) = rooms.get(room_name).and_then(|room| room.clients.get(&client_id)) else { return };
+    if !client.is_admin && client.username.is_none() && !state.config.allows_anon_read(room_name) {
+        let notice = ServerMessage::Notice { message: "Please set a username with `/user <name>` before using /find.".to_string() };
+        let _ = send_to_client(client, &notice, &state.config);
+        return;
+    }
+
+    let mut found: Vec<(&str, usize)> = rooms
+        .iter()
+        .filter(|(name, _)| client.is_admin || name.as_str() == room_name)
+        .filter_map(|(name, room)| {
+            let connections = room
+                .clients
+                .values()
+                .filter(|other| other.username.as_deref() == Some(target))
+                .filter(|other| client.is_admin || !(other.silent || other.stale))
+                .count();
+            (connections > 0).then_some((name.as_str(), connections))
+        })
+        .collect();
+    found.sort();
+    let message = if found.is_empty() {
+        format!("'{}' was not found in any room you can see.", target)
+    } else {
+        let listed: Vec<String> = found
+            .iter()
+            .map(|&(room, connections)| {
+                format!("'{}' ({} connection{})", room, connections, if connections == 1 { "" } else { "s" })
+            })
+            .collect();
+        format!("'{}' is in {}.", target, listed.join(", "))
+    };
+    let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+}
+
+/// Handles `/echo on|off`, choosing whether the client receives its own messages back from the server.
+async fn handle_set_echo(setting: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut rooms = state.rooms.lock().await;
+    if let Some(room) = rooms.get_mut(room_name)
+        && let Some(client) = room.clients.get_mut(&client_id)
+    {
+        let message = match setting {
+            "on" => {
+                client.echo = true;
+                "Your own messages will now be echoed back to you.".to_string()
             }
-            if client.sender.send(Message::Text(parsed_message.clone().into())).await.is_err() {
-                println!("Failed to send parsed message to client {}", id);
+            "off" => {
+                client.echo = false;
+                "Your own messages will no longer be echoed back to you.".to_string()
             }
+            _ => "Usage: /echo on|off".to_string(),
+        };
+        let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+    }
+}
+
+/// Handles `/color #rrggbb|off`, choosing the color attached to the client's messages for JSON clients to theme with.
+async fn handle_set_color(color: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut rooms = state.rooms.lock().await;
+    if let Some(room) = rooms.get_mut(room_name)
+        && let Some(client) = room.clients.get_mut(&client_id)
+    {
+        let message = if color == "off" {
+            client.color = None;
+            "Your messages no longer carry a color.".to_string()
+        } else if let Some(color) = parse_hex_color(color) {
+            let message = format!("Your messages will now be shown in {}.", color);
+            client.color = Some(color);
+            message
+        } else {
+            "Usage: /color #rrggbb|off (e.g. /color #ff8800)".to_string()
+        };
+        let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+    }
+}
+
+/// Handles `/ignore <username>` and `/unignore <username>`. The room stops delivering the user's chat and custom
+/// messages to this connection, so they never reach it; it lasts until `/unignore` or the connection closes.
+async fn handle_ignore(username: &str, ignore: bool, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut rooms = state.rooms.lock().await;
+    let Some(client) = rooms.get_mut(room_name).and_then(|room| room.clients.get_mut(&client_id)) else { return };
+    let message = if username.is_empty() || username.contains(char::is_whitespace) {
+        if ignore { "Usage: /ignore <username>" } else { "Usage: /unignore <username>" }.to_string()
+    } else if !ignore {
+        if client.ignored.remove(username) {
+            format!("You are no longer ignoring '{}'.", username)
+        } else {
+            format!("You are not ignoring '{}'.", username)
         }
+    } else if client.username.as_deref() == Some(username) {
+        "You can't ignore yourself.".to_string()
+    } else if client.ignored.len() >= MAX_IGNORED_USERS && !client.ignored.contains(username) {
+        format!("You can ignore at most {} users; /unignore someone first.", MAX_IGNORED_USERS)
+    } else {
+        client.ignored.insert(username.to_string());
+        format!("You are now ignoring '{}'. Use /unignore {} to see their messages again.", username, username)
+    };
+    let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+}
+
+/// Accepts `#rgb` or `#rrggbb` and returns the lowercase `#rrggbb` form.
+fn parse_hex_color(color: &str) -> Option<String> {
+    let hex = color.strip_prefix('#')?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
     }
+    let hex = match hex.len() {
+        3 => hex.chars().flat_map(|c| [c, c]).collect(),
+        6 => hex.to_string(),
+        _ => return None,
+    };
+    Some(format!("#{}", hex.to_ascii_lowercase()))
 }
 
-/// Converts a ServerMessage to a human-readable format for testing.
-fn parse_message_for_display(message: &ServerMessage) -> String {
-    match message {
-        ServerMessage::NewMessage { username, content } => format!("[{}] {}", username, content),
-        ServerMessage::UserJoined { username } => format!("--> {} joined the room", username),
-        ServerMessage::UserLeft { username } => format!("<-- {} left the room", username),
+/// Handles `/close` and `/reopen`: toggles a room's read-only archive mode (moderators only).
+async fn handle_set_room_closed(closed: bool, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let by = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        if !room.is_moderator(client) {
+            let notice = ServerMessage::Notice { message: "Only moderators can close or reopen rooms.".to_string() };
+            let _ = send_to_client(client, &notice, &state.config);
+            return;
+        }
+        client.display_name().to_string()
+    };
+
+    if let Err(e) = db_call(state, state.store.set_room_closed(room_name, closed)).await {
+        send_message(state, room_name, client_id, &db_error(&e)).await;
+        return;
     }
+    println!("Room '{}' {} by client {}.", room_name, if closed { "closed" } else { "reopened" }, client_id);
+    let action = if closed { "close_room" } else { "reopen_room" };
+    log_audit(state, action, &by, None, Some(room_name), None).await;
+
+    let mut rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    room.closed = closed;
+    let message = if closed {
+        "This room has been closed. History remains available, but new messages are no longer accepted.".to_string()
+    } else {
+        "This room has been reopened.".to_string()
+    };
+    announce(room, &ServerMessage::Notice { message }, &state.config);
 }
 
-/// Removes a client from the state, announces their departure, and saves the event to the DB.
-async fn cleanup_client(state: &ChatState, client_id: Uuid, room_name: &str) {
-    let mut username = "anonymous".to_string();
-    let mut should_broadcast = false;
+/// Handles `/kick <user> [reason]` and `/ban <user> [reason]` (moderators only): closes every connection the user has
+/// in the room. A ban also keeps anyone from taking the name here until `/unban` or the room is emptied. The room's
+/// moderators can't be kicked or banned.
+async fn handle_kick(args: &str, ban: bool, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut parts = args.trim().splitn(2, char::is_whitespace);
+    let target = parts.next().unwrap_or_default();
+    let reason = parts.next().map(str::trim).filter(|reason| !reason.is_empty()).map(String::from);
+    let (command, action, done) = if ban { ("/ban", "ban", "banned") } else { ("/kick", "kick", "kicked") };
 
-    // First, remove the client and get their username
-    {
+    let by = {
+        let mut rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get_mut(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        let mut connections = room.clients.values().filter(|member| member.username.as_deref() == Some(target)).peekable();
+        let refusal = if !room.is_moderator(client) {
+            Some(format!("Only moderators can use {}.", command))
+        } else if target.is_empty() {
+            Some(format!("Usage: {} <username> [reason]", command))
+        } else if room.ownership.includes(target) || room.clients.values().any(|member| member.is_admin && member.username.as_deref() == Some(target)) {
+            Some(format!("'{}' moderates this room and can't be {}.", target, done))
+        } else if !ban && connections.peek().is_none() {
+            Some(format!("There is nobody called '{}' in this room.", target))
+        } else {
+            None
+        };
+        if let Some(message) = refusal {
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+        let by = client.display_name().to_string();
+
+        // Told to everyone, the user included, before their connections close.
+        let message = match &reason {
+            Some(reason) => format!("'{}' was {} by {}: {}", target, done, by, reason),
+            None => format!("'{}' was {} by {}.", target, done, by),
+        };
+        announce(room, &ServerMessage::Notice { message }, &state.config);
+        for member in connections {
+            member.sender.close(CloseReason::Kicked);
+        }
+        if ban {
+            room.banned.insert(target.to_string());
+        }
+        by
+    };
+    println!("'{}' {} from room '{}' by client {}", target, done, room_name, client_id);
+    log_audit(state, action, &by, Some(target), Some(room_name), reason).await;
+}
+
+/// Handles `/unban <user>` (moderators only): lets the name into the room again.
+async fn handle_unban(target: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let by = {
         let mut rooms = state.rooms.lock().await;
-        if let Some(room) = rooms.get_mut(room_name) {
-            if let Some(client) = room.clients.remove(&client_id) {
-                username = client.username;
-                should_broadcast = username != "anonymous";
+        let Some(room) = rooms.get_mut(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        let refusal = if !room.is_moderator(client) {
+            Some("Only moderators can use /unban.".to_string())
+        } else if !room.banned.contains(target) {
+            Some(format!("'{}' isn't banned from this room.", target))
+        } else {
+            None
+        };
+        let message = refusal.unwrap_or_else(|| format!("'{}' is no longer banned from this room.", target));
+        let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+        let by = client.display_name().to_string();
+        if !room.banned.remove(target) {
+            return;
+        }
+        by
+    };
+    println!("'{}' unbanned from room '{}' by client {}", target, room_name, client_id);
+    log_audit(state, "unban", &by, Some(target), Some(room_name), None).await;
+}
+
+/// Handles `/mute <user> <seconds>` and `/unmute <user>` (moderators only). A muted user stays in the room and can
+/// read it, but their chat and custom messages are refused until the mute runs out. The room's moderators can't be muted.
+async fn handle_mute(args: &str, mute: bool, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    let (target, duration) = match (mute, parts.as_slice()) {
+        (true, [target, secs]) => match secs.parse::<u64>() {
+            Ok(secs) if (1..=MAX_MUTE_SECS).contains(&secs) => (*target, Some(Duration::from_secs(secs))),
+            _ => {
+                send_notice(state, room_name, client_id, format!("Mutes last from 1 to {} seconds.", MAX_MUTE_SECS)).await;
+                return;
             }
+        },
+        (false, [target]) => (*target, None),
+        _ => {
+            let usage = if mute { "Usage: /mute <username> <seconds>" } else { "Usage: /unmute <username>" };
+            send_notice(state, room_name, client_id, usage.to_string()).await;
+            return;
+        }
+    };
+
+    let by = {
+        let mut rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get_mut(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        let refusal = if !room.is_moderator(client) {
+            Some(format!("Only moderators can use {}.", if mute { "/mute" } else { "/unmute" }))
+        } else if room.ownership.includes(target) || room.clients.values().any(|member| member.is_admin && member.username.as_deref() == Some(target)) {
+            Some(format!("'{}' moderates this room and can't be muted.", target))
+        } else if !mute && room.mutes.get(target).is_none_or(|until| *until <= Instant::now()) {
+            Some(format!("'{}' isn't muted in this room.", target))
+        } else {
+            None
+        };
+        if let Some(message) = refusal {
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+        let by = client.display_name().to_string();
 
-            if room.clients.is_empty() {
-                println!("Room '{}' is empty, removing it.", room_name);
-                rooms.remove(room_name);
+        let message = match duration {
+            Some(duration) => {
+                let now = Instant::now();
+                room.mutes.retain(|_, until| *until > now);
+                room.mutes.insert(target.to_string(), now + duration);
+                format!("'{}' was muted by {} for {} seconds.", target, by, duration.as_secs())
+            }
+            None => {
+                room.mutes.remove(target);
+                format!("'{}' was unmuted by {}.", target, by)
             }
+        };
+        announce(room, &ServerMessage::Notice { message }, &state.config);
+        by
+    };
+    println!("'{}' {} in room '{}' by client {}", target, if mute { "muted" } else { "unmuted" }, room_name, client_id);
+    let (action, detail) = match duration {
+        Some(duration) => ("mute", Some(format!("duration={}s", duration.as_secs()))),
+        None => ("unmute", None),
+    };
+    log_audit(state, action, &by, Some(target), Some(room_name), detail).await;
+}
+
+/// Handles `/clear` (moderators only): deletes the room's history, from the store and from the cache joiners are
+/// replayed, and unpins the messages it held. Messages sent while it runs are kept.
+async fn handle_clear_history(client_id: Uuid, state: &ChatState, room_name: &str) {
+    let (by, seq) = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        if !room.is_moderator(client) {
+            let notice = ServerMessage::Notice { message: "Only moderators can clear the room's history.".to_string() };
+            let _ = send_to_client(client, &notice, &state.config);
+            return;
         }
-    } // First lock is released here
+        // The `seq` of the newest message so far: everything up to it goes, anything after stays.
+        (client.display_name().to_string(), room.next_seq)
+    };
 
-    // Now broadcast departure message with a fresh lock
-    if should_broadcast {
-        println!("Broadcasting leave message for {} from room '{}'", username, room_name);
-        let left_msg = ServerMessage::UserLeft { username: username.clone() };
-        let mut rooms_for_broadcast = state.rooms.lock().await;
-        broadcast_message(left_msg.clone(), &mut rooms_for_broadcast, room_name, None).await;
-        
-        // Persist the "left" message
-        database::save_message(&state.db_pool, room_name, &left_msg).await;
+    state.saves.wait_saved(room_name, seq, state.config.db_timeout).await;
+    let cleared = match db_call(state, state.store.clear_messages(room_name, seq)).await {
+        Ok(cleared) => cleared,
+        Err(e) => {
+            send_message(state, room_name, client_id, &db_error(&e)).await;
+            return;
+        }
+    };
+    // The numbers stay used, though no stored message carries them any more.
+    let _ = db_call(state, state.store.set_latest_seq(room_name, seq)).await;
+    println!("History of room '{}' cleared up to {} by client {} ({} messages)", room_name, seq, client_id, cleared);
+    log_audit(state, "clear_history", &by, None, Some(room_name), Some(format!("messages={}", cleared))).await;
+
+    let Some(_turn) = settings_turn(state, room_name).await else { return };
+    let pinned_ids = {
+        let mut rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get_mut(room_name) else { return };
+        let stale = room.history_before(seq).count();
+        room.history.drain(..stale);
+        for buffer in room.offline_buffers.values_mut() {
+            buffer.messages.retain(|(buffered_seq, _)| *buffered_seq >= seq);
+        }
+        let kept: HashSet<Uuid> = room.history.iter().filter_map(chat_message_id).collect();
+        let pinned = room.pinned.len();
+        room.pinned.retain(|pinned| chat_message_id(pinned).is_some_and(|id| kept.contains(&id)));
+        announce(room, &ServerMessage::HistoryCleared { by }, &state.config);
+        (room.pinned.len() < pinned).then(|| room.pinned.iter().filter_map(chat_message_id).collect::<Vec<Uuid>>())
+    };
+    if let Some(ids) = pinned_ids {
+        let _ = db_call(state, state.store.set_pinned_ids(room_name, &ids)).await;
     }
+}
 
-    println!("Client {} ({}) disconnected from room '{}'.", client_id, username, room_name);
+/// Handles `/broadcast <message>` (administrators only): announces a message to everyone in every room.
+async fn handle_admin_broadcast(message: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let by = {
+        let rooms = state.rooms.lock().await;
+        let Some(client) = rooms.get(room_name).and_then(|room| room.clients.get(&client_id)) else { return };
+        let refusal = if !client.is_admin {
+            Some("Only administrators can use /broadcast.")
+        } else if message.is_empty() {
+            Some("Usage: /broadcast <message>")
+        } else {
+            None
+        };
+        if let Some(refusal) = refusal {
+            let _ = send_to_client(client, &ServerMessage::Notice { message: refusal.to_string() }, &state.config);
+            return;
+        }
+        let announcement = ServerMessage::Announcement { message: message.to_string() };
+        for room in rooms.values() {
+            announce(room, &announcement, &state.config);
+        }
+        client.display_name().to_string()
+    };
+    println!("Client {} broadcast to every room: {}", client_id, message);
+    log_audit(state, "broadcast", &by, None, None, Some(message.to_string())).await;
+}
+
+/// Handles `/topic`: tells the client the room's current topic.
+async fn handle_show_topic(client_id: Uuid, state: &ChatState, room_name: &str) {
+    let rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get(room_name) else { return };
+    let Some(client) = room.clients.get(&client_id) else { return };
+    let message = match &room.topic {
+        Some(topic) => format!("Topic: {} (set by {})", topic.topic, topic.set_by),
+        None => "No topic is set for this room.".to_string(),
+    };
+    let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+}
+
+/// Handles `/topic <text>`: sets the room's topic and announces it to everyone in the room (admins only).
+async fn handle_set_topic(topic: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let topic = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        let refusal = if !room.is_moderator(client) {
+            Some("Only moderators can change the topic.".to_string())
+        } else if topic.chars().count() > MAX_TOPIC_CHARS {
+            Some(format!("Topics can be at most {} characters.", MAX_TOPIC_CHARS))
+        } else {
+            None
+        };
+        if let Some(message) = refusal {
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+        RoomTopic { topic: topic.to_string(), set_by: client.display_name().to_string() }
+    };
+
+    if let Err(e) = db_call(state, state.store.set_room_topic(room_name, &topic)).await {
+        send_message(state, room_name, client_id, &db_error(&e)).await;
+        return;
+    }
+    println!("Topic of room '{}' set by client {}: {}", room_name, client_id, topic.topic);
+    log_audit(state, "set_topic", &topic.set_by, None, Some(room_name), Some(topic.topic.clone())).await;
+
+    let mut rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    announce(room, &ServerMessage::TopicChanged { topic: topic.topic.clone(), by: topic.set_by.clone() }, &state.config);
+    room.topic = Some(topic);
+}
+
+/// Waits for the room's turn to change its pins, ownership, metadata or public keys (see `Room::settings_writes`),
+/// or returns `None` if the room is gone.
+async fn settings_turn(state: &ChatState, room_name: &str) -> Option<OwnedMutexGuard<()>> {
+    let writes = state.rooms.lock().await.get(room_name)?.settings_writes.clone();
+    Some(writes.lock_owned().await)
+}
+
+/// Id of a chat message, the only kind that can be pinned.
+fn chat_message_id(message: &ServerMessage) -> Option<Uuid> {
+    match message {
+        ServerMessage::NewMessage { id, .. } => Some(*id),
+        _ => None,
+    }
+}
+
+/// Handles `/pin <message_id>`: pins one of the room's chat messages and announces it (moderators only).
+async fn handle_pin(message_id: Uuid, client_id: Uuid, state: &ChatState, room_name: &str) {
+    // Held until the pin is installed, so the duplicate and `MAX_PINNED_MESSAGES` checks still hold when it is.
+    let Some(_turn) = settings_turn(state, room_name).await else { return };
+    let by = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        let refusal = if !room.is_moderator(client) {
+            Some("Only moderators can pin messages.".to_string())
+        } else if room.pinned.iter().any(|pinned| chat_message_id(pinned) == Some(message_id)) {
+            Some("That message is already pinned.".to_string())
+        } else if room.pinned.len() >= MAX_PINNED_MESSAGES {
+            Some(format!("A room can have at most {} pinned messages; unpin one first.", MAX_PINNED_MESSAGES))
+        } else {
+            None
+        };
+        if let Some(message) = refusal {
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+        client.display_name().to_string()
+    };
+
+    // Recent messages are usually still cached; older ones come from the store.
+    let Some(message) = find_chat_message(message_id, client_id, state, room_name).await else { return };
+    let Some(message) = message else {
+        send_notice(state, room_name, client_id, "No such message in this room.".to_string()).await;
+        return;
+    };
+    let ids: Vec<Uuid> = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        room.pinned.iter().chain([&message]).filter_map(chat_message_id).collect()
+    };
+
+    if let Err(e) = db_call(state, state.store.set_pinned_ids(room_name, &ids)).await {
+        send_message(state, room_name, client_id, &db_error(&e)).await;
+        return;
+    }
+    println!("Message {} pinned in room '{}' by client {}", message_id, room_name, client_id);
+    log_audit(state, "pin_message", &by, None, Some(room_name), Some(message_id.to_string())).await;
+
+    let mut rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    room.pinned.push(message.clone());
+    announce(room, &ServerMessage::MessagePinned { message: Box::new(message), by }, &state.config);
+}
+
+/// Handles `/unpin <message_id>` (moderators only).
+async fn handle_unpin(message_id: Uuid, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let Some(_turn) = settings_turn(state, room_name).await else { return };
+    let (ids, by) = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        let refusal = if !room.is_moderator(client) {
+            Some("Only moderators can unpin messages.".to_string())
+        } else if !room.pinned.iter().any(|pinned| chat_message_id(pinned) == Some(message_id)) {
+            Some("That message isn't pinned.".to_string())
+        } else {
+            None
+        };
+        if let Some(message) = refusal {
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+        let ids: Vec<Uuid> = room.pinned.iter().filter_map(chat_message_id).filter(|&id| id != message_id).collect();
+        (ids, client.display_name().to_string())
+    };
+
+    if let Err(e) = db_call(state, state.store.set_pinned_ids(room_name, &ids)).await {
+        send_message(state, room_name, client_id, &db_error(&e)).await;
+        return;
+    }
+    println!("Message {} unpinned in room '{}' by client {}", message_id, room_name, client_id);
+    log_audit(state, "unpin_message", &by, None, Some(room_name), Some(message_id.to_string())).await;
+
+    let mut rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    room.pinned.retain(|pinned| chat_message_id(pinned) != Some(message_id));
+    announce(room, &ServerMessage::MessageUnpinned { message_id, by }, &state.config);
+}
+
+fn ownership_message(room: &Room) -> ServerMessage {
+    ServerMessage::OwnershipChanged { owner: room.ownership.owner.clone(), operators: room.ownership.operators.clone() }
+}
+
+/// Handles `/op <user>`: lets a member of the room moderate it (owner and administrators only).
+async fn handle_grant_operator(target: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let Some(_turn) = settings_turn(state, room_name).await else { return };
+    let change = |ownership: &mut RoomOwnership| {
+        if !ownership.includes(target) {
+            ownership.operators.push(target.to_string());
+        }
+    };
+    let (ownership, by) = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        let is_owner = client.username.is_some() && client.username == room.ownership.owner;
+        let refusal = if !is_owner && !client.is_admin {
+            Some("Only the room's owner can appoint operators.".to_string())
+        } else if !room.clients.values().any(|member| member.username.as_deref() == Some(target)) {
+            Some(format!("There is nobody called '{}' in this room.", target))
+        } else if room.ownership.owner.as_deref() == Some(target) || room.ownership.operators.iter().any(|op| op == target) {
+            Some(format!("'{}' can already moderate this room.", target))
+        } else {
+            None
+        };
+        if let Some(message) = refusal {
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+        let mut ownership = room.ownership.clone();
+        change(&mut ownership);
+        (ownership, client.display_name().to_string())
+    };
+
+    if let Err(e) = db_call(state, state.store.set_room_ownership(room_name, &ownership)).await {
+        send_message(state, room_name, client_id, &db_error(&e)).await;
+        return;
+    }
+    println!("'{}' made an operator of room '{}' by client {}", target, room_name, client_id);
+    log_audit(state, "grant_operator", &by, Some(target), Some(room_name), None).await;
+    apply_ownership(state, room_name, ownership, change).await;
+}
+
+/// Handles `/invite`: gives a moderator of a private room a single-use token to pass on.
+async fn handle_create_invite(client_id: Uuid, state: &ChatState, room_name: &str) {
+    let rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get(room_name) else { return };
+    let Some(client) = room.clients.get(&client_id) else { return };
+
+    let refusal = if !room.is_moderator(client) {
+        Some("Only moderators can create invites.")
+    } else if state.config.room_password(room_name).is_none() {
+        Some("This room is open to everyone; no invite is needed.")
+    } else {
+        None
+    };
+    if let Some(message) = refusal {
+        let _ = send_to_client(client, &ServerMessage::Notice { message: message.to_string() }, &state.config);
+        return;
+    }
+
+    let (token, expires_at) = state.invites.issue(room_name, state.config.invite_ttl);
+    println!("Invite to room '{}' created by client {}, valid until {}", room_name, client_id, expires_at);
+    let invite = ServerMessage::InviteCreated { room: room_name.to_string(), token, expires_at };
+    let _ = send_to_client(client, &invite, &state.config);
+    let actor = client.display_name().to_string();
+    drop(rooms);
+    log_audit(state, "create_invite", &actor, None, Some(room_name), None).await;
+}
+
+/// Handles `/transfer <user>`: hands the room to another member (owner and administrators only).
+async fn handle_transfer_ownership(target: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let Some(_turn) = settings_turn(state, room_name).await else { return };
+    let change = |ownership: &mut RoomOwnership| {
+        ownership.operators.retain(|op| op != target);
+        ownership.owner = Some(target.to_string());
+    };
+    let (ownership, by) = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        let is_owner = client.username.is_some() && client.username == room.ownership.owner;
+        let refusal = if !is_owner && !client.is_admin {
+            Some("Only the room's owner can transfer it.".to_string())
+        } else if !room.clients.values().any(|member| member.username.as_deref() == Some(target)) {
+            Some(format!("There is nobody called '{}' in this room.", target))
+        } else if room.ownership.owner.as_deref() == Some(target) {
+            Some(format!("'{}' already owns this room.", target))
+        } else {
+            None
+        };
+        if let Some(message) = refusal {
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+        let mut ownership = room.ownership.clone();
+        change(&mut ownership);
+        (ownership, client.display_name().to_string())
+    };
+
+    if let Err(e) = db_call(state, state.store.set_room_ownership(room_name, &ownership)).await {
+        send_message(state, room_name, client_id, &db_error(&e)).await;
+        return;
+    }
+    println!("Room '{}' transferred to '{}' by client {}", room_name, target, client_id);
+    log_audit(state, "transfer_ownership", &by, Some(target), Some(room_name), None).await;
+    apply_ownership(state, room_name, ownership, change).await;
+}
+
+/// Installs a change to the room's ownership that has just been saved as `saved`, and announces it. The caller
+/// holds the room's turn, but an owner or operator leaving can still change the live ownership in the meantime,
+/// so the change is applied to that rather than replacing it, and the result is saved again if it differs.
+async fn apply_ownership(state: &ChatState, room_name: &str, saved: RoomOwnership, change: impl Fn(&mut RoomOwnership)) {
+    let ownership = {
+        let mut rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get_mut(room_name) else { return };
+        change(&mut room.ownership);
+        announce(room, &ownership_message(room), &state.config);
+        room.ownership.clone()
+    };
+    if ownership != saved {
+        let _ = db_call(state, state.store.set_room_ownership(room_name, &ownership)).await;
+    }
+}
+
+/// Saves the room's ownership as it stands once it is the room's turn (see `Room::settings_writes`), after it
+/// was changed under the rooms lock. Saving the live copy rather than the caller's keeps an older change from
+/// landing over a newer one; `fallback` is saved instead if the room has been emptied since.
+async fn save_ownership(state: &ChatState, room_name: &str, fallback: &RoomOwnership) {
+    let _turn = settings_turn(state, room_name).await;
+    let live = state.rooms.lock().await.get(room_name).map(|room| room.ownership.clone());
+    let _ = db_call(state, state.store.set_room_ownership(room_name, live.as_ref().unwrap_or(fallback))).await;
+}
+
+/// Handles `/name-pattern <regex>|off`: sets the pattern that new usernames in the room must match (moderators only).
+/// Names already in use are left alone.
+async fn handle_set_username_pattern(pattern: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let by = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        if !room.is_moderator(client) {
+            let notice = ServerMessage::Notice { message: "Only moderators can change the username pattern.".to_string() };
+            let _ = send_to_client(client, &notice, &state.config);
+            return;
+        }
+        client.display_name().to_string()
+    };
+
+    let compiled = match pattern {
+        "off" => None,
+        pattern => match UsernamePattern::compile(pattern) {
+            Ok(compiled) => Some(compiled),
+            Err(e) => {
+                send_notice(state, room_name, client_id, format!("Invalid pattern: {}", e)).await;
+                return;
+            }
+        },
+    };
+    let stored = compiled.as_ref().map(|compiled| compiled.pattern.clone());
+    if let Err(e) = db_call(state, state.store.set_username_pattern(room_name, stored.as_deref())).await {
+        send_message(state, room_name, client_id, &db_error(&e)).await;
+        return;
+    }
+    println!("Username pattern of room '{}' set to {:?} by client {}", room_name, stored, client_id);
+    log_audit(state, "set_username_pattern", &by, None, Some(room_name), stored.clone()).await;
+
+    let mut rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    room.username_pattern = compiled;
+    if let Some(client) = room.clients.get(&client_id) {
+        let message = match stored {
+            Some(pattern) => format!("New usernames in this room must now match '{}'.", pattern),
+            None => "Usernames in this room are no longer restricted.".to_string(),
+        };
+        let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+    }
+}
+
+/// Handles `/roommeta get [key]`, `/roommeta set <key> <value>` and `/roommeta unset <key>`. Anyone may read a
+/// room's metadata; changing it is for moderators.
+async fn handle_room_metadata(args: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut parts = args.trim().splitn(3, char::is_whitespace);
+    let (action, key, value) = (parts.next(), parts.next(), parts.next().map(str::trim));
+    // A change holds the room's turn until it is installed, so no other change can land between its checks, such
+    // as `MAX_ROOM_METADATA_KEYS`, and its save. Reads don't wait.
+    let _turn = match action {
+        Some("set" | "unset") => match settings_turn(state, room_name).await {
+            Some(turn) => Some(turn),
+            None => return,
+        },
+        _ => None,
+    };
+
+    // The new metadata, with the audit action and detail for the change, and what to tell the client once it is saved.
+    let (change, reply) = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        match (action, key, value) {
+            (Some("get"), key, None) => {
+                let metadata = match key {
+                    Some(key) => room.metadata.get_key_value(key).map(|(k, v)| (k.clone(), v.clone())).into_iter().collect(),
+                    None => room.metadata.clone(),
+                };
+                let reply = ServerMessage::RoomMetadata { room: room_name.to_string(), metadata };
+                let _ = send_to_client(client, &reply, &state.config);
+                return;
+            }
+            (Some("set" | "unset"), Some(_), _) if !room.is_moderator(client) => {
+                (None, "Only moderators can change room metadata.".to_string())
+            }
+            (Some("set"), Some(key), Some(_)) if key.chars().count() > MAX_METADATA_KEY_CHARS => {
+                (None, format!("Metadata keys can be at most {} characters.", MAX_METADATA_KEY_CHARS))
+            }
+            (Some("set"), Some(_), Some(value)) if value.chars().count() > MAX_METADATA_VALUE_CHARS => {
+                (None, format!("Metadata values can be at most {} characters.", MAX_METADATA_VALUE_CHARS))
+            }
+            (Some("set"), Some(key), Some(_))
+                if !room.metadata.contains_key(key) && room.metadata.len() >= MAX_ROOM_METADATA_KEYS =>
+            {
+                (None, format!("A room can have at most {} metadata keys.", MAX_ROOM_METADATA_KEYS))
+            }
+            (Some("set"), Some(key), Some(value)) if !value.is_empty() => {
+                let mut metadata = room.metadata.clone();
+                metadata.insert(key.to_string(), value.to_string());
+                let change = (metadata, "set_room_metadata", format!("{}={}", key, value), client.display_name().to_string());
+                (Some(change), format!("Set '{}' to '{}' for this room.", key, value))
+            }
+            (Some("unset"), Some(key), None) => {
+                let mut metadata = room.metadata.clone();
+                if metadata.remove(key).is_none() {
+                    (None, format!("This room has no '{}' metadata.", key))
+                } else {
+                    let change = (metadata, "unset_room_metadata", key.to_string(), client.display_name().to_string());
+                    (Some(change), format!("Removed '{}' from this room's metadata.", key))
+                }
+            }
+            _ => (None, "Usage: /roommeta get [key] | /roommeta set <key> <value> | /roommeta unset <key>".to_string()),
+        }
+    };
+
+    if let Some((metadata, audit_action, detail, by)) = change {
+        if let Err(e) = db_call(state, state.store.set_room_metadata(room_name, &metadata)).await {
+            send_message(state, room_name, client_id, &db_error(&e)).await;
+            return;
+        }
+        println!("Metadata of room '{}' changed by client {}: {} {}", room_name, client_id, audit_action, detail);
+        log_audit(state, audit_action, &by, None, Some(room_name), Some(detail)).await;
+        if let Some(room) = state.rooms.lock().await.get_mut(room_name) {
+            room.metadata = metadata;
+        }
+    }
+    send_notice(state, room_name, client_id, reply).await;
+}
+
+/// Handles `/key publish <public_key>`: records the requester's public key so other members can encrypt messages
+/// to them. Clients generate their key pairs themselves; the server only ever sees the public half.
+async fn handle_publish_key(args: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    // Held until the key is installed, so a key another member publishes meanwhile isn't saved over.
+    let Some(_turn) = settings_turn(state, room_name).await else { return };
+    let checked = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        let Some(client) = room.clients.get(&client_id) else { return };
+        match (parts.as_slice(), &client.username) {
+            (["publish", _], None) => Err("Please set a username with `/user <name>` before publishing a key.".to_string()),
+            (["publish", key], _) if key.chars().count() > MAX_PUBLIC_KEY_CHARS => {
+                Err(format!("Public keys can be at most {} characters.", MAX_PUBLIC_KEY_CHARS))
+            }
+            (["publish", key], _) if !key.chars().all(|c| c.is_ascii_graphic()) => {
+                Err("Public keys must be sent in a text encoding such as base64.".to_string())
+            }
+            (["publish", key], Some(username)) => {
+                let mut keys = room.public_keys.clone();
+                keys.insert(username.clone(), key.to_string());
+                Ok((keys, username.clone(), key.to_string()))
+            }
+            _ => Err("Usage: /key publish <public_key>".to_string()),
+        }
+    };
+    let (keys, username, key) = match checked {
+        Ok(publish) => publish,
+        Err(refusal) => {
+            send_notice(state, room_name, client_id, refusal).await;
+            return;
+        }
+    };
+
+    if let Err(e) = db_call(state, state.store.set_public_keys(room_name, &keys)).await {
+        send_message(state, room_name, client_id, &db_error(&e)).await;
+        return;
+    }
+    println!("Client {} published a public key for '{}' in room '{}'", client_id, username, room_name);
+    let mut rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    room.public_keys.insert(username.clone(), key.clone());
+    if let Some(client) = room.clients.get_mut(&client_id) {
+        client.public_key = Some(key);
+        let notice = ServerMessage::Notice { message: format!("Published your public key for '{}'.", username) };
+        let _ = send_to_client(client, &notice, &state.config);
+    }
+}
+
+/// Handles `/keys`: sends the requester every public key published in the room, so it can encrypt per recipient.
+async fn handle_list_keys(client_id: Uuid, state: &ChatState, room_name: &str) {
+    let rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get(room_name) else { return };
+    let Some(client) = room.clients.get(&client_id) else { return };
+    let reply = ServerMessage::PublicKeys { room: room_name.to_string(), keys: room.public_keys.clone() };
+    let _ = send_to_client(client, &reply, &state.config);
+}
+
+/// The checks every chat and custom message must pass before it is delivered: the sender has a name, the room
+/// is open, and neither the sender's `MESSAGE_RATE_LIMIT`, the room's `ROOM_RATE_LIMITS` rate nor its backlog
+/// turns it away. Returns the sender's name, or an error after telling the sender why; the error carries the
+/// sender and audit detail of an auto-mute, for the caller to log once it has released the rooms lock.
+fn admit_message(room: &mut Room, client_id: Uuid, state: &ChatState, room_name: &str) -> Result<String, Option<(String, String)>> {
+    let Some(client) = room.clients.get(&client_id) else { return Err(None) };
+    let muted_for = client.username.as_ref().and_then(|username| room.mutes.get(username)).and_then(|until| until.checked_duration_since(Instant::now()));
+    let refusal = match &client.username {
+        None => "Please set a username with `/user <name>` before sending messages.".to_string(),
+        Some(_) if room.closed => "This room is closed; new messages are not accepted.".to_string(),
+        Some(_) if let Some(remaining) = muted_for => {
+            format!("You are muted in this room for another {} seconds.", remaining.as_secs_f64().ceil())
+        }
+        Some(username) => {
+            let username = username.clone();
+            admit_under_rate_limit(room, client_id, &username, state, room_name)
+                .map_err(|auto_mute| auto_mute.map(|detail| (username.clone(), detail)))?;
+            if !admit_under_room_rate(room, client_id, state, room_name) {
+                return Err(None);
+            }
+            if state.config.room_shed_policy != ShedPolicy::Throttle || !room.backlog.is_over(state.config.room_max_in_flight) {
+                return Ok(username);
+            }
+            "The room is busy; please wait a moment before sending.".to_string()
+        }
+    };
+    if let Some(client) = room.clients.get(&client_id) {
+        let _ = send_to_client(client, &ServerMessage::Notice { message: refusal }, &state.config);
+    }
+    Err(None)
+}
+
+/// Counts a chat or custom message against `MESSAGE_RATE_LIMIT`. Users who keep breaking the limit are muted
+/// for longer each time (`AUTO_MUTE_DURATIONS_SECS`). Returns an error, after telling the sender why, if the
+/// message must be dropped; it carries the audit detail of an auto-mute, for the caller to log once it has
+/// released the rooms lock. Moderators are exempt.
+fn admit_under_rate_limit(room: &mut Room, client_id: Uuid, username: &str, state: &ChatState, room_name: &str) -> Result<(), Option<String>> {
+    let Some(limit) = state.config.message_rate_limit else { return Ok(()) };
+    if room.clients.get(&client_id).is_none_or(|client| room.is_moderator(client)) {
+        return Ok(());
+    }
+
+    let now = Instant::now();
+    let key = username.to_string();
+    if !room.flood_records.contains_key(&key) {
+        room.flood_records.insert(key.clone(), FloodRecord::new(now));
+    }
+    let Some(record) = room.flood_records.get_mut(&key) else { return Ok(()) };
+    let mut auto_mute = None;
+    let message = match record.check(now, limit, &state.config) {
+        FloodVerdict::Allowed => return Ok(()),
+        FloodVerdict::Muted(remaining) => {
+            format!("You are muted for another {} seconds for sending messages too fast.", remaining.as_secs_f64().ceil())
+        }
+        FloodVerdict::AutoMuted { duration, violations } => {
+            println!("Auto-muted '{}' in room '{}' for {:?} (violation {}).", username, room_name, duration, violations);
+            auto_mute = Some(format!("duration={}s violations={}", duration.as_secs(), violations));
+            format!("You are sending messages too fast and have been muted for {} seconds.", duration.as_secs_f64().ceil())
+        }
+        FloodVerdict::Dropped => "You are sending messages too fast; that one was not delivered.".to_string(),
+    };
+    if let Some(client) = room.clients.get(&client_id) {
+        let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+    }
+    Err(auto_mute)
+}
+
+/// Counts a chat or custom message against the room's `ROOM_RATE_LIMITS` rate, which all its members share.
+/// Returns `false`, after telling the sender the room is too busy, if the message must be dropped. Moderators
+/// are exempt and don't use up the room's rate.
+fn admit_under_room_rate(room: &mut Room, client_id: Uuid, state: &ChatState, room_name: &str) -> bool {
+    let Some(rate) = state.config.room_rate_limits.rate(room_name) else { return true };
+    if room.clients.get(&client_id).is_none_or(|client| room.is_moderator(client)) {
+        return true;
+    }
+
+    let now = Instant::now();
+    let Err(wait) = room.rate_bucket.get_or_insert_with(|| TokenBucket::full(rate, now)).take(rate, now) else {
+        return true;
+    };
+    if let Some(client) = room.clients.get(&client_id) {
+        let message = format!(
+            "This room is too busy right now; your message was not delivered. Try again in {} seconds.",
+            wait.as_secs_f64().ceil().max(1.0)
+        );
+        let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+    }
+    false
+}
+
+/// Records a moderation action in the audit log.
+async fn log_audit(
+    state: &ChatState,
+    action: &str,
+    actor: &str,
+    target: Option<&str>,
+    room_name: Option<&str>,
+    detail: Option<String>,
+) {
+    let entry = AuditEntry {
+        action: action.to_string(),
+        actor: actor.to_string(),
+        target: target.map(String::from),
+        room: room_name.map(String::from),
+        timestamp: Utc::now(),
+        detail,
+    };
+    // A failed write is logged and counted by `db_call`; it doesn't undo the action being audited.
+    let _ = db_call(state, state.store.log_audit(&entry)).await;
+}
+
+/// Saves a message to the store with its `seq`, unless `PERSIST_TYPES` leaves its type out in this room. The
+/// message has already been delivered by then, so a failure only means it is missing from stored history.
+///
+/// Numbers of messages that aren't saved are recorded when the room is emptied (see `cleanup_client`).
+async fn persist_message(
+    state: &ChatState,
+    room_name: &str,
+    save: PendingSave,
+    message: &ServerMessage,
+    created_at: DateTime<Utc>,
+) -> DbResult<()> {
+    if !state.config.persist_types.allows(room_name, message) {
+        return Ok(());
+    }
+    db_call(state, state.store.save_message(room_name, save.seq(), message, created_at)).await
+}
+
+/// The error sent to a client whose message was delivered but couldn't be saved to the room's history.
+fn not_saved_error() -> ServerMessage {
+    ServerMessage::Error { message: "Your message was delivered but could not be saved to the room's history.".to_string() }
+}
+
+/// Sends a message to every client in a room without adding it to the history cache.
+fn announce(room: &Room, message: &ServerMessage, config: &Config) {
+    for (id, client) in room.clients.iter() {
+        if send_to_client(client, message, config).is_err() {
+            println!("Failed to send announcement to client {}", id);
+        }
+    }
+}
+
+/// Checks `username` against the server-wide `MIN_USERNAME_LENGTH` and `RESERVED_USERNAMES` rules, returning the
+/// reason it is refused, if it is.
+fn username_policy_violation(username: &str, client: &Client, config: &Config) -> Option<String> {
+    if username.chars().count() < config.min_username_chars {
+        return Some(format!("'{}' is too short: usernames must be at least {} characters.", username, config.min_username_chars));
+    }
+    if config.is_reserved_username(username) && !client.is_admin {
+        return Some(format!("'{}' is reserved for administrators.", username));
+    }
+    None
+}
+
+/// Handles `/user`: the first name joins the room and replays its history, later ones rename the client.
+/// A silent first join (admins only) replays history as usual but isn't announced to the room.
+async fn handle_set_username(
+    username: String,
+    secret: Option<String>,
+    silent: bool,
+    client_id: Uuid,
+    state: &ChatState,
+    room_name: &str,
+) {
+    // Check the reservation before taking the rooms lock: the lookup hits the database and verification is slow.
+    let Some(authenticated) = verify_username_claim(&username, secret, client_id, state, room_name).await else {
+        return;
+    };
+
+    let mut rooms = state.rooms.lock().await;
+    let old_username: String;
+
+    if let Some(room) = rooms.get_mut(room_name) {
+        if let Some(client) = room.clients.get(&client_id)
+            && let Some(message) = username_policy_violation(&username, client, &state.config)
+        {
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+
+        if room.banned.contains(&username)
+            && let Some(client) = room.clients.get(&client_id)
+        {
+            let message = format!("'{}' is banned from this room.", username);
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+
+        if let Some(pattern) = &room.username_pattern
+            && !pattern.matches(&username)
+            && let Some(client) = room.clients.get(&client_id)
+        {
+            let message = format!("'{}' is not allowed here: usernames in this room must match '{}'.", username, pattern.pattern);
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+
+        // Moderator rights go with the name, so only its registered owner may take a moderator's name.
+        if room.ownership.includes(&username)
+            && !authenticated
+            && let Some(client) = room.clients.get(&client_id)
+            && client.username.as_deref() != Some(username.as_str())
+        {
+            let message = format!("'{}' belongs to a moderator of this room; claim it with its registered secret.", username);
+            let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+            return;
+        }
+
+        // Setting the name you already have is a no-op: no join broadcast and no history replay.
+        if let Some(client) = room.clients.get_mut(&client_id)
+            && client.username.as_deref() == Some(username.as_str())
+        {
+            let notice = ServerMessage::Notice { message: format!("You are already known as '{}'.", username) };
+            let _ = send_to_client(client, &notice, &state.config);
+            return;
+        }
+
+        // After the first join a new name is just a rename: no history replay and no join broadcast.
+        if let Some(client) = room.clients.get_mut(&client_id)
+            && client.has_joined
+        {
+            if let Some(last) = client.last_renamed_at
+                && let Some(remaining) = state.config.rename_cooldown.checked_sub(last.elapsed())
+            {
+                let message = format!("You can change your name again in {} seconds.", remaining.as_secs_f64().ceil());
+                let _ = send_to_client(client, &ServerMessage::Notice { message }, &state.config);
+                return;
+            }
+            let old_username = client.display_name().to_string();
+            client.username = Some(username.clone());
+            client.last_renamed_at = Some(Instant::now());
+            println!("Client {} renamed from '{}' to '{}' in room '{}'", client_id, old_username, &username, room_name);
+
+            if client.silent {
+                return;
+            }
+            // Ownership and operator rights are held by name, so they move with the rename.
+            let ownership = &mut room.ownership;
+            let mut ownership_changed = false;
+            for name in ownership.owner.iter_mut().chain(ownership.operators.iter_mut()) {
+                if *name == old_username {
+                    *name = username.clone();
+                    ownership_changed = true;
+                }
+            }
+            let ownership = ownership_changed.then(|| room.ownership.clone());
+            if ownership.is_some() {
+                announce(room, &ownership_message(room), &state.config);
+            }
+            let created_at = Utc::now();
+            let rename_msg = ServerMessage::UserRenamed { old_username, new_username: username };
+            let save = broadcast_message(rename_msg.clone(), state, &mut rooms, room_name, None).await;
+            drop(rooms);
+            if let Some(ownership) = &ownership {
+                save_ownership(state, room_name, ownership).await;
+            }
+            let _ = persist_message(state, room_name, save, &rename_msg, created_at).await;
+            return;
+        }
+
+        if silent
+            && let Some(client) = room.clients.get(&client_id)
+            && !client.is_admin
+        {
+            let notice = ServerMessage::Notice { message: "Only administrators can join silently.".to_string() };
+            let _ = send_to_client(client, &notice, &state.config);
+            return;
+        }
+
+        // Replay only the messages that predate this client's live feed. Everything newer was
+        // already delivered by `broadcast_message`, and the whole sequence runs under the rooms
+        // lock, so nothing can be broadcast in between: each message arrives exactly once, in order.
+        let next_seq = room.next_seq;
+        let anon_read = state.config.allows_anon_read(room_name);
+        let Some(client) = room.clients.get_mut(&client_id) else {
+            return;
+        };
+        // In rooms without anonymous read access the live feed only starts now.
+        if client.username.is_none() && !anon_read {
+            client.live_from_seq = next_seq;
+        }
+        let live_from_seq = client.live_from_seq;
+
+        // A user returning within the grace period gets exactly what they missed instead of the usual history.
+        let offline_buffer = room.offline_buffers.remove(&username).filter(|buffer| !buffer.is_expired());
+        let replay: Vec<(u64, ServerMessage)> = match &offline_buffer {
+            Some(buffer) => buffer.messages.iter().filter(|(seq, _)| *seq < live_from_seq).cloned().collect(),
+            None => {
+                let history: Vec<(u64, ServerMessage)> =
+                    room.history_before(live_from_seq).map(|(seq, msg)| (seq, msg.clone())).collect();
+                let skip = history.len().saturating_sub(state.config.join_replay_count);
+                history.into_iter().skip(skip).collect()
+            }
+        };
+        let ownership = room.ownership.owner.is_some().then(|| ownership_message(room));
+
+        let Some(client) = room.clients.get_mut(&client_id) else {
+            return;
+        };
+        old_username = client.display_name().to_string();
+        client.username = Some(username.clone());
+        client.has_joined = true;
+        client.silent = silent;
+        let presence = room.presence();
+        let latest_seq = room.next_seq;
+        let Some(client) = room.clients.get(&client_id) else {
+            return;
+        };
+
+        if offline_buffer.is_some() {
+            let notice = ServerMessage::Notice { message: format!("Welcome back! You missed {} messages while away.", replay.len()) };
+            let _ = send_to_client(client, &notice, &state.config);
+        }
+
+        // Send room history to the user who just set their name, then the topic, pins and owner.
+        for (seq, msg) in &replay {
+            let rendered = render_sequenced(msg, *seq, client.protocol, &state.config);
+            if client.sender.push(Message::Text(rendered.into())).is_err() {
+                println!("Failed to send history to client {}", client_id);
+                return;
+            }
+        }
+        if let Some(topic) = &room.topic {
+            let topic_msg = ServerMessage::TopicChanged { topic: topic.topic.clone(), by: topic.set_by.clone() };
+            let _ = send_to_client(client, &topic_msg, &state.config);
+        }
+        if !room.pinned.is_empty() {
+            let _ = send_to_client(client, &ServerMessage::PinnedMessages { messages: room.pinned.clone() }, &state.config);
+        }
+        if let Some(ownership) = &ownership {
+            let _ = send_to_client(client, ownership, &state.config);
+        }
+        let _ = send_to_client(client, &ServerMessage::PresenceSnapshot { users: presence, latest_seq }, &state.config);
+    } else { return; } // Room doesn't exist, something is wrong
+
+    println!("Client {} ({}) is now known as '{}' in room '{}'", client_id, old_username, &username, room_name);
+    if silent {
+        println!("'{}' joined room '{}' silently.", username, room_name);
+        return;
+    }
+
+    let created_at = Utc::now();
+    let join_msg = ServerMessage::UserJoined { username: username.clone(), sent_at: Some(created_at) };
+    let save = broadcast_message(join_msg.clone(), state, &mut rooms, room_name, Some(client_id)).await;
+
+    // The first named user in a room without an owner becomes its owner.
+    let mut ownership = None;
+    if let Some(room) = rooms.get_mut(room_name)
+        && room.ownership.owner.is_none()
+    {
+        room.ownership.owner = Some(username.clone());
+        println!("'{}' now owns room '{}'", username, room_name);
+        announce(room, &ownership_message(room), &state.config);
+        ownership = Some(room.ownership.clone());
+    }
+    drop(rooms);
+
+    if let Some(ownership) = &ownership {
+        save_ownership(state, room_name, ownership).await;
+    }
+    // Persist the join message to the database
+    let _ = persist_message(state, room_name, save, &join_msg, created_at).await;
+}
+
+/// Checks whether the client may use `username`: unregistered names are free, registered ones need the matching secret.
+/// Returns `None`, after sending the client a notice explaining why, if it may not, and otherwise whether the name
+/// was authenticated with its secret.
+async fn verify_username_claim(
+    username: &str,
+    secret: Option<String>,
+    client_id: Uuid,
+    state: &ChatState,
+    room_name: &str,
+) -> Option<bool> {
+    let stored_hash = match db_call(state, state.store.get_secret_hash(username)).await {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return Some(false),
+        Err(_) => {
+            send_notice(state, room_name, client_id, "Could not check that username right now; please try again.".to_string()).await;
+            return None;
+        }
+    };
+
+    let Some(secret) = secret else {
+        let notice = format!("'{}' is a registered username; use `/user {} <secret>`.", username, username);
+        send_notice(state, room_name, client_id, notice).await;
+        return None;
+    };
+    if !auth::verify_secret(secret, stored_hash).await {
+        println!("Client {} gave the wrong secret for registered username '{}'.", client_id, username);
+        send_notice(state, room_name, client_id, format!("Incorrect secret for '{}'.", username)).await;
+        return None;
+    }
+    Some(true)
+}
+
+/// Handles `/register <secret>`, reserving the client's current username for whoever knows the secret.
+async fn handle_register(secret: String, client_id: Uuid, state: &ChatState, room_name: &str) {
+    if secret.is_empty() {
+        send_notice(state, room_name, client_id, "Usage: /register <secret>".to_string()).await;
+        return;
+    }
+    let username = {
+        let rooms = state.rooms.lock().await;
+        rooms.get(room_name).and_then(|room| room.clients.get(&client_id)).and_then(|client| client.username.clone())
+    };
+    let Some(username) = username else {
+        send_notice(state, room_name, client_id, "Set a username with `/user <name>` before registering it.".to_string()).await;
+        return;
+    };
+
+    let Some(secret_hash) = auth::hash_secret(secret).await else {
+        send_notice(state, room_name, client_id, "Registration failed; please try again.".to_string()).await;
+        return;
+    };
+    let registered = db_call(state, state.store.register_user(&username, &secret_hash)).await;
+    let notice = match registered {
+        Ok(true) => {
+            println!("Client {} registered username '{}'.", client_id, username);
+            format!("'{}' is now registered. Use `/user {} <secret>` to claim it in future sessions.", username, username)
+        }
+        Ok(false) => format!("'{}' is already registered.", username),
+        Err(_) => "Registration failed; please try again.".to_string(),
+    };
+    send_notice(state, room_name, client_id, notice).await;
+}
+
+/// Handles loading full history from the database for a specific client.
+async fn handle_load_full_history(include_presence: bool, client_id: Uuid, state: &ChatState, room_name: &str) {
+    println!("Loading full history for client {} in room '{}'", client_id, room_name);
+    let sent = send_stored_history(client_id, state, room_name, 1, MAX_HISTORY_SIZE as i64, include_presence).await;
+    if let Some(sent) = sent {
+        println!("Sent {} messages from full history to client {}", sent, client_id);
+    }
+}
+
+/// Handles `/history <page> <page_size> [--with-presence]`, sending one page of persisted history to the requester.
+async fn handle_load_history_page(args: &str, include_presence: bool, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut parts = args.split_whitespace();
+    let (page, page_size) = match (
+        parts.next().and_then(|p| p.parse::<i64>().ok()),
+        parts.next().and_then(|p| p.parse::<i64>().ok()),
+        parts.next(),
+    ) {
+        (Some(page), Some(page_size), None) if page >= 1 && (1..=MAX_HISTORY_PAGE_SIZE).contains(&page_size) => {
+            (page, page_size)
+        }
+        _ => {
+            let usage = format!(
+                "Usage: /history <page> <page_size> [--with-presence] (page >= 1, page_size 1-{})",
+                MAX_HISTORY_PAGE_SIZE
+            );
+            send_notice(state, room_name, client_id, usage).await;
+            return;
+        }
+    };
+
+    println!("Loading history page {} (size {}) for client {} in room '{}'", page, page_size, client_id, room_name);
+    send_stored_history(client_id, state, room_name, page, page_size, include_presence).await;
+}
+
+/// Loads a page of persisted history and queues it for the client, leaving out joins and leaves unless
+/// `include_presence` is set. Once the client's user has a read cursor, each entry says whether it was seen.
+/// Returns how many messages were sent.
+///
+/// The rooms lock is a global mutex, so it is only held to check the client's access and, at the end,
+/// to queue the already-rendered frames. The database query and rendering happen without it, so a large
+/// replay doesn't stall every other room.
+async fn send_stored_history(
+    client_id: Uuid,
+    state: &ChatState,
+    room_name: &str,
+    page: i64,
+    page_size: i64,
+    include_presence: bool,
+) -> Option<usize> {
+    let (protocol, username) = {
+        let rooms = state.rooms.lock().await;
+        let client = rooms.get(room_name)?.clients.get(&client_id)?;
+        if client.username.is_none() && !state.config.allows_anon_read(room_name) {
+            let notice = ServerMessage::Notice { message: "Please set a username with `/user <name>` before loading history.".to_string() };
+            let _ = send_to_client(client, &notice, &state.config);
+            return None;
+        }
+        (client.protocol, client.username.clone())
+    };
+
+    // Released before the rooms lock is taken again, so the database is never awaited while holding it.
+    let permit = match state.config.history_busy_policy {
+        BusyPolicy::Queue => state.history_loads.acquire().await.ok(),
+        BusyPolicy::Reject => state.history_loads.try_acquire().ok(),
+    };
+    let Some(permit) = permit else {
+        let busy = ServerMessage::Error { message: "The server is busy loading history; please try again shortly.".to_string() };
+        send_message(state, room_name, client_id, &busy).await;
+        return None;
+    };
+    let history = db_call(state, state.store.load_history_paginated(room_name, page, page_size, include_presence)).await;
+    drop(permit);
+    let last_seen = match &username {
+        Some(username) => db_call(state, state.store.get_last_seen(room_name, username)).await.ok().flatten(),
+        None => None,
+    };
+    let history = match history {
+        Ok(history) => history,
+        Err(e) => {
+            let rooms = state.rooms.lock().await;
+            let client = rooms.get(room_name)?.clients.get(&client_id)?;
+            let _ = send_to_client(client, &db_error(&e), &state.config);
+            return None;
+        }
+    };
+    let frames: Vec<Message> = history
+        .into_iter()
+        .map(|(msg, reactions, stored_at)| {
+            let seen = last_seen.map(|last_seen| stored_at <= last_seen);
+            let entry = ServerMessage::HistoryEntry { message: Box::new(msg), reactions, seen };
+            Message::Text(render_message(&entry, protocol, &state.config).into())
+        })
+        .collect();
+
+    // Pushing only appends to the client's queue; its writer task does the actual sending.
+    let rooms = state.rooms.lock().await;
+    let client = rooms.get(room_name)?.clients.get(&client_id)?;
+    let sent = frames.len();
+    for frame in frames {
+        if client.sender.push(frame).is_err() {
+            println!("Failed to send history to client {}", client_id);
+            return None;
+        }
+    }
+    Some(sent)
+}
+
+/// Handles `/history-count`, replying with the number of persisted messages in the room.
+async fn handle_history_count(client_id: Uuid, state: &ChatState, room_name: &str) {
+    if !may_read_history(client_id, state, room_name).await {
+        return;
+    }
+    let reply = match db_call(state, state.store.get_message_count(room_name)).await {
+        Ok(count) => ServerMessage::MessageCount { room: room_name.to_string(), count },
+        Err(e) => db_error(&e),
+    };
+    send_message(state, room_name, client_id, &reply).await;
+}
+
+/// Whether the client may read the room's history: it has a name, or the room allows anonymous reading.
+/// A client that may not is told why; one that has gone counts as not allowed.
+async fn may_read_history(client_id: Uuid, state: &ChatState, room_name: &str) -> bool {
+    let rooms = state.rooms.lock().await;
+    let Some(client) = rooms.get(room_name).and_then(|room| room.clients.get(&client_id)) else { return false };
+    if client.username.is_none() && !state.config.allows_anon_read(room_name) {
+        let notice = ServerMessage::Notice { message: "Please set a username with `/user <name>` before loading history.".to_string() };
+        let _ = send_to_client(client, &notice, &state.config);
+        return false;
+    }
+    true
+}
+
+/// Looks up one of the room's chat messages: in the cache while the rooms lock is held, then in the store once
+/// it has been released. A failed lookup is reported to the client and returns `None`, like a room that is gone;
+/// a message the room doesn't have is `Some(None)`.
+async fn find_chat_message(message_id: Uuid, client_id: Uuid, state: &ChatState, room_name: &str) -> Option<Option<ServerMessage>> {
+    let cached = {
+        let rooms = state.rooms.lock().await;
+        let room = rooms.get(room_name)?;
+        room.history.iter().find(|message| chat_message_id(message) == Some(message_id)).cloned()
+    };
+    if cached.is_some() {
+        return Some(cached);
+    }
+    match db_call(state, state.store.load_message(room_name, message_id)).await {
+        Ok(message) => Some(message),
+        Err(e) => {
+            send_message(state, room_name, client_id, &db_error(&e)).await;
+            None
+        }
+    }
+}
+
+/// Handles `/markread`: moves the user's read cursor in the room to now, so everything stored so far
+/// comes back from `/history` marked as seen.
+async fn handle_mark_read(client_id: Uuid, state: &ChatState, room_name: &str) {
+    let username = {
+        let rooms = state.rooms.lock().await;
+        let Some(client) = rooms.get(room_name).and_then(|room| room.clients.get(&client_id)) else { return };
+        client.username.clone()
+    };
+    let Some(username) = username else {
+        send_notice(state, room_name, client_id, "Please set a username with `/user <name>` before using /markread.".to_string()).await;
+        return;
+    };
+
+    match db_call(state, state.store.set_last_seen(room_name, &username, Utc::now())).await {
+        Ok(()) => send_notice(state, room_name, client_id, format!("Marked everything in '{}' as read.", room_name)).await,
+        Err(e) => send_message(state, room_name, client_id, &db_error(&e)).await,
+    }
+}
+
+/// Handles `/get <message_id>`: sends the requester one of the room's chat messages, e.g. one a reply refers to.
+async fn handle_get_message(message_id: Uuid, client_id: Uuid, state: &ChatState, room_name: &str) {
+    if !may_read_history(client_id, state, room_name).await {
+        return;
+    }
+    // Recent messages are usually still cached; older ones come from the store.
+    let Some(message) = find_chat_message(message_id, client_id, state, room_name).await else { return };
+    let reply = match message {
+        Some(message) => ServerMessage::FetchedMessage { message: Box::new(message) },
+        None => ServerMessage::Error { message: format!("Message {} was not found in this room.", message_id) },
+    };
+    send_message(state, room_name, client_id, &reply).await;
+}
+
+/// Handles `/leave [room]`: the client leaves the room as if it had quit, but keeps its connection and
+/// is anonymous again, so `/user` joins it anew. A connection only ever belongs to the room in its URL,
+/// so naming any other room just gets a notice.
+async fn handle_leave(target: Option<&str>, client_id: Uuid, state: &ChatState, room_name: &str) {
+    if let Some(target) = target {
+        let target = state.config.room_name_normalization.normalize(target);
+        if state.config.room_aliases.resolve(&target) != room_name {
+            send_notice(state, room_name, client_id, format!("You are not in room '{}'.", target)).await;
+            return;
+        }
+    }
+
+    let mut rooms = state.rooms.lock().await;
+    let Some(room) = rooms.get_mut(room_name) else { return };
+    let Some(client) = room.clients.get_mut(&client_id) else { return };
+    let Some(username) = client.username.take() else {
+        let notice = ServerMessage::Notice { message: "You haven't joined this room; set a username with `/user <name>`.".to_string() };
+        let _ = send_to_client(client, &notice, &state.config);
+        return;
+    };
+    let silent = client.silent || client.stale;
+    client.has_joined = false;
+    client.stale = false;
+    client.silent = false;
+    client.unacked.clear();
+    let notice = ServerMessage::Notice { message: format!("You left room '{}'. Use `/user <name>` to join it again.", room_name) };
+    let _ = send_to_client(client, &notice, &state.config);
+    println!("Client {} ({}) left room '{}' but stays connected.", client_id, username, room_name);
+
+    let ownership = hand_off_ownership(room, &username, room_name, &state.config);
+    let left = if silent {
+        None
+    } else {
+        let created_at = Utc::now();
+        let left_msg = ServerMessage::UserLeft { username, message: None, sent_at: Some(created_at) };
+        let save = broadcast_message(left_msg.clone(), state, &mut rooms, room_name, Some(client_id)).await;
+        Some((save, left_msg, created_at))
+    };
+    drop(rooms);
+
+    if let Some(ownership) = &ownership {
+        save_ownership(state, room_name, ownership).await;
+    }
+    if let Some((save, left_msg, created_at)) = left {
+        let _ = persist_message(state, room_name, save, &left_msg, created_at).await;
+    }
+}
+
+/// Handles `/thread <message_id>`: the message and its replies, down to `THREAD_MAX_DEPTH` levels.
+async fn handle_show_thread(message_id: Uuid, client_id: Uuid, state: &ChatState, room_name: &str) {
+    if !may_read_history(client_id, state, room_name).await {
+        return;
+    }
+    let Some(root) = find_chat_message(message_id, client_id, state, room_name).await else { return };
+    let Some(root) = root else {
+        let error = ServerMessage::Error { message: format!("Message {} was not found in this room.", message_id) };
+        send_message(state, room_name, client_id, &error).await;
+        return;
+    };
+    let replies = state.store.load_thread(room_name, message_id, state.config.thread_max_depth, MAX_HISTORY_SIZE);
+    let reply = match db_call(state, replies).await {
+        Ok(replies) => ServerMessage::Thread { root: Box::new(root), replies },
+        Err(e) => db_error(&e),
+    };
+    send_message(state, room_name, client_id, &reply).await;
+}
+
+/// Handles `/report <message_id> [reason]`, recording the report and alerting every connected administrator.
+async fn handle_report(args: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut parts = args.trim().splitn(2, char::is_whitespace);
+    let message_id = parts.next().and_then(|id| Uuid::parse_str(id).ok());
+    let reason = parts.next().map(str::trim).filter(|reason| !reason.is_empty());
+    let (message_id, reason) = match (message_id, reason) {
+        (Some(message_id), reason) if reason.is_none_or(|r| r.chars().count() <= MAX_REPORT_REASON_CHARS) => {
+            (message_id, reason.map(String::from))
+        }
+        _ => {
+            let usage = format!("Usage: /report <message_id> [reason] (reason up to {} characters)", MAX_REPORT_REASON_CHARS);
+            send_notice(state, room_name, client_id, usage).await;
+            return;
+        }
+    };
+
+    let reporter = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        match room.clients.get(&client_id) {
+            Some(Client { username: Some(username), .. }) => username.clone(),
+            Some(client) => {
+                let notice = ServerMessage::Notice { message: "Please set a username with `/user <name>` before reporting.".to_string() };
+                let _ = send_to_client(client, &notice, &state.config);
+                return;
+            }
+            None => return,
+        }
+    };
+
+    let report = Report { message_id, room: room_name.to_string(), reporter, reason, reported_at: Utc::now() };
+    let filed = db_call(state, state.store.add_report(&report)).await;
+    let reply = match filed {
+        Ok(true) => {
+            println!("Client {} ({}) reported message {} in room '{}'", client_id, report.reporter, message_id, room_name);
+            ServerMessage::Notice { message: "Thanks, the moderators have been notified.".to_string() }
+        }
+        Ok(false) => ServerMessage::Notice {
+            message: format!("Could not report {}: no such message in this room, or you already reported it.", message_id),
+        },
+        Err(ref e) => db_error(e),
+    };
+    let rooms = state.rooms.lock().await;
+    if let Some(client) = rooms.get(room_name).and_then(|room| room.clients.get(&client_id)) {
+        let _ = send_to_client(client, &reply, &state.config);
+    }
+    if !matches!(filed, Ok(true)) {
+        return;
+    }
+
+    let alert = ServerMessage::ReportFiled { message_id, room: report.room, reporter: report.reporter, reason: report.reason };
+    tap(state, room_name, &alert);
+    alert_admins(&rooms, &alert, &state.config);
+}
+
+/// Sends a message to every administrator connected to any room.
+fn alert_admins(rooms: &HashMap<String, Room>, alert: &ServerMessage, config: &Config) {
+    for admin in rooms.values().flat_map(|room| room.clients.values()).filter(|client| client.is_admin) {
+        let _ = send_to_client(admin, alert, config);
+    }
+}
+
+/// Handles `/react <message_id> <emoji>`, recording the reaction and announcing it to the room.
+async fn handle_reaction(args: &str, client_id: Uuid, state: &ChatState, room_name: &str) {
+    let mut parts = args.split_whitespace();
+    let (message_id, emoji) = match (parts.next().and_then(|id| Uuid::parse_str(id).ok()), parts.next(), parts.next()) {
+        (Some(message_id), Some(emoji), None) if emoji.chars().count() <= MAX_REACTION_CHARS => (message_id, emoji.to_string()),
+        _ => {
+            let usage = format!("Usage: /react <message_id> <emoji> (emoji up to {} characters)", MAX_REACTION_CHARS);
+            send_notice(state, room_name, client_id, usage).await;
+            return;
+        }
+    };
+
+    let username = {
+        let rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get(room_name) else { return };
+        match room.clients.get(&client_id) {
+            Some(Client { username: Some(username), .. }) => username.clone(),
+            Some(client) => {
+                let notice = ServerMessage::Notice { message: "Please set a username with `/user <name>` before reacting.".to_string() };
+                let _ = send_to_client(client, &notice, &state.config);
+                return;
+            }
+            None => return,
+        }
+    };
+
+    let refusal = match db_call(state, state.store.add_reaction(room_name, message_id, &username, &emoji)).await {
+        Ok(true) => None,
+        Ok(false) => Some(ServerMessage::Notice { message: format!("Could not react to {}: no such message in this room, or you already reacted with {}.", message_id, emoji) }),
+        Err(e) => Some(db_error(&e)),
+    };
+    if let Some(refusal) = refusal {
+        send_message(state, room_name, client_id, &refusal).await;
+        return;
+    }
+
+    if let Some(room) = state.rooms.lock().await.get(room_name) {
+        announce(room, &ServerMessage::ReactionAdded { message_id, username, emoji }, &state.config);
+    }
+}
+
+/// Handles a regular chat message, adds it to history, and broadcasts it.
+/// Encrypted content is opaque to the server: it is relayed and stored exactly as received.
+/// A `reply_to` must name a chat message of the same room.
+async fn handle_chat_message(
+    content: String,
+    encrypted: bool,
+    reply_to: Option<Uuid>,
+    expires_in_secs: Option<u64>,
+    client_id: Uuid,
+    state: &ChatState,
+    room_name: &str,
+) {
+    if !encrypted && state.config.requires_encryption(room_name) {
+        send_notice(state, room_name, client_id, "This room only accepts end-to-end encrypted messages.".to_string()).await;
+        return;
+    }
+    let ttl = expires_in_secs.map(Duration::from_secs);
+    if let Some(ttl) = ttl
+        && (ttl < state.config.message_ttl_min || ttl > state.config.message_ttl_max)
+    {
+        let notice = format!(
+            "expires_in_secs must be between {} and {}.",
+            state.config.message_ttl_min.as_secs(),
+            state.config.message_ttl_max.as_secs()
+        );
+        send_notice(state, room_name, client_id, notice).await;
+        return;
+    }
+
+    // Blank and invisible-only messages are dropped silently, as before.
+    let content = if encrypted {
+        if content.is_empty() { return; }
+        content
+    } else {
+        let Some(content) = state.config.content_normalization.normalize(&content) else { return };
+        state.config.text_macros.expand(&content)
+    };
+
+    // Ciphertext can't be scored, so encrypted messages always pass.
+    let (severity, action) = match &state.config.content_moderation {
+        Some(moderation) if !encrypted => moderation.assess(&content),
+        _ => (0, ModerationAction::Allow),
+    };
+    if action == ModerationAction::Block {
+        println!("Blocked message from client {} in room '{}' (severity {}).", client_id, room_name, severity);
+        send_notice(state, room_name, client_id, "Your message was blocked by the content filter.".to_string()).await;
+        return;
+    }
+    let severity = Some(severity).filter(|&severity| severity > 0);
+
+    // Timestamp the message on arrival, before waiting for the lock or the database.
+    let created_at = Utc::now();
+
+    // The parent is looked up before the rooms lock is taken, since older messages come from the store.
+    if let Some(parent) = reply_to {
+        let Some(found) = find_chat_message(parent, client_id, state, room_name).await else { return };
+        if found.is_none() {
+            let error = ServerMessage::Error { message: format!("Message {} was not found in this room.", parent) };
+            send_message(state, room_name, client_id, &error).await;
+            return;
+        }
+    }
+
+    let mut rooms = state.rooms.lock().await;
+    let new_msg: ServerMessage;
+    let save: PendingSave;
+
+    if let Some(room) = rooms.get_mut(room_name) {
+        let username = match admit_message(room, client_id, state, room_name) {
+            Ok(username) => username,
+            Err(auto_mute) => {
+                drop(rooms);
+                if let Some((username, detail)) = auto_mute {
+                    log_audit(state, "auto_mute", RATE_LIMITER_ACTOR, Some(&username), Some(room_name), Some(detail)).await;
+                }
+                return;
+            }
+        };
+        let Some(Client { echo, color, .. }) = room.clients.get(&client_id) else { return };
+        let (echo, color) = (*echo, color.clone());
+
+        if let Some(window) = state.config.duplicate_window
+            && let Some(client) = room.clients.get_mut(&client_id)
+        {
+            let now = Instant::now();
+            if let Some((last, sent_at)) = &client.last_message
+                && *last == content
+                && now.duration_since(*sent_at) < window
+            {
+                println!("Dropping duplicate message from {}({}).", &username, client_id);
+                let notice = ServerMessage::Notice { message: "You just sent that; the duplicate was not delivered.".to_string() };
+                let _ = send_to_client(client, &notice, &state.config);
+                return;
+            }
+            client.last_message = Some((content.clone(), now));
+        }
+
+        if encrypted {
+            println!("Encrypted message from {}({}): {} bytes", &username, client_id, content.len());
+        } else {
+            let preview = truncate_graphemes(&content, LOG_PREVIEW_BYTES);
+            let ellipsis = if preview.len() < content.len() { "…" } else { "" };
+            println!("Message from {}({}): {}{}", &username, client_id, preview, ellipsis);
+        }
+        if let Some(client) = room.clients.get_mut(&client_id) {
+            client.messages_sent += 1;
+        }
+        let id = Uuid::new_v4();
+        let expires_at = ttl.map(|ttl| created_at + ttl);
+        new_msg = ServerMessage::NewMessage { id, username, content, encrypted, color, severity, reply_to, expires_at, sent_at: Some(created_at) };
+        let exclude = (!echo).then_some(client_id);
+        save = broadcast_message(new_msg.clone(), state, &mut rooms, room_name, exclude).await;
+
+        if state.config.delivery_receipts
+            && let Some(client) = rooms.get(room_name).and_then(|room| room.clients.get(&client_id))
+        {
+            let receipt = ServerMessage::Delivered { id, seq: save.seq(), server_time_ms: created_at.timestamp_millis() };
+            let _ = send_to_client(client, &receipt, &state.config);
+        }
+    } else {
+        return; // Room not found
+    }
+    drop(rooms);
+
+    // Persist the new message to the database
+    if persist_message(state, room_name, save, &new_msg, created_at).await.is_err() {
+        send_message(state, room_name, client_id, &not_saved_error()).await;
+    }
+    // Queued only once stored, so the sweeper never runs ahead of the row it deletes.
+    if let ServerMessage::NewMessage { id, expires_at: Some(expires_at), .. } = &new_msg {
+        state.expiries.schedule(room_name, *id, *expires_at);
+    }
+
+    // Flagged messages go to the moderators like a report, once they are stored and can be looked up.
+    if action == ModerationAction::Flag
+        && let ServerMessage::NewMessage { id, severity: Some(severity), .. } = &new_msg
+    {
+        let reason = Some(format!("content filter severity {}", severity));
+        let report = Report { message_id: *id, room: room_name.to_string(), reporter: CONTENT_FILTER_REPORTER.to_string(), reason, reported_at: created_at };
+        if matches!(db_call(state, state.store.add_report(&report)).await, Ok(true)) {
+            let alert = ServerMessage::ReportFiled { message_id: *id, room: report.room, reporter: report.reporter, reason: report.reason };
+            tap(state, room_name, &alert);
+            let rooms = state.rooms.lock().await;
+            alert_admins(&rooms, &alert, &state.config);
+        }
+    }
+
+    state.events.publish(|| ServerEvent::MessageSent { room: room_name.to_string(), client_id, message: new_msg, timestamp: created_at });
+}
+
+/// Validates a structured `Custom` message, then broadcasts and persists it verbatim.
+async fn handle_custom_message(
+    kind: String,
+    payload: serde_json::Value,
+    client_id: Uuid,
+    state: &ChatState,
+    room_name: &str,
+) {
+    if !ALLOWED_CUSTOM_KINDS.contains(&kind.as_str()) {
+        let notice = format!("Unsupported custom message kind '{}'. Allowed kinds: {}", kind, ALLOWED_CUSTOM_KINDS.join(", "));
+        send_notice(state, room_name, client_id, notice).await;
+        return;
+    }
+    if payload.to_string().len() > MAX_CUSTOM_PAYLOAD_BYTES {
+        let notice = format!("Custom payload is too large (max {} bytes).", MAX_CUSTOM_PAYLOAD_BYTES);
+        send_notice(state, room_name, client_id, notice).await;
+        return;
+    }
+
+    // Timestamp the message on arrival, before waiting for the lock or the database.
+    let created_at = Utc::now();
+    let mut rooms = state.rooms.lock().await;
+    let new_msg: ServerMessage;
+    let save: PendingSave;
+
+    if let Some(room) = rooms.get_mut(room_name) {
+        let username = match admit_message(room, client_id, state, room_name) {
+            Ok(username) => username,
+            Err(auto_mute) => {
+                drop(rooms);
+                if let Some((username, detail)) = auto_mute {
+                    log_audit(state, "auto_mute", RATE_LIMITER_ACTOR, Some(&username), Some(room_name), Some(detail)).await;
+                }
+                return;
+            }
+        };
+        let Some(echo) = room.clients.get(&client_id).map(|client| client.echo) else { return };
+
+        println!("Custom '{}' message from {}({})", &kind, &username, client_id);
+        if let Some(client) = room.clients.get_mut(&client_id) {
+            client.messages_sent += 1;
+        }
+        new_msg = ServerMessage::Custom { username, kind, payload };
+        let exclude = (!echo).then_some(client_id);
+        save = broadcast_message(new_msg.clone(), state, &mut rooms, room_name, exclude).await;
+    } else {
+        return; // Room not found
+    }
+    drop(rooms);
+
+    if persist_message(state, room_name, save, &new_msg, created_at).await.is_err() {
+        send_message(state, room_name, client_id, &not_saved_error()).await;
+    }
+    state.events.publish(|| ServerEvent::MessageSent { room: room_name.to_string(), client_id, message: new_msg, timestamp: created_at });
+}
+
+/// Broadcasts a message and adds it to the room's in-memory history cache. Returns the message's save, waited
+/// for until it is passed to `persist_message` or dropped. Its `seq` is the message's number as clients see it,
+/// the room's `next_seq` once the message is counted (see `render_sequenced`), or 0 if the room is gone.
+async fn broadcast_message(
+    message: ServerMessage,
+    state: &ChatState,
+    rooms: &mut MutexGuard<'_, HashMap<String, Room>>,
+    room_name: &str,
+    exclude_client_id: Option<Uuid>,
+) -> PendingSave {
+    if let Some(room) = rooms.get_mut(room_name) {
+        // Add message to the in-memory cache, ensuring it doesn't exceed the cache size.
+        let seq = room.next_seq;
+        room.push_history(message.clone());
+        tap(state, room_name, &message);
+
+        // Hold a copy for recently disconnected users who may still come back.
+        room.offline_buffers.retain(|_, buffer| !buffer.is_expired());
+        for buffer in room.offline_buffers.values_mut() {
+            buffer.push(seq, message.clone());
+        }
+
+        let anon_read = state.config.allows_anon_read(room_name);
+
+        // Render once per protocol rather than once per client.
+        let parsed_message = render_message(&message, ProtocolVersion::V1, &state.config);
+        let json_message = render_sequenced(&message, seq, ProtocolVersion::V2, &state.config);
+        let ack_id = match &message {
+            ServerMessage::NewMessage { id, .. } => Some(*id),
+            _ => None,
+        };
+        let author = message.author();
+        let mut failed = Vec::new();
+        for (id, client) in room.clients.iter_mut() {
+            if exclude_client_id == Some(*id) || (client.username.is_none() && !anon_read) {
+                continue;
+            }
+            if author.is_some_and(|author| client.ignored.contains(author)) {
+                continue;
+            }
+            let rendered = match client.protocol {
+                ProtocolVersion::V1 => parsed_message.clone(),
+                ProtocolVersion::V2 => json_message.clone(),
+            };
+            if let Some(message_id) = ack_id
+                && client.requires_acks(&state.config)
+            {
+                let pending = PendingAck { frame: rendered.clone(), sent_at: Instant::now(), resends: 0 };
+                client.unacked.insert(message_id, pending);
+            }
+            if client.sender.push(Message::Text(rendered.into())).is_err() {
+                println!("Failed to send parsed message to client {}", id);
+                failed.push(*id);
+            }
+        }
+
+        // A failed send means the connection is dead or the client fell too far behind, but a half-open
+        // socket may never end its read loop. Evict the client now and stop its reader so `cleanup_client`
+        // runs right away.
+        for id in failed {
+            if let Some(client) = room.clients.remove(&id) {
+                println!("Evicting client {} ({}) after a failed send.", id, client.display_name());
+                client.receive_task.abort();
+                room.evicted.insert(id, client.username);
+            }
+        }
+        state.saves.expect(room_name, room.next_seq)
+    } else {
+        PendingSave::default()
+    }
+}
+
+/// Renders a message that has a place in the room's history. JSON clients also get its `seq`, the
+/// room's running message number (from 1), so they can tell when they have missed messages.
+fn render_sequenced(message: &ServerMessage, seq: u64, protocol: ProtocolVersion, config: &Config) -> String {
+    match protocol {
+        ProtocolVersion::V1 => render_message(message, protocol, config),
+        ProtocolVersion::V2 => match serde_json::to_value(message) {
+            Ok(serde_json::Value::Object(mut frame)) => {
+                frame.insert("seq".to_string(), (seq + 1).into());
+                serde_json::Value::Object(frame).to_string()
+            }
+            _ => render_message(message, protocol, config),
+        },
+    }
+}
+
+/// Renders a message in the given protocol: plain text for v1, JSON for v2.
+fn render_message(message: &ServerMessage, protocol: ProtocolVersion, config: &Config) -> String {
+    match protocol {
+        ProtocolVersion::V1 => {
+            let line = parse_message_for_display(message, config);
+            // JSON clients can tell system messages by their type; plain-text ones only by a signature.
+            match &config.system_signing_secret {
+                Some(secret) if message.is_system() => auth::sign_line(&line, secret),
+                _ => line,
+            }
+        }
+        ProtocolVersion::V2 => serde_json::to_string(message).unwrap_or_else(|e| {
+            eprintln!("Failed to serialize message for client: {}", e);
+            String::new()
+        }),
+    }
+}
+
+/// Converts a ServerMessage to a human-readable format using the configured display templates.
+fn parse_message_for_display(message: &ServerMessage, config: &Config) -> String {
+    let templates = &config.templates;
+    // `{timestamp}` is when the message was sent, which for history and replays is well before now.
+    let timestamp = templates.timestamp_format.format(message.sent_at().unwrap_or_else(Utc::now));
+    match message {
+        ServerMessage::NewMessage { id, username, content, encrypted, .. } => {
+            let content = match config.display_max_bytes {
+                Some(limit) => truncate_for_display(content, limit),
+                None => Cow::Borrowed(content.as_str()),
+            };
+            templates.new_message.render(&[
+                ("id", &id.to_string()),
+                ("username", username),
+                // Plain-text clients can't decrypt, so they only learn that a message was sent.
+                ("content", if *encrypted { "[encrypted message]" } else { content.as_ref() }),
+                ("timestamp", &timestamp),
+            ])
+        }
+        ServerMessage::UserJoined { username, .. } => {
+            templates.user_joined.render(&[("username", username), ("timestamp", &timestamp)])
+        }
+        ServerMessage::UserRenamed { old_username, new_username } => {
+            format!("*** {} is now known as {}", old_username, new_username)
+        }
+        ServerMessage::UserLeft { username, message, .. } => {
+            let line = templates.user_left.render(&[("username", username), ("timestamp", &timestamp)]);
+            match message {
+                Some(message) => format!("{} ({})", line, message),
+                None => line,
+            }
+        }
+        ServerMessage::Custom { username, kind, payload } => format!("[{}] <{}> {}", username, kind, payload),
+        ServerMessage::ReactionAdded { message_id, username, emoji } => {
+            format!("*** {} reacted {} to {}", username, emoji, message_id)
+        }
+        ServerMessage::HistoryEntry { message, reactions, seen } => {
+            let line = parse_message_for_display(message, config);
+            let line = if *seen == Some(false) { format!("[new] {}", line) } else { line };
+            if reactions.is_empty() {
+                line
+            } else {
+                let counts: Vec<String> = reactions.iter().map(|(emoji, count)| format!("{} {}", emoji, count)).collect();
+                format!("{}  [{}]", line, counts.join(", "))
+            }
+        }
+        ServerMessage::Pong { server_time_ms } => format!("*** pong {}", server_time_ms),
+        ServerMessage::Delivered { id, seq, .. } => format!("*** delivered {} (#{})", id, seq),
+        ServerMessage::Motd { message } => format!("*** Message of the day: {}", message),
+        ServerMessage::Announcement { message } => format!("*** Announcement: {}", message),
+        ServerMessage::MessageDeleted { id } => format!("*** message {} was deleted", id),
+        ServerMessage::HistoryCleared { by } => format!("*** {} cleared the room's history", by),
+        ServerMessage::Unreadable { row_id } => format!("*** [stored message {} could not be read]", row_id),
+        ServerMessage::ReportFiled { message_id, room, reporter, reason } => {
+            let line = format!("*** {} reported message {} in '{}'", reporter, message_id, room);
+            match reason {
+                Some(reason) => format!("{}: {}", line, reason),
+                None => line,
+            }
+        }
+        ServerMessage::TopicChanged { topic, by } => format!("*** Topic: {} (set by {})", topic, by),
+        ServerMessage::MessagePinned { message, by } => {
+            format!("*** {} pinned: {}", by, parse_message_for_display(message, config))
+        }
+        ServerMessage::OwnershipChanged { owner: Some(owner), operators } if operators.is_empty() => {
+            format!("*** {} owns this room", owner)
+        }
+        ServerMessage::OwnershipChanged { owner: Some(owner), operators } => {
+            format!("*** {} owns this room (operators: {})", owner, operators.join(", "))
+        }
+        ServerMessage::OwnershipChanged { owner: None, .. } => "*** This room no longer has an owner".to_string(),
+        ServerMessage::FetchedMessage { message } => parse_message_for_display(message, config),
+        ServerMessage::Thread { root, replies } => {
+            let mut lines = vec![format!("*** Thread ({} replies):", replies.len()), parse_message_for_display(root, config)];
+            for ThreadReply { depth, message } in replies {
+                lines.push(format!("{}↳ {}", "  ".repeat(*depth as usize), parse_message_for_display(message, config)));
+            }
+            lines.join("\n")
+        }
+        ServerMessage::MessageUnpinned { message_id, by } => format!("*** {} unpinned message {}", by, message_id),
+        ServerMessage::PresenceSnapshot { users, .. } => {
+            let names: Vec<&str> = users.iter().map(|user| user.username.as_str()).collect();
+            format!("*** In the room: {}", names.join(", "))
+        }
+        ServerMessage::PinnedMessages { messages } => {
+            let lines: Vec<String> = messages.iter().map(|message| parse_message_for_display(message, config)).collect();
+            format!("*** Pinned:\n{}", lines.join("\n"))
+        }
+        ServerMessage::RoomMetadata { room, metadata } if metadata.is_empty() => format!("*** Room '{}' has no metadata", room),
+        ServerMessage::RoomMetadata { room, metadata } => {
+            let entries: Vec<String> = metadata.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+            format!("*** Room '{}' metadata: {}", room, entries.join(", "))
+        }
+        ServerMessage::PublicKeys { room, keys } if keys.is_empty() => format!("*** Nobody in room '{}' has published a key", room),
+        ServerMessage::PublicKeys { room, keys } => {
+            let entries: Vec<String> = keys.iter().map(|(username, key)| format!("{}: {}", username, key)).collect();
+            format!("*** Public keys in room '{}':\n{}", room, entries.join("\n"))
+        }
+        ServerMessage::SessionStats { client_id, username, room, joined_at, messages_sent } => format!(
+            "*** You are {} ({}) in room '{}', connected since {}, {} messages sent",
+            username.as_deref().unwrap_or("anonymous"),
+            client_id,
+            room,
+            joined_at.format("%Y-%m-%d %H:%M:%S UTC"),
+            messages_sent
+        ),
+        ServerMessage::MessageCount { room, count } => format!("*** Room '{}' has {} stored messages", room, count),
+        ServerMessage::InviteCreated { room, token, expires_at } => format!(
+            "*** Invite to '{}': connect with ?invite={} (single use, expires {})",
+            room,
+            token,
+            expires_at.format("%Y-%m-%d %H:%M UTC")
+        ),
+        ServerMessage::Error { message } => format!("!!! {}", message),
+        ServerMessage::Notice { message } => message.clone(),
+        ServerMessage::Welcome { client_id, room, server_version, .. } => {
+            format!("*** Welcome to '{}'! Your client id is {} (server v{})", room, client_id, server_version)
+        }
+    }
+}
+
+/// Starts a graceful shutdown: refuses new connections, gives existing ones the configured drain
+/// period to leave, then closes whatever is left.
+pub async fn shut_down(state: &ChatState) {
+    println!("Shutdown requested; refusing new connections and draining existing ones...");
+    state.shutting_down.store(true, Ordering::SeqCst);
+
+    let drain = async {
+        while !state.rooms.lock().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    };
+    if tokio::time::timeout(state.config.shutdown_drain, drain).await.is_err() {
+        println!("Drain period elapsed; closing remaining connections.");
+        close_all_connections(state).await;
+    }
+}
+
+/// Waits for every connection handler to finish, including the messages it still had to persist,
+/// so exiting the process doesn't cut off writes for messages clients have already seen.
+pub async fn finish_connections(state: &ChatState) {
+    let open = state.connections.count();
+    if open > 0 {
+        println!("Waiting for {} connection(s) to finish writing...", open);
+    }
+    // Each write is itself bounded by `db_timeout`; allow for a handful of them per connection.
+    if tokio::time::timeout(state.config.db_timeout * 3, state.connections.wait_idle()).await.is_err() {
+        eprintln!("{} connection(s) still busy at exit; their pending writes are lost.", state.connections.count());
+    }
+}
+
+/// Closes every remaining connection at the end of a shutdown drain.
+pub async fn close_all_connections(state: &ChatState) {
+    let mut rooms = state.rooms.lock().await;
+    for room in rooms.values_mut() {
+        for client in room.clients.values() {
+            client.sender.close(CloseReason::ShuttingDown);
+        }
+    }
+}
+
+/// Delivers a scheduled announcement to everyone in its room, or in every room for `*`. Nobody who
+/// joins later sees it, and an announcement for a room that is empty when it falls due is dropped.
+pub async fn deliver_announcement(state: &ChatState, scheduled: &ScheduledMessage) {
+    let message = ServerMessage::Announcement { message: scheduled.text.clone() };
+    let rooms = state.rooms.lock().await;
+    let targets: Vec<&Room> = if scheduled.room == "*" {
+        rooms.values().collect()
+    } else {
+        rooms.get(&scheduled.room).into_iter().collect()
+    };
+    if targets.is_empty() {
+        println!("Scheduled announcement {} for '{}' had nobody to deliver to.", scheduled.id, scheduled.room);
+    }
+    for room in targets {
+        announce(room, &message, &state.config);
+    }
+}
+
+/// Deletes a chat message whose `expires_in_secs` has run out: from the store, then from the room's cache, pins and
+/// offline buffers, and tells everyone in the room to remove it. Cached copies are replaced by the `MessageDeleted`
+/// notice rather than removed, so the sequence numbers of the messages around them stay the same. If the store
+/// can't delete it, nothing else changes and the deletion is retried after `EXPIRY_RETRY`.
+pub async fn expire_message(state: &ChatState, room_name: &str, message_id: Uuid) {
+    if db_call(state, state.store.delete_message(room_name, message_id)).await.is_err() {
+        eprintln!("Could not delete expired message {} in room '{}'; retrying in {:?}.", message_id, room_name, EXPIRY_RETRY);
+        state.expiries.schedule(room_name, message_id, Utc::now() + EXPIRY_RETRY);
+        return;
+    }
+
+    let Some(_turn) = settings_turn(state, room_name).await else { return };
+    let pinned_ids = {
+        let mut rooms = state.rooms.lock().await;
+        let Some(room) = rooms.get_mut(room_name) else { return };
+        let deleted = ServerMessage::MessageDeleted { id: message_id };
+        for cached in room.history.iter_mut().filter(|message| chat_message_id(message) == Some(message_id)) {
+            *cached = deleted.clone();
+        }
+        for buffer in room.offline_buffers.values_mut() {
+            for (_, buffered) in buffer.messages.iter_mut().filter(|(_, message)| chat_message_id(message) == Some(message_id)) {
+                *buffered = deleted.clone();
+            }
+        }
+        let unpinned = room.pinned.iter().any(|pinned| chat_message_id(pinned) == Some(message_id));
+        room.pinned.retain(|pinned| chat_message_id(pinned) != Some(message_id));
+        println!("Message {} in room '{}' expired.", message_id, room_name);
+        tap(state, room_name, &deleted);
+        announce(room, &deleted, &state.config);
+        unpinned.then(|| room.pinned.iter().filter_map(chat_message_id).collect::<Vec<Uuid>>())
+    };
+    // Saved once the lock is released, so a slow store doesn't hold up every room.
+    if let Some(ids) = pinned_ids {
+        let _ = db_call(state, state.store.set_pinned_ids(room_name, &ids)).await;
+    }
+}
+
+/// When the owner's last connection leaves, the room passes to someone still here, or to nobody; an operator's
+/// last connection leaving ends their rights, so nobody who takes the name later inherits them.
+/// Returns the new ownership if it changed, for the caller to persist once the lock is released.
+fn hand_off_ownership(room: &mut Room, leaving: &str, room_name: &str, config: &Config) -> Option<RoomOwnership> {
+    if !room.ownership.includes(leaving) || room.clients.values().any(|client| client.username.as_deref() == Some(leaving)) {
+        return None;
+    }
+    if room.ownership.owner.as_deref() == Some(leaving) {
+        room.ownership.owner = room.next_owner();
+        room.ownership.operators.retain(|op| room.ownership.owner.as_ref() != Some(op));
+        println!("Owner '{}' left room '{}'; it now belongs to {:?}.", leaving, room_name, room.ownership.owner);
+    } else {
+        room.ownership.operators.retain(|op| op != leaving);
+        println!("Operator '{}' left room '{}'.", leaving, room_name);
+    }
+    announce(room, &ownership_message(room), config);
+    Some(room.ownership.clone())
+}
+
+/// Removes a client from the state, announces their departure, and saves the event to the DB.
+async fn cleanup_client(state: &ChatState, client_id: Uuid, room_name: &str, disconnect: Disconnect) {
+    let mut username = None;
+    let mut silent = false;
+    let mut ownership_changed = None;
+    let mut emptied = false;
+    let mut emptied_at_seq = None;
+    let mut left = None;
+
+    let mut rooms = state.rooms.lock().await;
+    if let Some(room) = rooms.get_mut(room_name) {
+        if let Some(client) = room.clients.remove(&client_id) {
+            username = client.username;
+            // A stale client's departure was announced when it went quiet.
+            silent = client.silent || client.stale;
+        } else if let Some(evicted_username) = room.evicted.remove(&client_id) {
+            username = evicted_username;
+        }
+
+        if let Some(name) = &username {
+            ownership_changed = hand_off_ownership(room, name, room_name, &state.config);
+        }
+        emptied = room.clients.is_empty();
+    }
+
+    // Announced before an emptied room is dropped, so even the last leave gets the room's next number.
+    // Nobody was told a silent client arrived, so nobody is told it left.
+    if let Some(username) = &username
+        && !silent
+    {
+        println!("Broadcasting leave message for {} from room '{}'", username, room_name);
+        let message = match &disconnect {
+            Disconnect::Quit(parting) => parting.clone(),
+            Disconnect::Dropped => None,
+        };
+        let created_at = Utc::now();
+        let left_msg = ServerMessage::UserLeft { username: username.clone(), message, sent_at: Some(created_at) };
+        let save = broadcast_message(left_msg.clone(), state, &mut rooms, room_name, None).await;
+
+        // Start buffering for this user in case they reconnect shortly, unless they're still here on another connection.
+        if let Some(room) = rooms.get_mut(room_name)
+            && !emptied
+            && !room.clients.values().any(|client| client.username.as_ref() == Some(username))
+        {
+            room.offline_buffers.insert(username.clone(), OfflineBuffer::new());
+        }
+        left = Some((save, left_msg, created_at));
+    }
+
+    if emptied {
+        println!("Room '{}' is empty, removing it.", room_name);
+        emptied_at_seq = rooms.remove(room_name).map(|room| room.next_seq);
+        // Until it is recorded below, a reopening takes the room's numbering from here rather than the store.
+        if let Some(seq) = emptied_at_seq {
+            state.saves.room_emptied(room_name, seq);
+        }
+    }
+    drop(rooms);
+
+    if let Some(ownership) = &ownership_changed {
+        save_ownership(state, room_name, ownership).await;
+    }
+    if let Some((save, left_msg, created_at)) = left {
+        let _ = persist_message(state, room_name, save, &left_msg, created_at).await;
+    }
+    // Saved messages carry their own numbers; this keeps the ones that were never saved, or have since been
+    // deleted, from being handed out again when the room is next opened.
+    if let Some(seq) = emptied_at_seq
+        && db_call(state, state.store.set_latest_seq(room_name, seq)).await.is_ok()
+    {
+        state.saves.seq_recorded(room_name, seq);
+    }
+
+    let display_name = username.as_deref().unwrap_or("anonymous");
+    match disconnect {
+        Disconnect::Quit(_) => println!("Client {} ({}) quit room '{}'.", client_id, display_name, room_name),
+        Disconnect::Dropped => println!("Client {} ({}) disconnected from room '{}'.", client_id, display_name, room_name),
+    }
+    state.events.publish(|| ServerEvent::ClientLeft { room: room_name.to_string(), client_id, username });
 }
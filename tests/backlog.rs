@@ -0,0 +1,55 @@
+// tests/backlog.rs
+
+mod common;
+
+use chat_server::outbound::ShedPolicy;
+use common::TestServer;
+use serde_json::{json, Value};
+
+const ROOM_LIMIT: usize = 20;
+
+#[tokio::test]
+async fn burst_to_a_stalled_reader_is_shed_at_the_room_limit() {
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.room_max_in_flight = Some(ROOM_LIMIT);
+        config.room_shed_policy = ShedPolicy::DropOldest;
+        config.outbound_queue_size = 100_000;
+        config.frame_rate_limit = 100_000;
+        config.max_inbound_bytes = None;
+    })
+    .await;
+
+    let mut sender = server.connect("burst").await;
+    let mut stalled = server.connect("burst").await;
+    sender.recv_type("Welcome").await;
+    stalled.recv_type("Welcome").await;
+    sender.join_as("sender").await;
+    stalled.join_as("stalled").await;
+    sender.recv_type("UserJoined").await;
+
+    // `stalled` never reads again, so once its socket buffers fill up everything queues on the server.
+    let content = "x".repeat(32 * 1024);
+    for _ in 0..1000 {
+        sender.send_json(json!({ "type": "Message", "content": content })).await;
+    }
+    // Commands are handled in order, so the pong means every message has been broadcast.
+    sender.send_text("/ping").await;
+    sender.recv_type("Pong").await;
+
+    let metrics: Value = reqwest::Client::new()
+        .get(server.url("/admin/metrics"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let room = &metrics["rooms"][0];
+    assert_eq!(room["room"], "burst");
+    // A client with an empty queue may still get one message while the room is at the limit.
+    let high_water = room["high_water"].as_u64().unwrap() as usize;
+    assert!(high_water <= ROOM_LIMIT + 2, "high water mark {} exceeds the limit", high_water);
+    assert!(room["shed"].as_u64().unwrap() > 0, "nothing was shed: {}", room);
+}
@@ -0,0 +1,3450 @@
+// tests/chat.rs
+
+mod common;
+
+use chat_server::auth::{token_matches, verify_signed_line};
+use chat_server::config::{ContentNormalization, PersistTypes, RoomAliases, RoomNameNormalization, RoomRateLimits, Template};
+use chat_server::database::PostgresStore;
+use chat_server::memory_store::MemoryStore;
+use chat_server::models::ServerMessage;
+use chat_server::outbound::DropPolicy;
+use chat_server::sqlite_store::SqliteStore;
+use chat_server::state::{IN_MEMORY_CACHE_SIZE, OFFLINE_BUFFER_SIZE};
+use chat_server::store::{MessageStore, ScheduledMessage};
+use chat_server::throttle::TokenBucket;
+use chat_server::websocket::{finish_connections, shut_down};
+use chrono::Utc;
+use common::hooked_store::HookedStore;
+use common::{TestClient, TestServer};
+use serde_json::{json, Value};
+use sqlx::{Connection, SqliteConnection};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::HeaderValue,
+    protocol::frame::{coding::{Data, OpCode}, Frame},
+    Error as WsError, Message,
+};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn message_from_one_client_reaches_the_other() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    // Alice is told about Bob joining, which also means both names are set.
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+
+    alice.send_json(json!({ "type": "Message", "content": "hello bob" })).await;
+
+    let message = bob.recv_type("NewMessage").await;
+    assert_eq!(message["username"], "alice");
+    assert_eq!(message["content"], "hello bob");
+}
+
+#[tokio::test]
+async fn ping_replies_with_the_server_time() {
+    let server = TestServer::start().await;
+    let mut client = server.connect("general").await;
+    client.recv_type("Welcome").await;
+
+    let sent_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+    client.send_text("/ping").await;
+    let pong = tokio::time::timeout(Duration::from_secs(1), client.recv_type("Pong")).await.expect("no timely pong");
+
+    let server_time_ms = pong["server_time_ms"].as_i64().unwrap();
+    let received_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+    assert!((sent_at..=received_at).contains(&server_time_ms), "{} not within [{}, {}]", server_time_ms, sent_at, received_at);
+}
+
+#[tokio::test]
+async fn room_username_pattern_is_enforced() {
+    let server = TestServer::start().await;
+    server.state.store.set_username_pattern("corp", Some(r"[a-z]+\.[a-z]+")).await.unwrap();
+
+    let mut client = server.connect("corp").await;
+    client.recv_type("Welcome").await;
+
+    client.join_as("alice").await;
+    let notice = client.recv_type("Notice").await;
+    assert!(notice["message"].as_str().unwrap().contains(r"[a-z]+\.[a-z]+"), "{}", notice);
+
+    client.join_as("alice.smith").await;
+    client.send_json(json!({ "type": "Message", "content": "hi" })).await;
+    client.send_text("/history-count").await;
+    assert_eq!(client.recv_type("MessageCount").await["count"], 2); // the join and the message
+}
+
+#[tokio::test]
+async fn delivery_receipts_carry_the_servers_id_and_seq() {
+    let server = TestServer::start_with(|config| {
+        config.delivery_receipts = true;
+        config.echo_own_messages = true;
+    })
+    .await;
+    let mut alice = server.connect("lobby").await;
+    assert!(alice.recv_type("Welcome").await["features"].as_array().unwrap().iter().any(|f| f == "delivery-receipts"));
+    alice.join_as("alice").await;
+
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+    alice.send_json(json!({ "type": "Message", "content": "optimistic" })).await;
+    let echoed = alice.recv_type("NewMessage").await;
+    let receipt = alice.recv_type("Delivered").await;
+    assert_eq!(receipt["id"], echoed["id"]);
+    assert_eq!(receipt["seq"], echoed["seq"]);
+    assert_eq!(receipt["seq"], 2); // after alice's join
+    assert!(receipt["server_time_ms"].as_i64().unwrap() >= before);
+}
+
+#[tokio::test]
+async fn published_public_keys_are_listed_for_every_member() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("secret-club").await;
+    let mut bob = server.connect("secret-club").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+
+    alice.send_text("/key publish bm90LWEta2V5").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "Please set a username with `/user <name>` before publishing a key.");
+
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.send_text("/key publish YWxpY2Uta2V5").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "Published your public key for 'alice'.");
+    bob.send_text("/key publish Ym9iLWtleQ==").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "Published your public key for 'bob'.");
+
+    alice.send_text("/keys").await;
+    let keys = alice.recv_type("PublicKeys").await;
+    assert_eq!(keys["keys"], json!({ "alice": "YWxpY2Uta2V5", "bob": "Ym9iLWtleQ==" }));
+    assert_eq!(server.state.store.get_public_keys("secret-club").await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn repeated_flooding_earns_longer_mutes() {
+    let server = TestServer::start_with(|config| {
+        config.message_rate_limit = Some(2);
+        config.message_rate_window = Duration::from_secs(10);
+        config.auto_mute_durations = vec![Duration::from_secs(1), Duration::from_secs(3)];
+    })
+    .await;
+    // The room's first member owns it, and moderators aren't rate limited.
+    let mut owner = server.connect("lobby").await;
+    owner.recv_type("Welcome").await;
+    owner.join_as("owner").await;
+    owner.send_text("/ping").await;
+    owner.recv_type("Pong").await;
+    let mut alice = server.connect("lobby").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    let flood = |n: usize| json!({ "type": "Message", "content": format!("spam {}", n) });
+
+    for n in 0..3 {
+        alice.send_json(flood(n)).await;
+    }
+    assert_eq!(alice.recv_type("Notice").await["message"], "You are sending messages too fast and have been muted for 1 seconds.");
+    alice.send_json(flood(3)).await;
+    assert!(alice.recv_type("Notice").await["message"].as_str().unwrap().starts_with("You are muted for another"));
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    for n in 4..7 {
+        alice.send_json(flood(n)).await;
+    }
+    assert_eq!(alice.recv_type("Notice").await["message"], "You are sending messages too fast and have been muted for 3 seconds.");
+
+    let audit = server.state.store.load_audit_log(Some("lobby"), 1, 10).await.unwrap();
+    let mutes: Vec<_> = audit.iter().filter(|entry| entry.action == "auto_mute").filter_map(|entry| entry.detail.as_deref()).collect();
+    assert_eq!(mutes, ["duration=3s violations=2", "duration=1s violations=1"]);
+    // Only the messages within the limit were delivered.
+    alice.send_text("/history-count").await;
+    assert_eq!(alice.recv_type("MessageCount").await["count"], 6); // two joins and four messages
+}
+
+#[tokio::test]
+async fn room_rate_limit_is_shared_by_all_members() {
+    let server = TestServer::start_with(|config| {
+        config.room_rate_limits = RoomRateLimits::new(HashMap::from([("busy".to_string(), 2.0)]));
+    })
+    .await;
+    // The owner is a moderator, so exempt from the room's rate.
+    let mut owner = server.connect("busy").await;
+    owner.recv_type("Welcome").await;
+    owner.join_as("owner").await;
+    owner.send_text("/ping").await;
+    owner.recv_type("Pong").await;
+    let mut members = Vec::new();
+    for name in ["alice", "bob", "carol"] {
+        let mut member = server.connect("busy").await;
+        member.recv_type("Welcome").await;
+        member.join_as(name).await;
+        members.push(member);
+    }
+    let too_busy = "This room is too busy right now; your message was not delivered. Try again in 1 seconds.";
+
+    // Each member stays far below any per-user limit, but together they use up the room's rate.
+    for (n, member) in members.iter_mut().take(2).enumerate() {
+        member.send_json(json!({ "type": "Message", "content": format!("hello {}", n) })).await;
+        assert_eq!(owner.recv_type("NewMessage").await["content"], format!("hello {}", n));
+    }
+    members[2].send_json(json!({ "type": "Message", "content": "one too many" })).await;
+    assert_eq!(members[2].recv_type("Notice").await["message"], too_busy);
+    members[0].send_json(json!({ "type": "Message", "content": "me too" })).await;
+    assert_eq!(members[0].recv_type("Notice").await["message"], too_busy);
+
+    owner.send_json(json!({ "type": "Message", "content": "calm down" })).await;
+    assert_eq!(members[2].recv_type("NewMessage").await["content"], "calm down");
+
+    // Other rooms aren't limited, and the room accepts messages again once its rate allows.
+    let mut elsewhere = server.connect("quiet").await;
+    elsewhere.recv_type("Welcome").await;
+    elsewhere.join_as("dave").await;
+    for n in 0..5 {
+        elsewhere.send_json(json!({ "type": "Message", "content": format!("quiet {}", n) })).await;
+    }
+    elsewhere.send_text("/history-count").await;
+    assert_eq!(elsewhere.recv_type("MessageCount").await["count"], 6); // the join and five messages
+
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    members[2].send_json(json!({ "type": "Message", "content": "finally" })).await;
+    assert_eq!(owner.recv_type("NewMessage").await["content"], "finally");
+}
+
+#[test]
+fn room_rates_too_slow_to_wait_for_are_ignored() {
+    let limits = RoomRateLimits::new(HashMap::from([
+        ("*".to_string(), 1e-300),
+        ("slow".to_string(), 1.0 / 3600.0),
+        ("exempt".to_string(), 0.0),
+    ]));
+    assert_eq!(limits.rate("anywhere"), None);
+    assert_eq!(limits.rate("slow"), Some(1.0 / 3600.0));
+    assert_eq!(limits.rate("exempt"), None);
+
+    // Even a bucket refilling absurdly slowly reports a wait instead of panicking.
+    let now = Instant::now();
+    let mut bucket = TokenBucket::full(1e-300, now);
+    assert!(bucket.take(1e-300, now).is_ok());
+    assert_eq!(bucket.take(1e-300, now), Err(Duration::MAX));
+}
+
+#[tokio::test]
+async fn inbound_byte_budget_is_counted_per_window() {
+    let server = TestServer::start_with(|config| {
+        config.max_inbound_bytes = Some(1000);
+        config.inbound_window = Duration::from_secs(1);
+    })
+    .await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    let chunk = |n: usize| json!({ "type": "Message", "content": format!("{} {}", n, "x".repeat(400)) });
+
+    // Well-behaved clients can send far more than the budget over the life of the connection.
+    for window in 0..3 {
+        alice.send_json(chunk(2 * window)).await;
+        alice.send_json(chunk(2 * window + 1)).await;
+        alice.send_text("/ping").await;
+        alice.recv_type("Pong").await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+    }
+
+    for n in 10..13 {
+        alice.send_json(chunk(n)).await;
+    }
+    assert_eq!(alice.recv_until_closed().await, Some(4010));
+}
+
+#[tokio::test]
+async fn anonymous_clients_are_only_timed_out_when_configured() {
+    let server = TestServer::start().await;
+    assert_eq!(server.state.config.anon_timeout, None, "the timeout is opt-in");
+
+    let server = TestServer::start_with(|config| config.anon_timeout = Some(Duration::from_millis(200))).await;
+    let mut lurker = server.connect("general").await;
+    lurker.recv_type("Welcome").await;
+    let mut named = server.connect("general").await;
+    named.recv_type("Welcome").await;
+    named.join_as("alice").await;
+    assert_eq!(lurker.recv_until_closed().await, Some(4009));
+
+    named.send_text("/ping").await;
+    named.recv_type("Pong").await;
+}
+
+#[tokio::test]
+async fn admin_monitor_sees_events_from_every_room() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let anonymous = tokio_tungstenite::connect_async(format!("ws://{}/ws/admin", server.addr)).await;
+    assert!(matches!(anonymous, Err(WsError::Http(response)) if response.status() == 401));
+
+    let mut monitor = server.connect_monitor("secret").await;
+    let mut alice = server.connect("lobby").await;
+    let mut bob = server.connect("games").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.send_json(json!({ "type": "Message", "content": "hello lobby" })).await;
+    bob.send_json(json!({ "type": "Message", "content": "hello games" })).await;
+
+    let mut seen = Vec::new();
+    while seen.len() < 4 {
+        let frame = monitor.recv_json().await;
+        let event = &frame["event"];
+        let what = match event["type"].as_str().unwrap() {
+            "UserJoined" => format!("{} joined", event["username"].as_str().unwrap()),
+            "NewMessage" => event["content"].as_str().unwrap().to_string(),
+            other => panic!("unexpected event {}", other),
+        };
+        seen.push((frame["room"].as_str().unwrap().to_string(), what));
+    }
+    seen.sort();
+    let expected = [("games", "bob joined"), ("games", "hello games"), ("lobby", "alice joined"), ("lobby", "hello lobby")];
+    assert_eq!(seen, expected.map(|(room, what)| (room.to_string(), what.to_string())));
+}
+
+#[tokio::test]
+async fn history_endpoints_throttle_clients_that_hammer_them() {
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.rest_rate_limit = Some(3);
+    })
+    .await;
+    let http = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for _ in 0..5 {
+        let response = http.get(server.url("/rooms/lobby/export")).bearer_auth("secret").send().await.unwrap();
+        statuses.push(response.status().as_u16());
+        if response.status() == 429 {
+            let retry_after: u64 = response.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+            assert!((1..=60).contains(&retry_after));
+        }
+    }
+    assert_eq!(statuses, [200, 200, 200, 429, 429]);
+    // Other endpoints aren't counted.
+    let metrics = http.get(server.url("/admin/metrics")).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(metrics.status(), 200);
+}
+
+#[tokio::test]
+async fn history_endpoints_cap_the_bytes_sent_to_each_client() {
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.rest_max_response_bytes = Some(1000);
+    })
+    .await;
+    for i in 0..20 {
+        let message = ServerMessage::NewMessage {
+            id: Uuid::new_v4(),
+            username: "alice".to_string(),
+            content: format!("message {} {}", i, "x".repeat(50)),
+            encrypted: false,
+            color: None,
+            severity: None,
+            reply_to: None,
+            expires_at: None,
+            sent_at: None,
+        };
+        server.state.store.save_message("lobby", 0, &message, Utc::now()).await.unwrap();
+    }
+
+    let http = reqwest::Client::new();
+    let export = || http.get(server.url("/rooms/lobby/export")).bearer_auth("secret").send();
+    let first = export().await.unwrap();
+    assert_eq!(first.status(), 200);
+    // The export that crosses the cap is still sent in full.
+    assert_eq!(first.text().await.unwrap().lines().count(), 20);
+    assert_eq!(export().await.unwrap().status(), 429);
+}
+
+#[tokio::test]
+async fn too_short_usernames_are_refused() {
+    let server = TestServer::start_with(|config| config.min_username_chars = 3).await;
+    let mut client = server.connect("lobby").await;
+    client.recv_type("Welcome").await;
+
+    client.join_as("al").await;
+    assert_eq!(client.recv_type("Notice").await["message"], "'al' is too short: usernames must be at least 3 characters.");
+    client.join_as("ali").await;
+    client.send_text("/stats-me").await;
+    assert_eq!(client.recv_type("SessionStats").await["username"], "ali");
+}
+
+#[tokio::test]
+async fn reserved_usernames_are_left_to_administrators() {
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.reserved_usernames = vec!["admin".to_string(), "helpdesk".to_string()];
+    })
+    .await;
+    let mut bob = server.connect("lobby").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("HelpDesk").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "'HelpDesk' is reserved for administrators.");
+
+    let mut admin = server.connect("lobby").await;
+    admin.recv_type("Welcome").await;
+    admin.send_text("/admin secret").await;
+    admin.recv_type("Notice").await;
+    admin.join_as("helpdesk").await;
+    admin.send_text("/stats-me").await;
+    assert_eq!(admin.recv_type("SessionStats").await["username"], "helpdesk");
+}
+
+#[tokio::test]
+async fn messages_sent_before_shutdown_are_persisted() {
+    let server = TestServer::start_with(|config| config.shutdown_drain = Duration::ZERO).await;
+    let mut client = server.connect("general").await;
+    client.recv_type("Welcome").await;
+    client.join_as("alice").await;
+
+    for i in 0..20 {
+        client.send_json(json!({ "type": "Message", "content": format!("message {}", i) })).await;
+    }
+    shut_down(&server.state).await;
+    assert_eq!(client.recv_until_closed().await, Some(1001));
+    finish_connections(&server.state).await;
+
+    // The join, all 20 messages and the leave.
+    assert_eq!(server.state.store.get_message_count("general").await.unwrap(), 22);
+}
+
+#[tokio::test]
+async fn color_is_attached_to_later_messages() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    alice.send_text("/color #FF8800").await;
+    alice.recv_type("Notice").await;
+    alice.send_json(json!({ "type": "Message", "content": "in color" })).await;
+
+    let message = bob.recv_type("NewMessage").await;
+    assert_eq!(message["color"], "#ff8800");
+}
+
+/// Starts a server requiring acks, with Alice connected and Bob (who receives her messages) joined.
+async fn ack_setup() -> (TestServer, common::TestClient, common::TestClient) {
+    let server = TestServer::start_with(|config| {
+        config.ack_timeout = Some(Duration::from_millis(200));
+        config.ack_max_resends = 2;
+    })
+    .await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+    (server, alice, bob)
+}
+
+#[tokio::test]
+async fn acknowledged_messages_are_not_resent() {
+    let (_server, mut alice, mut bob) = ack_setup().await;
+
+    alice.send_json(json!({ "type": "Message", "content": "hello" })).await;
+    let message = bob.recv_type("NewMessage").await;
+    bob.send_json(json!({ "type": "Ack", "message_id": message["id"] })).await;
+
+    bob.expect_silence(Duration::from_millis(600)).await;
+}
+
+#[tokio::test]
+async fn unacknowledged_messages_are_resent_then_the_client_is_closed() {
+    let (_server, mut alice, mut bob) = ack_setup().await;
+
+    alice.send_json(json!({ "type": "Message", "content": "hello" })).await;
+    let first = bob.recv_type("NewMessage").await;
+    for _ in 0..2 {
+        assert_eq!(bob.recv_type("NewMessage").await, first);
+    }
+    assert_eq!(bob.recv_until_closed().await, Some(4011));
+}
+
+#[tokio::test]
+async fn clients_on_different_aliases_share_a_room() {
+    let aliases = HashMap::from([
+        ("lobby".to_string(), "general".to_string()),
+        ("hall".to_string(), "lobby".to_string()),
+        ("loop-a".to_string(), "loop-b".to_string()),
+        ("loop-b".to_string(), "loop-a".to_string()),
+    ]);
+    let server = TestServer::start_with(|config| config.room_aliases = RoomAliases::new(aliases)).await;
+    assert_eq!(server.state.config.room_aliases.resolve("loop-a"), "loop-a");
+
+    let mut alice = server.connect("lobby").await;
+    let mut bob = server.connect("hall").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+
+    alice.send_json(json!({ "type": "Message", "content": "same room?" })).await;
+    let message = bob.recv_type("NewMessage").await;
+    assert_eq!(message["content"], "same room?");
+    assert!(server.state.rooms.lock().await.contains_key("general"));
+}
+
+#[tokio::test]
+async fn differently_cased_room_urls_share_a_room_when_normalized() {
+    assert_eq!(RoomNameNormalization::AsIs.normalize("General"), "General");
+    let server =
+        TestServer::start_with(|config| config.room_name_normalization = RoomNameNormalization::Strict).await;
+
+    let mut alice = server.connect("General").await;
+    let mut bob = server.connect("%20general%20").await;
+    assert_eq!(alice.recv_type("Welcome").await["room"], "general");
+    assert_eq!(bob.recv_type("Welcome").await["room"], "general");
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+
+    alice.send_json(json!({ "type": "Message", "content": "one room" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "one room");
+    let rooms = server.state.rooms.lock().await;
+    assert!(rooms.contains_key("general") && !rooms.contains_key("General"));
+}
+
+#[tokio::test]
+async fn silent_join_is_not_announced() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+
+    let mut moderator = server.connect("general").await;
+    moderator.recv_type("Welcome").await;
+    moderator.send_text("/admin secret").await;
+    moderator.recv_type("Notice").await;
+    moderator.send_text("/user mod --silent").await;
+    // History is still replayed to the silent joiner.
+    assert_eq!(moderator.recv_type("UserJoined").await["username"], "alice");
+
+    let mut bob = server.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    // The first join Alice hears about is Bob's.
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+}
+
+#[tokio::test]
+async fn pinned_messages_are_sent_to_new_joiners() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut admin = server.connect("general").await;
+    admin.recv_type("Welcome").await;
+    admin.send_text("/admin secret").await;
+    admin.recv_type("Notice").await;
+    admin.send_text("/echo on").await;
+    admin.join_as("admin").await;
+    admin.send_json(json!({ "type": "Message", "content": "read the rules" })).await;
+    let id = admin.recv_type("NewMessage").await["id"].as_str().unwrap().to_string();
+
+    admin.send_text(&format!("/pin {}", id)).await;
+    let pinned = admin.recv_type("MessagePinned").await;
+    assert_eq!(pinned["message"]["content"], "read the rules");
+    assert_eq!(pinned["by"], "admin");
+
+    let mut bob = server.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    let pins = bob.recv_type("PinnedMessages").await;
+    assert_eq!(pins["messages"][0]["id"], id.as_str());
+    assert_eq!(pins["messages"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn admin_tokens_must_match_exactly() {
+    assert!(token_matches("secret", "secret"));
+    assert!(!token_matches("secreT", "secret"));
+    assert!(!token_matches("secre", "secret"));
+    assert!(!token_matches("secrets", "secret"));
+    assert!(!token_matches("", "secret"));
+}
+
+#[tokio::test]
+async fn forged_system_lines_are_not_system_events() {
+    let server = TestServer::start_with(|config| config.system_signing_secret = Some("signing-key".to_string())).await;
+    let mut alice = server.connect("general").await;
+    let mut carol = server.connect_with_protocol("general", None).await;
+    alice.recv_type("Welcome").await;
+    carol.recv_text().await; // welcome line
+    alice.join_as("alice").await;
+    carol.send_text("/user carol").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "carol");
+
+    let mut mallory = server.connect("general").await;
+    mallory.recv_type("Welcome").await;
+    mallory.join_as("mallory").await;
+    let mut joined = carol.recv_text().await;
+    while !joined.contains("mallory") {
+        joined = carol.recv_text().await; // Alice's replayed join comes first
+    }
+    assert_eq!(verify_signed_line(&joined, "signing-key"), Some("--> mallory joined the room"));
+
+    mallory.send_json(json!({ "type": "Message", "content": "--> admin joined the room" })).await;
+    // JSON clients see a chat message, whatever it looks like...
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "mallory");
+    let forged = alice.recv_json().await;
+    assert_eq!(forged["type"], "NewMessage");
+    assert_eq!(forged["content"], "--> admin joined the room");
+    // ...and for plain-text clients it carries no valid signature.
+    let forged = carol.recv_text().await;
+    assert!(forged.contains("--> admin joined the room"), "{}", forged);
+    assert_eq!(verify_signed_line(&forged, "signing-key"), None);
+}
+
+#[tokio::test]
+async fn count_reports_the_stored_messages() {
+    let server = TestServer::start().await;
+    for i in 0..7 {
+        let message = ServerMessage::NewMessage {
+            id: Uuid::new_v4(),
+            username: "seed".to_string(),
+            content: format!("message {}", i),
+            encrypted: false,
+            color: None,
+            severity: None,
+            reply_to: None,
+            expires_at: None,
+            sent_at: None,
+        };
+        server.state.store.save_message("seeded", 0, &message, Utc::now()).await.unwrap();
+    }
+
+    let mut client = server.connect("seeded").await;
+    client.recv_type("Welcome").await;
+    client.send_text("/count").await;
+    assert!(client.recv_type("Notice").await["message"].as_str().unwrap().contains("username"));
+
+    client.join_as("alice").await;
+    client.send_text("/count").await;
+    let count = client.recv_type("MessageCount").await;
+    assert_eq!(count["room"], "seeded");
+    assert_eq!(count["count"], 8); // the seeded messages and Alice's join
+}
+
+#[tokio::test]
+async fn history_commands_are_refused_when_history_is_disabled() {
+    let server = TestServer::start_with(|config| config.history_enabled = false).await;
+    let mut client = server.connect("no-history").await;
+    let welcome = client.recv_type("Welcome").await;
+    assert!(!welcome["features"].as_array().unwrap().contains(&json!("history-paging")));
+    client.join_as("alice").await;
+    client.send_json(json!({ "type": "Message", "content": "still stored" })).await;
+
+    for command in ["/history", "/history 1 10", "/count", "/history-count"] {
+        client.send_text(command).await;
+        assert_eq!(client.recv_type("Error").await["message"], "History is disabled on this server.", "{}", command);
+    }
+    assert_eq!(server.state.store.get_message_count("no-history").await.unwrap(), 2); // the join and the message
+}
+
+#[tokio::test]
+async fn only_the_configured_message_types_are_persisted() {
+    let server = TestServer::start_with(|config| {
+        config.persist_types =
+            PersistTypes::new(HashMap::from([("*".to_string(), vec!["NewMessage".to_string()]), ("scratch".to_string(), vec![])]));
+    })
+    .await;
+    let mut alice = server.connect("churn").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    let mut bob = server.connect("churn").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+    alice.send_json(json!({ "type": "Message", "content": "kept" })).await;
+    bob.recv_type("NewMessage").await;
+    drop(bob);
+    alice.recv_type("UserLeft").await;
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+
+    let stored: Vec<_> = server.state.store.load_history("churn", 10).await.unwrap().into_iter().collect();
+    assert!(matches!(stored.as_slice(), [ServerMessage::NewMessage { content, .. }] if content == "kept"), "{:?}", stored);
+
+    let mut scratch = server.connect("scratch").await;
+    scratch.recv_type("Welcome").await;
+    scratch.join_as("carol").await;
+    scratch.send_json(json!({ "type": "Message", "content": "gone" })).await;
+    scratch.send_text("/ping").await;
+    scratch.recv_type("Pong").await;
+    assert_eq!(server.state.store.get_message_count("scratch").await.unwrap(), 0);
+}
+
+/// Sends `command` and returns the type of each `HistoryEntry`'s message, in order.
+async fn history_types(client: &mut TestClient, command: &str) -> Vec<String> {
+    client.send_text(command).await;
+    client.send_text("/ping").await;
+    let mut types = Vec::new();
+    loop {
+        let frame = client.recv_json().await;
+        match frame["type"].as_str().unwrap() {
+            "HistoryEntry" => types.push(frame["message"]["type"].as_str().unwrap().to_string()),
+            "Pong" => return types,
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn history_leaves_out_presence_unless_asked() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("quiet-history").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    let mut bob = server.connect("quiet-history").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+    alice.send_json(json!({ "type": "Message", "content": "hello" })).await;
+    bob.recv_type("NewMessage").await;
+    drop(bob);
+    alice.recv_type("UserLeft").await;
+
+    assert_eq!(history_types(&mut alice, "/history").await, ["NewMessage"]);
+    assert_eq!(history_types(&mut alice, "/history 1 10").await, ["NewMessage"]);
+    assert_eq!(
+        history_types(&mut alice, "/history --with-presence").await,
+        ["UserJoined", "UserJoined", "NewMessage", "UserLeft"]
+    );
+    assert_eq!(history_types(&mut alice, "/history 1 2 --with-presence").await, ["NewMessage", "UserLeft"]);
+
+    // Servers can list presence by default instead.
+    let server = TestServer::start_with(|config| config.history_includes_presence = true).await;
+    let mut carol = server.connect("chatty-history").await;
+    carol.recv_type("Welcome").await;
+    carol.join_as("carol").await;
+    assert_eq!(history_types(&mut carol, "/history").await, ["UserJoined"]);
+}
+
+/// Sends `command` and returns the content of each chat message in the history it replies with, and its `seen` flag.
+async fn history_seen_flags(client: &mut TestClient, command: &str) -> Vec<(String, Value)> {
+    client.send_text(command).await;
+    client.send_text("/ping").await;
+    let mut entries = Vec::new();
+    loop {
+        let frame = client.recv_json().await;
+        match frame["type"].as_str().unwrap() {
+            "HistoryEntry" if frame["message"]["type"] == "NewMessage" => {
+                entries.push((frame["message"]["content"].as_str().unwrap().to_string(), frame["seen"].clone()))
+            }
+            "Pong" => return entries,
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn history_marks_what_the_user_has_read() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("reading-room").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    for content in ["first", "second"] {
+        alice.send_json(json!({ "type": "Message", "content": content })).await;
+    }
+
+    // No cursor yet: entries carry no annotation.
+    assert_eq!(
+        history_seen_flags(&mut alice, "/history").await,
+        [("first".to_string(), Value::Null), ("second".to_string(), Value::Null)]
+    );
+
+    alice.send_text("/markread").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "Marked everything in 'reading-room' as read.");
+    alice.send_json(json!({ "type": "Message", "content": "third" })).await;
+
+    assert_eq!(
+        history_seen_flags(&mut alice, "/history").await,
+        [("first".to_string(), json!(true)), ("second".to_string(), json!(true)), ("third".to_string(), json!(false))]
+    );
+    assert!(server.state.store.get_last_seen("reading-room", "alice").await.unwrap().is_some_and(|cursor| cursor <= Utc::now()));
+}
+
+#[tokio::test]
+async fn expiring_messages_are_deleted_once_their_ttl_runs_out() {
+    let server = TestServer::start_with(|config| config.message_ttl_min = Duration::from_secs(1)).await;
+    let mut alice = server.connect("vanishing").await;
+    let mut bob = server.connect("vanishing").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    alice.send_json(json!({ "type": "Message", "content": "forever", "expires_in_secs": 0 })).await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "expires_in_secs must be between 1 and 604800.");
+
+    alice.send_json(json!({ "type": "Message", "content": "gone soon", "expires_in_secs": 1 })).await;
+    let message = bob.recv_type("NewMessage").await;
+    assert!(message["expires_at"].is_string());
+    let id = Uuid::parse_str(message["id"].as_str().unwrap()).unwrap();
+    let sent = Instant::now();
+
+    assert_eq!(bob.recv_type("MessageDeleted").await["id"], message["id"]);
+    assert!(sent.elapsed() >= Duration::from_millis(900), "deleted after {:?}", sent.elapsed());
+    assert!(server.state.store.load_message("vanishing", id).await.unwrap().is_none());
+
+    // Someone joining afterwards doesn't get the content in the replay either.
+    let mut carol = server.connect("vanishing").await;
+    carol.recv_type("Welcome").await;
+    carol.send_json(json!({ "type": "SetUsername", "username": "carol" })).await;
+    carol.send_text("/ping").await;
+    loop {
+        let frame = carol.recv_json().await;
+        assert_ne!(frame["content"], "gone soon");
+        if frame["type"] == "Pong" {
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn ignored_users_messages_are_not_delivered_to_the_ignorer() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("quarrel").await;
+    let mut bob = server.connect("quarrel").await;
+    let mut carol = server.connect("quarrel").await;
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.recv_type("Welcome").await;
+    }
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    carol.join_as("carol").await;
+
+    alice.send_text("/ignore alice").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "You can't ignore yourself.");
+    alice.send_text("/ignore bob").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "You are now ignoring 'bob'. Use /unignore bob to see their messages again.");
+
+    bob.send_json(json!({ "type": "Message", "content": "are you there?" })).await;
+    assert_eq!(carol.recv_type("NewMessage").await["content"], "are you there?");
+    carol.send_json(json!({ "type": "Message", "content": "I am" })).await;
+    // Alice gets carol's message but never bob's, which was sent first.
+    assert_eq!(alice.recv_type("NewMessage").await["content"], "I am");
+
+    alice.send_text("/unignore bob").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "You are no longer ignoring 'bob'.");
+    bob.send_json(json!({ "type": "Message", "content": "hello again" })).await;
+    assert_eq!(alice.recv_type("NewMessage").await["content"], "hello again");
+}
+
+#[tokio::test]
+async fn find_locates_users_within_what_the_requester_may_see() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut alice = server.connect("help-desk").await;
+    let mut bob_phone = server.connect("help-desk").await;
+    let mut bob_laptop = server.connect("help-desk").await;
+    let mut admin = server.connect("staff").await;
+    for client in [&mut alice, &mut bob_phone, &mut bob_laptop, &mut admin] {
+        client.recv_type("Welcome").await;
+    }
+    alice.join_as("alice").await;
+    bob_phone.join_as("bob").await;
+    bob_laptop.join_as("bob").await;
+    admin.join_as("root").await;
+    for client in [&mut alice, &mut bob_phone, &mut bob_laptop] {
+        client.send_text("/ping").await;
+        client.recv_type("Pong").await; // joined
+    }
+    admin.send_text("/admin secret").await;
+    admin.recv_type("Notice").await;
+
+    admin.send_text("/find bob").await;
+    assert_eq!(admin.recv_type("Notice").await["message"], "'bob' is in 'help-desk' (2 connections).");
+    admin.send_text("/find nobody").await;
+    assert_eq!(admin.recv_type("Notice").await["message"], "'nobody' was not found in any room you can see.");
+
+    // Alice can find Bob next to her, but not the admin in a room she isn't in.
+    alice.send_text("/find bob").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "'bob' is in 'help-desk' (2 connections).");
+    alice.send_text("/find root").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "'root' was not found in any room you can see.");
+}
+
+#[tokio::test]
+async fn stats_me_reports_the_requesters_own_session() {
+    let server = TestServer::start().await;
+    let mut client = server.connect("stats").await;
+    let client_id = client.recv_type("Welcome").await["client_id"].clone();
+
+    client.send_text("/stats-me").await;
+    let anonymous = client.recv_type("SessionStats").await;
+    assert_eq!(anonymous["username"], Value::Null);
+    assert_eq!(anonymous["messages_sent"], 0);
+
+    client.join_as("alice").await;
+    for content in ["one", "two", "three"] {
+        client.send_json(json!({ "type": "Message", "content": content })).await;
+    }
+    client.send_json(json!({ "type": "Custom", "kind": "poll", "payload": { "question": "lunch?" } })).await;
+    client.send_text("/stats-me").await;
+    let stats = client.recv_type("SessionStats").await;
+    assert_eq!(stats["client_id"], client_id);
+    assert_eq!(stats["username"], "alice");
+    assert_eq!(stats["room"], "stats");
+    assert_eq!(stats["messages_sent"], 4);
+    assert_eq!(stats["joined_at"], anonymous["joined_at"]);
+}
+
+#[tokio::test]
+async fn only_registered_rooms_can_be_joined_when_auto_create_is_off() {
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.auto_create_rooms = false;
+        config.registered_rooms = vec!["lobby".to_string()];
+    })
+    .await;
+
+    let mut stranger = server.connect("nowhere").await;
+    assert_eq!(stranger.recv_until_closed().await, Some(4004));
+
+    let mut lobby = server.connect("lobby").await;
+    lobby.recv_type("Welcome").await;
+
+    let registered = reqwest::Client::new().put(server.url("/admin/rooms/nowhere")).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(registered.status(), 204);
+    let mut newcomer = server.connect("nowhere").await;
+    newcomer.recv_type("Welcome").await;
+}
+
+#[tokio::test]
+async fn scheduled_announcements_are_delivered_when_due_unless_cancelled() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut alice = server.connect("lobby").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+
+    let http = reqwest::Client::new();
+    let at = Utc::now() + chrono::Duration::milliseconds(1500);
+    let schedule = |text: &str| {
+        http.post(server.url("/admin/schedule")).bearer_auth("secret").json(&json!({ "at": at, "room": "lobby", "text": text })).send()
+    };
+    let kept = schedule("Maintenance in five minutes").await.unwrap();
+    assert_eq!(kept.status(), 201);
+    let cancelled: Value = schedule("Never mind").await.unwrap().json().await.unwrap();
+    let cancel = |id: &str| http.delete(server.url(&format!("/admin/schedule/{}", id))).bearer_auth("secret").send();
+    assert_eq!(cancel(cancelled["id"].as_str().unwrap()).await.unwrap().status(), 204);
+    assert_eq!(cancel(cancelled["id"].as_str().unwrap()).await.unwrap().status(), 404);
+
+    let announced = alice.recv_type("Announcement").await;
+    assert_eq!(announced["message"], "Maintenance in five minutes");
+    alice.expect_silence(Duration::from_millis(1500)).await;
+
+    let audit = server.state.store.load_audit_log(None, 1, 10).await.unwrap();
+    assert!(audit.iter().any(|entry| entry.action == "schedule_announcement"));
+    assert!(audit.iter().any(|entry| entry.action == "cancel_announcement"));
+}
+
+#[tokio::test]
+async fn announcements_pending_in_the_store_are_delivered_after_a_restart() {
+    // Scheduled before the "restart": the new server only knows about it through the store.
+    let store = Arc::new(MemoryStore::default());
+    let scheduled = ScheduledMessage {
+        id: Uuid::new_v4(),
+        room: "*".to_string(),
+        text: "We're back".to_string(),
+        deliver_at: Utc::now() + chrono::Duration::seconds(1),
+    };
+    store.add_scheduled_message(&scheduled).await.unwrap();
+
+    let server = TestServer::start_with_store(store, |_| {}).await;
+    let mut alice = server.connect("lobby").await;
+    alice.recv_type("Welcome").await;
+    assert_eq!(alice.recv_type("Announcement").await["message"], "We're back");
+}
+
+#[tokio::test]
+async fn room_creator_owns_the_room_until_they_transfer_it() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("clubhouse").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    assert_eq!(alice.recv_type("OwnershipChanged").await["owner"], "alice");
+
+    let mut bob = server.connect("clubhouse").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    assert_eq!(bob.recv_type("OwnershipChanged").await["owner"], "alice");
+
+    // Only moderators may set the topic, and Bob isn't one yet.
+    bob.send_text("/topic bob was here").await;
+    assert!(bob.recv_type("Notice").await["message"].as_str().unwrap().contains("moderators"));
+
+    alice.send_text("/transfer bob").await;
+    let transferred = bob.recv_type("OwnershipChanged").await;
+    assert_eq!(transferred["owner"], "bob");
+    assert_eq!(server.state.store.get_room_ownership("clubhouse").await.unwrap().owner.as_deref(), Some("bob"));
+
+    bob.send_text("/topic bob was here").await;
+    assert_eq!(alice.recv_type("TopicChanged").await["by"], "bob");
+    alice.send_text("/transfer alice").await;
+    assert!(alice.recv_type("Notice").await["message"].as_str().unwrap().contains("owner"));
+}
+
+#[tokio::test]
+async fn moderator_names_cannot_be_taken_over() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("clubhouse").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    let mut bob = server.connect("clubhouse").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    alice.send_text("/op bob").await;
+    assert_eq!(alice.recv_type("OwnershipChanged").await["operators"], json!(["bob"]));
+
+    // Nobody else can join or rename into the owner's or an operator's name.
+    let mut mallory = server.connect("clubhouse").await;
+    mallory.recv_type("Welcome").await;
+    for name in ["alice", "bob"] {
+        mallory.join_as(name).await;
+        assert_eq!(
+            mallory.recv_type("Notice").await["message"],
+            format!("'{}' belongs to a moderator of this room; claim it with its registered secret.", name)
+        );
+    }
+    mallory.join_as("mallory").await;
+    mallory.send_text("/user alice").await;
+    assert!(mallory.recv_type("Notice").await["message"].as_str().unwrap().contains("belongs to a moderator"));
+    mallory.send_text("/topic hijacked").await;
+    assert!(mallory.recv_type("Notice").await["message"].as_str().unwrap().contains("moderators"));
+
+    // An operator's rights end with their last connection, so whoever takes the name next has none.
+    bob.send_text("/quit").await;
+    assert_eq!(alice.recv_type("OwnershipChanged").await["operators"], json!([]));
+    assert!(server.state.store.get_room_ownership("clubhouse").await.unwrap().operators.is_empty());
+    let mut imposter = server.connect("clubhouse").await;
+    imposter.recv_type("Welcome").await;
+    imposter.join_as("bob").await;
+    assert!(imposter.recv_type("Notice").await["message"].as_str().unwrap().starts_with("Welcome back!"));
+    imposter.send_text("/topic hijacked").await;
+    assert!(imposter.recv_type("Notice").await["message"].as_str().unwrap().contains("moderators"));
+}
+
+#[tokio::test]
+async fn malformed_frames_get_specific_errors() {
+    let server = TestServer::start().await;
+    let mut client = server.connect("strict").await;
+    client.recv_type("Welcome").await;
+
+    let cases = [
+        (r#"{"type": "SetUsername"}"#, "SetUsername requires 'username' to be a non-empty string."),
+        (r#"{"type": "SetUsername", "username": "  "}"#, "SetUsername requires 'username' to be a non-empty string."),
+        (r#"{"type": "Message", "content": 42}"#, "Message requires 'content' to be a string."),
+        (r#"{"type": "Message", "content": "hi", "encrypted": "yes"}"#, "Message's optional 'encrypted' must be true or false if given."),
+        (r#"{"type": "Ack", "message_id": "not-a-uuid"}"#, "Ack requires 'message_id' to be a message id (UUID)."),
+        (r#"{"type": "Mesage", "content": "hi"}"#, "Unknown message type 'Mesage'; expected one of: SetUsername, Message, Custom, Ack."),
+        (r#"{"content": "hi"}"#, "A message needs a 'type' string, one of: SetUsername, Message, Custom, Ack."),
+    ];
+    for (frame, expected) in cases {
+        client.send_text(frame).await;
+        assert_eq!(client.recv_type("Error").await["message"], expected, "for {}", frame);
+    }
+
+    client.send_text(r#"{"type": "Message""#).await;
+    assert!(client.recv_type("Error").await["message"].as_str().unwrap().starts_with("Frame is not valid JSON"));
+}
+
+#[tokio::test]
+async fn invites_admit_one_client_to_a_private_room() {
+    let server = TestServer::start_with(|config| {
+        config.room_passwords = HashMap::from([
+            ("vault".to_string(), "hunter2".to_string()),
+            ("safe".to_string(), "swordfish".to_string()),
+        ]);
+    })
+    .await;
+    assert_eq!(server.connect("vault").await.recv_until_closed().await, Some(4001));
+    assert_eq!(server.connect("vault?password=wrong").await.recv_until_closed().await, Some(4001));
+
+    let mut alice = server.connect("vault?password=hunter2").await;
+    assert!(alice.recv_type("Welcome").await["features"].as_array().unwrap().contains(&json!("private")));
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    alice.send_text("/invite").await;
+    let invite = alice.recv_type("InviteCreated").await;
+    assert_eq!(invite["room"], "vault");
+    let token = invite["token"].as_str().unwrap();
+
+    // An invite only works for the room it was made for, and trying it elsewhere doesn't use it up.
+    assert_eq!(server.connect(&format!("safe?invite={}", token)).await.recv_until_closed().await, Some(4001));
+
+    let mut bob = server.connect(&format!("vault?invite={}", token)).await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+
+    let reused = server.connect(&format!("vault?invite={}", token)).await.recv_until_closed().await;
+    assert_eq!(reused, Some(4001), "an invite must not be usable twice");
+}
+
+#[tokio::test]
+async fn an_invite_is_not_used_up_by_a_connection_the_room_turns_away() {
+    let failing = Arc::new(AtomicBool::new(false));
+    let switch = failing.clone();
+    let store = HookedStore::new(move |method| (method == "get_latest_seq" && switch.load(Ordering::SeqCst)).then_some(Duration::from_secs(1)));
+    let server = TestServer::start_with_store(Arc::new(store), |config| {
+        config.room_passwords = HashMap::from([("vault".to_string(), "hunter2".to_string())]);
+        config.db_timeout = Duration::from_millis(200);
+    })
+    .await;
+    let mut alice = server.connect("vault?password=hunter2").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    alice.send_text("/invite").await;
+    let token = alice.recv_type("InviteCreated").await["token"].as_str().unwrap().to_string();
+    alice.send_text("/quit").await;
+    alice.recv_until_closed().await;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while server.state.rooms.lock().await.contains_key("vault") {
+        assert!(tokio::time::Instant::now() < deadline, "the room was never closed");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // The room can't be opened while the database is down, and the invite survives for another try.
+    failing.store(true, Ordering::SeqCst);
+    assert_eq!(server.connect(&format!("vault?invite={}", token)).await.recv_until_closed().await, Some(1011));
+    failing.store(false, Ordering::SeqCst);
+    server.connect(&format!("vault?invite={}", token)).await.recv_type("Welcome").await;
+}
+
+#[tokio::test]
+async fn banned_addresses_are_closed_with_4002() {
+    let server = TestServer::start_with(|config| config.banned_ips = vec!["127.0.0.1".parse().unwrap()]).await;
+    let mut client = server.connect("general").await;
+    assert_eq!(client.recv_until_closed().await, Some(4002));
+    assert!(!server.state.rooms.lock().await.contains_key("general"));
+}
+
+#[tokio::test]
+async fn full_rooms_close_further_connections_with_4003() {
+    let server = TestServer::start_with(|config| config.room_max_clients = Some(2)).await;
+    let mut alice = server.connect("small").await;
+    let mut bob = server.connect("small").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    assert_eq!(server.connect("small").await.recv_until_closed().await, Some(4003));
+
+    // Other rooms have room of their own, and a slot frees up once someone leaves.
+    server.connect("other").await.recv_type("Welcome").await;
+    bob.send_text("/quit").await;
+    bob.recv_until_closed().await;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while server.state.rooms.lock().await["small"].clients.len() > 1 {
+        assert!(tokio::time::Instant::now() < deadline, "bob was never cleaned up");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    server.connect("small").await.recv_type("Welcome").await;
+}
+
+#[tokio::test]
+async fn clients_connecting_at_once_cannot_overfill_a_room() {
+    let server = TestServer::start_with(|config| config.room_max_clients = Some(2)).await;
+    let mut clients = futures_util::future::join_all((0..8).map(|_| server.connect("crowded"))).await;
+    let mut closed = 0;
+    for client in &mut clients {
+        // Admitted clients stay open, so waiting for their close runs out.
+        if let Ok(Some(4003)) = tokio::time::timeout(Duration::from_millis(500), client.recv_until_closed()).await {
+            closed += 1;
+        }
+    }
+    assert_eq!(closed, 6);
+    assert_eq!(server.state.rooms.lock().await["crowded"].clients.len(), 2);
+}
+
+#[tokio::test]
+async fn identical_messages_sent_in_quick_succession_are_delivered_once() {
+    let server = TestServer::start_with(|config| config.duplicate_window = Some(Duration::from_secs(2))).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    alice.send_json(json!({ "type": "Message", "content": "lunch?" })).await;
+    alice.send_json(json!({ "type": "Message", "content": "lunch?" })).await;
+    assert!(alice.recv_type("Notice").await["message"].as_str().unwrap().contains("duplicate"));
+    alice.send_json(json!({ "type": "Message", "content": "anyone?" })).await;
+
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "lunch?");
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "anyone?");
+}
+
+#[tokio::test]
+async fn joiners_receive_a_presence_snapshot_of_the_room() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    let mut carol = server.connect("general").await;
+    carol.recv_type("Welcome").await;
+    carol.join_as("carol").await;
+    let snapshot = carol.recv_type("PresenceSnapshot").await;
+    let users: Vec<&str> = snapshot["users"].as_array().unwrap().iter().map(|user| user["username"].as_str().unwrap()).collect();
+    assert_eq!(users, ["alice", "bob", "carol"]);
+    assert_eq!(snapshot["users"][0]["status"], "online");
+}
+
+#[tokio::test]
+async fn get_fetches_a_message_by_id() {
+    let server = TestServer::start().await;
+    let stored = ServerMessage::NewMessage {
+        id: Uuid::new_v4(),
+        username: "seed".to_string(),
+        content: "from long ago".to_string(),
+        encrypted: false,
+        color: None,
+        severity: None,
+        reply_to: None,
+        expires_at: None,
+        sent_at: None,
+    };
+    server.state.store.save_message("archive", 0, &stored, Utc::now()).await.unwrap();
+    let ServerMessage::NewMessage { id: stored_id, .. } = stored else { unreachable!() };
+
+    let mut client = server.connect("archive").await;
+    client.recv_type("Welcome").await;
+    client.join_as("alice").await;
+    client.send_text(&format!("/get {}", stored_id)).await;
+    let fetched = client.recv_type("FetchedMessage").await;
+    assert_eq!(fetched["message"]["content"], "from long ago");
+
+    client.send_text(&format!("/get {}", Uuid::new_v4())).await;
+    assert!(client.recv_type("Error").await["message"].as_str().unwrap().contains("not found"));
+
+    // Messages of other rooms can't be fetched from here.
+    let mut other = server.connect("elsewhere").await;
+    other.recv_type("Welcome").await;
+    other.join_as("bob").await;
+    other.send_text(&format!("/get {}", stored_id)).await;
+    assert!(other.recv_type("Error").await["message"].as_str().unwrap().contains("not found"));
+}
+
+#[tokio::test]
+async fn connections_beyond_the_server_wide_cap_are_refused() {
+    let server = TestServer::start_with(|config| config.max_total_connections = Some(2)).await;
+    let url = format!("ws://{}/ws/general", server.addr);
+    let refused = |result: Result<_, WsError>| matches!(result, Err(WsError::Http(response)) if response.status() == 503);
+
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("other").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    assert!(refused(tokio_tungstenite::connect_async(&url).await));
+
+    // Once a connection has been cleaned up its slot is free again.
+    bob.send_text("/quit").await;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while server.state.connections.count() > 1 {
+        assert!(tokio::time::Instant::now() < deadline, "the closed connection was never released");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut carol = server.connect("general").await;
+    carol.recv_type("Welcome").await;
+}
+
+#[tokio::test]
+async fn message_sequence_numbers_continue_after_a_restart() {
+    let store: Arc<dyn MessageStore> = Arc::new(MemoryStore::default());
+    let first = TestServer::start_with_store(store.clone(), |_| {}).await;
+    let mut alice = first.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    assert_eq!(alice.recv_type("PresenceSnapshot").await["latest_seq"], 0);
+    alice.send_text("/echo on").await;
+    alice.send_json(json!({ "type": "Message", "content": "one" })).await;
+    assert_eq!(alice.recv_type("NewMessage").await["seq"], 2); // after Alice's join
+
+    // Let the writes land, then bring up a fresh server on the same store.
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while store.get_message_count("general").await.unwrap() < 2 {
+        assert!(tokio::time::Instant::now() < deadline, "messages were never stored");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let second = TestServer::start_with_store(store, |_| {}).await;
+    let mut bob = second.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    let replayed = bob.recv_type("NewMessage").await;
+    assert_eq!((replayed["content"].as_str(), replayed["seq"].as_u64()), (Some("one"), Some(2)));
+    assert_eq!(bob.recv_type("PresenceSnapshot").await["latest_seq"], 2);
+    bob.send_text("/echo on").await;
+    bob.send_json(json!({ "type": "Message", "content": "two" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["seq"], 4); // after Bob's join
+}
+
+#[tokio::test]
+async fn sequence_numbers_are_not_reused_for_deleted_or_unstored_messages() {
+    let store: Arc<dyn MessageStore> = Arc::new(MemoryStore::default());
+    let only_chat = || PersistTypes::new(HashMap::from([("*".to_string(), vec!["NewMessage".to_string()])]));
+    let first = TestServer::start_with_store(store.clone(), |config| config.persist_types = only_chat()).await;
+    let mut alice = first.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.send_text("/echo on").await;
+    alice.send_json(json!({ "type": "Message", "content": "kept" })).await;
+    alice.recv_type("NewMessage").await;
+    alice.send_json(json!({ "type": "Message", "content": "deleted" })).await;
+    let deleted = alice.recv_type("NewMessage").await;
+    assert_eq!(deleted["seq"], 3);
+    // Alice's join is numbered 1 but never stored, and the newest stored message goes away.
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while store.get_message_count("general").await.unwrap() < 2 {
+        assert!(tokio::time::Instant::now() < deadline, "messages were never stored");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let deleted_id = Uuid::parse_str(deleted["id"].as_str().unwrap()).unwrap();
+    assert!(store.delete_message("general", deleted_id).await.unwrap());
+    assert_eq!(store.get_message_count("general").await.unwrap(), 1);
+    // The room's number, counting Alice's unstored leave, is recorded once it is emptied.
+    alice.send_text("/quit").await;
+    alice.recv_until_closed().await;
+    while first.state.rooms.lock().await.contains_key("general") || store.get_latest_seq("general").await.unwrap() < 4 {
+        assert!(tokio::time::Instant::now() < deadline, "the room's number was never recorded");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let second = TestServer::start_with_store(store, |config| config.persist_types = only_chat()).await;
+    let mut bob = second.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    assert_eq!(bob.recv_type("PresenceSnapshot").await["latest_seq"], 4);
+    bob.send_text("/echo on").await;
+    bob.send_json(json!({ "type": "Message", "content": "after" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["seq"], 6); // after Bob's join
+}
+
+#[tokio::test]
+async fn leaving_a_room_stops_its_messages_but_keeps_the_connection() {
+    let server = TestServer::start().await;
+    let mut alice_lobby = server.connect("leave-lobby").await;
+    let mut alice_games = server.connect("leave-games").await;
+    let mut bob_lobby = server.connect("leave-lobby").await;
+    let mut bob_games = server.connect("leave-games").await;
+    for client in [&mut alice_lobby, &mut alice_games, &mut bob_lobby, &mut bob_games] {
+        client.recv_type("Welcome").await;
+    }
+    alice_lobby.join_as("alice").await;
+    alice_games.join_as("alice").await;
+    bob_lobby.join_as("bob").await;
+    bob_games.join_as("bob").await;
+    alice_lobby.recv_type("UserJoined").await;
+    alice_games.recv_type("UserJoined").await;
+
+    alice_lobby.send_text("/leave leave-games").await;
+    assert!(alice_lobby.recv_type("Notice").await["message"].as_str().unwrap().contains("not in room"));
+    alice_lobby.send_text("/leave").await;
+    assert!(alice_lobby.recv_type("Notice").await["message"].as_str().unwrap().starts_with("You left room"));
+    // Alice was the lobby's first member, so it passes to bob.
+    assert_eq!(alice_lobby.recv_type("OwnershipChanged").await["owner"], "bob");
+    let left = bob_lobby.recv_type("UserLeft").await;
+    assert_eq!(left["username"], "alice");
+
+    bob_lobby.send_json(json!({ "type": "Message", "content": "lobby chatter" })).await;
+    bob_games.send_json(json!({ "type": "Message", "content": "games chatter" })).await;
+    assert_eq!(alice_games.recv_type("NewMessage").await["content"], "games chatter");
+    alice_lobby.expect_silence(Duration::from_millis(300)).await;
+
+    // The connection is still open: a new name rejoins the room.
+    alice_lobby.send_text("/leave").await;
+    assert!(alice_lobby.recv_type("Notice").await["message"].as_str().unwrap().contains("haven't joined"));
+    alice_lobby.join_as("alice").await;
+    assert_eq!(bob_lobby.recv_type("UserJoined").await["username"], "alice");
+}
+
+#[tokio::test]
+async fn clients_that_stop_sending_are_treated_as_left() {
+    let server = TestServer::start_with(|config| config.heartbeat_timeout = Some(Duration::from_secs(1))).await;
+    let mut alice = server.connect("heartbeats").await;
+    let mut bob = server.connect("heartbeats").await;
+    alice.recv_type("Welcome").await;
+    assert!(bob.recv_type("Welcome").await["features"].as_array().unwrap().contains(&json!("heartbeat")));
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+
+    // Alice keeps beating while bob's app goes quiet; his socket stays open throughout.
+    for _ in 0..8 {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        alice.send_text("/heartbeat").await;
+    }
+    assert_eq!(alice.recv_type("UserLeft").await["username"], "bob");
+    {
+        let rooms = server.state.rooms.lock().await;
+        let presence = rooms["heartbeats"].presence();
+        assert_eq!(presence.iter().map(|entry| entry.username.as_str()).collect::<Vec<_>>(), ["alice"]);
+    }
+
+    bob.send_text("/heartbeat").await;
+    assert!(bob.recv_type("Notice").await["message"].as_str().unwrap().contains("you are back"));
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+}
+
+#[tokio::test]
+async fn admins_can_change_the_timestamp_format_at_runtime() {
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.echo_own_messages = true;
+        config.templates.new_message = Template::parse("{timestamp} {content}", &["timestamp", "content"]).unwrap();
+    })
+    .await;
+    let mut client = server.connect_with_protocol("clock", None).await;
+    client.recv_text().await; // welcome line
+    client.send_text("/user alice").await;
+
+    let set_format = |format: &str| {
+        reqwest::Client::new().put(server.url("/admin/timestamp-format")).bearer_auth("secret").body(format.to_string()).send()
+    };
+    assert_eq!(set_format("year %Y").await.unwrap().status(), 204);
+    assert_eq!(set_format("%H:%").await.unwrap().status(), 400);
+
+    client.send_text("tick").await;
+    let mut line = client.recv_text().await;
+    while !line.ends_with("tick") {
+        line = client.recv_text().await; // the join lines
+    }
+    assert_eq!(line, format!("year {} tick", Utc::now().format("%Y")));
+}
+
+#[tokio::test]
+async fn plain_text_clients_see_long_messages_cut_between_graphemes() {
+    let server = TestServer::start_with(|config| {
+        config.display_max_bytes = Some(30);
+        config.echo_own_messages = true;
+    })
+    .await;
+    let mut plain = server.connect_with_protocol("emoji", None).await;
+    plain.recv_text().await; // welcome line
+    plain.send_text("/user plain").await;
+    let mut json = server.connect("emoji").await;
+    json.recv_type("Welcome").await;
+    json.join_as("json").await;
+
+    // "[json] " takes nothing from the budget: only the content is cut, and the family emoji is one grapheme of 18 bytes.
+    let family = "👨\u{200D}👩\u{200D}👧";
+    let content = format!("hello {}{}", family, family);
+    json.send_json(json!({ "type": "Message", "content": content })).await;
+    assert_eq!(json.recv_type("NewMessage").await["content"], content.as_str());
+    let mut line = plain.recv_text().await;
+    while !line.starts_with("[json]") {
+        line = plain.recv_text().await; // the join lines
+    }
+    assert_eq!(line, format!("[json] hello {}…", family));
+}
+
+#[tokio::test]
+async fn timestamps_show_when_a_stored_message_was_sent() {
+    let server = TestServer::start_with(|config| {
+        config.templates.new_message = Template::parse("{timestamp} {content}", &["timestamp", "content"]).unwrap();
+    })
+    .await;
+    let chat = |content: &str, sent_at| ServerMessage::NewMessage {
+        id: Uuid::new_v4(),
+        username: "seed".to_string(),
+        content: content.to_string(),
+        encrypted: false,
+        color: None,
+        severity: None,
+        reply_to: None,
+        expires_at: None,
+        sent_at,
+    };
+    let sent_at = "2021-03-04T05:06:07Z".parse().unwrap();
+    let stored_at = "2020-01-02T03:04:05Z".parse().unwrap();
+    let store = &server.state.store;
+    store.save_message("archive", 0, &chat("recorded", Some(sent_at)), Utc::now()).await.unwrap();
+    // Stored before messages carried their time: the time it was stored stands in.
+    store.save_message("archive", 0, &chat("legacy", None), stored_at).await.unwrap();
+
+    let mut client = server.connect_with_protocol("archive", None).await;
+    client.recv_text().await; // welcome line
+    client.send_text("/user alice").await;
+    client.send_text("/history").await;
+    let mut lines = Vec::new();
+    while lines.len() < 2 {
+        let line = client.recv_text().await;
+        if line.contains("recorded") || line.contains("legacy") {
+            lines.push(line);
+        }
+    }
+    assert!(lines.contains(&"05:06:07 recorded".to_string()), "{:?}", lines);
+    assert!(lines.contains(&"03:04:05 legacy".to_string()), "{:?}", lines);
+}
+
+#[tokio::test]
+async fn room_metadata_appears_in_the_room_listing() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut owner = server.connect("lounge").await;
+    let mut guest = server.connect("lounge").await;
+    owner.recv_type("Welcome").await;
+    guest.recv_type("Welcome").await;
+    owner.join_as("owner").await;
+    owner.recv_type("OwnershipChanged").await;
+    guest.join_as("guest").await;
+    owner.recv_type("UserJoined").await;
+
+    owner.send_text("/roommeta set category games").await;
+    assert!(owner.recv_type("Notice").await["message"].as_str().unwrap().starts_with("Set 'category'"));
+    owner.send_text("/roommeta set language en").await;
+    owner.recv_type("Notice").await;
+    owner.send_text(&format!("/roommeta set motto {}", "x".repeat(201))).await;
+    assert!(owner.recv_type("Notice").await["message"].as_str().unwrap().contains("at most 200"));
+    guest.send_text("/roommeta set category spam").await;
+    assert!(guest.recv_type("Notice").await["message"].as_str().unwrap().contains("Only moderators"));
+
+    guest.send_text("/roommeta get category").await;
+    let reply = guest.recv_type("RoomMetadata").await;
+    assert_eq!(reply["metadata"], json!({ "category": "games" }));
+
+    let rooms: serde_json::Value = reqwest::Client::new()
+        .get(server.url("/rooms/recent"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let lounge = rooms.as_array().unwrap().iter().find(|room| room["room"] == "lounge").expect("lounge is listed");
+    assert_eq!(lounge["metadata"], json!({ "category": "games", "language": "en" }));
+}
+
+#[tokio::test]
+async fn the_first_frame_is_a_welcome_with_the_assigned_id() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+
+    let welcome = alice.recv_json().await;
+    assert_eq!(welcome["type"], "Welcome");
+    assert_eq!(welcome["room"], "general");
+    assert_eq!(welcome["server_version"], env!("CARGO_PKG_VERSION"));
+    let alice_id = Uuid::parse_str(welcome["client_id"].as_str().unwrap()).expect("client_id is a UUID");
+
+    let welcome = bob.recv_json().await;
+    assert_eq!(welcome["type"], "Welcome");
+    assert_ne!(Uuid::parse_str(welcome["client_id"].as_str().unwrap()).unwrap(), alice_id);
+}
+
+#[tokio::test]
+async fn repeating_the_current_name_is_not_announced_again() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+    alice.send_json(json!({ "type": "Message", "content": "hello" })).await;
+    bob.recv_type("NewMessage").await;
+
+    // Bob is only told he already has the name: no second join for the room and no history replay for him.
+    bob.send_text("/user bob").await;
+    let reply = bob.recv_json().await;
+    assert_eq!(reply["type"], "Notice");
+    assert_eq!(reply["message"], "You are already known as 'bob'.");
+    bob.expect_silence(Duration::from_millis(200)).await;
+    alice.expect_silence(Duration::from_millis(200)).await;
+}
+
+#[tokio::test]
+async fn custom_payloads_are_relayed_and_stored_verbatim() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    let payload = json!({ "question": "Lunch?", "options": ["pizza", "sushi"], "closes": null });
+    alice.send_json(json!({ "type": "Custom", "kind": "poll", "payload": payload })).await;
+    let relayed = bob.recv_type("Custom").await;
+    assert_eq!(relayed["username"], "alice");
+    assert_eq!(relayed["kind"], "poll");
+    assert_eq!(relayed["payload"], payload);
+
+    alice.send_json(json!({ "type": "Custom", "kind": "virus", "payload": {} })).await;
+    assert!(alice.recv_type("Notice").await["message"].as_str().unwrap().starts_with("Unsupported custom message kind 'virus'"));
+    alice.send_json(json!({ "type": "Custom", "kind": "card", "payload": "x".repeat(5000) })).await;
+    assert!(alice.recv_type("Notice").await["message"].as_str().unwrap().starts_with("Custom payload is too large"));
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+
+    let stored = server.state.store.load_history("general", 10).await.unwrap();
+    let customs: Vec<_> = stored.iter().filter(|message| matches!(message, ServerMessage::Custom { .. })).collect();
+    assert_eq!(customs.len(), 1, "refused payloads are not stored");
+    assert!(matches!(customs[0], ServerMessage::Custom { kind, payload: stored, .. } if kind == "poll" && *stored == payload));
+}
+
+#[tokio::test]
+async fn clients_joining_mid_conversation_see_every_message_exactly_once() {
+    const MESSAGES: usize = 40;
+    let server = TestServer::start_with(|config| config.frame_rate_limit = 1000).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+
+    // Alice keeps talking while the others connect and join, so each join lands somewhere in the middle.
+    let talker = tokio::spawn(async move {
+        for n in 0..MESSAGES {
+            alice.send_json(json!({ "type": "Message", "content": format!("message {}", n) })).await;
+            tokio::task::yield_now().await;
+        }
+        alice
+    });
+    let mut joiners = Vec::new();
+    for name in ["bob", "carol", "dave"] {
+        let mut client = server.connect("general").await;
+        client.recv_type("Welcome").await;
+        client.join_as(name).await;
+        joiners.push(client);
+    }
+    let _alice = talker.await.unwrap();
+
+    // Between the replay and the live feed, each joiner sees the whole conversation once and in order.
+    let expected: Vec<String> = (0..MESSAGES).map(|n| format!("message {}", n)).collect();
+    for client in &mut joiners {
+        let mut seen = Vec::new();
+        while seen.len() < MESSAGES {
+            seen.push(client.recv_type("NewMessage").await["content"].as_str().unwrap().to_string());
+        }
+        assert_eq!(seen, expected);
+        client.send_text("/ping").await;
+        loop {
+            let frame = client.recv_json().await;
+            assert_ne!(frame["type"], "NewMessage", "a message was delivered twice");
+            if frame["type"] == "Pong" {
+                break;
+            }
+        }
+    }
+}
+
+/// Waits for the next plain-text line that isn't a `***` server notice.
+async fn recv_chat_line(client: &mut TestClient) -> String {
+    loop {
+        let line = client.recv_text().await;
+        if !line.starts_with("***") {
+            return line;
+        }
+    }
+}
+
+#[tokio::test]
+async fn plain_text_lines_follow_the_configured_templates() {
+    let allowed = ["id", "username", "content", "timestamp"];
+    assert_eq!(Template::parse("{user}: {content}", &allowed).err().unwrap(), "unknown placeholder '{user}'");
+    assert_eq!(Template::parse("{username: {content}", &allowed).err().unwrap(), "unknown placeholder '{username: {content}'");
+    assert_eq!(Template::parse("{username}} {content}", &allowed).err().unwrap(), "unmatched '}'");
+    assert_eq!(Template::parse("{content", &allowed).err().unwrap(), "unclosed '{'");
+
+    let server = TestServer::start_with(|config| {
+        config.templates.new_message = Template::parse("{username} says: {content}", &allowed).unwrap();
+        config.templates.user_joined = Template::parse("+ {username}", &["username", "timestamp"]).unwrap();
+        config.templates.user_left = Template::parse("- {username}", &["username", "timestamp"]).unwrap();
+    })
+    .await;
+    let mut alice = server.connect_with_protocol("general", None).await;
+    let mut bob = server.connect_with_protocol("general", None).await;
+    alice.recv_text().await; // welcome line
+    bob.recv_text().await;
+    alice.send_text("/user alice").await;
+    bob.send_text("/user bob").await;
+    assert_eq!(recv_chat_line(&mut alice).await, "+ bob");
+
+    // Placeholders inside what a user typed are left as typed.
+    bob.send_text("hi {username}").await;
+    assert_eq!(recv_chat_line(&mut alice).await, "bob says: hi {username}");
+    bob.send_text("/quit").await;
+    assert_eq!(recv_chat_line(&mut alice).await, "- bob");
+}
+
+#[tokio::test]
+async fn the_server_picks_a_subprotocol_it_speaks_and_refuses_the_rest() {
+    let server = TestServer::start().await;
+    let offer = |protocols: &str| {
+        let mut request = format!("ws://{}/ws/general", server.addr).into_client_request().unwrap();
+        request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_str(protocols).unwrap());
+        tokio_tungstenite::connect_async(request)
+    };
+
+    let (_ws, response) = offer("chat.v9, chat.v2").await.unwrap();
+    assert_eq!(response.headers()["Sec-WebSocket-Protocol"], "chat.v2");
+    let (_ws, response) = offer("chat.v1").await.unwrap();
+    assert_eq!(response.headers()["Sec-WebSocket-Protocol"], "chat.v1");
+    let refused = offer("chat.v9").await;
+    assert!(matches!(refused, Err(WsError::Http(response)) if response.status() == 400));
+
+    // The negotiated version decides the wire format: JSON for v2, plain text for v1 and for clients that offer nothing.
+    let mut json_client = server.connect("general").await;
+    assert_eq!(json_client.recv_json().await["type"], "Welcome");
+    let mut text_client = server.connect_with_protocol("general", None).await;
+    assert!(text_client.recv_text().await.starts_with("*** Welcome to 'general'!"));
+}
+
+#[tokio::test]
+async fn chat_messages_are_posted_to_the_webhook_with_retries() {
+    // A stand-in endpoint that fails the first delivery and records the ones after it.
+    let (posted, mut received) = tokio::sync::mpsc::unbounded_channel::<Value>();
+    let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let hook = axum::Router::new().route(
+        "/hook",
+        axum::routing::post(move |axum::Json(body): axum::Json<Value>| async move {
+            if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                return axum::http::StatusCode::INTERNAL_SERVER_ERROR;
+            }
+            posted.send(body).unwrap();
+            axum::http::StatusCode::OK
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook_url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, hook).await.unwrap() });
+
+    let server = TestServer::start_with(|config| config.webhook_url = Some(hook_url)).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.send_json(json!({ "type": "Message", "content": "mirror me" })).await;
+
+    let body = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.expect("the webhook was not called").unwrap();
+    assert_eq!(body["room"], "general");
+    assert_eq!(body["username"], "alice");
+    assert_eq!(body["content"], "mirror me");
+    let timestamp: chrono::DateTime<Utc> = body["timestamp"].as_str().unwrap().parse().unwrap();
+    assert!((Utc::now() - timestamp).num_seconds() < 10);
+    // Joins aren't chat messages, so nothing else is posted.
+    assert!(tokio::time::timeout(Duration::from_millis(300), received.recv()).await.is_err());
+}
+
+async fn history_contents(client: &mut TestClient, command: &str) -> Vec<String> {
+    client.send_text(command).await;
+    client.send_text("/ping").await;
+    let mut contents = Vec::new();
+    loop {
+        let frame = client.recv_json().await;
+        match frame["type"].as_str().unwrap() {
+            "HistoryEntry" => contents.push(frame["message"]["content"].as_str().unwrap().to_string()),
+            "Pong" => return contents,
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn history_pages_count_back_from_the_newest_message() {
+    let server = TestServer::start().await;
+    for n in 0..5 {
+        let message = ServerMessage::NewMessage {
+            id: Uuid::new_v4(),
+            username: "seed".to_string(),
+            content: format!("message {}", n),
+            encrypted: false,
+            color: None,
+            severity: None,
+            reply_to: None,
+            expires_at: None,
+            sent_at: None,
+        };
+        server.state.store.save_message("paged", 0, &message, Utc::now()).await.unwrap();
+    }
+    let mut client = server.connect("paged").await;
+    client.recv_type("Welcome").await;
+    client.send_text("/history 1 2").await;
+    assert!(client.recv_type("Notice").await["message"].as_str().unwrap().contains("set a username"));
+    client.join_as("alice").await;
+
+    assert_eq!(history_contents(&mut client, "/history 1 2").await, ["message 3", "message 4"]);
+    assert_eq!(history_contents(&mut client, "/history 2 2").await, ["message 1", "message 2"]);
+    assert_eq!(history_contents(&mut client, "/history 3 2").await, ["message 0"]);
+    assert!(history_contents(&mut client, "/history 4 2").await.is_empty());
+
+    for command in ["/history 0 2", "/history 1 0", "/history 1 101", "/history 1", "/history one 2"] {
+        client.send_text(command).await;
+        assert!(client.recv_type("Notice").await["message"].as_str().unwrap().starts_with("Usage: /history"), "{}", command);
+    }
+    client.send_text("/history-count").await;
+    assert_eq!(client.recv_type("MessageCount").await["count"], 6); // the seeded messages and Alice's join
+}
+
+#[tokio::test]
+async fn anonymous_clients_read_only_where_anonymous_reading_is_allowed() {
+    let server = TestServer::start_with(|config| config.anon_read_rooms = vec!["public".to_string()]).await;
+    for (room, anon_read) in [("public", true), ("members", false)] {
+        let mut member = server.connect(room).await;
+        let mut lurker = server.connect(room).await;
+        member.recv_type("Welcome").await;
+        lurker.recv_type("Welcome").await;
+        member.join_as("member").await;
+        member.send_json(json!({ "type": "Message", "content": "first" })).await;
+        member.send_json(json!({ "type": "Message", "content": "second" })).await;
+        member.send_text("/ping").await;
+        member.recv_type("Pong").await;
+
+        lurker.send_text("/history").await;
+        if anon_read {
+            assert_eq!(lurker.recv_type("NewMessage").await["content"], "first");
+            assert_eq!(lurker.recv_type("NewMessage").await["content"], "second");
+            assert_eq!(lurker.recv_type("HistoryEntry").await["message"]["type"], "NewMessage");
+        } else {
+            // Nothing was delivered live, and history is refused too.
+            let reply = loop {
+                let frame = lurker.recv_json().await;
+                assert_ne!(frame["type"], "NewMessage");
+                if frame["type"] == "Notice" {
+                    break frame;
+                }
+            };
+            assert!(reply["message"].as_str().unwrap().contains("before loading history"));
+        }
+
+        // Either way, sending takes a name.
+        lurker.send_json(json!({ "type": "Message", "content": "hi" })).await;
+        assert!(lurker.recv_type("Notice").await["message"].as_str().unwrap().contains("before sending messages"));
+        member.expect_silence(Duration::from_millis(200)).await;
+    }
+}
+
+#[tokio::test]
+async fn macros_are_expanded_as_standalone_tokens_before_delivery() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    alice.send_json(json!({ "type": "Message", "content": "no idea /shrug" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], r"no idea ¯\_(ツ)_/¯");
+    alice.send_json(json!({ "type": "Message", "content": "see /shrugging and x/shrug" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "see /shrugging and x/shrug");
+
+    // The expanded form is what gets stored.
+    let stored = server.state.store.load_history("general", 10).await.unwrap();
+    assert!(stored.iter().any(|message| matches!(message, ServerMessage::NewMessage { content, .. } if content == r"no idea ¯\_(ツ)_/¯")));
+}
+
+#[tokio::test]
+async fn a_room_seeded_from_a_long_history_caches_only_the_newest_messages() {
+    let server = TestServer::start().await;
+    for n in 0..1000 {
+        let message = ServerMessage::NewMessage {
+            id: Uuid::new_v4(),
+            username: "seed".to_string(),
+            content: format!("message {}", n),
+            encrypted: false,
+            color: None,
+            severity: None,
+            reply_to: None,
+            expires_at: None,
+            sent_at: None,
+        };
+        server.state.store.save_message("archive", 0, &message, Utc::now()).await.unwrap();
+    }
+
+    // Nobody has joined or sent anything yet: the cache is bounded from the moment the room opens.
+    let mut client = server.connect("archive").await;
+    client.recv_type("Welcome").await;
+    let rooms = server.state.rooms.lock().await;
+    let history = &rooms["archive"].history;
+    assert_eq!(history.len(), IN_MEMORY_CACHE_SIZE);
+    assert!(matches!(history.back(), Some(ServerMessage::NewMessage { content, .. }) if content == "message 999"));
+    assert!(matches!(history.front(), Some(ServerMessage::NewMessage { content, .. }) if *content == format!("message {}", 1000 - IN_MEMORY_CACHE_SIZE)));
+}
+
+#[tokio::test]
+async fn closed_rooms_refuse_new_messages_but_still_serve_history() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("archive").await;
+    let mut bob = server.connect("archive").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    bob.join_as("bob").await;
+    alice.send_json(json!({ "type": "Message", "content": "before closing" })).await;
+    bob.recv_type("NewMessage").await;
+
+    bob.send_text("/close").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "Only moderators can close or reopen rooms.");
+    alice.send_text("/close").await;
+    assert!(bob.recv_type("Notice").await["message"].as_str().unwrap().starts_with("This room has been closed."));
+    assert!(server.state.store.is_room_closed("archive").await.unwrap());
+
+    bob.send_json(json!({ "type": "Message", "content": "too late" })).await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "This room is closed; new messages are not accepted.");
+    assert_eq!(history_contents(&mut bob, "/history 1 10").await, ["before closing"]);
+
+    // Newcomers can still join to read.
+    let mut carol = server.connect("archive").await;
+    carol.recv_type("Welcome").await;
+    carol.join_as("carol").await;
+    assert_eq!(history_contents(&mut carol, "/history 1 10").await, ["before closing"]);
+
+    alice.send_text("/reopen").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "This room has been reopened.");
+    bob.send_json(json!({ "type": "Message", "content": "back again" })).await;
+    assert_eq!(carol.recv_type("NewMessage").await["content"], "back again");
+}
+
+#[tokio::test]
+async fn a_user_named_anonymous_is_a_named_user() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    let mut named = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    named.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    named.join_as("anonymous").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "anonymous");
+
+    named.send_text("/stats-me").await;
+    assert_eq!(named.recv_type("SessionStats").await["username"], "anonymous");
+    named.send_json(json!({ "type": "Message", "content": "hello" })).await;
+    let message = alice.recv_type("NewMessage").await;
+    assert_eq!(message["username"], "anonymous");
+    alice.send_json(json!({ "type": "Message", "content": "hi there" })).await;
+    assert_eq!(named.recv_type("NewMessage").await["content"], "hi there");
+    assert_eq!(history_contents(&mut named, "/history 1 10").await, ["hello", "hi there"]);
+}
+
+#[tokio::test]
+async fn clients_that_cannot_be_sent_to_are_evicted_while_still_connected() {
+    let server = TestServer::start_with(|config| {
+        config.outbound_queue_size = 8;
+        config.outbound_drop_policy = DropPolicy::Disconnect;
+        config.frame_rate_limit = 100_000;
+        config.max_inbound_bytes = None;
+    })
+    .await;
+    let mut sender = server.connect("general").await;
+    let mut stalled = server.connect("general").await;
+    sender.recv_type("Welcome").await;
+    let stalled_id = Uuid::parse_str(stalled.recv_type("Welcome").await["client_id"].as_str().unwrap()).unwrap();
+    sender.join_as("sender").await;
+    stalled.join_as("stalled").await;
+    sender.recv_type("UserJoined").await;
+
+    // `stalled` keeps its socket open but never reads again, so its reader never ends on its own.
+    let content = "x".repeat(32 * 1024);
+    for _ in 0..1000 {
+        sender.send_json(json!({ "type": "Message", "content": content })).await;
+    }
+
+    // The failed send removed it from the room straight away, and the room was told it left.
+    assert_eq!(sender.recv_type("UserLeft").await["username"], "stalled");
+    sender.send_text("/ping").await;
+    sender.recv_type("Pong").await;
+    let rooms = server.state.rooms.lock().await;
+    assert!(!rooms["general"].clients.contains_key(&stalled_id));
+    assert!(rooms["general"].evicted.is_empty(), "the eviction was cleaned up");
+    drop(rooms);
+    drop(stalled);
+}
+
+#[tokio::test]
+async fn users_who_reconnect_soon_get_what_they_missed() {
+    let server = TestServer::start_with(|config| {
+        config.join_replay_count = 0;
+        config.frame_rate_limit = 1000;
+    })
+    .await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+    bob.send_text("/quit").await;
+    bob.recv_until_closed().await;
+    assert_eq!(alice.recv_type("UserLeft").await["username"], "bob");
+
+    // More than the buffer holds, so only the newest ones are kept.
+    let missed = OFFLINE_BUFFER_SIZE + 5;
+    for n in 0..missed {
+        alice.send_json(json!({ "type": "Message", "content": format!("message {}", n) })).await;
+    }
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+
+    let mut bob = server.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], format!("Welcome back! You missed {} messages while away.", OFFLINE_BUFFER_SIZE));
+    for n in missed - OFFLINE_BUFFER_SIZE..missed {
+        assert_eq!(bob.recv_type("NewMessage").await["content"], format!("message {}", n));
+    }
+
+    // The buffer was Bob's alone, and it was used up; anyone else gets the usual (here empty) replay.
+    let mut carol = server.connect("general").await;
+    carol.recv_type("Welcome").await;
+    carol.join_as("carol").await;
+    carol.send_text("/ping").await;
+    loop {
+        let frame = carol.recv_json().await;
+        assert_ne!(frame["type"], "NewMessage");
+        if frame["type"] == "Pong" {
+            break;
+        }
+    }
+    assert!(!server.state.rooms.lock().await["general"].offline_buffers.contains_key(&"bob".to_string()));
+}
+
+#[tokio::test]
+async fn quit_announces_the_parting_message_and_closes_normally() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    let mut carol = server.connect("general").await;
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.recv_type("Welcome").await;
+    }
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    carol.join_as("carol").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "carol");
+
+    bob.send_text("/quit see you tomorrow").await;
+    assert_eq!(bob.recv_until_closed().await, Some(1000));
+    let left = alice.recv_type("UserLeft").await;
+    assert_eq!(left["username"], "bob");
+    assert_eq!(left["message"], "see you tomorrow");
+
+    // Dropping the connection is a leave too, just without any parting words.
+    drop(carol);
+    let left = alice.recv_type("UserLeft").await;
+    assert_eq!(left["username"], "carol");
+    assert!(left.get("message").is_none());
+    assert_eq!(server.state.rooms.lock().await["general"].clients.len(), 1);
+}
+
+#[tokio::test]
+async fn joiners_get_only_the_configured_number_of_recent_messages() {
+    let server = TestServer::start_with(|config| config.join_replay_count = 5).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    for n in 0..20 {
+        alice.send_json(json!({ "type": "Message", "content": format!("message {}", n) })).await;
+    }
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+
+    let mut bob = server.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    bob.send_text("/ping").await;
+    let mut replayed = Vec::new();
+    loop {
+        let frame = bob.recv_json().await;
+        match frame["type"].as_str().unwrap() {
+            "NewMessage" => replayed.push(frame["content"].as_str().unwrap().to_string()),
+            "Pong" => break,
+            _ => {}
+        }
+    }
+    assert_eq!(replayed, ["message 15", "message 16", "message 17", "message 18", "message 19"]);
+    // The rest is still there for the asking.
+    assert_eq!(history_contents(&mut bob, "/history 1 100").await.len(), 20);
+}
+
+#[tokio::test]
+async fn a_draining_server_refuses_newcomers_while_existing_clients_carry_on() {
+    let server = TestServer::start_with(|config| config.shutdown_drain = Duration::from_secs(30)).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    let state = server.state.clone();
+    let shutdown = tokio::spawn(async move { shut_down(&state).await });
+    while !server.state.shutting_down.load(std::sync::atomic::Ordering::SeqCst) {
+        tokio::task::yield_now().await;
+    }
+
+    let refused = tokio_tungstenite::connect_async(format!("ws://{}/ws/general", server.addr)).await;
+    assert!(matches!(refused, Err(WsError::Http(response)) if response.status() == 503));
+    alice.send_json(json!({ "type": "Message", "content": "wrapping up" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "wrapping up");
+
+    // Once everyone has left, the drain ends without waiting out its full period.
+    alice.send_text("/quit").await;
+    bob.send_text("/quit").await;
+    assert_eq!(alice.recv_until_closed().await, Some(1000));
+    assert_eq!(bob.recv_until_closed().await, Some(1000));
+    tokio::time::timeout(Duration::from_secs(5), shutdown).await.expect("the drain waited for its full period").unwrap();
+}
+
+#[tokio::test]
+async fn a_database_that_stops_answering_is_reported_instead_of_hanging() {
+    let path = std::env::temp_dir().join(format!("chat-db-timeout-{}.db", Uuid::new_v4()));
+    let store = SqliteStore::connect(path.to_str().unwrap()).await.unwrap();
+    let server = TestServer::start_with_store(Arc::new(store), |config| config.db_timeout = Duration::from_millis(300)).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+
+    // Another connection holds the database locked, so every query waits on it.
+    let mut locker = SqliteConnection::connect(&format!("sqlite://{}", path.display())).await.unwrap();
+    sqlx::query("BEGIN EXCLUSIVE").execute(&mut locker).await.unwrap();
+    const NOT_RESPONDING: &str = "The database is not responding; please try again shortly.";
+
+    let started = Instant::now();
+    alice.send_text("/history 1 10").await;
+    assert_eq!(alice.recv_type("Error").await["message"], NOT_RESPONDING);
+    assert!(started.elapsed() < Duration::from_secs(2), "the request waited {:?}", started.elapsed());
+
+    // A room whose history can't be loaded isn't opened on an empty history; the joiner is told why.
+    let mut bob = server.connect("other").await;
+    bob.recv_type("Welcome").await;
+    assert_eq!(bob.recv_type("Error").await["message"], NOT_RESPONDING);
+    assert_eq!(bob.recv_until_closed().await, Some(1011));
+
+    sqlx::query("ROLLBACK").execute(&mut locker).await.unwrap();
+    alice.send_text("/history-count").await;
+    assert_eq!(alice.recv_type("MessageCount").await["count"], 1);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn history_entries_carry_their_reaction_counts() {
+    let server = TestServer::start().await;
+    let store = &server.state.store;
+    let mut ids = Vec::new();
+    for content in ["popular", "ignored"] {
+        let id = Uuid::new_v4();
+        let message = ServerMessage::NewMessage {
+            id,
+            username: "seed".to_string(),
+            content: content.to_string(),
+            encrypted: false,
+            color: None,
+            severity: None,
+            reply_to: None,
+            expires_at: None,
+            sent_at: None,
+        };
+        store.save_message("general", 0, &message, Utc::now()).await.unwrap();
+        ids.push(id);
+    }
+    for username in ["ann", "ben", "cat"] {
+        assert!(store.add_reaction("general", ids[0], username, "👍").await.unwrap());
+    }
+    assert!(store.add_reaction("general", ids[0], "ann", "❤️").await.unwrap());
+    assert!(!store.add_reaction("general", ids[0], "ann", "👍").await.unwrap(), "a user reacts with an emoji once");
+
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+    bob.send_text(&format!("/react {} 👍", ids[0])).await;
+    let reaction = alice.recv_type("ReactionAdded").await;
+    assert_eq!(reaction["username"], "bob");
+    assert_eq!(reaction["emoji"], "👍");
+
+    alice.send_text("/history 1 10").await;
+    let popular = alice.recv_type("HistoryEntry").await;
+    assert_eq!(popular["message"]["content"], "popular");
+    assert_eq!(popular["reactions"], json!({ "👍": 4, "❤️": 1 }));
+    let ignored = alice.recv_type("HistoryEntry").await;
+    assert_eq!(ignored["message"]["content"], "ignored");
+    assert_eq!(ignored["reactions"], json!({}));
+}
+
+#[tokio::test]
+async fn compressed_and_plain_rows_load_back_intact() {
+    // Compression is only offered for PostgreSQL, so this needs the development database running.
+    let Ok(compressed) = PostgresStore::connect(Duration::from_secs(1), true, false).await else {
+        eprintln!("Skipping: PostgreSQL is not reachable.");
+        return;
+    };
+    let plain = PostgresStore::connect(Duration::from_secs(1), false, false).await.unwrap();
+    let room = format!("compression-{}", Uuid::new_v4());
+    let chat = |content: String| ServerMessage::NewMessage {
+        id: Uuid::new_v4(),
+        username: "seed".to_string(),
+        content,
+        encrypted: false,
+        color: None,
+        severity: None,
+        reply_to: None,
+        expires_at: None,
+        sent_at: Some(Utc::now()),
+    };
+    let long = "all work and no play ".repeat(500);
+    plain.save_message(&room, 0, &chat("stored as JSONB".to_string()), Utc::now()).await.unwrap();
+    compressed.save_message(&room, 0, &chat(long.clone()), Utc::now()).await.unwrap();
+
+    // Either store reads both kinds of row.
+    for store in [&compressed, &plain] {
+        let contents: Vec<String> = store
+            .load_history(&room, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| match message {
+                ServerMessage::NewMessage { content, .. } => content,
+                other => panic!("expected a chat message, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(contents, ["stored as JSONB".to_string(), long.clone()]);
+    }
+}
+
+#[tokio::test]
+async fn full_outbound_queues_follow_the_configured_drop_policy() {
+    for policy in [DropPolicy::DropOldest, DropPolicy::DropNewest, DropPolicy::Disconnect] {
+        let server = TestServer::start_with(|config| {
+            config.outbound_queue_size = 16;
+            config.outbound_drop_policy = policy;
+            config.frame_rate_limit = 100_000;
+            config.max_inbound_bytes = None;
+        })
+        .await;
+        let mut sender = server.connect("general").await;
+        let mut stalled = server.connect("general").await;
+        sender.recv_type("Welcome").await;
+        stalled.recv_type("Welcome").await;
+        sender.join_as("sender").await;
+        stalled.join_as("stalled").await;
+        sender.recv_type("UserJoined").await;
+        stalled.send_text("/ping").await;
+        stalled.recv_type("Pong").await;
+
+        // `stalled` reads nothing until the burst is over, so once its socket buffers fill up the rest queues.
+        let padding = "x".repeat(32 * 1024);
+        for n in 0..1000 {
+            sender.send_json(json!({ "type": "Message", "content": format!("{} {}", n, padding) })).await;
+        }
+        sender.send_text("/ping").await;
+        sender.recv_type("Pong").await;
+
+        if policy == DropPolicy::Disconnect {
+            assert_eq!(stalled.recv_until_closed().await, Some(4008), "{:?}", policy);
+            continue;
+        }
+        let mut received = Vec::new();
+        while let Some(frame) = stalled.recv_json_within(Duration::from_millis(500)).await {
+            if frame["type"] == "NewMessage" {
+                let content = frame["content"].as_str().unwrap();
+                received.push(content.split(' ').next().unwrap().parse::<usize>().unwrap());
+            }
+        }
+        assert!(received.len() < 1000, "nothing was dropped under {:?}", policy);
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]), "{:?} reordered messages", policy);
+        match policy {
+            // Each new message pushes out the oldest queued one, so the burst's last messages are kept.
+            DropPolicy::DropOldest => assert!(received.ends_with(&(984..1000).collect::<Vec<_>>()), "{:?}", received),
+            // What was already queued is kept, so the burst's last messages are the ones lost.
+            _ => assert!(!received.contains(&999), "{:?}", received),
+        }
+    }
+}
+
+#[tokio::test]
+async fn clients_sending_too_many_frames_are_closed_with_1008() {
+    let server = TestServer::start_with(|config| config.frame_rate_limit = 5).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    for _ in 0..10 {
+        bob.send_text("/ping").await;
+    }
+    assert_eq!(bob.recv_until_closed().await, Some(1008));
+    assert_eq!(alice.recv_type("UserLeft").await["username"], "bob");
+    // A well-behaved client in the same room is unaffected.
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+}
+
+#[tokio::test]
+async fn renaming_is_announced_without_replaying_history_again() {
+    let server = TestServer::start_with(|config| config.rename_cooldown = Duration::ZERO).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.send_json(json!({ "type": "Message", "content": "earlier" })).await;
+    let mut bob = server.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "earlier");
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+
+    bob.join_as("robert").await;
+    let rename = alice.recv_type("UserRenamed").await;
+    assert_eq!(rename["old_username"], "bob");
+    assert_eq!(rename["new_username"], "robert");
+    // Bob sees his own rename, but no history and no second join.
+    bob.send_text("/ping").await;
+    loop {
+        let frame = bob.recv_json().await;
+        assert!(frame["type"] != "NewMessage" && frame["type"] != "UserJoined", "{}", frame);
+        if frame["type"] == "Pong" {
+            break;
+        }
+    }
+    alice.send_text("/ping").await;
+    assert_eq!(alice.recv_json().await["type"], "Pong");
+}
+
+#[tokio::test]
+async fn admins_can_list_every_room_a_user_is_in() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut ids = HashMap::new();
+    let mut clients = Vec::new();
+    for (room, name) in [("lobby", "alice"), ("games", "alice"), ("games", "bob")] {
+        let mut client = server.connect(room).await;
+        let welcome = client.recv_type("Welcome").await;
+        client.join_as(name).await;
+        client.send_text("/ping").await;
+        client.recv_type("Pong").await;
+        ids.insert((room, name), welcome["client_id"].clone());
+        clients.push(client);
+    }
+
+    let http = reqwest::Client::new();
+    let found: Value = http
+        .get(server.url("/admin/users/alice/rooms"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        found,
+        json!({
+            "username": "alice",
+            "rooms": [
+                { "room": "games", "client_ids": [ids[&("games", "alice")]] },
+                { "room": "lobby", "client_ids": [ids[&("lobby", "alice")]] },
+            ]
+        })
+    );
+
+    let nobody: Value = http.get(server.url("/admin/users/carol/rooms")).bearer_auth("secret").send().await.unwrap().json().await.unwrap();
+    assert_eq!(nobody["rooms"], json!([]));
+    let anonymous = http.get(server.url("/admin/users/alice/rooms")).send().await.unwrap();
+    assert_eq!(anonymous.status(), 401);
+}
+
+#[tokio::test]
+async fn messages_are_normalized_and_invisible_ones_are_dropped() {
+    let server = TestServer::start_with(|config| config.content_normalization = ContentNormalization::Collapse).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+
+    alice.send_json(json!({ "type": "Message", "content": "\u{200B}\u{FEFF}" })).await;
+    alice.send_json(json!({ "type": "Message", "content": " \n\u{200B} \n" })).await;
+    alice.send_json(json!({ "type": "Message", "content": "\u{200B}  hello \n\n  world \t" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "hello world");
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+    assert_eq!(history_contents(&mut bob, "/history").await, ["hello world"]);
+
+    assert_eq!(ContentNormalization::Trim.normalize("  keep  inner  spacing\n"), Some("keep  inner  spacing".to_string()));
+    assert_eq!(ContentNormalization::None.normalize(" as written "), Some(" as written ".to_string()));
+    assert_eq!(ContentNormalization::None.normalize("\u{2060} "), None);
+}
+
+#[tokio::test]
+async fn registered_names_need_their_secret_while_others_are_free() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.send_text("/register hunter2").await;
+    assert_eq!(
+        alice.recv_type("Notice").await["message"],
+        "'alice' is now registered. Use `/user alice <secret>` to claim it in future sessions."
+    );
+    let stored = server.state.store.get_secret_hash("alice").await.unwrap().expect("the name is registered");
+    assert!(!stored.contains("hunter2"), "only a hash of the secret is stored");
+    alice.send_text("/quit").await;
+    alice.recv_until_closed().await;
+
+    let mut observer = server.connect("general").await;
+    observer.recv_type("Welcome").await;
+    observer.join_as("carol").await;
+    observer.send_text("/ping").await;
+    observer.recv_type("Pong").await;
+
+    let mut claimant = server.connect("general").await;
+    claimant.recv_type("Welcome").await;
+    claimant.join_as("alice").await;
+    assert_eq!(claimant.recv_type("Notice").await["message"], "'alice' is a registered username; use `/user alice <secret>`.");
+    claimant.send_text("/user alice letmein").await;
+    assert_eq!(claimant.recv_type("Notice").await["message"], "Incorrect secret for 'alice'.");
+    claimant.send_json(json!({ "type": "SetUsername", "username": "alice", "secret": "hunter2" })).await;
+    assert_eq!(observer.recv_type("UserJoined").await["username"], "alice");
+}
+
+#[tokio::test]
+async fn flooding_with_control_and_binary_frames_is_cut_off_too() {
+    let server = TestServer::start_with(|config| config.frame_rate_limit = 20).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    // None of these carry a message, but each one still has to be read.
+    for n in 0..40u8 {
+        let frame = if n % 2 == 0 { Message::Ping(vec![n].into()) } else { Message::Binary(vec![n; 16].into()) };
+        bob.send_frame(frame).await;
+    }
+    assert_eq!(bob.recv_until_closed().await, Some(1008));
+    assert_eq!(alice.recv_type("UserLeft").await["username"], "bob");
+    assert!(!server.state.rooms.lock().await["general"].clients.values().any(|client| client.username.as_deref() == Some("bob")));
+}
+
+#[tokio::test]
+async fn a_delayed_write_keeps_the_time_the_message_was_sent() {
+    let path = std::env::temp_dir().join(format!("chat-db-delayed-{}.db", Uuid::new_v4()));
+    let store = SqliteStore::connect(path.to_str().unwrap()).await.unwrap();
+    let server = TestServer::start_with_store(Arc::new(store), |config| config.db_timeout = Duration::from_secs(5)).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+
+    // The write has to wait for another connection to let go of the database.
+    let mut locker = SqliteConnection::connect(&format!("sqlite://{}", path.display())).await.unwrap();
+    sqlx::query("BEGIN EXCLUSIVE").execute(&mut locker).await.unwrap();
+    let sent = Utc::now();
+    alice.send_json(json!({ "type": "Message", "content": "held up" })).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let released = Utc::now();
+    sqlx::query("ROLLBACK").execute(&mut locker).await.unwrap();
+
+    // Commands are handled in order, so the count comes back once the write has gone through.
+    alice.send_text("/history-count").await;
+    assert_eq!(alice.recv_type("MessageCount").await["count"], 2);
+    let page = server.state.store.load_history_paginated("general", 1, 10, false).await.unwrap();
+    let (message, _, stored_at) = page.back().unwrap();
+    assert!(matches!(message, ServerMessage::NewMessage { content, .. } if content == "held up"));
+    assert!(*stored_at >= sent - chrono::Duration::milliseconds(5), "stored at {}, sent at {}", stored_at, sent);
+    assert!(released - *stored_at >= chrono::Duration::milliseconds(900), "stored at {}, released at {}", stored_at, released);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn quick_renames_wait_out_the_cooldown() {
+    let server = TestServer::start_with(|config| config.rename_cooldown = Duration::from_millis(500)).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    // Joining isn't a rename, so the first rename goes through straight away.
+    bob.join_as("robert").await;
+    assert_eq!(alice.recv_type("UserRenamed").await["new_username"], "robert");
+    bob.join_as("bobby").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "You can change your name again in 1 seconds.");
+    alice.send_text("/ping").await;
+    loop {
+        let frame = alice.recv_json().await;
+        assert_ne!(frame["type"], "UserRenamed", "the refused rename wasn't announced");
+        if frame["type"] == "Pong" {
+            break;
+        }
+    }
+
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    bob.join_as("bobby").await;
+    let renamed = alice.recv_type("UserRenamed").await;
+    assert_eq!((renamed["old_username"].as_str(), renamed["new_username"].as_str()), (Some("robert"), Some("bobby")));
+}
+
+#[tokio::test]
+async fn room_exports_stream_one_json_line_per_stored_message() {
+    let path = std::env::temp_dir().join(format!("chat-export-{}.db", Uuid::new_v4()));
+    let store = SqliteStore::connect(path.to_str().unwrap()).await.unwrap();
+    let server = TestServer::start_with_store(Arc::new(store), |config| config.admin_token = Some("secret".to_string())).await;
+    let store = &server.state.store;
+    for n in 0..250 {
+        let room = if n % 5 == 0 { "games" } else { "lobby" };
+        let message = ServerMessage::NewMessage {
+            id: Uuid::new_v4(),
+            username: "seed".to_string(),
+            content: format!("message {}", n),
+            encrypted: false,
+            color: None,
+            severity: None,
+            reply_to: None,
+            expires_at: None,
+            sent_at: None,
+        };
+        store.save_message(room, 0, &message, Utc::now()).await.unwrap();
+    }
+    store.save_message("lobby", 0, &ServerMessage::UserJoined { username: "alice".to_string(), sent_at: None }, Utc::now()).await.unwrap();
+
+    let http = reqwest::Client::new();
+    let refused = http.get(server.url("/rooms/lobby/export")).send().await.unwrap();
+    assert_eq!(refused.status(), 401);
+    let export = http.get(server.url("/rooms/lobby/export")).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(export.status(), 200);
+    assert_eq!(export.headers()["content-type"], "application/x-ndjson");
+    let body = export.text().await.unwrap();
+    let lines: Vec<Value> = body.lines().map(|line| serde_json::from_str(line).expect("each line is one message")).collect();
+
+    assert_eq!(lines.len() as i64, store.get_message_count("lobby").await.unwrap());
+    assert_eq!(lines.len(), 201);
+    let expected: Vec<String> = (0..250).filter(|n| n % 5 != 0).map(|n| format!("message {}", n)).collect();
+    assert_eq!(lines[..200].iter().map(|line| line["content"].as_str().unwrap()).collect::<Vec<_>>(), expected);
+    assert_eq!(lines[200]["type"], "UserJoined");
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn encrypted_messages_are_relayed_and_stored_untouched() {
+    let server = TestServer::start_with(|config| {
+        config.encrypted_rooms = vec!["vault".to_string()];
+        config.content_normalization = ContentNormalization::Collapse;
+    })
+    .await;
+    let mut alice = server.connect("vault").await;
+    let mut bob = server.connect("vault").await;
+    let mut carol = server.connect_with_protocol("vault", None).await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    carol.send_text("/user carol").await;
+    alice.recv_type("UserJoined").await;
+    alice.recv_type("UserJoined").await;
+
+    alice.send_json(json!({ "type": "Message", "content": "plain words" })).await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "This room only accepts end-to-end encrypted messages.");
+
+    // Whitespace, macros and the like are all left alone: the server can't know what the bytes mean.
+    let blob = "  q0/shrug \n\n x9==  ";
+    alice.send_json(json!({ "type": "Message", "content": blob, "encrypted": true })).await;
+    let relayed = bob.recv_type("NewMessage").await;
+    assert_eq!(relayed["content"], blob);
+    assert_eq!(relayed["encrypted"], true);
+    assert_eq!(relayed["username"], "alice");
+    assert!(Uuid::parse_str(relayed["id"].as_str().unwrap()).is_ok());
+    // Plain-text clients can't decrypt, so they get a placeholder rather than the blob.
+    loop {
+        let line = recv_chat_line(&mut carol).await;
+        assert!(!line.contains("q0"), "the blob was shown as text: {}", line);
+        if line.contains("[encrypted message]") {
+            break;
+        }
+    }
+
+    bob.send_text("/ping").await;
+    bob.recv_type("Pong").await;
+    let stored = server.state.store.load_history("vault", 10).await.unwrap();
+    assert!(stored.iter().any(|message| matches!(
+        message,
+        ServerMessage::NewMessage { content, encrypted: true, .. } if content == blob
+    )));
+}
+
+#[tokio::test]
+async fn a_panicking_handler_only_takes_its_own_client_down() {
+    // The first save of the room's owner panics, in the middle of a join and with the rooms lock held.
+    let armed = Arc::new(AtomicBool::new(true));
+    let trigger = armed.clone();
+    let store = HookedStore::new(move |method| {
+        if method == "set_room_ownership" && trigger.swap(false, Ordering::SeqCst) {
+            panic!("injected store failure");
+        }
+        None
+    });
+    let server = TestServer::start_with_store(Arc::new(store), |_| {}).await;
+    let mut alice = server.connect("clubhouse").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_until_closed().await;
+    assert!(!armed.load(Ordering::SeqCst));
+
+    // Everyone else carries on in the same room, and nothing of alice's connection is left behind.
+    let mut bob = server.connect("clubhouse").await;
+    let mut carol = server.connect("clubhouse").await;
+    bob.recv_type("Welcome").await;
+    carol.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    carol.join_as("carol").await;
+    assert_eq!(bob.recv_type("UserJoined").await["username"], "carol");
+    bob.send_json(json!({ "type": "Message", "content": "still standing" })).await;
+    assert_eq!(carol.recv_type("NewMessage").await["content"], "still standing");
+    let rooms = server.state.rooms.lock().await;
+    let names: Vec<_> = rooms["clubhouse"].clients.values().filter_map(|client| client.username.clone()).collect();
+    assert_eq!(names.len(), 2);
+    assert!(!names.contains(&"alice".to_string()));
+}
+
+#[tokio::test]
+async fn the_built_in_client_is_served_only_when_enabled() {
+    let enabled = TestServer::start_with(|config| config.serve_client = true).await;
+    let page = reqwest::get(enabled.url("/")).await.unwrap();
+    assert_eq!(page.status(), 200);
+    assert!(page.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+    let html = page.text().await.unwrap();
+    assert!(html.contains("/ws/") && html.contains("chat.v2"), "the page talks to the WebSocket endpoint");
+
+    let disabled = TestServer::start().await;
+    assert_eq!(reqwest::get(disabled.url("/")).await.unwrap().status(), 404);
+}
+
+#[tokio::test]
+async fn recently_active_rooms_are_listed_newest_first() {
+    let path = std::env::temp_dir().join(format!("chat-recent-{}.db", Uuid::new_v4()));
+    let store = SqliteStore::connect(path.to_str().unwrap()).await.unwrap();
+    let server = TestServer::start_with_store(Arc::new(store), |config| config.admin_token = Some("secret".to_string())).await;
+    let now = Utc::now();
+    let seeded = [("archive", 50), ("archive", 30), ("busy", 5), ("busy", 2), ("busy", 3), ("fresh", 0)];
+    for (room, hours_ago) in seeded {
+        let message = ServerMessage::NewMessage {
+            id: Uuid::new_v4(),
+            username: "seed".to_string(),
+            content: format!("{} hours ago", hours_ago),
+            encrypted: false,
+            color: None,
+            severity: None,
+            reply_to: None,
+            expires_at: None,
+            sent_at: None,
+        };
+        let created_at = now - chrono::Duration::hours(hours_ago) - chrono::Duration::minutes(1);
+        server.state.store.save_message(room, 0, &message, created_at).await.unwrap();
+    }
+
+    let http = reqwest::Client::new();
+    let recent = |since_hours: &'static str| {
+        http.get(server.url(&format!("/rooms/recent?since_hours={}", since_hours))).bearer_auth("secret").send()
+    };
+    let rooms: Value = recent("24").await.unwrap().json().await.unwrap();
+    // Counts cover every stored message, but only rooms active since the cutoff are listed.
+    let listed: Vec<_> = rooms
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|room| (room["room"].as_str().unwrap(), room["message_count"].as_i64().unwrap()))
+        .collect();
+    assert_eq!(listed, [("fresh", 1), ("busy", 3)]);
+    let last_active: chrono::DateTime<Utc> = rooms[1]["last_active"].as_str().unwrap().parse().unwrap();
+    assert!((last_active - (now - chrono::Duration::hours(2))).num_minutes().abs() <= 1, "busy was last active {}", last_active);
+
+    let rooms: Value = recent("48").await.unwrap().json().await.unwrap();
+    let names: Vec<_> = rooms.as_array().unwrap().iter().map(|room| room["room"].as_str().unwrap()).collect();
+    assert_eq!(names, ["fresh", "busy", "archive"]);
+    let _ = std::fs::remove_file(&path);
+}
+
+/// Whether `client` gets a `NewMessage` back before its next `/ping` is answered.
+async fn echoed_before_pong(client: &mut TestClient) -> Option<Value> {
+    client.send_text("/ping").await;
+    let mut echoed = None;
+    loop {
+        let frame = client.recv_json().await;
+        match frame["type"].as_str().unwrap() {
+            "NewMessage" => echoed = Some(frame),
+            "Pong" => return echoed,
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn senders_get_their_own_messages_back_only_with_echo_on() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    alice.send_json(json!({ "type": "Message", "content": "quiet" })).await;
+    bob.recv_type("NewMessage").await;
+    assert_eq!(echoed_before_pong(&mut alice).await, None, "echo is off by default");
+
+    alice.send_text("/echo on").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "Your own messages will now be echoed back to you.");
+    alice.send_json(json!({ "type": "Message", "content": "loud" })).await;
+    let seen_by_bob = bob.recv_type("NewMessage").await;
+    let echoed = echoed_before_pong(&mut alice).await.expect("the message was echoed");
+    assert_eq!(echoed["content"], "loud");
+    assert_eq!(echoed["id"], seen_by_bob["id"], "the echo is the server's copy, with the server's id");
+
+    alice.send_text("/echo off").await;
+    alice.recv_type("Notice").await;
+    alice.send_json(json!({ "type": "Message", "content": "quiet again" })).await;
+    bob.recv_type("NewMessage").await;
+    assert_eq!(echoed_before_pong(&mut alice).await, None);
+
+    // ECHO_OWN_MESSAGES turns it on for every new connection.
+    let server = TestServer::start_with(|config| config.echo_own_messages = true).await;
+    let mut carol = server.connect("general").await;
+    carol.recv_type("Welcome").await;
+    carol.join_as("carol").await;
+    carol.send_json(json!({ "type": "Message", "content": "hello me" })).await;
+    assert_eq!(echoed_before_pong(&mut carol).await.unwrap()["content"], "hello me");
+}
+
+/// Chats in `room` on one server, then checks what a second server on the same store serves from it.
+async fn run_history_scenario(store: Arc<dyn MessageStore>, room: &str) {
+    let first = TestServer::start_with_store(store.clone(), |_| {}).await;
+    let mut alice = first.connect(room).await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    for word in ["one", "two", "three", "four", "five"] {
+        alice.send_json(json!({ "type": "Message", "content": word })).await;
+    }
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+
+    // A fresh server knows nothing but what the store gives back.
+    let second = TestServer::start_with_store(store, |_| {}).await;
+    let mut bob = second.connect(room).await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    let mut replayed = Vec::new();
+    while replayed.len() < 5 {
+        replayed.push(bob.recv_type("NewMessage").await["content"].as_str().unwrap().to_string());
+    }
+    assert_eq!(replayed, ["one", "two", "three", "four", "five"]);
+    bob.send_text("/history-count").await;
+    assert_eq!(bob.recv_type("MessageCount").await["count"], 7, "five messages and two joins");
+    assert_eq!(history_contents(&mut bob, "/history 1 2").await, ["four", "five"]);
+    assert_eq!(history_contents(&mut bob, "/history 3 2").await, ["one"]);
+}
+
+#[tokio::test]
+async fn every_store_backend_serves_the_same_history() {
+    run_history_scenario(Arc::new(MemoryStore::default()), "general").await;
+
+    let path = std::env::temp_dir().join(format!("chat-backend-{}.db", Uuid::new_v4()));
+    run_history_scenario(Arc::new(SqliteStore::connect(path.to_str().unwrap()).await.unwrap()), "general").await;
+    let _ = std::fs::remove_file(&path);
+
+    match PostgresStore::connect(Duration::from_secs(1), false, false).await {
+        Ok(store) => run_history_scenario(Arc::new(store), &format!("backend-{}", Uuid::new_v4())).await,
+        Err(_) => eprintln!("Skipping PostgreSQL: it is not reachable."),
+    }
+}
+
+#[tokio::test]
+async fn moderation_actions_are_written_to_the_audit_log() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    // Refused actions leave no trace.
+    bob.send_text("/close").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "Only moderators can close or reopen rooms.");
+    alice.send_text("/op bob").await;
+    alice.recv_type("OwnershipChanged").await;
+    alice.send_text("/close").await;
+    alice.recv_type("Notice").await;
+    let mut carol = server.connect("elsewhere").await;
+    carol.recv_type("Welcome").await;
+    carol.join_as("carol").await;
+    carol.recv_type("OwnershipChanged").await;
+    carol.send_text("/topic somewhere else").await;
+    carol.recv_type("TopicChanged").await;
+
+    let http = reqwest::Client::new();
+    let audit = |query: &'static str| http.get(server.url(&format!("/admin/audit{}", query))).bearer_auth("secret").send();
+    let entries: Value = audit("?room=general").await.unwrap().json().await.unwrap();
+    let actions: Vec<_> = entries.as_array().unwrap().iter().map(|entry| entry["action"].as_str().unwrap()).collect();
+    assert_eq!(actions, ["close_room", "grant_operator"], "newest first, only the requested room");
+    assert_eq!(entries[1]["actor"], "alice");
+    assert_eq!(entries[1]["target"], "bob");
+    assert_eq!(entries[1]["room"], "general");
+
+    let everything: Value = audit("").await.unwrap().json().await.unwrap();
+    assert_eq!(everything[0]["action"], "set_topic");
+    assert_eq!(everything[0]["detail"], "somewhere else");
+    let second_page: Value = audit("?page=2&page_size=1").await.unwrap().json().await.unwrap();
+    assert_eq!(second_page.as_array().unwrap().len(), 1);
+    assert_eq!(second_page[0]["action"], "close_room");
+    assert_eq!(http.get(server.url("/admin/audit")).send().await.unwrap().status(), 401);
+}
+
+#[tokio::test]
+async fn kicked_users_are_disconnected_and_the_kick_is_audited() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    bob.send_text("/kick alice").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "Only moderators can use /kick.");
+    alice.send_text("/kick nobody").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "There is nobody called 'nobody' in this room.");
+    alice.send_text("/kick bob spamming").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "'bob' was kicked by alice: spamming");
+    assert_eq!(bob.recv_until_closed().await, Some(4005));
+    assert_eq!(alice.recv_type("UserLeft").await["username"], "bob");
+
+    // A kick isn't a ban: the name can come straight back.
+    let mut again = server.connect("general").await;
+    again.recv_type("Welcome").await;
+    again.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    let entries: Value = reqwest::Client::new()
+        .get(server.url("/admin/audit?room=general"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(entries.as_array().unwrap().len(), 1, "refused kicks leave no trace");
+    assert_eq!(entries[0]["action"], "kick");
+    assert_eq!(entries[0]["actor"], "alice");
+    assert_eq!(entries[0]["target"], "bob");
+    assert_eq!(entries[0]["detail"], "spamming");
+}
+
+#[tokio::test]
+async fn bans_mutes_and_clears_are_enforced_and_audited() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    let mut carol = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    carol.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+    carol.join_as("carol").await;
+    alice.recv_type("UserJoined").await;
+
+    alice.send_text("/mute carol 60").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "'carol' was muted by alice for 60 seconds.");
+    carol.recv_type("Notice").await;
+    carol.send_json(json!({ "type": "Message", "content": "hello?" })).await;
+    assert_eq!(carol.recv_type("Notice").await["message"], "You are muted in this room for another 60 seconds.");
+    alice.send_text("/unmute carol").await;
+    alice.recv_type("Notice").await;
+    carol.recv_type("Notice").await;
+    carol.send_json(json!({ "type": "Message", "content": "hello again" })).await;
+    assert_eq!(alice.recv_type("NewMessage").await["content"], "hello again");
+
+    alice.send_text("/ban bob").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "'bob' was banned by alice.");
+    assert_eq!(bob.recv_until_closed().await, Some(4005));
+    let mut returning = server.connect("general").await;
+    returning.recv_type("Welcome").await;
+    returning.send_text("/user bob").await;
+    assert_eq!(returning.recv_type("Notice").await["message"], "'bob' is banned from this room.");
+    alice.send_text("/unban bob").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "'bob' is no longer banned from this room.");
+    returning.join_as("bob").await;
+
+    // Three joins, carol's message, bob's leave and his return.
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while server.state.store.get_message_count("general").await.unwrap() < 6 {
+        assert!(tokio::time::Instant::now() < deadline, "the messages were never saved");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    alice.send_text("/clear").await;
+    assert_eq!(alice.recv_type("HistoryCleared").await["by"], "alice");
+    assert_eq!(server.state.store.get_message_count("general").await.unwrap(), 0);
+    let mut late = server.connect("general").await;
+    late.recv_type("Welcome").await;
+    late.join_as("dave").await;
+    alice.send_json(json!({ "type": "Message", "content": "fresh start" })).await;
+    assert_eq!(late.recv_type("NewMessage").await["content"], "fresh start", "no history is replayed before it");
+
+    let entries: Value = reqwest::Client::new()
+        .get(server.url("/admin/audit?room=general"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let actions: Vec<_> = entries.as_array().unwrap().iter().map(|entry| entry["action"].as_str().unwrap()).collect();
+    assert_eq!(actions, ["clear_history", "unban", "ban", "unmute", "mute"]);
+    assert_eq!(entries[4]["detail"], "duration=60s");
+    assert_eq!(entries[0]["detail"], "messages=6");
+}
+
+#[tokio::test]
+async fn joiners_are_shown_the_topic_after_the_history() {
+    let store: Arc<dyn MessageStore> = Arc::new(MemoryStore::default());
+    let server = TestServer::start_with_store(store.clone(), |_| {}).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    alice.send_text("/topic").await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "No topic is set for this room.");
+    alice.send_text(&format!("/topic {}", "x".repeat(201))).await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "Topics can be at most 200 characters.");
+    alice.send_json(json!({ "type": "Message", "content": "earlier" })).await;
+    alice.send_text("/topic release planning").await;
+    let changed = alice.recv_type("TopicChanged").await;
+    assert_eq!((changed["topic"].as_str(), changed["by"].as_str()), (Some("release planning"), Some("alice")));
+
+    let mut bob = server.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "earlier");
+    assert_eq!(bob.recv_type("TopicChanged").await["topic"], "release planning");
+    bob.send_text("/topic").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "Topic: release planning (set by alice)");
+    bob.send_text("/topic mine now").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "Only moderators can change the topic.");
+
+    // The topic is kept in the store, so it outlives the room.
+    let restarted = TestServer::start_with_store(store, |_| {}).await;
+    let mut carol = restarted.connect("general").await;
+    carol.recv_type("Welcome").await;
+    carol.join_as("carol").await;
+    assert_eq!(carol.recv_type("TopicChanged").await["by"], "alice");
+}
+
+#[tokio::test]
+async fn other_rooms_stay_responsive_while_a_large_history_loads() {
+    let loading = Arc::new(AtomicBool::new(false));
+    let flag = loading.clone();
+    let store = Arc::new(HookedStore::new(move |method| {
+        (method == "load_history_paginated").then(|| {
+            flag.store(true, Ordering::SeqCst);
+            Duration::from_secs(1)
+        })
+    }));
+    for n in 0..1000 {
+        let message = ServerMessage::NewMessage {
+            id: Uuid::new_v4(),
+            username: "seed".to_string(),
+            content: format!("archived {}", n),
+            encrypted: false,
+            color: None,
+            severity: None,
+            reply_to: None,
+            expires_at: None,
+            sent_at: None,
+        };
+        store.save_message("archive", 0, &message, Utc::now()).await.unwrap();
+    }
+    let server = TestServer::start_with_store(store, |_| {}).await;
+    let mut alice = server.connect("archive").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    let mut bob = server.connect("lobby").await;
+    let mut carol = server.connect("lobby").await;
+    bob.recv_type("Welcome").await;
+    carol.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    carol.join_as("carol").await;
+    bob.recv_type("UserJoined").await;
+
+    alice.send_text("/history").await;
+    while !loading.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // The load is under way and holds nothing the lobby needs.
+    let started = Instant::now();
+    bob.send_json(json!({ "type": "Message", "content": "anyone there?" })).await;
+    assert_eq!(carol.recv_type("NewMessage").await["content"], "anyone there?");
+    let mut newcomer = server.connect("lobby").await;
+    newcomer.recv_type("Welcome").await;
+    assert!(started.elapsed() < Duration::from_millis(500), "the lobby waited {:?}", started.elapsed());
+
+    let mut entries = 0;
+    while entries < 1000 {
+        alice.recv_type("HistoryEntry").await;
+        entries += 1;
+    }
+    assert!(started.elapsed() >= Duration::from_millis(800), "the lobby was served while the history was still loading");
+}
+
+/// The `features` listed in the `Welcome` a new client of `room` receives.
+async fn welcome_features(server: &TestServer, room: &str) -> Vec<String> {
+    let mut client = server.connect(room).await;
+    let welcome = client.recv_type("Welcome").await;
+    assert_eq!(welcome["server_version"], env!("CARGO_PKG_VERSION"));
+    serde_json::from_value(welcome["features"].clone()).unwrap()
+}
+
+#[tokio::test]
+async fn the_welcome_lists_the_features_enabled_for_the_room() {
+    let optional = ["admin", "anon-read", "encrypted-only"];
+    let plain = welcome_features(&TestServer::start().await, "general").await;
+    for feature in ["json", "reactions", "custom-messages", "history-paging", "rename", "topics", "echo"] {
+        assert!(plain.iter().any(|f| f == feature), "{} is always supported", feature);
+    }
+    assert!(!plain.iter().any(|f| optional.contains(&f.as_str())), "{:?}", plain);
+
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.anon_read_rooms = vec!["lobby".to_string()];
+        config.encrypted_rooms = vec!["vault".to_string()];
+    })
+    .await;
+    let listed = |features: Vec<String>| -> Vec<String> { features.into_iter().filter(|f| optional.contains(&f.as_str())).collect() };
+    assert_eq!(listed(welcome_features(&server, "lobby").await), ["admin", "anon-read"]);
+    assert_eq!(listed(welcome_features(&server, "vault").await), ["admin", "encrypted-only"]);
+    assert_eq!(listed(welcome_features(&server, "general").await), ["admin"]);
+}
+
+/// A raw text frame: the first fragment of a message, or the whole of it if `is_final`.
+fn text_frame(data: &[u8], is_final: bool) -> Message {
+    Message::Frame(Frame::message(data.to_vec(), OpCode::Data(Data::Text), is_final))
+}
+
+/// A continuation frame carrying the next fragment of a message.
+fn continuation_frame(data: &[u8], is_final: bool) -> Message {
+    Message::Frame(Frame::message(data.to_vec(), OpCode::Data(Data::Continue), is_final))
+}
+
+#[tokio::test]
+async fn fragmented_messages_are_reassembled_and_unreadable_ones_refused() {
+    let server = TestServer::start_with(|config| config.max_message_bytes = 1000).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    // The split falls inside a multi-byte character, which only decodes once the pieces are joined.
+    let json = json!({ "type": "Message", "content": "fragmented ünïcode" }).to_string();
+    let (first, rest) = json.as_bytes().split_at(json.find('ü').unwrap() + 1);
+    let (second, third) = rest.split_at(rest.len() / 2);
+    alice.send_frame(text_frame(first, false)).await;
+    alice.send_frame(continuation_frame(second, false)).await;
+    alice.send_frame(continuation_frame(third, true)).await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "fragmented ünïcode");
+
+    // Small fragments don't get a message past the size limit.
+    let mut carol = server.connect("general").await;
+    carol.recv_type("Welcome").await;
+    carol.send_frame(text_frame(&[b'a'; 400], false)).await;
+    carol.send_frame(continuation_frame(&[b'a'; 400], false)).await;
+    carol.send_frame(continuation_frame(&[b'a'; 400], true)).await;
+    assert!(carol.recv_type("Error").await["message"].as_str().unwrap().starts_with("Invalid frame"));
+    assert_eq!(carol.recv_until_closed().await, Some(1007));
+
+    // Text that isn't UTF-8 is reported, not silently dropped.
+    bob.send_frame(text_frame(b"caf\xe9", true)).await;
+    assert!(bob.recv_type("Error").await["message"].as_str().unwrap().starts_with("Invalid frame"));
+    assert_eq!(bob.recv_until_closed().await, Some(1007));
+    assert_eq!(alice.recv_type("UserLeft").await["username"], "bob");
+}
+
+#[tokio::test]
+async fn reported_messages_reach_admins_and_the_review_endpoint() {
+    let server = TestServer::start_with(|config| config.admin_token = Some("secret".to_string())).await;
+    let mut moderator = server.connect("ops").await;
+    moderator.recv_type("Welcome").await;
+    moderator.join_as("mod").await;
+    moderator.send_text("/admin secret").await;
+    moderator.recv_type("Notice").await;
+
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+    bob.send_json(json!({ "type": "Message", "content": "something nasty" })).await;
+    let id = alice.recv_type("NewMessage").await["id"].as_str().unwrap().to_string();
+
+    alice.send_text(&format!("/report {} abusive language", id)).await;
+    assert_eq!(alice.recv_type("Notice").await["message"], "Thanks, the moderators have been notified.");
+    let alert = moderator.recv_type("ReportFiled").await;
+    assert_eq!(alert["message_id"], id.as_str());
+    assert_eq!((alert["room"].as_str(), alert["reporter"].as_str()), (Some("general"), Some("alice")));
+    assert_eq!(alert["reason"], "abusive language");
+
+    // The same user reporting the same message again, or a message that isn't there, files nothing.
+    alice.send_text(&format!("/report {}", id)).await;
+    assert!(alice.recv_type("Notice").await["message"].as_str().unwrap().contains("already reported"));
+    let missing = Uuid::new_v4();
+    alice.send_text(&format!("/report {}", missing)).await;
+    assert!(alice.recv_type("Notice").await["message"].as_str().unwrap().contains("no such message"));
+    bob.send_text(&format!("/report {}", id)).await;
+    bob.recv_type("Notice").await;
+
+    let reports: Value = reqwest::Client::new()
+        .get(server.url("/admin/reports"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let filed: Vec<_> = reports.as_array().unwrap().iter().map(|report| report["reporter"].as_str().unwrap()).collect();
+    assert_eq!(filed, ["bob", "alice"], "one report per user, newest first");
+    assert!(reports.as_array().unwrap().iter().all(|report| report["message_id"] == id.as_str()));
+}
+
+#[tokio::test]
+async fn the_message_of_the_day_follows_the_welcome_until_cleared() {
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.startup_motd = Some("Scheduled downtime at 2am".to_string());
+    })
+    .await;
+    let mut early = server.connect("general").await;
+    assert_eq!(early.recv_json().await["type"], "Welcome");
+    let motd = early.recv_json().await;
+    assert_eq!((motd["type"].as_str(), motd["message"].as_str()), (Some("Motd"), Some("Scheduled downtime at 2am")));
+
+    let http = reqwest::Client::new();
+    let set = http.put(server.url("/admin/motd")).bearer_auth("secret").body("Downtime moved to 3am").send().await.unwrap();
+    assert_eq!(set.status(), 204);
+    let blank = http.put(server.url("/admin/motd")).bearer_auth("secret").body("  ").send().await.unwrap();
+    assert_eq!(blank.status(), 400);
+    let mut later = server.connect("other").await;
+    later.recv_type("Welcome").await;
+    assert_eq!(later.recv_json().await["message"], "Downtime moved to 3am");
+
+    let cleared = http.delete(server.url("/admin/motd")).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(cleared.status(), 204);
+    let mut last = server.connect("general").await;
+    last.recv_type("Welcome").await;
+    last.send_text("/ping").await;
+    assert_eq!(last.recv_json().await["type"], "Pong", "no message of the day once cleared");
+    assert_eq!(http.delete(server.url("/admin/motd")).send().await.unwrap().status(), 401);
+}
+
+#[tokio::test]
+async fn opening_a_room_on_a_slow_database_does_not_stall_other_rooms() {
+    let slow = Arc::new(AtomicBool::new(false));
+    let switch = slow.clone();
+    let store = HookedStore::new(move |method| (method == "get_room_topic" && switch.load(Ordering::SeqCst)).then_some(Duration::from_secs(1)));
+    let server = TestServer::start_with_store(Arc::new(store), |_| {}).await;
+    let mut bob = server.connect("lobby").await;
+    let mut carol = server.connect("lobby").await;
+    bob.recv_type("Welcome").await;
+    carol.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    carol.join_as("carol").await;
+    bob.recv_type("UserJoined").await;
+
+    slow.store(true, Ordering::SeqCst);
+    let mut alice = server.connect("new-room").await;
+    alice.recv_type("Welcome").await;
+    let started = Instant::now();
+    bob.send_json(json!({ "type": "Message", "content": "still here" })).await;
+    assert_eq!(carol.recv_type("NewMessage").await["content"], "still here");
+    assert!(started.elapsed() < Duration::from_millis(500), "the lobby waited {:?} for another room to open", started.elapsed());
+
+    // Joining at the same time doesn't open the room twice: both clients end up in it together.
+    let mut dave = server.connect("new-room").await;
+    dave.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    dave.join_as("dave").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "dave");
+}
+
+#[tokio::test]
+async fn moderation_writes_on_a_slow_database_do_not_stall_other_rooms() {
+    let store = HookedStore::new(|method| (method == "set_room_topic").then_some(Duration::from_secs(1)));
+    let server = TestServer::start_with_store(Arc::new(store), |_| {}).await;
+    let mut alice = server.connect("slow-topic").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    let mut bob = server.connect("lobby").await;
+    let mut carol = server.connect("lobby").await;
+    bob.recv_type("Welcome").await;
+    carol.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    carol.join_as("carol").await;
+    bob.recv_type("UserJoined").await;
+
+    alice.send_text("/topic Release planning").await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let started = Instant::now();
+    bob.send_json(json!({ "type": "Message", "content": "still here" })).await;
+    assert_eq!(carol.recv_type("NewMessage").await["content"], "still here");
+    assert!(started.elapsed() < Duration::from_millis(500), "the lobby waited {:?} for a topic write", started.elapsed());
+    assert_eq!(alice.recv_type("TopicChanged").await["topic"], "Release planning");
+}
+
+#[tokio::test]
+async fn saving_a_chat_message_does_not_hold_up_the_next_one() {
+    let slow = Arc::new(AtomicBool::new(false));
+    let switch = slow.clone();
+    let store = HookedStore::new(move |method| (method == "save_message" && switch.load(Ordering::SeqCst)).then_some(Duration::from_secs(1)));
+    let server = TestServer::start_with_store(Arc::new(store), |_| {}).await;
+    let mut bob = server.connect("lobby").await;
+    let mut carol = server.connect("lobby").await;
+    let mut dave = server.connect("lobby").await;
+    for (client, name) in [(&mut bob, "bob"), (&mut carol, "carol"), (&mut dave, "dave")] {
+        client.recv_type("Welcome").await;
+        client.join_as(name).await;
+        client.recv_type("PresenceSnapshot").await;
+    }
+
+    slow.store(true, Ordering::SeqCst);
+    let started = Instant::now();
+    bob.send_json(json!({ "type": "Message", "content": "one" })).await;
+    assert_eq!(dave.recv_type("NewMessage").await["content"], "one");
+    carol.send_json(json!({ "type": "Message", "content": "two" })).await;
+    assert_eq!(dave.recv_type("NewMessage").await["content"], "two");
+    assert!(started.elapsed() < Duration::from_millis(500), "the second message waited {:?} for the first to be saved", started.elapsed());
+}
+
+#[tokio::test]
+async fn pinning_on_a_slow_database_does_not_stall_other_rooms() {
+    let store = HookedStore::new(|method| (method == "set_pinned_ids").then_some(Duration::from_secs(1)));
+    let server = TestServer::start_with_store(Arc::new(store), |_| {}).await;
+    let mut alice = server.connect("slow-pins").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    alice.send_text("/echo on").await;
+    alice.send_json(json!({ "type": "Message", "content": "pin me" })).await;
+    let id = alice.recv_type("NewMessage").await["id"].as_str().unwrap().to_string();
+    let mut bob = server.connect("lobby").await;
+    let mut carol = server.connect("lobby").await;
+    bob.recv_type("Welcome").await;
+    carol.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    carol.join_as("carol").await;
+    bob.recv_type("UserJoined").await;
+
+    alice.send_text(&format!("/pin {}", id)).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let started = Instant::now();
+    bob.send_json(json!({ "type": "Message", "content": "still here" })).await;
+    assert_eq!(carol.recv_type("NewMessage").await["content"], "still here");
+    assert!(started.elapsed() < Duration::from_millis(500), "the lobby waited {:?} for a pin to be saved", started.elapsed());
+    assert_eq!(alice.recv_type("MessagePinned").await["message"]["content"], "pin me");
+}
+
+#[tokio::test]
+async fn concurrent_pins_are_checked_one_at_a_time() {
+    let store = Arc::new(HookedStore::new(|method| (method == "set_pinned_ids").then_some(Duration::from_millis(300))));
+    let server = TestServer::start_with_store(store.clone(), |_| {}).await;
+    let mut alice = server.connect("pins").await;
+    let mut bob = server.connect("pins").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+    alice.send_text("/op bob").await;
+    bob.recv_type("OwnershipChanged").await;
+    alice.send_text("/echo on").await;
+    alice.send_json(json!({ "type": "Message", "content": "pin me" })).await;
+    let id = alice.recv_type("NewMessage").await["id"].as_str().unwrap().to_string();
+
+    // Both are checked while the other's save is still on its way.
+    alice.send_text(&format!("/pin {}", id)).await;
+    bob.send_text(&format!("/pin {}", id)).await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "That message is already pinned.");
+    alice.recv_type("MessagePinned").await;
+    assert_eq!(store.inner.get_pinned_messages("pins").await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn concurrent_ownership_changes_are_all_kept() {
+    let store = Arc::new(HookedStore::new(|method| (method == "set_room_ownership").then_some(Duration::from_millis(300))));
+    let server = TestServer::start_with_store(store.clone(), |config| config.admin_token = Some("secret".to_string())).await;
+    let mut alice = server.connect("owned").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    let mut admin = server.connect("owned").await;
+    admin.recv_type("Welcome").await;
+    admin.send_text("/admin secret").await;
+    admin.recv_type("Notice").await;
+    admin.join_as("mod").await;
+    alice.recv_type("UserJoined").await;
+    let mut members = Vec::new();
+    for name in ["bob", "carol"] {
+        let mut member = server.connect("owned").await;
+        member.recv_type("Welcome").await;
+        member.join_as(name).await;
+        assert_eq!(alice.recv_type("UserJoined").await["username"], name);
+        members.push(member);
+    }
+
+    // Each is checked and saved while the other is still on its way.
+    alice.send_text("/op bob").await;
+    admin.send_text("/op carol").await;
+    alice.recv_type("OwnershipChanged").await;
+    let changed = alice.recv_type("OwnershipChanged").await;
+    let mut operators: Vec<_> = changed["operators"].as_array().unwrap().iter().map(|op| op.as_str().unwrap().to_string()).collect();
+    operators.sort();
+    assert_eq!(operators, ["bob", "carol"]);
+    let mut saved = store.inner.get_room_ownership("owned").await.unwrap().operators;
+    saved.sort();
+    assert_eq!(saved, ["bob", "carol"]);
+}
+
+#[tokio::test]
+async fn concurrent_metadata_changes_are_all_kept_within_the_key_limit() {
+    let slow = Arc::new(AtomicBool::new(false));
+    let switch = slow.clone();
+    let store = Arc::new(HookedStore::new(move |method| {
+        (method == "set_room_metadata" && switch.load(Ordering::SeqCst)).then_some(Duration::from_millis(300))
+    }));
+    let server = TestServer::start_with_store(store.clone(), |_| {}).await;
+    let mut alice = server.connect("tagged").await;
+    let mut bob = server.connect("tagged").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+    alice.send_text("/op bob").await;
+    bob.recv_type("OwnershipChanged").await;
+    for key in 0..14 {
+        alice.send_text(&format!("/roommeta set key{} value", key)).await;
+        alice.recv_type("Notice").await;
+    }
+
+    // Each is checked and saved while the other is still on its way, and both are kept.
+    slow.store(true, Ordering::SeqCst);
+    alice.send_text("/roommeta set language en").await;
+    bob.send_text("/roommeta set category games").await;
+    alice.recv_type("Notice").await;
+    bob.recv_type("Notice").await;
+    let saved = store.inner.get_room_metadata("tagged").await.unwrap();
+    assert_eq!((saved.len(), saved.get("language"), saved.get("category")), (16, Some(&"en".to_string()), Some(&"games".to_string())));
+
+    // With one key left, only one of two new keys fits.
+    bob.send_text("/roommeta unset key0").await;
+    bob.recv_type("Notice").await;
+    alice.send_text("/roommeta set region eu").await;
+    bob.send_text("/roommeta set audience adults").await;
+    let replies = [alice.recv_type("Notice").await, bob.recv_type("Notice").await];
+    let refused = replies.iter().filter(|reply| reply["message"] == "A room can have at most 16 metadata keys.").count();
+    assert_eq!(refused, 1);
+    assert_eq!(store.inner.get_room_metadata("tagged").await.unwrap().len(), 16);
+}
+
+#[tokio::test]
+async fn keys_published_at_the_same_time_are_all_kept() {
+    let store = Arc::new(HookedStore::new(|method| (method == "set_public_keys").then_some(Duration::from_millis(300))));
+    let server = TestServer::start_with_store(store.clone(), |_| {}).await;
+    let mut alice = server.connect("secrets").await;
+    let mut bob = server.connect("secrets").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    alice.send_text("/key publish YWxpY2U=").await;
+    bob.send_text("/key publish Ym9i").await;
+    alice.recv_type("Notice").await;
+    bob.recv_type("Notice").await;
+    let saved = store.inner.get_public_keys("secrets").await.unwrap();
+    assert_eq!((saved.get("alice").map(String::as_str), saved.get("bob").map(String::as_str)), (Some("YWxpY2U="), Some("Ym9i")));
+    alice.send_text("/keys").await;
+    assert_eq!(alice.recv_type("PublicKeys").await["keys"], json!({ "alice": "YWxpY2U=", "bob": "Ym9i" }));
+}
+
+#[tokio::test]
+async fn a_room_reopened_before_its_saves_land_keeps_its_numbering_and_history() {
+    let store = Arc::new(HookedStore::new(|method| matches!(method, "save_message" | "set_latest_seq").then_some(Duration::from_millis(300))));
+    let server = TestServer::start_with_store(store.clone(), |_| {}).await;
+    let mut alice = server.connect("brief").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.send_json(json!({ "type": "Message", "content": "before" })).await;
+    alice.send_text("/quit").await;
+    alice.recv_until_closed().await;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while server.state.rooms.lock().await.contains_key("brief") {
+        assert!(tokio::time::Instant::now() < deadline, "the room was never removed");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Alice's join, message and leave are 1 to 3, none of them saved yet.
+    let mut bob = server.connect("brief").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "before");
+    let left = bob.recv_type("UserLeft").await;
+    assert_eq!((left["username"].as_str(), left["seq"].as_u64()), (Some("alice"), Some(3)));
+    assert_eq!(bob.recv_type("PresenceSnapshot").await["latest_seq"], 3);
+    bob.send_text("/echo on").await;
+    bob.send_json(json!({ "type": "Message", "content": "after" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["seq"], 5); // after Bob's join
+}
@@ -0,0 +1,254 @@
+// tests/common/hooked_store.rs
+//
+// A `MemoryStore` a test can step into before any call, to slow that call down or make it panic.
+
+use async_trait::async_trait;
+use chat_server::{
+    memory_store::MemoryStore,
+    models::{ServerMessage, ThreadReply},
+    store::{AuditEntry, DbResult, MessageStore, RecentRoom, Report, RoomOwnership, RoomTopic, ScheduledMessage},
+};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+type Hook = Box<dyn Fn(&'static str) -> Option<Duration> + Send + Sync>;
+
+pub struct HookedStore {
+    pub inner: MemoryStore,
+    hook: Hook,
+}
+
+impl HookedStore {
+    /// Wraps an empty `MemoryStore`, calling `hook` with the method's name before every call. The call waits for
+    /// the duration `hook` returns, if any, and a panic in `hook` unwinds out of the call as a bug in the store would.
+    pub fn new(hook: impl Fn(&'static str) -> Option<Duration> + Send + Sync + 'static) -> Self {
+        HookedStore { inner: MemoryStore::default(), hook: Box::new(hook) }
+    }
+
+    async fn before(&self, method: &'static str) {
+        if let Some(delay) = (self.hook)(method) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[async_trait]
+impl MessageStore for HookedStore {
+    async fn save_message(&self, room_name: &str, seq: u64, message: &ServerMessage, created_at: DateTime<Utc>) -> DbResult<()> {
+        self.before("save_message").await;
+        self.inner.save_message(room_name, seq, message, created_at).await
+    }
+
+    async fn load_history(&self, room_name: &str, limit: usize) -> DbResult<VecDeque<ServerMessage>> {
+        self.before("load_history").await;
+        self.inner.load_history(room_name, limit).await
+    }
+
+    async fn load_history_paginated(
+        &self,
+        room_name: &str,
+        page: i64,
+        page_size: i64,
+        include_presence: bool,
+    ) -> DbResult<VecDeque<(ServerMessage, BTreeMap<String, i64>, DateTime<Utc>)>> {
+        self.before("load_history_paginated").await;
+        self.inner.load_history_paginated(room_name, page, page_size, include_presence).await
+    }
+
+    async fn get_message_count(&self, room_name: &str) -> DbResult<i64> {
+        self.before("get_message_count").await;
+        self.inner.get_message_count(room_name).await
+    }
+
+    async fn get_latest_seq(&self, room_name: &str) -> DbResult<u64> {
+        self.before("get_latest_seq").await;
+        self.inner.get_latest_seq(room_name).await
+    }
+
+    async fn set_latest_seq(&self, room_name: &str, seq: u64) -> DbResult<()> {
+        self.before("set_latest_seq").await;
+        self.inner.set_latest_seq(room_name, seq).await
+    }
+
+    async fn stream_room_messages(&self, room_name: String, tx: mpsc::Sender<DbResult<ServerMessage>>) {
+        self.before("stream_room_messages").await;
+        self.inner.stream_room_messages(room_name, tx).await
+    }
+
+    async fn add_reaction(&self, room_name: &str, message_id: Uuid, username: &str, emoji: &str) -> DbResult<bool> {
+        self.before("add_reaction").await;
+        self.inner.add_reaction(room_name, message_id, username, emoji).await
+    }
+
+    async fn delete_message(&self, room_name: &str, message_id: Uuid) -> DbResult<bool> {
+        self.before("delete_message").await;
+        self.inner.delete_message(room_name, message_id).await
+    }
+
+    async fn clear_messages(&self, room_name: &str, seq: u64) -> DbResult<u64> {
+        self.before("clear_messages").await;
+        self.inner.clear_messages(room_name, seq).await
+    }
+
+    async fn load_message_expiries(&self) -> DbResult<Vec<(String, Uuid, DateTime<Utc>)>> {
+        self.before("load_message_expiries").await;
+        self.inner.load_message_expiries().await
+    }
+
+    async fn is_room_closed(&self, room_name: &str) -> DbResult<bool> {
+        self.before("is_room_closed").await;
+        self.inner.is_room_closed(room_name).await
+    }
+
+    async fn set_room_closed(&self, room_name: &str, closed: bool) -> DbResult<()> {
+        self.before("set_room_closed").await;
+        self.inner.set_room_closed(room_name, closed).await
+    }
+
+    async fn is_room_registered(&self, room_name: &str) -> DbResult<bool> {
+        self.before("is_room_registered").await;
+        self.inner.is_room_registered(room_name).await
+    }
+
+    async fn set_room_registered(&self, room_name: &str, registered: bool) -> DbResult<()> {
+        self.before("set_room_registered").await;
+        self.inner.set_room_registered(room_name, registered).await
+    }
+
+    async fn get_room_topic(&self, room_name: &str) -> DbResult<Option<RoomTopic>> {
+        self.before("get_room_topic").await;
+        self.inner.get_room_topic(room_name).await
+    }
+
+    async fn set_room_topic(&self, room_name: &str, topic: &RoomTopic) -> DbResult<()> {
+        self.before("set_room_topic").await;
+        self.inner.set_room_topic(room_name, topic).await
+    }
+
+    async fn get_room_metadata(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        self.before("get_room_metadata").await;
+        self.inner.get_room_metadata(room_name).await
+    }
+
+    async fn set_room_metadata(&self, room_name: &str, metadata: &BTreeMap<String, String>) -> DbResult<()> {
+        self.before("set_room_metadata").await;
+        self.inner.set_room_metadata(room_name, metadata).await
+    }
+
+    async fn get_public_keys(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        self.before("get_public_keys").await;
+        self.inner.get_public_keys(room_name).await
+    }
+
+    async fn set_public_keys(&self, room_name: &str, keys: &BTreeMap<String, String>) -> DbResult<()> {
+        self.before("set_public_keys").await;
+        self.inner.set_public_keys(room_name, keys).await
+    }
+
+    async fn get_username_pattern(&self, room_name: &str) -> DbResult<Option<String>> {
+        self.before("get_username_pattern").await;
+        self.inner.get_username_pattern(room_name).await
+    }
+
+    async fn set_username_pattern(&self, room_name: &str, pattern: Option<&str>) -> DbResult<()> {
+        self.before("set_username_pattern").await;
+        self.inner.set_username_pattern(room_name, pattern).await
+    }
+
+    async fn get_room_ownership(&self, room_name: &str) -> DbResult<RoomOwnership> {
+        self.before("get_room_ownership").await;
+        self.inner.get_room_ownership(room_name).await
+    }
+
+    async fn set_room_ownership(&self, room_name: &str, ownership: &RoomOwnership) -> DbResult<()> {
+        self.before("set_room_ownership").await;
+        self.inner.set_room_ownership(room_name, ownership).await
+    }
+
+    async fn load_message(&self, room_name: &str, message_id: Uuid) -> DbResult<Option<ServerMessage>> {
+        self.before("load_message").await;
+        self.inner.load_message(room_name, message_id).await
+    }
+
+    async fn load_thread(&self, room_name: &str, root: Uuid, max_depth: u32, limit: usize) -> DbResult<Vec<ThreadReply>> {
+        self.before("load_thread").await;
+        self.inner.load_thread(room_name, root, max_depth, limit).await
+    }
+
+    async fn get_pinned_messages(&self, room_name: &str) -> DbResult<Vec<ServerMessage>> {
+        self.before("get_pinned_messages").await;
+        self.inner.get_pinned_messages(room_name).await
+    }
+
+    async fn set_pinned_ids(&self, room_name: &str, message_ids: &[Uuid]) -> DbResult<()> {
+        self.before("set_pinned_ids").await;
+        self.inner.set_pinned_ids(room_name, message_ids).await
+    }
+
+    async fn get_secret_hash(&self, username: &str) -> DbResult<Option<String>> {
+        self.before("get_secret_hash").await;
+        self.inner.get_secret_hash(username).await
+    }
+
+    async fn register_user(&self, username: &str, secret_hash: &str) -> DbResult<bool> {
+        self.before("register_user").await;
+        self.inner.register_user(username, secret_hash).await
+    }
+
+    async fn get_recent_rooms(&self, since: DateTime<Utc>) -> DbResult<Vec<RecentRoom>> {
+        self.before("get_recent_rooms").await;
+        self.inner.get_recent_rooms(since).await
+    }
+
+    async fn add_report(&self, report: &Report) -> DbResult<bool> {
+        self.before("add_report").await;
+        self.inner.add_report(report).await
+    }
+
+    async fn load_reports(&self, page: i64, page_size: i64) -> DbResult<Vec<Report>> {
+        self.before("load_reports").await;
+        self.inner.load_reports(page, page_size).await
+    }
+
+    async fn log_audit(&self, entry: &AuditEntry) -> DbResult<()> {
+        self.before("log_audit").await;
+        self.inner.log_audit(entry).await
+    }
+
+    async fn load_audit_log(&self, room_name: Option<&str>, page: i64, page_size: i64) -> DbResult<Vec<AuditEntry>> {
+        self.before("load_audit_log").await;
+        self.inner.load_audit_log(room_name, page, page_size).await
+    }
+
+    async fn get_last_seen(&self, room_name: &str, username: &str) -> DbResult<Option<DateTime<Utc>>> {
+        self.before("get_last_seen").await;
+        self.inner.get_last_seen(room_name, username).await
+    }
+
+    async fn set_last_seen(&self, room_name: &str, username: &str, seen_at: DateTime<Utc>) -> DbResult<()> {
+        self.before("set_last_seen").await;
+        self.inner.set_last_seen(room_name, username, seen_at).await
+    }
+
+    async fn add_scheduled_message(&self, message: &ScheduledMessage) -> DbResult<()> {
+        self.before("add_scheduled_message").await;
+        self.inner.add_scheduled_message(message).await
+    }
+
+    async fn cancel_scheduled_message(&self, id: Uuid) -> DbResult<bool> {
+        self.before("cancel_scheduled_message").await;
+        self.inner.cancel_scheduled_message(id).await
+    }
+
+    async fn take_due_scheduled_messages(&self, now: DateTime<Utc>) -> DbResult<Vec<ScheduledMessage>> {
+        self.before("take_due_scheduled_messages").await;
+        self.inner.take_due_scheduled_messages(now).await
+    }
+
+    fn undecodable_messages(&self) -> u64 {
+        self.inner.undecodable_messages()
+    }
+}
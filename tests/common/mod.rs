@@ -0,0 +1,185 @@
+// tests/common/mod.rs
+//
+// Shared helpers for the integration tests: boot the server on an ephemeral port with an
+// in-memory store and drive it with real WebSocket clients.
+
+#![allow(dead_code)] // Not every test file uses every helper.
+
+pub mod hooked_store;
+
+use chat_server::{
+    config::Config, listener::{HandshakeTimeoutListener, PeerAddr}, memory_store::MemoryStore, router, state::ChatState,
+    store::MessageStore,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+/// How long a helper waits for an expected frame before failing the test.
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running in the background for the duration of a test.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub state: ChatState,
+}
+
+impl TestServer {
+    /// Starts a server with the default configuration and an in-memory store.
+    pub async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Starts a server after letting the test adjust the configuration.
+    pub async fn start_with(configure: impl FnOnce(&mut Config)) -> Self {
+        Self::start_with_store(Arc::new(MemoryStore::default()), configure).await
+    }
+
+    /// Starts a server on top of the given store, e.g. one instrumented by the test.
+    pub async fn start_with_store(store: Arc<dyn MessageStore>, configure: impl FnOnce(&mut Config)) -> Self {
+        let mut config = Config::from_env();
+        configure(&mut config);
+        let state = ChatState::new(config, store);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind test listener");
+        let addr = listener.local_addr().unwrap();
+        let listener = HandshakeTimeoutListener::new(listener, state.config.handshake_timeout);
+        let app = router(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<PeerAddr>()).await.unwrap() });
+
+        TestServer { addr, state }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Connects a `chat.v2` (JSON) client to `room`.
+    pub async fn connect(&self, room: &str) -> TestClient {
+        self.connect_with_protocol(room, Some("chat.v2")).await
+    }
+
+    /// Connects a client to `room`, offering `protocol` as the subprotocol if given.
+    pub async fn connect_with_protocol(&self, room: &str, protocol: Option<&str>) -> TestClient {
+        let mut request = format!("ws://{}/ws/{}", self.addr, room).into_client_request().unwrap();
+        if let Some(protocol) = protocol {
+            request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_str(protocol).unwrap());
+        }
+        let (ws, _) = connect_async(request).await.expect("Failed to connect test client");
+        TestClient { ws }
+    }
+
+    /// Connects to the `/ws/admin` monitor feed with the given admin token.
+    pub async fn connect_monitor(&self, token: &str) -> TestClient {
+        let mut request = format!("ws://{}/ws/admin", self.addr).into_client_request().unwrap();
+        request.headers_mut().insert("Authorization", HeaderValue::from_str(&format!("Bearer {}", token)).unwrap());
+        let (ws, _) = connect_async(request).await.expect("Failed to connect monitor");
+        TestClient { ws }
+    }
+}
+
+/// A WebSocket client talking to a `TestServer`.
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    pub async fn send_text(&mut self, text: &str) {
+        self.ws.send(Message::text(text)).await.expect("Failed to send frame");
+    }
+
+    /// Sends any frame as-is, including control and binary frames.
+    pub async fn send_frame(&mut self, frame: Message) {
+        self.ws.send(frame).await.expect("Failed to send frame");
+    }
+
+    pub async fn send_json(&mut self, value: Value) {
+        self.send_text(&value.to_string()).await;
+    }
+
+    /// Waits for the next frame, failing the test if none arrives in time. Control frames are
+    /// returned too, so tests can assert on close codes.
+    pub async fn recv(&mut self) -> Message {
+        match tokio::time::timeout(RECV_TIMEOUT, self.ws.next()).await {
+            Ok(Some(Ok(message))) => message,
+            Ok(Some(Err(e))) => panic!("WebSocket error while waiting for a frame: {}", e),
+            Ok(None) => panic!("Connection closed while waiting for a frame"),
+            Err(_) => panic!("Timed out waiting for a frame"),
+        }
+    }
+
+    /// Waits for the next text frame, skipping pings and pongs.
+    pub async fn recv_text(&mut self) -> String {
+        loop {
+            match self.recv().await {
+                Message::Text(text) => return text.to_string(),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                other => panic!("Expected a text frame, got {:?}", other),
+            }
+        }
+    }
+
+    /// Waits for the next frame and parses it as a JSON `ServerMessage`.
+    pub async fn recv_json(&mut self) -> Value {
+        let text = self.recv_text().await;
+        serde_json::from_str(&text).unwrap_or_else(|e| panic!("Frame is not JSON ({}): {}", e, text))
+    }
+
+    /// Skips frames until a JSON message of the given `type` arrives and returns it.
+    pub async fn recv_type(&mut self, message_type: &str) -> Value {
+        loop {
+            let message = self.recv_json().await;
+            if message["type"] == message_type {
+                return message;
+            }
+        }
+    }
+
+    /// Waits up to `wait` for the next text frame and parses it as JSON; `None` if nothing arrives in time.
+    pub async fn recv_json_within(&mut self, wait: Duration) -> Option<Value> {
+        loop {
+            match tokio::time::timeout(wait, self.ws.next()).await {
+                Ok(Some(Ok(Message::Text(text)))) => return Some(serde_json::from_str(&text).expect("Frame is not JSON")),
+                Ok(Some(Ok(Message::Ping(_) | Message::Pong(_)))) => continue,
+                Ok(Some(Ok(other))) => panic!("Expected a text frame, got {:?}", other),
+                Ok(Some(Err(e))) => panic!("WebSocket error while waiting for a frame: {}", e),
+                Ok(None) => panic!("Connection closed while waiting for a frame"),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Asserts that nothing arrives within `wait`.
+    pub async fn expect_silence(&mut self, wait: Duration) {
+        if let Ok(Some(frame)) = tokio::time::timeout(wait, self.ws.next()).await {
+            panic!("Expected no frame, got {:?}", frame);
+        }
+    }
+
+    /// Reads until the server closes the connection and returns the close code, if one was sent.
+    /// Reading also answers the server's close frame, completing the closing handshake.
+    pub async fn recv_until_closed(&mut self) -> Option<u16> {
+        let mut code = None;
+        loop {
+            match tokio::time::timeout(RECV_TIMEOUT, self.ws.next()).await {
+                Ok(Some(Ok(Message::Close(frame)))) => code = frame.map(|frame| u16::from(frame.code)),
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(_)) | None) => return code,
+                Err(_) => panic!("Timed out waiting for the connection to close"),
+            }
+        }
+    }
+
+    /// Sets the username with a JSON `SetUsername` frame.
+    pub async fn join_as(&mut self, username: &str) {
+        self.send_json(serde_json::json!({ "type": "SetUsername", "username": username })).await;
+    }
+}
@@ -0,0 +1,146 @@
+// tests/db_errors.rs
+//
+// Database failures are returned by the store, counted, and reported to whoever triggered the call.
+
+mod common;
+
+use chat_server::sqlite_store::SqliteStore;
+use chat_server::store::{DbError, MessageStore, RoomTopic};
+use chat_server::websocket::expire_message;
+use common::TestServer;
+use serde_json::{json, Value};
+use sqlx::sqlite::SqlitePool;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+const DB_ERROR: &str = "The database could not complete the request; please try again shortly.";
+
+/// A database file that is removed when the test ends.
+struct TempDb(PathBuf);
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl TempDb {
+    fn new() -> Self {
+        TempDb(std::env::temp_dir().join(format!("chat-db-errors-{}.db", Uuid::new_v4())))
+    }
+
+    /// Drops `tables` behind the store's back, so every query touching them fails.
+    async fn drop_tables(&self, tables: &[&str]) {
+        let pool = SqlitePool::connect(&format!("sqlite://{}", self.0.display())).await.unwrap();
+        for table in tables {
+            sqlx::query(&format!("DROP TABLE {}", table)).execute(&pool).await.unwrap();
+        }
+        pool.close().await;
+    }
+}
+
+async fn database_errors(server: &TestServer) -> u64 {
+    let metrics: Value = reqwest::Client::new()
+        .get(server.url("/admin/metrics"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    metrics["database_errors"].as_u64().unwrap()
+}
+
+#[tokio::test]
+async fn failed_queries_are_returned_instead_of_empty_results() {
+    let db = TempDb::new();
+    let store = SqliteStore::connect(db.0.to_str().unwrap()).await.unwrap();
+    assert_eq!(store.get_message_count("general").await.unwrap(), 0);
+    db.drop_tables(&["messages", "rooms_meta"]).await;
+
+    assert!(matches!(store.get_message_count("general").await, Err(DbError::Query(_))));
+    assert!(matches!(store.load_history("general", 10).await, Err(DbError::Query(_))));
+    assert!(store.get_room_topic("general").await.is_err());
+    let topic = RoomTopic { topic: "lost".to_string(), set_by: "alice".to_string() };
+    assert!(store.set_room_topic("general", &topic).await.is_err());
+}
+
+#[tokio::test]
+async fn clients_are_told_when_the_database_fails() {
+    let db = TempDb::new();
+    let store = SqliteStore::connect(db.0.to_str().unwrap()).await.unwrap();
+    let server = TestServer::start_with_store(Arc::new(store), |config| config.admin_token = Some("secret".to_string())).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    assert_eq!(database_errors(&server).await, 0);
+
+    db.drop_tables(&["messages", "rooms_meta"]).await;
+
+    alice.send_text("/history-count").await;
+    assert_eq!(alice.recv_type("Error").await["message"], DB_ERROR);
+
+    // The topic isn't changed unless it was saved.
+    alice.send_text("/topic unsaved").await;
+    assert_eq!(alice.recv_type("Error").await["message"], DB_ERROR);
+    alice.send_text("/topic").await;
+    assert!(!alice.recv_type("Notice").await["message"].as_str().unwrap().contains("unsaved"));
+
+    // Chat still reaches the room, but the sender learns it wasn't kept.
+    let mut bob = server.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    alice.send_json(json!({ "type": "Message", "content": "ephemeral" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "ephemeral");
+    assert_eq!(
+        alice.recv_type("Error").await["message"],
+        "Your message was delivered but could not be saved to the room's history."
+    );
+
+    assert!(database_errors(&server).await >= 3);
+}
+
+#[tokio::test]
+async fn rooms_are_not_opened_without_their_message_numbering() {
+    let db = TempDb::new();
+    let store = SqliteStore::connect(db.0.to_str().unwrap()).await.unwrap();
+    db.drop_tables(&["rooms_meta"]).await;
+    let server = TestServer::start_with_store(Arc::new(store), |_| {}).await;
+
+    // Starting the room's numbering from scratch would hand out numbers clients have already seen.
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    assert_eq!(alice.recv_type("Error").await["message"], DB_ERROR);
+    assert_eq!(alice.recv_until_closed().await, Some(1011));
+    assert!(!server.state.rooms.lock().await.contains_key("general"));
+}
+
+#[tokio::test]
+async fn expired_messages_are_kept_until_they_are_deleted() {
+    let db = TempDb::new();
+    let store = SqliteStore::connect(db.0.to_str().unwrap()).await.unwrap();
+    let server = TestServer::start_with_store(Arc::new(store), |_| {}).await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.recv_type("OwnershipChanged").await;
+    let mut bob = server.connect("general").await;
+    bob.recv_type("Welcome").await;
+    bob.join_as("bob").await;
+    // Bob is in before the message is sent, so it reaches him live and nothing else is on its way to him.
+    alice.recv_type("UserJoined").await;
+    alice.send_json(json!({ "type": "Message", "content": "still here", "expires_in_secs": 60 })).await;
+    let id = bob.recv_type("NewMessage").await["id"].as_str().unwrap().to_string();
+
+    db.drop_tables(&["messages"]).await;
+    expire_message(&server.state, "general", Uuid::parse_str(&id).unwrap()).await;
+
+    // The delete failed and will be retried, so nobody is told the message is gone.
+    bob.expect_silence(Duration::from_millis(300)).await;
+    bob.send_text(&format!("/get {}", id)).await;
+    assert_eq!(bob.recv_type("FetchedMessage").await["message"]["content"], "still here");
+}
@@ -0,0 +1,70 @@
+// tests/db_optional.rs
+
+mod common;
+
+use chat_server::fallback_store::FallbackStore;
+use chat_server::memory_store::MemoryStore;
+use chat_server::store::MessageStore;
+use common::TestServer;
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const RETRY: Duration = Duration::from_millis(50);
+
+#[tokio::test]
+async fn server_runs_without_a_database() {
+    let store = FallbackStore::start(|| async { Err(sqlx::Error::PoolTimedOut) }, RETRY);
+    let server = TestServer::start_with_store(Arc::new(store), |_| {}).await;
+
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    assert_eq!(alice.recv_type("UserJoined").await["username"], "bob");
+
+    alice.send_json(json!({ "type": "Message", "content": "anyone there?" })).await;
+    assert_eq!(bob.recv_type("NewMessage").await["content"], "anyone there?");
+}
+
+#[tokio::test]
+async fn persistence_starts_once_the_database_is_reachable() {
+    let database = Arc::new(MemoryStore::default());
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let (db, tries) = (database.clone(), attempts.clone());
+    let store = Arc::new(FallbackStore::start(
+        move || {
+            let (db, tries) = (db.clone(), tries.clone());
+            async move {
+                // The first attempt fails, the second one gets through.
+                if tries.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err(sqlx::Error::PoolTimedOut)
+                } else {
+                    Ok(db as Arc<dyn MessageStore>)
+                }
+            }
+        },
+        RETRY,
+    ));
+    assert!(!store.is_connected());
+    let server = TestServer::start_with_store(store.clone(), |_| {}).await;
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !store.is_connected() {
+            tokio::time::sleep(RETRY).await;
+        }
+    })
+    .await
+    .expect("never connected to the database");
+
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.send_json(json!({ "type": "Message", "content": "saved" })).await;
+    alice.send_text("/history-count").await;
+    assert_eq!(alice.recv_type("MessageCount").await["count"], 2); // the join and the message
+    assert_eq!(database.get_message_count("general").await.unwrap(), 2);
+}
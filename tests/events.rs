@@ -0,0 +1,97 @@
+// tests/events.rs
+//
+// The event bus every server event is published to, watched the way the monitor feed and webhook do.
+
+mod common;
+
+use chat_server::events::ServerEvent;
+use chat_server::models::ServerMessage;
+use common::TestServer;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+async fn next_event(events: &mut broadcast::Receiver<ServerEvent>) -> ServerEvent {
+    tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("Timed out waiting for an event")
+        .expect("The event bus closed or lagged")
+}
+
+#[tokio::test]
+async fn join_send_and_leave_are_published_in_order() {
+    let server = TestServer::start().await;
+    let mut events = server.state.events.subscribe();
+
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    alice.send_json(json!({ "type": "Message", "content": "hello" })).await;
+    alice.send_text("/quit bye").await;
+    alice.recv_until_closed().await;
+
+    let client_id = match next_event(&mut events).await {
+        ServerEvent::ClientJoined { room, client_id } if room == "general" => client_id,
+        other => panic!("expected the client to join, got {:?}", other),
+    };
+    assert!(matches!(
+        next_event(&mut events).await,
+        ServerEvent::RoomMessage { room, message: ServerMessage::UserJoined { username, .. } } if room == "general" && username == "alice"
+    ));
+    let sent_id = match next_event(&mut events).await {
+        ServerEvent::RoomMessage { message: ServerMessage::NewMessage { id, content, .. }, .. } if content == "hello" => id,
+        other => panic!("expected the message to reach the room, got {:?}", other),
+    };
+    assert!(matches!(
+        next_event(&mut events).await,
+        ServerEvent::MessageSent { client_id: sender, message: ServerMessage::NewMessage { id, .. }, .. } if sender == client_id && id == sent_id
+    ));
+    // Alice was the last one in the room, so nobody is shown her leaving, but it still takes its place in the history.
+    assert!(matches!(
+        next_event(&mut events).await,
+        ServerEvent::RoomMessage { room, message: ServerMessage::UserLeft { username, .. } } if room == "general" && username == "alice"
+    ));
+    assert!(matches!(
+        next_event(&mut events).await,
+        ServerEvent::ClientLeft { room, client_id: left, username: Some(username) } if room == "general" && left == client_id && username == "alice"
+    ));
+    assert!(events.try_recv().is_err(), "nothing else was published");
+}
+
+#[tokio::test]
+async fn recent_events_are_replayed_to_late_subscribers() {
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.event_replay_size = 3;
+    })
+    .await;
+    let mut alice = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    for n in 0..3 {
+        alice.send_json(json!({ "type": "Message", "content": format!("message {}", n) })).await;
+    }
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+
+    // Only the newest three are kept: the second message as it was sent, then the last one as the room saw it and as it was sent.
+    let (replayed, mut live) = server.state.events.subscribe_with_replay();
+    let kinds: Vec<_> = replayed.iter().map(|event| serde_json::to_value(event).unwrap()["type"].clone()).collect();
+    assert_eq!(kinds, ["MessageSent", "RoomMessage", "MessageSent"]);
+
+    let recent: Value = reqwest::Client::new()
+        .get(server.url("/admin/events"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(recent[2]["message"]["content"], "message 2");
+    assert_eq!(recent[2]["room"], "general");
+
+    alice.send_text("/quit").await;
+    assert!(matches!(next_event(&mut live).await, ServerEvent::RoomMessage { message: ServerMessage::UserLeft { .. }, .. }));
+    assert!(matches!(next_event(&mut live).await, ServerEvent::ClientLeft { .. }));
+}
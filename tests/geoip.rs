@@ -0,0 +1,154 @@
+// tests/geoip.rs
+//
+// Country tagging of connections, against a tiny MaxMind database written by the test itself.
+
+mod common;
+
+use chat_server::geoip::{CountryLookup, GeoIp, MaxMindCountries};
+use common::TestServer;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// A database file that is removed when the test ends.
+struct TempDb(PathBuf);
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Encodes a MaxMind DB value header: `kind` is the data type, `size` the length or entry count.
+fn header(kind: u8, size: usize) -> Vec<u8> {
+    assert!(size < 29);
+    match kind {
+        1..=7 => vec![kind << 5 | size as u8],
+        _ => vec![size as u8, kind - 7],
+    }
+}
+
+fn string(value: &str) -> Vec<u8> {
+    [header(2, value.len()), value.as_bytes().to_vec()].concat()
+}
+
+fn uint16(value: u16) -> Vec<u8> {
+    [header(5, 2), value.to_be_bytes().to_vec()].concat()
+}
+
+fn uint32(value: u32) -> Vec<u8> {
+    [header(6, 4), value.to_be_bytes().to_vec()].concat()
+}
+
+fn uint64(value: u64) -> Vec<u8> {
+    [header(9, 8), value.to_be_bytes().to_vec()].concat()
+}
+
+fn map(entries: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+    let mut out = header(7, entries.len());
+    for (key, value) in entries {
+        out.extend(string(key));
+        out.extend(value);
+    }
+    out
+}
+
+fn array(items: Vec<Vec<u8>>) -> Vec<u8> {
+    [header(11, items.len()), items.concat()].concat()
+}
+
+/// Writes an IPv4 database (24-bit records) that maps the single network `network`/24 to `country`.
+fn write_country_db(network: [u8; 3], country: &str) -> TempDb {
+    let node_count = 24u32;
+    let not_found = node_count;
+    let data_pointer = node_count + 16; // the record lives at offset 0 of the data section
+    let mut tree = Vec::new();
+    for i in 0..24 {
+        let bit = (network[i / 8] >> (7 - i % 8)) & 1;
+        let next = if i == 23 { data_pointer } else { i as u32 + 1 };
+        let (left, right) = if bit == 0 { (next, not_found) } else { (not_found, next) };
+        tree.extend(&left.to_be_bytes()[1..]);
+        tree.extend(&right.to_be_bytes()[1..]);
+    }
+
+    let data = map(vec![("country", map(vec![("iso_code", string(country))]))]);
+    let metadata = map(vec![
+        ("binary_format_major_version", uint16(2)),
+        ("binary_format_minor_version", uint16(0)),
+        ("build_epoch", uint64(0)),
+        ("database_type", string("Test-Country")),
+        ("description", map(vec![])),
+        ("ip_version", uint16(4)),
+        ("languages", array(vec![string("en")])),
+        ("node_count", uint32(node_count)),
+        ("record_size", uint16(24)),
+    ]);
+    let file = [tree, vec![0; 16], data, b"\xAB\xCD\xEFMaxMind.com".to_vec(), metadata].concat();
+
+    let db = TempDb(std::env::temp_dir().join(format!("chat-geoip-{}.mmdb", Uuid::new_v4())));
+    std::fs::write(&db.0, file).unwrap();
+    db
+}
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+#[test]
+fn known_addresses_resolve_to_their_country() {
+    let db = write_country_db([81, 2, 69], "GB");
+    let geoip = GeoIp::new(Box::new(MaxMindCountries::open(db.0.to_str().unwrap()).unwrap()));
+    assert_eq!(geoip.country_of(ip("81.2.69.142")).as_deref(), Some("GB"));
+    assert_eq!(geoip.country_of(ip("::ffff:81.2.69.142")).as_deref(), Some("GB"));
+    assert_eq!(geoip.country_of(ip("81.2.70.1")), None);
+}
+
+#[test]
+fn private_addresses_are_never_looked_up() {
+    struct Everywhere;
+    impl CountryLookup for Everywhere {
+        fn country(&self, _ip: IpAddr) -> Option<String> {
+            Some("ZZ".to_string())
+        }
+    }
+    let geoip = GeoIp::new(Box::new(Everywhere));
+    for private in ["127.0.0.1", "10.1.2.3", "192.168.0.5", "169.254.0.1", "::1", "fd00::1", "fe80::1"] {
+        assert_eq!(geoip.country_of(ip(private)), None, "{}", private);
+    }
+    assert_eq!(geoip.country_of(ip("8.8.8.8")).as_deref(), Some("ZZ"));
+}
+
+#[tokio::test]
+async fn whois_shows_admins_the_country_and_is_audited() {
+    let db = write_country_db([81, 2, 69], "GB");
+    let path = db.0.to_str().unwrap().to_string();
+    let server = TestServer::start_with(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.geoip = Some(GeoIp::new(Box::new(MaxMindCountries::open(&path).unwrap())));
+    })
+    .await;
+    let mut admin = server.connect("lobby").await;
+    let mut bob = server.connect("lobby").await;
+    admin.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    admin.join_as("root").await;
+    bob.join_as("bob").await;
+    admin.recv_type("UserJoined").await;
+
+    bob.send_text("/whois root").await;
+    assert_eq!(bob.recv_type("Notice").await["message"], "Only administrators can use /whois.");
+
+    admin.send_text("/admin secret").await;
+    admin.recv_type("Notice").await;
+    // Test clients connect over loopback, which has no country.
+    admin.send_text("/whois bob").await;
+    assert_eq!(admin.recv_type("Notice").await["message"], "'bob' is connected to room 'lobby' (country: unknown).");
+    admin.send_text("/whois nobody").await;
+    assert_eq!(admin.recv_type("Notice").await["message"], "'nobody' is not connected.");
+
+    let audit = server.state.store.load_audit_log(Some("lobby"), 1, 10).await.unwrap();
+    let lookups: Vec<_> = audit.iter().filter(|entry| entry.action == "whois").collect();
+    assert_eq!(lookups.len(), 2);
+    assert!(lookups.iter().all(|entry| entry.actor == "root"));
+    assert!(lookups.iter().any(|entry| entry.target.as_deref() == Some("bob") && entry.detail.as_deref() == Some("country=unknown")));
+}
@@ -0,0 +1,29 @@
+// tests/handshake.rs
+
+mod common;
+
+use common::TestServer;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn stalled_handshakes_are_dropped() {
+    let server = TestServer::start_with(|config| config.handshake_timeout = Some(Duration::from_millis(500))).await;
+
+    // Start an upgrade request and never finish its headers.
+    let mut stalled = TcpStream::connect(server.addr).await.unwrap();
+    stalled.write_all(b"GET /ws/lobby HTTP/1.1\r\nHost: localhost\r\n").await.unwrap();
+    let mut buf = [0u8; 64];
+    let read = tokio::time::timeout(Duration::from_secs(3), stalled.read(&mut buf))
+        .await
+        .expect("stalled connection was not dropped");
+    assert!(matches!(read, Ok(0) | Err(_)), "expected the connection to be closed, got {:?}", read);
+
+    // A client that completed its handshake may stay past the deadline.
+    let mut client = server.connect("lobby").await;
+    client.recv_type("Welcome").await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    client.join_as("alice").await;
+    assert_eq!(client.recv_type("OwnershipChanged").await["owner"], "alice");
+}
@@ -0,0 +1,282 @@
+// tests/history_limit.rs
+
+mod common;
+
+use async_trait::async_trait;
+use chat_server::{
+    memory_store::MemoryStore,
+    models::{ServerMessage, ThreadReply},
+    store::{AuditEntry, DbResult, MessageStore, RecentRoom, Report, RoomOwnership, RoomTopic, ScheduledMessage},
+};
+use chrono::{DateTime, Utc};
+use common::TestServer;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// A `MemoryStore` whose paginated history loads are slow and record how many run at once, and whose single
+/// lookups (`/history-count`, `/get`, `/thread`, `/report`) wait while `hold_lookups` is set.
+#[derive(Default)]
+struct SlowHistoryStore {
+    inner: MemoryStore,
+    running: AtomicUsize,
+    max_running: AtomicUsize,
+    hold_lookups: AtomicBool,
+    held_lookups: AtomicUsize,
+}
+
+impl SlowHistoryStore {
+    async fn wait_while_held(&self) {
+        self.held_lookups.fetch_add(1, Ordering::SeqCst);
+        while self.hold_lookups.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.held_lookups.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl MessageStore for SlowHistoryStore {
+    async fn save_message(&self, room_name: &str, seq: u64, message: &ServerMessage, created_at: DateTime<Utc>) -> DbResult<()> {
+        self.inner.save_message(room_name, seq, message, created_at).await
+    }
+
+    async fn load_history(&self, room_name: &str, limit: usize) -> DbResult<VecDeque<ServerMessage>> {
+        self.inner.load_history(room_name, limit).await
+    }
+
+    async fn load_history_paginated(
+        &self,
+        room_name: &str,
+        page: i64,
+        page_size: i64,
+        include_presence: bool,
+    ) -> DbResult<VecDeque<(ServerMessage, BTreeMap<String, i64>, DateTime<Utc>)>> {
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_running.fetch_max(running, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        self.inner.load_history_paginated(room_name, page, page_size, include_presence).await
+    }
+
+    async fn get_message_count(&self, room_name: &str) -> DbResult<i64> {
+        self.wait_while_held().await;
+        self.inner.get_message_count(room_name).await
+    }
+
+    async fn get_latest_seq(&self, room_name: &str) -> DbResult<u64> {
+        self.inner.get_latest_seq(room_name).await
+    }
+
+    async fn set_latest_seq(&self, room_name: &str, seq: u64) -> DbResult<()> {
+        self.inner.set_latest_seq(room_name, seq).await
+    }
+
+    async fn stream_room_messages(&self, room_name: String, tx: mpsc::Sender<DbResult<ServerMessage>>) {
+        self.inner.stream_room_messages(room_name, tx).await
+    }
+
+    async fn add_reaction(&self, room_name: &str, message_id: Uuid, username: &str, emoji: &str) -> DbResult<bool> {
+        self.inner.add_reaction(room_name, message_id, username, emoji).await
+    }
+
+    async fn delete_message(&self, room_name: &str, message_id: Uuid) -> DbResult<bool> {
+        self.inner.delete_message(room_name, message_id).await
+    }
+
+    async fn clear_messages(&self, room_name: &str, seq: u64) -> DbResult<u64> {
+        self.inner.clear_messages(room_name, seq).await
+    }
+
+    async fn load_message_expiries(&self) -> DbResult<Vec<(String, Uuid, DateTime<Utc>)>> {
+        self.inner.load_message_expiries().await
+    }
+
+    async fn is_room_closed(&self, room_name: &str) -> DbResult<bool> {
+        self.inner.is_room_closed(room_name).await
+    }
+
+    async fn set_room_closed(&self, room_name: &str, closed: bool) -> DbResult<()> {
+        self.inner.set_room_closed(room_name, closed).await
+    }
+
+    async fn is_room_registered(&self, room_name: &str) -> DbResult<bool> {
+        self.inner.is_room_registered(room_name).await
+    }
+
+    async fn set_room_registered(&self, room_name: &str, registered: bool) -> DbResult<()> {
+        self.inner.set_room_registered(room_name, registered).await
+    }
+
+    async fn get_room_topic(&self, room_name: &str) -> DbResult<Option<RoomTopic>> {
+        self.inner.get_room_topic(room_name).await
+    }
+
+    async fn set_room_topic(&self, room_name: &str, topic: &RoomTopic) -> DbResult<()> {
+        self.inner.set_room_topic(room_name, topic).await
+    }
+
+    async fn get_room_metadata(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        self.inner.get_room_metadata(room_name).await
+    }
+
+    async fn set_room_metadata(&self, room_name: &str, metadata: &BTreeMap<String, String>) -> DbResult<()> {
+        self.inner.set_room_metadata(room_name, metadata).await
+    }
+
+    async fn get_public_keys(&self, room_name: &str) -> DbResult<BTreeMap<String, String>> {
+        self.inner.get_public_keys(room_name).await
+    }
+
+    async fn set_public_keys(&self, room_name: &str, keys: &BTreeMap<String, String>) -> DbResult<()> {
+        self.inner.set_public_keys(room_name, keys).await
+    }
+
+    async fn get_username_pattern(&self, room_name: &str) -> DbResult<Option<String>> {
+        self.inner.get_username_pattern(room_name).await
+    }
+
+    async fn set_username_pattern(&self, room_name: &str, pattern: Option<&str>) -> DbResult<()> {
+        self.inner.set_username_pattern(room_name, pattern).await
+    }
+
+    async fn get_room_ownership(&self, room_name: &str) -> DbResult<RoomOwnership> {
+        self.inner.get_room_ownership(room_name).await
+    }
+
+    async fn set_room_ownership(&self, room_name: &str, ownership: &RoomOwnership) -> DbResult<()> {
+        self.inner.set_room_ownership(room_name, ownership).await
+    }
+
+    async fn load_message(&self, room_name: &str, message_id: Uuid) -> DbResult<Option<ServerMessage>> {
+        self.wait_while_held().await;
+        self.inner.load_message(room_name, message_id).await
+    }
+
+    async fn load_thread(&self, room_name: &str, root: Uuid, max_depth: u32, limit: usize) -> DbResult<Vec<ThreadReply>> {
+        self.wait_while_held().await;
+        self.inner.load_thread(room_name, root, max_depth, limit).await
+    }
+
+    async fn get_pinned_messages(&self, room_name: &str) -> DbResult<Vec<ServerMessage>> {
+        self.inner.get_pinned_messages(room_name).await
+    }
+
+    async fn set_pinned_ids(&self, room_name: &str, message_ids: &[Uuid]) -> DbResult<()> {
+        self.inner.set_pinned_ids(room_name, message_ids).await
+    }
+
+    async fn get_secret_hash(&self, username: &str) -> DbResult<Option<String>> {
+        self.inner.get_secret_hash(username).await
+    }
+
+    async fn register_user(&self, username: &str, secret_hash: &str) -> DbResult<bool> {
+        self.inner.register_user(username, secret_hash).await
+    }
+
+    async fn get_recent_rooms(&self, since: DateTime<Utc>) -> DbResult<Vec<RecentRoom>> {
+        self.inner.get_recent_rooms(since).await
+    }
+
+    async fn add_report(&self, report: &Report) -> DbResult<bool> {
+        self.wait_while_held().await;
+        self.inner.add_report(report).await
+    }
+
+    async fn load_reports(&self, page: i64, page_size: i64) -> DbResult<Vec<Report>> {
+        self.inner.load_reports(page, page_size).await
+    }
+
+    async fn log_audit(&self, entry: &AuditEntry) -> DbResult<()> {
+        self.inner.log_audit(entry).await
+    }
+
+    async fn load_audit_log(&self, room_name: Option<&str>, page: i64, page_size: i64) -> DbResult<Vec<AuditEntry>> {
+        self.inner.load_audit_log(room_name, page, page_size).await
+    }
+
+    async fn get_last_seen(&self, room_name: &str, username: &str) -> DbResult<Option<DateTime<Utc>>> {
+        self.inner.get_last_seen(room_name, username).await
+    }
+
+    async fn set_last_seen(&self, room_name: &str, username: &str, seen_at: DateTime<Utc>) -> DbResult<()> {
+        self.inner.set_last_seen(room_name, username, seen_at).await
+    }
+
+    async fn add_scheduled_message(&self, message: &ScheduledMessage) -> DbResult<()> {
+        self.inner.add_scheduled_message(message).await
+    }
+
+    async fn cancel_scheduled_message(&self, id: Uuid) -> DbResult<bool> {
+        self.inner.cancel_scheduled_message(id).await
+    }
+
+    async fn take_due_scheduled_messages(&self, now: DateTime<Utc>) -> DbResult<Vec<ScheduledMessage>> {
+        self.inner.take_due_scheduled_messages(now).await
+    }
+
+    fn undecodable_messages(&self) -> u64 {
+        self.inner.undecodable_messages()
+    }
+}
+
+#[tokio::test]
+async fn concurrent_history_loads_are_capped() {
+    let store = Arc::new(SlowHistoryStore::default());
+    let stored = ServerMessage::UserJoined { username: "someone".to_string(), sent_at: None };
+    store.save_message("general", 0, &stored, Utc::now()).await.unwrap();
+    let server = TestServer::start_with_store(store.clone(), |config| {
+        config.history_load_limit = 2;
+        config.history_includes_presence = true;
+    })
+    .await;
+
+    let mut clients = Vec::new();
+    for i in 0..8 {
+        let mut client = server.connect("general").await;
+        client.join_as(&format!("user{}", i)).await;
+        clients.push(client);
+    }
+    for client in &mut clients {
+        client.send_text("/history").await;
+    }
+    // Every request is served eventually; the extra ones just wait their turn.
+    for client in &mut clients {
+        client.recv_type("HistoryEntry").await;
+    }
+
+    assert_eq!(store.max_running.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn slow_lookups_do_not_hold_up_other_clients() {
+    let store = Arc::new(SlowHistoryStore::default());
+    let server = TestServer::start_with_store(store.clone(), |_| {}).await;
+    let mut alice = server.connect("general").await;
+    alice.join_as("alice").await;
+    let mut bob = server.connect("general").await;
+    bob.join_as("bob").await;
+
+    let id = Uuid::new_v4();
+    let lookups = [
+        ("/history-count".to_string(), "MessageCount"),
+        (format!("/get {}", id), "Error"),
+        (format!("/thread {}", id), "Error"),
+        (format!("/report {}", id), "Notice"),
+    ];
+    for (command, reply) in lookups {
+        store.hold_lookups.store(true, Ordering::SeqCst);
+        alice.send_text(&command).await;
+        while store.held_lookups.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // Alice's lookup is stuck in the store, but the rooms lock isn't held while it waits.
+        bob.send_text("/ping").await;
+        bob.recv_type("Pong").await;
+        store.hold_lookups.store(false, Ordering::SeqCst);
+        alice.recv_type(reply).await;
+    }
+}
@@ -0,0 +1,32 @@
+// tests/lru.rs
+
+use chat_server::lru::LruMap;
+
+#[test]
+fn filling_past_capacity_evicts_the_oldest_entries() {
+    let mut users = LruMap::new(3);
+    for (i, name) in ["ann", "ben", "cat", "dan", "eve"].into_iter().enumerate() {
+        let evicted = users.insert(name.to_string(), i);
+        match name {
+            "dan" => assert_eq!(evicted, Some(("ann".to_string(), 0))),
+            "eve" => assert_eq!(evicted, Some(("ben".to_string(), 1))),
+            _ => assert_eq!(evicted, None),
+        }
+    }
+    assert_eq!(users.len(), 3);
+    assert!(!users.contains_key(&"ann".to_string()) && !users.contains_key(&"ben".to_string()));
+}
+
+#[test]
+fn lookups_and_updates_keep_entries_alive() {
+    let mut users = LruMap::new(2);
+    users.insert("ann".to_string(), 1);
+    users.insert("ben".to_string(), 2);
+    assert_eq!(users.get(&"ann".to_string()), Some(&1)); // ben is now the least recently used
+    assert_eq!(users.insert("cat".to_string(), 3), Some(("ben".to_string(), 2)));
+
+    // Replacing an entry never evicts another one.
+    assert_eq!(users.insert("ann".to_string(), 10), None);
+    assert_eq!(users.insert("dan".to_string(), 4), Some(("cat".to_string(), 3)));
+    assert_eq!(users.remove(&"ann".to_string()), Some(10));
+}
@@ -0,0 +1,89 @@
+// tests/migrations.rs
+//
+// Schema migrations, exercised against SQLite files in the temp directory.
+
+use chat_server::models::ServerMessage;
+use chat_server::sqlite_store::{SqliteStore, MIGRATIONS};
+use chat_server::store::{MessageStore, RoomTopic};
+use chrono::Utc;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// A database file that is removed when the test ends.
+struct TempDb(PathBuf);
+
+impl TempDb {
+    fn new() -> Self {
+        TempDb(std::env::temp_dir().join(format!("chat-migrations-{}.db", Uuid::new_v4())))
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn latest_version() -> i64 {
+    MIGRATIONS.last().unwrap().version
+}
+
+#[tokio::test]
+async fn migrations_can_run_repeatedly() {
+    let db = TempDb::new();
+    let first = SqliteStore::connect(db.path()).await.expect("first run failed");
+    assert_eq!(first.schema_version().await.unwrap(), latest_version());
+    drop(first);
+
+    let second = SqliteStore::connect(db.path()).await.expect("second run failed");
+    assert_eq!(second.schema_version().await.unwrap(), latest_version());
+
+    let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(db.path())).await.unwrap();
+    let recorded: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM schema_version").fetch_one(&pool).await.unwrap();
+    assert_eq!(recorded, MIGRATIONS.len() as i64, "each migration is recorded once");
+}
+
+#[tokio::test]
+async fn databases_from_before_versioning_are_upgraded() {
+    let db = TempDb::new();
+    let options = SqliteConnectOptions::new().filename(db.path()).create_if_missing(true);
+    let pool = SqlitePool::connect_with(options).await.unwrap();
+    sqlx::query("CREATE TABLE rooms_meta (room TEXT PRIMARY KEY, closed BOOLEAN NOT NULL DEFAULT FALSE)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO rooms_meta (room, closed) VALUES ('archive', TRUE)").execute(&pool).await.unwrap();
+    pool.close().await;
+
+    let store = SqliteStore::connect(db.path()).await.expect("upgrade failed");
+    assert_eq!(store.schema_version().await.unwrap(), latest_version());
+    assert!(store.is_room_closed("archive").await.unwrap(), "existing rows survive");
+    store.set_room_topic("archive", &RoomTopic { topic: "old stuff".to_string(), set_by: "alice".to_string() }).await.unwrap();
+    assert_eq!(store.get_room_topic("archive").await.unwrap().unwrap().topic, "old stuff");
+}
+
+#[tokio::test]
+async fn latest_seq_comes_from_saved_and_recorded_numbers() {
+    let db = TempDb::new();
+    let store = SqliteStore::connect(db.path()).await.unwrap();
+    let joined = |username: &str| ServerMessage::UserJoined { username: username.to_string(), sent_at: None };
+
+    // Rows saved before messages were numbered are counted.
+    store.save_message("legacy", 0, &joined("alice"), Utc::now()).await.unwrap();
+    store.save_message("legacy", 0, &joined("bob"), Utc::now()).await.unwrap();
+    assert_eq!(store.get_latest_seq("legacy").await.unwrap(), 2);
+
+    store.save_message("general", 4, &joined("alice"), Utc::now()).await.unwrap();
+    assert_eq!(store.get_latest_seq("general").await.unwrap(), 4);
+    store.set_latest_seq("general", 6).await.unwrap();
+    assert_eq!(store.get_latest_seq("general").await.unwrap(), 6);
+    store.save_message("general", 7, &joined("bob"), Utc::now()).await.unwrap();
+    assert_eq!(store.get_latest_seq("general").await.unwrap(), 7);
+    store.set_latest_seq("general", 5).await.unwrap();
+    assert_eq!(store.get_latest_seq("general").await.unwrap(), 7, "a lower number changes nothing");
+}
@@ -0,0 +1,77 @@
+// tests/moderation.rs
+
+mod common;
+
+use chat_server::moderation::{ContentModeration, ContentScorer, ModerationAction, RegexScorer};
+use common::TestServer;
+use serde_json::json;
+
+fn categories() -> RegexScorer {
+    RegexScorer::new([
+        ("mild".to_string(), r"(?i)\b(darn|heck)\b".to_string(), 1),
+        ("insults".to_string(), r"(?i)\b(idiot|moron)\b".to_string(), 3),
+        ("threats".to_string(), r"(?i)\bhurt you\b".to_string(), 5),
+    ])
+}
+
+fn moderation() -> ContentModeration {
+    ContentModeration::new(Box::new(categories()), Some(1), Some(5))
+}
+
+#[test]
+fn scores_add_up_over_matching_categories() {
+    let scorer = categories();
+    assert_eq!(scorer.score("good morning"), 0);
+    assert_eq!(scorer.score("heck, darn it"), 1); // a category counts once
+    assert_eq!(scorer.score("you idiot, I will hurt you"), 8);
+}
+
+#[test]
+fn clean_mild_and_severe_content_map_to_actions() {
+    let moderation = moderation();
+    assert_eq!(moderation.assess("good morning"), (0, ModerationAction::Allow));
+    assert_eq!(moderation.assess("oh heck"), (1, ModerationAction::Flag));
+    assert_eq!(moderation.assess("moron, I will hurt you"), (8, ModerationAction::Block));
+}
+
+#[test]
+fn scorers_are_pluggable() {
+    struct Shouting;
+    impl ContentScorer for Shouting {
+        fn score(&self, content: &str) -> u32 {
+            content.chars().filter(char::is_ascii_uppercase).count() as u32
+        }
+    }
+    let moderation = ContentModeration::new(Box::new(Shouting), None, Some(4));
+    assert_eq!(moderation.assess("Hi"), (1, ModerationAction::Allow));
+    assert_eq!(moderation.assess("STOP IT"), (6, ModerationAction::Block));
+}
+
+#[tokio::test]
+async fn flagged_messages_are_tagged_and_reported_and_blocked_ones_dropped() {
+    let server = TestServer::start_with(|config| config.content_moderation = Some(moderation())).await;
+    let mut alice = server.connect("general").await;
+    let mut bob = server.connect("general").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    alice.send_json(json!({ "type": "Message", "content": "you moron, I will hurt you" })).await;
+    assert!(alice.recv_type("Notice").await["message"].as_str().unwrap().contains("blocked"));
+    alice.send_json(json!({ "type": "Message", "content": "oh heck" })).await;
+    alice.send_json(json!({ "type": "Message", "content": "good morning" })).await;
+
+    let flagged = bob.recv_type("NewMessage").await;
+    assert_eq!(flagged["content"], "oh heck");
+    assert_eq!(flagged["severity"], 1);
+    let clean = bob.recv_type("NewMessage").await;
+    assert_eq!(clean["content"], "good morning");
+    assert!(clean.get("severity").is_none());
+
+    let reports = server.state.store.load_reports(1, 10).await.unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].reporter, "content-filter");
+    assert_eq!(reports[0].message_id.to_string(), flagged["id"].as_str().unwrap());
+}
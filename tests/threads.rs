@@ -0,0 +1,85 @@
+// tests/threads.rs
+//
+// Reply threading and `/thread`, against both the in-memory and the SQLite store.
+
+mod common;
+
+use chat_server::memory_store::MemoryStore;
+use chat_server::sqlite_store::SqliteStore;
+use chat_server::store::MessageStore;
+use common::{TestClient, TestServer};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A database file that is removed when the test ends.
+struct TempDb(PathBuf);
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Has `alice` post `content` (as a reply to `reply_to`, if given) and returns its id as `bob` sees it.
+async fn post(alice: &mut TestClient, bob: &mut TestClient, content: &str, reply_to: Option<&Value>) -> Value {
+    let mut message = json!({ "type": "Message", "content": content });
+    if let Some(parent) = reply_to {
+        message["reply_to"] = parent.clone();
+    }
+    alice.send_json(message).await;
+    let received = bob.recv_type("NewMessage").await;
+    assert_eq!(received["content"], content);
+    assert_eq!(received.get("reply_to"), reply_to);
+    received["id"].clone()
+}
+
+/// Builds `root -> (a1 -> b1 -> c1), a2` with a depth limit of 2 and checks what `/thread root` lists.
+async fn check_thread_listing(store: Arc<dyn MessageStore>) {
+    let server = TestServer::start_with_store(store, |config| config.thread_max_depth = 2).await;
+    let mut alice = server.connect("threads").await;
+    let mut bob = server.connect("threads").await;
+    alice.recv_type("Welcome").await;
+    bob.recv_type("Welcome").await;
+    alice.join_as("alice").await;
+    bob.join_as("bob").await;
+    alice.recv_type("UserJoined").await;
+
+    let root = post(&mut alice, &mut bob, "root", None).await;
+    let a1 = post(&mut alice, &mut bob, "a1", Some(&root)).await;
+    post(&mut alice, &mut bob, "unrelated", None).await;
+    let b1 = post(&mut alice, &mut bob, "b1", Some(&a1)).await;
+    post(&mut alice, &mut bob, "a2", Some(&root)).await;
+    post(&mut alice, &mut bob, "c1", Some(&b1)).await;
+
+    let missing = Uuid::new_v4();
+    alice.send_json(json!({ "type": "Message", "content": "lost", "reply_to": missing })).await;
+    assert!(alice.recv_type("Error").await["message"].as_str().unwrap().contains(&missing.to_string()));
+    // Alice's messages are handled in order, so once the pong arrives every message above is stored.
+    alice.send_text("/ping").await;
+    alice.recv_type("Pong").await;
+
+    bob.send_text(&format!("/thread {}", root.as_str().unwrap())).await;
+    let thread = bob.recv_type("Thread").await;
+    assert_eq!(thread["root"]["content"], "root");
+    let replies: Vec<(u64, &str)> = thread["replies"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|reply| (reply["depth"].as_u64().unwrap(), reply["message"]["content"].as_str().unwrap()))
+        .collect();
+    assert_eq!(replies, [(1, "a1"), (2, "b1"), (1, "a2")], "c1 is below the depth limit");
+
+    bob.send_text(&format!("/thread {}", missing)).await;
+    assert!(bob.recv_type("Error").await["message"].as_str().unwrap().contains("not found"));
+}
+
+#[tokio::test]
+async fn thread_listing_returns_nested_replies() {
+    check_thread_listing(Arc::new(MemoryStore::default())).await;
+
+    let db = TempDb(std::env::temp_dir().join(format!("chat-threads-{}.db", Uuid::new_v4())));
+    let store = SqliteStore::connect(db.0.to_str().unwrap()).await.expect("Failed to open SQLite store");
+    check_thread_listing(Arc::new(store)).await;
+}
@@ -0,0 +1,45 @@
+// tests/truncate.rs
+
+use chat_server::config::{truncate_for_display, truncate_graphemes};
+
+#[test]
+fn short_text_is_left_alone() {
+    assert_eq!(truncate_graphemes("hello", 5), "hello");
+    assert_eq!(truncate_graphemes("", 0), "");
+}
+
+#[test]
+fn multibyte_characters_are_never_split() {
+    // "é" is two bytes and "€" three, so byte limits land in the middle of them.
+    assert_eq!(truncate_graphemes("aé", 2), "a");
+    assert_eq!(truncate_graphemes("a€b", 3), "a");
+    assert_eq!(truncate_graphemes("a€b", 4), "a€");
+    assert_eq!(truncate_graphemes("€", 1), "");
+}
+
+#[test]
+fn emoji_and_combined_characters_stay_whole() {
+    let family = "👨\u{200D}👩\u{200D}👧"; // one grapheme, 18 bytes
+    assert_eq!(truncate_graphemes(&format!("hi {}", family), 10), "hi ");
+    assert_eq!(truncate_graphemes(&format!("{}!", family), family.len()), family);
+
+    let flag = "🇩🇪"; // two regional indicators, one grapheme
+    assert_eq!(truncate_graphemes(&format!("{}{}", flag, flag), 12), flag);
+
+    let combined = "e\u{0301}"; // "e" followed by a combining acute accent
+    assert_eq!(truncate_graphemes(&format!("{}x", combined), 2), "");
+    assert_eq!(truncate_graphemes(&format!("{}x", combined), 3), combined);
+}
+
+#[test]
+fn display_truncation_marks_the_cut_and_keeps_graphemes_whole() {
+    assert_eq!(truncate_for_display("short", 5), "short");
+    // The ellipsis takes three of the ten bytes.
+    assert_eq!(truncate_for_display("abcdefghijk", 10), "abcdefg…");
+
+    let family = "👨\u{200D}👩\u{200D}👧"; // one grapheme, 18 bytes
+    // "hi " and one family take 21 bytes, so 24 fits it and the ellipsis while 23 doesn't.
+    assert_eq!(truncate_for_display(&format!("hi {}{}", family, family), 23), "hi …");
+    assert_eq!(truncate_for_display(&format!("hi {}{}", family, family), 24), format!("hi {}…", family));
+    assert_eq!(truncate_for_display("e\u{0301}e\u{0301}", 5), "…");
+}
@@ -0,0 +1,103 @@
+// tests/undecodable.rs
+//
+// Stored messages that no longer decode: they are logged, counted, and optionally shown as a placeholder.
+
+mod common;
+
+use chat_server::models::ServerMessage;
+use chat_server::sqlite_store::SqliteStore;
+use chat_server::store::MessageStore;
+use chrono::Utc;
+use common::TestServer;
+use serde_json::Value;
+use sqlx::sqlite::SqlitePool;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A database file that is removed when the test ends.
+struct TempDb(PathBuf);
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn chat(content: &str) -> ServerMessage {
+    ServerMessage::NewMessage {
+        id: Uuid::new_v4(),
+        username: "alice".to_string(),
+        content: content.to_string(),
+        encrypted: false,
+        color: None,
+        severity: None,
+        reply_to: None,
+        expires_at: None,
+        sent_at: None,
+    }
+}
+
+/// Stores `before`, a row whose JSON isn't a `ServerMessage`, then `after`, and returns the bad row's id.
+async fn store_with_bad_row(store: &SqliteStore, db: &TempDb, room: &str) -> i64 {
+    store.save_message(room, 0, &chat("before"), Utc::now()).await.unwrap();
+    let pool = SqlitePool::connect(&format!("sqlite://{}", db.0.display())).await.unwrap();
+    let row_id: i64 = sqlx::query_scalar("INSERT INTO messages (room, message, timestamp) VALUES (?, ?, ?) RETURNING id")
+        .bind(room)
+        .bind(r#"{"type":"RetiredMessageKind","body":"?"}"#)
+        .bind(Utc::now())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+    store.save_message(room, 0, &chat("after"), Utc::now()).await.unwrap();
+    row_id
+}
+
+fn contents(history: &[ServerMessage]) -> Vec<String> {
+    history
+        .iter()
+        .map(|message| match message {
+            ServerMessage::NewMessage { content, .. } => content.clone(),
+            ServerMessage::Unreadable { row_id } => format!("unreadable {}", row_id),
+            other => panic!("unexpected message {:?}", other),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn undecodable_rows_are_counted_and_left_out_by_default() {
+    let db = TempDb(std::env::temp_dir().join(format!("chat-undecodable-{}.db", Uuid::new_v4())));
+    let store = SqliteStore::connect(db.0.to_str().unwrap()).await.unwrap();
+    store_with_bad_row(&store, &db, "archive").await;
+
+    let history: Vec<_> = store.load_history("archive", 10).await.unwrap().into();
+    assert_eq!(contents(&history), ["before", "after"]);
+    assert_eq!(store.undecodable_messages(), 1);
+
+    let server = TestServer::start_with_store(Arc::new(store), |config| config.admin_token = Some("secret".to_string())).await;
+    let metrics: Value = reqwest::Client::new()
+        .get(server.url("/admin/metrics"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(metrics["undecodable_messages"], 1);
+}
+
+#[tokio::test]
+async fn tombstones_mark_where_undecodable_rows_were() {
+    let db = TempDb(std::env::temp_dir().join(format!("chat-undecodable-{}.db", Uuid::new_v4())));
+    let store = SqliteStore::connect(db.0.to_str().unwrap()).await.unwrap().with_tombstones(true);
+    let row_id = store_with_bad_row(&store, &db, "archive").await;
+    let tombstone = format!("unreadable {}", row_id);
+
+    let history: Vec<_> = store.load_history("archive", 10).await.unwrap().into();
+    assert_eq!(contents(&history), ["before", tombstone.as_str(), "after"]);
+    let page: Vec<_> = store.load_history_paginated("archive", 1, 10, false).await.unwrap().into_iter().map(|(message, ..)| message).collect();
+    assert_eq!(contents(&page), ["before", tombstone.as_str(), "after"]);
+    assert_eq!(store.undecodable_messages(), 2, "each failed decode is counted");
+}